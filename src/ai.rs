@@ -1,14 +1,11 @@
 use rand::Rng;
-use rand;
 use std::any::TypeId;
 
 use ship::{Ship, ShipPlans};
 use module;
 use module::{IModule, ModuleClass};
 
-pub fn run_ai(ship: &Ship, plans: &mut ShipPlans, enemy_ships: &Vec<&Ship>) {
-    // Random number generater
-    let mut rng = rand::thread_rng();
+pub fn run_ai<R: Rng>(ship: &Ship, plans: &mut ShipPlans, enemy_ships: &Vec<&Ship>, rng: &mut R) {
 
     // Activate stuff, notice order of priority
     let mut activating_stuff = true;
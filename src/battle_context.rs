@@ -1,32 +1,37 @@
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter;
 use std::slice;
 
 use module::ModelStore;
 use net::{ClientId, InPacket, OutPacket};
-use ship::{Ship, ShipId, ShipIndex};
+use ship::{Ship, ShipId, ShipIndex, ShipPlans};
 use sim::SimEvents;
+use team::Alliances;
+use turn_config::TurnConfig;
+use turn_seed::TurnSeed;
 
 #[cfg(feature = "client")]
 use sim::SimEffects;
 #[cfg(feature = "client")]
 use asset_store::AssetStore;
 
-// Time value of 1 tick in seconds
-pub static TICKS_PER_SECOND: u32 = 20;
-
-pub fn tick_to_time(tick: u32) -> f64 {
-    tick as f64 / TICKS_PER_SECOND as f64
-}
-
 pub struct BattleContext {
     pub ships_ship_id: HashMap<ShipId, usize>,
     pub ships_client_id: HashMap<ClientId, usize>,
 
     pub ships: Vec<Option<Ship>>,
     
+    // How the sector this battle is in paces its turns
+    pub turn_config: TurnConfig,
+    
+    // Who's on whose side in this sector
+    pub alliances: Alliances,
+    
+    // Where this turn's random numbers come from
+    pub turn_seed: TurnSeed,
+    
     free_ship_indices: Vec<usize>,
 }
 
@@ -61,6 +66,9 @@ impl BattleContext {
             ships_ship_id: ships_ship_id,
             ships_client_id: ships_client_id,
             ships: ships,
+            turn_config: TurnConfig::new(),
+            alliances: Alliances::new(),
+            turn_seed: TurnSeed::new(0),
             free_ship_indices: vec!(),
         }
     }
@@ -120,6 +128,25 @@ impl BattleContext {
         ShipIndex(index as u32)
     }
     
+    // Put a ship back at the index it has on the server. Clients only hear about the ships they can
+    // see, so they can't just add them to the end.
+    pub fn insert_ship(&mut self, ship: Ship) -> ShipIndex {
+        let index = ship.index.to_usize();
+        
+        while self.ships.len() <= index {
+            self.ships.push(None);
+        }
+        
+        self.ships_ship_id.insert(ship.id, index);
+        if let Some(client_id) = ship.client_id {
+            self.ships_client_id.insert(client_id, index);
+        }
+        
+        self.ships[index] = Some(ship);
+        
+        ShipIndex(index as u32)
+    }
+    
     pub fn add_ships(&mut self, ships: Vec<Ship>) {
         for ship in ships {
             self.add_ship(ship);
@@ -131,6 +158,54 @@ impl BattleContext {
     
         self.ships[ship_index.to_usize()].take().expect("Tried to remove non-existant ship")
     }
+    
+    // Drop any targets in `plans` that `ship` isn't allowed to shoot at, whatever the player's
+    // client let them pick
+    pub fn remove_forbidden_targets(&self, ship: ShipIndex, plans: &mut ShipPlans) {
+        let ship = ship.get(self);
+        
+        for module_plans in plans.module_plans.iter_mut() {
+            let forbidden =
+                match module_plans.target {
+                    Some(ref target) => {
+                        self.ships.get(target.ship.to_usize())
+                            .and_then(|s| s.as_ref())
+                            .map_or(false, |target| !self.alliances.can_target(ship, target))
+                    },
+                    None => false,
+                };
+            
+            if forbidden {
+                module_plans.target = None;
+            }
+        }
+    }
+
+    // Ships within `sensor_range` of `ship`, along with everything they're targeting, since a ship
+    // can't be simulated without its targets
+    pub fn ships_in_sensor_range(&self, ship: ShipIndex, sensor_range: f64) -> HashSet<ShipIndex> {
+        let position = ship.get(self).position;
+        
+        let mut visible: HashSet<ShipIndex> =
+            self.ships_iter()
+                .filter(|s| (s.position - position).length() <= sensor_range)
+                .map(|s| s.index)
+                .collect();
+        
+        let mut unchecked: Vec<ShipIndex> = visible.iter().cloned().collect();
+        while let Some(index) = unchecked.pop() {
+            for module in &index.get(self).modules {
+                if let Some(ref target) = module.target {
+                    let exists = self.ships.get(target.ship.to_usize()).map_or(false, |s| s.is_some());
+                    if exists && visible.insert(target.ship) {
+                        unchecked.push(target.ship);
+                    }
+                }
+            }
+        }
+        
+        visible
+    }
 
     pub fn server_preprocess(&self, model_store: &ModelStore) {
         for ship in self.ships_iter() {
@@ -183,6 +258,7 @@ impl BattleContext {
     }
     
     pub fn write_results(&self, packet: &mut OutPacket) {
+        packet.write(&self.turn_seed);
         packet.write(&(self.ships_iter().count() as u32));
         for ship in self.ships_iter() {
             packet.write(&ship.index);
@@ -190,7 +266,16 @@ impl BattleContext {
         }
     }
     
+    // Checksums of how the ships a player can see ended up, for them to check their simulation against
+    pub fn visible_checksums(&self, visible: &HashSet<ShipIndex>) -> Vec<(ShipIndex, u32)> {
+        self.ships_iter()
+            .filter(|s| visible.contains(&s.index))
+            .map(|s| (s.index, s.state.checksum()))
+            .collect()
+    }
+    
     pub fn read_results(&mut self, packet: &mut InPacket) {
+        self.turn_seed = packet.read().unwrap();
         let num_ships: u32 = packet.read().unwrap();
         for _ in 0 .. num_ships {
             let ship: ShipIndex = packet.read().unwrap();
@@ -1,8 +1,326 @@
-#[derive(RustcEncodable, RustcDecodable)]
+use std::collections::HashMap;
+
+use battle_context::BattleContext;
+use ship::ShipId;
+
+// How many turns a match can go on for before it's called
+pub const TURN_LIMIT: u32 = 100;
+
+// The match a sector runs. Sectors without one are an endless free for all.
+#[derive(Clone, Copy, RustcEncodable, RustcDecodable)]
 pub enum BattleType {
-    // Player vs player free for all
+    // Player vs player free for all. With a score limit, ships respawn and the first to that many
+    // kills wins. Without one, it's the last ship standing.
     FreeForAll {
         num_players: u8,
-    }, 
+        score_limit: Option<u32>,
+    },
     Ai, // Player vs AI
 }
+
+impl BattleType {
+    pub fn game_mode(&self) -> GameModeBox {
+        match *self {
+            BattleType::FreeForAll { num_players, score_limit } => {
+                Box::new(FreeForAll {
+                    num_players: num_players as usize,
+                    score_limit: score_limit,
+                })
+            },
+            BattleType::Ai => Box::new(AiBattle),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// What ended a match
+#[derive(Clone, Copy, Debug, RustcEncodable, RustcDecodable)]
+pub enum VictoryCondition {
+    LastShipStanding,
+    ScoreLimit(u32),
+    TurnLimit(u32),
+}
+
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub enum MatchOutcome {
+    Won(Vec<String>), // Names of the winners
+    AiWon,
+    Draw,
+}
+
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct MatchResults {
+    pub outcome: MatchOutcome,
+    pub ended_by: VictoryCondition,
+    pub scores: Vec<(String, u32)>, // Every player's name and score, best first
+}
+
+// What players in a sector hear about its match
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub enum MatchEvent {
+    Lobby { players: u8, needed: u8 }, // Waiting for the lobby to fill up
+    Started(String), // Name of the game mode
+    Full, // No room for the player, who's being sent home
+    Over(MatchResults),
+}
+
+// How a match stands after a turn, for the game mode to judge
+pub struct MatchState {
+    pub turns_played: u32,
+    pub players: Vec<PlayerStanding>,
+    pub npcs_alive: usize,
+}
+
+pub struct PlayerStanding {
+    pub name: String,
+    pub score: u32,
+    pub alive: bool, // Players whose ship blew up or left the sector are out
+}
+
+impl MatchState {
+    pub fn results(&self, outcome: MatchOutcome, ended_by: VictoryCondition) -> MatchResults {
+        let mut scores: Vec<(String, u32)> = self.players.iter().map(|p| (p.name.clone(), p.score)).collect();
+        scores.sort_by(|a, b| b.1.cmp(&a.1));
+
+        MatchResults {
+            outcome: outcome,
+            ended_by: ended_by,
+            scores: scores,
+        }
+    }
+
+    // The player with the best score wins, unless it's a tie
+    pub fn leader_outcome(&self) -> MatchOutcome {
+        let best = self.players.iter().map(|p| p.score).max().unwrap_or(0);
+        let leaders: Vec<String> = self.players.iter().filter(|p| p.score == best).map(|p| p.name.clone()).collect();
+
+        if leaders.len() == 1 {
+            MatchOutcome::Won(leaders)
+        } else {
+            MatchOutcome::Draw
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// The rules of a match. The sector runs the match, and asks the game mode who's allowed in and
+// when it's over.
+pub trait IGameMode : Send {
+    fn get_name(&self) -> String;
+
+    // Players the lobby waits for before the match starts
+    fn get_num_players(&self) -> usize;
+
+    // Whether players can still join once the match has started
+    fn takes_late_players(&self) -> bool { false }
+
+    // Whether wrecks follow the sector's respawn policies during the match. Otherwise they stay
+    // where they are until it's over.
+    fn respawns(&self) -> bool { false }
+
+    // Called after each turn of the match. Returns how it ended once it's over.
+    fn check_victory(&self, state: &MatchState) -> Option<MatchResults>;
+}
+
+pub type GameModeBox = Box<IGameMode+'static>;
+
+pub struct FreeForAll {
+    num_players: usize,
+    score_limit: Option<u32>,
+}
+
+impl IGameMode for FreeForAll {
+    fn get_name(&self) -> String {
+        match self.score_limit {
+            Some(score_limit) => format!("Free for all, first to {} kills", score_limit),
+            None => "Free for all, last ship standing".to_string(),
+        }
+    }
+
+    fn get_num_players(&self) -> usize {
+        self.num_players
+    }
+
+    fn respawns(&self) -> bool {
+        self.score_limit.is_some()
+    }
+
+    fn check_victory(&self, state: &MatchState) -> Option<MatchResults> {
+        match self.score_limit {
+            Some(score_limit) => {
+                if state.players.iter().any(|p| p.score >= score_limit) {
+                    return Some(state.results(state.leader_outcome(), VictoryCondition::ScoreLimit(score_limit)));
+                }
+            },
+            None => {
+                let alive: Vec<&PlayerStanding> = state.players.iter().filter(|p| p.alive).collect();
+                if alive.len() <= 1 {
+                    let outcome =
+                        match alive.first() {
+                            Some(player) => MatchOutcome::Won(vec!(player.name.clone())),
+                            None => MatchOutcome::Draw,
+                        };
+                    return Some(state.results(outcome, VictoryCondition::LastShipStanding));
+                }
+            },
+        }
+
+        if state.turns_played >= TURN_LIMIT {
+            Some(state.results(state.leader_outcome(), VictoryCondition::TurnLimit(TURN_LIMIT)))
+        } else {
+            None
+        }
+    }
+}
+
+// Players team up against the sector's NPCs, which stay down once they're beaten
+pub struct AiBattle;
+
+impl IGameMode for AiBattle {
+    fn get_name(&self) -> String {
+        "Player vs AI".to_string()
+    }
+
+    fn get_num_players(&self) -> usize {
+        1
+    }
+
+    fn takes_late_players(&self) -> bool {
+        true
+    }
+
+    fn check_victory(&self, state: &MatchState) -> Option<MatchResults> {
+        if state.npcs_alive == 0 {
+            let winners = state.players.iter().filter(|p| p.alive).map(|p| p.name.clone()).collect();
+            Some(state.results(MatchOutcome::Won(winners), VictoryCondition::LastShipStanding))
+        } else if !state.players.iter().any(|p| p.alive) {
+            Some(state.results(MatchOutcome::AiWon, VictoryCondition::LastShipStanding))
+        } else if state.turns_played >= TURN_LIMIT {
+            Some(state.results(MatchOutcome::Draw, VictoryCondition::TurnLimit(TURN_LIMIT)))
+        } else {
+            None
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// A sector's match, from the lobby to the results
+pub struct SectorMatch {
+    mode: GameModeBox,
+
+    // The first turn of the match, once the lobby has filled up
+    started_on: Option<u32>,
+
+    // Players in the match, with their names for once they've left
+    players: Vec<(ShipId, String)>,
+    scores: HashMap<ShipId, u32>,
+}
+
+impl SectorMatch {
+    pub fn new(mode: GameModeBox) -> SectorMatch {
+        SectorMatch {
+            mode: mode,
+            started_on: None,
+            players: vec!(),
+            scores: HashMap::new(),
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.started_on.is_some()
+    }
+
+    // Nobody dies in the lobby, so this only matters once the match is on
+    pub fn ships_respawn(&self) -> bool {
+        !self.in_progress() || self.mode.respawns()
+    }
+
+    pub fn is_player(&self, ship_id: ShipId) -> bool {
+        self.players.iter().any(|&(id, _)| id == ship_id)
+    }
+
+    // Returns false if there's no room for the player
+    pub fn add_player(&mut self, ship_id: ShipId, name: String) -> bool {
+        let has_room =
+            if self.in_progress() {
+                self.mode.takes_late_players()
+            } else {
+                self.players.len() < self.mode.get_num_players()
+            };
+
+        if has_room {
+            self.players.push((ship_id, name));
+        }
+
+        has_room
+    }
+
+    // Players leaving the lobby free up their spot. Once the match is on, they stay in the
+    // standings. Returns whether the lobby changed.
+    pub fn remove_player(&mut self, ship_id: ShipId) -> bool {
+        if self.in_progress() || !self.is_player(ship_id) {
+            return false;
+        }
+
+        self.players.retain(|&(id, _)| id != ship_id);
+        true
+    }
+
+    pub fn lobby_event(&self) -> Option<MatchEvent> {
+        if self.in_progress() {
+            None
+        } else {
+            Some(MatchEvent::Lobby { players: self.players.len() as u8, needed: self.mode.get_num_players() as u8 })
+        }
+    }
+
+    // Start the match on `turn_number` if the lobby is full
+    pub fn try_start(&mut self, turn_number: u32) -> Option<MatchEvent> {
+        if self.in_progress() || self.players.len() < self.mode.get_num_players() {
+            return None;
+        }
+
+        self.started_on = Some(turn_number);
+        Some(MatchEvent::Started(self.mode.get_name()))
+    }
+
+    pub fn record_kill(&mut self, killer: ShipId) {
+        if self.in_progress() && self.is_player(killer) {
+            *self.scores.entry(killer).or_insert(0) += 1;
+        }
+    }
+
+    // Ask the game mode whether the match is over after `turn_number`
+    pub fn check_victory(&self, context: &BattleContext, turn_number: u32) -> Option<MatchResults> {
+        let started_on = match self.started_on {
+            Some(started_on) => started_on,
+            None => return None,
+        };
+
+        let state = MatchState {
+            turns_played: turn_number + 1 - started_on,
+            players: self.players.iter()
+                .map(|&(ship_id, ref name)| {
+                    PlayerStanding {
+                        name: name.clone(),
+                        score: self.scores.get(&ship_id).cloned().unwrap_or(0),
+                        alive: context.ships_iter().any(|s| s.id == ship_id && !s.exploding),
+                    }
+                })
+                .collect(),
+            npcs_alive: context.ships_iter().filter(|s| s.client_id.is_none() && !s.exploding).count(),
+        };
+
+        self.mode.check_victory(&state)
+    }
+
+    // Back to an empty lobby for the next match
+    pub fn reset(&mut self) {
+        self.started_on = None;
+        self.players.clear();
+        self.scores.clear();
+    }
+}
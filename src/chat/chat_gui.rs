@@ -51,6 +51,11 @@ impl ChatGui {
         self.messages.push(msg);
     }
 
+    // Whether the player is typing a message
+    pub fn has_focus(&self) -> bool {
+        self.msg_box.has_focus
+    }
+    
     pub fn event<E: GenericEvent>(&mut self, e: &E, mouse_pos: Vec2f) -> Option<ChatGuiAction> {
         use piston::event_loop::*;
         
@@ -4,24 +4,33 @@ use super::ChatMsg;
 
 pub struct ChatServer {
     msg_receiver: Receiver<ChatMsg>,
+    
+    // Sectors can come and go, so they sign up for chat here
+    new_msg_senders: Receiver<Sender<ChatMsg>>,
     msg_senders: Vec<Sender<ChatMsg>>,
+    
     msg_log: Vec<ChatMsg>,
 }
 
 impl ChatServer {
-    pub fn new(msg_receiver: Receiver<ChatMsg>, msg_senders: Vec<Sender<ChatMsg>>) -> ChatServer {
+    pub fn new(msg_receiver: Receiver<ChatMsg>, new_msg_senders: Receiver<Sender<ChatMsg>>) -> ChatServer {
         ChatServer {
             msg_receiver: msg_receiver,
-            msg_senders: msg_senders,
+            new_msg_senders: new_msg_senders,
+            msg_senders: vec!(),
             msg_log: vec!(),
         }
     }
     
     pub fn run(&mut self) {
         while let Ok(msg) = self.msg_receiver.recv() {
-            for msg_sender in &self.msg_senders {
-                msg_sender.send(msg.clone());
+            while let Ok(msg_sender) = self.new_msg_senders.try_recv() {
+                self.msg_senders.push(msg_sender);
             }
+            
+            // Forget about sectors that have gone away
+            self.msg_senders.retain(|msg_sender| msg_sender.send(msg.clone()).is_ok());
+            
             self.add_msg(msg);
         }
         
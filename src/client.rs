@@ -21,12 +21,15 @@
 #![feature(std_misc)]
 #![feature(path_ext)]
 #![feature(path_ext_deprecated)]
+#![feature(mpsc_select)]
 
 extern crate bincode;
 extern crate float;
 extern crate num;
 extern crate rand;
 extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate flate2;
 extern crate time;
 
 // Piston stuff
@@ -38,6 +41,7 @@ extern crate graphics;
 extern crate sdl2_mixer;
 extern crate vecmath;
 
+use std::env;
 use std::os;
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -59,12 +63,15 @@ use battle_context::BattleContext;
 use battle_type::BattleType;
 use sector_client::ClientBattleState;
 use client_state::run_client_state_manager;
-use login::{LoginPacket, LoginError};
+use login::{LoginError, LoginPacket, LoginSlot};
 use login_screen::{LoginScreen, LoginGuiAction};
 use main_menu::{MainMenu, MainMenuSelection};
 use module::ModelStore;
-use net::{Client, OutPacket};
-use star_map::StarMapServer;
+use net::{Client, ClientId, FrameCodec, NetError, parse_server_key};
+use replay::Replay;
+use replay_viewer::run_replay;
+use sector_data::SectorId;
+use star_map::{StarMapServer, StarMapSlot};
 
 // Server stuff
 use net::Server;
@@ -87,6 +94,10 @@ mod nav_map_gui;
 mod net;
 mod no_encode;
 mod packet_types;
+mod replay;
+mod respawn_policy;
+mod results_delta;
+mod replay_viewer;
 mod sector_data;
 mod sector_server;
 mod ship;
@@ -97,6 +108,10 @@ mod space_gui;
 //mod sprite_mgr;
 mod sprite_sheet;
 mod star_map;
+mod team;
+mod timer;
+mod turn_config;
+mod turn_seed;
 mod vec;
 
 #[cfg(feature = "client")]
@@ -136,10 +151,73 @@ fn main () {
     
     music.play(-1).ok().expect("Failed to play background music");
     
-    // Start a local server
+    let args: Vec<String> = env::args().collect();
+    
+    // Pass --battle-replay <file> to watch a battle recorded by a server started with --record,
+    // without connecting to anything
+    if let Some(path) = args.iter().position(|arg| arg == "--battle-replay").and_then(|i| args.get(i + 1)) {
+        match Replay::open(path.as_str()) {
+            Ok(replay) => {
+                if let Err(e) = run_replay(&window, &mut gl, &mut glyph_cache, asset_store, model_store, &replay) {
+                    println!("Failed to play replay {}: {}", path, e);
+                }
+            },
+            Err(e) => println!("Failed to open replay {}: {}", path, e),
+        }
+        
+        sdl2_mixer::Music::halt();
+        sdl2_mixer::quit();
+        return;
+    }
+    
+    // Pass --replay <file> <client ID> to play back what a capture says the server sent that
+    // client, instead of connecting to a server
+    let replay: Option<(String, ClientId)> =
+        args.iter().position(|arg| arg == "--replay").and_then(|i| {
+            match (args.get(i + 1), args.get(i + 2).and_then(|id| id.parse().ok())) {
+                (Some(path), Some(client_id)) => Some((path.clone(), client_id)),
+                _ => None,
+            }
+        });
+    
+    // Pass --spectate <sector ID> to watch that sector's battle instead of playing
+    let spectate: Option<SectorId> =
+        args.iter().position(|arg| arg == "--spectate")
+            .and_then(|i| args.get(i + 1))
+            .and_then(|id| id.parse().ok())
+            .map(SectorId);
+    
+    // Pass --server-key <hex> to only connect to servers holding that key, as printed by the
+    // server on startup
+    let server_key =
+        args.iter().position(|arg| arg == "--server-key")
+            .and_then(|i| args.get(i + 1))
+            .map(|key| parse_server_key(key.as_str()).expect("--server-key needs a server key in hex"));
+    
+    // Start a local server. This client talks to it in-process, but other clients on this machine,
+    // or on the local network with --lan, can join over TCP.
     let mut server = Server::new();
-    let login_slot = server.create_slot();
-    let star_map_slot = server.create_slot();
+    let local_address = if args.iter().any(|arg| arg == "--lan") { "0.0.0.0:30000" } else { "localhost:30000" };
+    
+    // If another client on this machine already has the port, "localhost" means its server
+    let hosting =
+        match server.start_listening(local_address) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Local server can't take connections on {}: {}", local_address, e);
+                false
+            },
+        };
+    
+    // Pass --capture <file> to record the local server's slot traffic to a capture file
+    if let Some(path) = args.iter().position(|arg| arg == "--capture").and_then(|i| args.get(i + 1)) {
+        if let Err(e) = server.start_capture(path.as_str()) {
+            println!("Failed to start capture to {}: {}", path, e);
+        }
+    }
+    let local_server = server.loopback_connector();
+    let login_slot: LoginSlot = server.create_slot();
+    let star_map_slot: StarMapSlot = server.create_slot();
     let star_map_slot_id = star_map_slot.get_id();
     let (star_map_account_sender, star_map_account_receiver) = channel();
     let (logout_sender, logout_receiver) = channel();
@@ -147,14 +225,14 @@ fn main () {
     let star_map_model_store = login_model_store.clone();
     
     Builder::new().name("server_master".to_string()).spawn(move || {
-        server.listen("localhost:30000");
+        server.run();
     });
     
     Builder::new().name("login_server".to_string()).spawn(move || {
         login::run_login_server(login_model_store, login_slot, star_map_slot_id, star_map_account_sender, logout_receiver);
     });
     
-    let mut star_map_server = StarMapServer::new(star_map_model_store, star_map_slot);
+    let mut star_map_server = StarMapServer::new(star_map_model_store, star_map_slot, vec!(), None);
     Builder::new().name("star_map_server".to_string()).spawn(move || {
         star_map_server.run(star_map_account_receiver, logout_sender);
     });
@@ -169,23 +247,44 @@ fn main () {
                 loop {
                     match login_screen.run(&window, gl, &mut glyph_cache, menu_bg) {
                         LoginGuiAction::Login(username, password, ip_address) => {
-                            // Connect to server
-                            let mut client = Client::new((ip_address+":30000").as_str());
-
-                            let mut packet = OutPacket::new();
-                            packet.write(&LoginPacket{username: username, password: password});
-                            client.send(&packet);
-                            
-                            let mut login_result_packet = client.receive();
-                            let login_result: Option<LoginError> = login_result_packet.read().unwrap();
+                            // Connect to server. "localhost" means the server running inside this
+                            // client, if it got the port.
+                            let client =
+                                if let Some((ref path, client_id)) = replay {
+                                    Client::replay(path.as_str(), client_id)
+                                } else if ip_address == "localhost" && hosting {
+                                    local_server.connect()
+                                } else {
+                                    Client::with_server_key((ip_address+":30000").as_str(), FrameCodec::new(), server_key)
+                                };
+                            let mut client =
+                                match client {
+                                    Ok(client) => client,
+                                    Err(e) => {
+                                        println!("Failed to connect to server: {}", e);
+                                        login_screen.net_error = Some(e);
+                                        continue;
+                                    },
+                                };
+                            login_screen.net_error = None;
                             
-                            match login_result {
-                                Some(login_error) => {
+                            match log_in(&mut client, username, password, spectate) {
+                                Ok(Some(login_error)) => {
                                     login_screen.login_error = Some(login_error);
                                 },
-                                None => {
-                                    run_client_state_manager(&window, gl, &mut glyph_cache, asset_store, model_store, client);
-                                    break;
+                                Ok(None) => {
+                                    match run_client_state_manager(&window, gl, &mut glyph_cache, asset_store, model_store, client) {
+                                        Ok(()) => { break; },
+                                        Err(e) => {
+                                            // Lost the server, back to the login screen
+                                            println!("Lost connection to server: {}", e);
+                                            login_screen.net_error = Some(e);
+                                        },
+                                    }
+                                },
+                                Err(e) => {
+                                    println!("Failed to log in: {}", e);
+                                    login_screen.net_error = Some(e);
                                 },
                             }
                         },
@@ -204,3 +303,10 @@ fn main () {
     sdl2_mixer::Music::halt();
     sdl2_mixer::quit();
 }
+
+// Send our credentials and wait for the server's verdict
+#[cfg(feature = "client")]
+fn log_in(client: &mut Client, username: String, password: String, spectate: Option<SectorId>) -> Result<Option<LoginError>, NetError> {
+    try!(client.send_message(&LoginPacket{username: username, password: password, spectate: spectate}));
+    client.receive_message()
+}
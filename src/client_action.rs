@@ -1,6 +1,7 @@
 #[derive(Copy, Clone, RustcEncodable, RustcDecodable)]
 pub enum ClientAction {
     JoinSector,
+    SpectateSector,
     JoinStation,
     Logout,
 }
\ No newline at end of file
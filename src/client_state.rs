@@ -12,60 +12,118 @@ use client_action::ClientAction;
 use module::ModelStore;
 use sector_client::ClientBattleState;
 use star_map::station::StationClient;
-use net::Client;
+use net::{Client, NetError};
+use packet_types::{ClientBattlePacket, ClientStarMapPacket, ClientStationPacket};
 use sector_data::SectorData;
-use ship::{Ship, ShipStored};
 
 pub fn run_client_state_manager(window: &Rc<RefCell<GlutinWindow>>,
                                 gl: &mut GlGraphics,
                                 glyph_cache: &mut GlyphCache,
                                 asset_store: &AssetStore,
                                 model_store: &ModelStore,
-                                mut client: Client) {
+                                mut client: Client) -> Result<(), NetError> {
     use client_action::ClientAction::*;
     
     let ref mut chat_gui = ChatGui::new();
 
     // Receive the star map
-    let mut packet = client.receive();
-    let sectors: Vec<SectorData> = packet.read().ok().expect("Failed to read star map");
+    let sectors = try!(receive_sectors(&mut client));
     
     loop {
-        let mut client_action_packet = client.receive();
-        let client_action: ClientAction = client_action_packet.read().ok().expect("Failed to read next ClientAction");
+        let client_action = try!(receive_client_action(&mut client));
     
         match client_action {
             JoinSector => {
                 // Receive the sector join packet
-                let mut packet = client.receive();
-                let my_ship: Ship = packet.read().ok().expect("Failed to read my Ship");
-                let server_results_sent = packet.read().ok().expect("Failed to read server_results_sent from server");
-                let ships: Vec<Option<Ship>> = packet.read().ok().expect("Unable to receive ships froms server");
+                let (my_ship, server_results_sent, ships, turn_config, alliances) =
+                    loop {
+                        match try!(client.receive_message()) {
+                            ClientBattlePacket::Join(my_ship, server_results_sent, ships, turn_config, alliances) => {
+                                break (try!(my_ship.decode()), server_results_sent, ships, turn_config, alliances);
+                            },
+                            _ => { println!("Skipping battle packet sent before we joined the sector"); },
+                        }
+                    };
 
-                // Create the battle state
-                let mut battle_context = BattleContext::new(ships);
+                // Create the battle state with the ships where the server has them
+                let mut battle_context = BattleContext::new(vec!());
+                battle_context.turn_config = turn_config;
+                battle_context.alliances = alliances;
+                for ship in ships.iter() {
+                    battle_context.insert_ship(try!(ship.decode()));
+                }
                 
                 // Add the player's ship
-                battle_context.add_ship(my_ship);
+                battle_context.insert_ship(my_ship);
                 
                 let mut battle = ClientBattleState::new(&mut client, battle_context);
 
-                battle.run(window, gl, glyph_cache, asset_store, model_store, chat_gui, sectors.clone(), server_results_sent);
+                try!(battle.run(window, gl, glyph_cache, asset_store, model_store, chat_gui, sectors.clone(), server_results_sent));
                 
                 println!("I (client) left a sector");
             },
+            SpectateSector => {
+                // Receive every ship in the sector
+                let (server_results_sent, ships, turn_config, alliances) =
+                    loop {
+                        match try!(client.receive_message()) {
+                            ClientBattlePacket::Spectate(server_results_sent, ships, turn_config, alliances) => {
+                                break (server_results_sent, ships, turn_config, alliances);
+                            },
+                            _ => { println!("Skipping battle packet sent before we started spectating"); },
+                        }
+                    };
+                
+                let mut battle_context = BattleContext::new(vec!());
+                battle_context.turn_config = turn_config;
+                battle_context.alliances = alliances;
+                for ship in ships.iter() {
+                    battle_context.insert_ship(try!(ship.decode()));
+                }
+                
+                let mut battle = ClientBattleState::spectate(&mut client, battle_context);
+                
+                try!(battle.run(window, gl, glyph_cache, asset_store, model_store, chat_gui, sectors.clone(), server_results_sent));
+                
+                println!("I (client) stopped spectating a sector");
+            },
             JoinStation => {
                 // Receive the station join packet
-                let mut packet = client.receive();
-                let my_ship: Option<ShipStored> = packet.read().ok().expect("Failed to read my Ship");
+                let my_ship =
+                    loop {
+                        match try!(client.receive_message()) {
+                            ClientStationPacket::Join(my_ship) => { break try!(my_ship.decode()); },
+                            _ => { println!("Skipping station packet sent before we joined the station"); },
+                        }
+                    };
                 
                 let mut station_client = StationClient::new(&mut client, my_ship);
                 
-                station_client.run(window, gl, glyph_cache, asset_store, model_store, chat_gui, sectors.clone());
+                try!(station_client.run(window, gl, glyph_cache, asset_store, model_store, chat_gui, sectors.clone()));
             },
             Logout => {
                 break;
             },
         }
     }
+    
+    Ok(())
+}
+
+fn receive_sectors(client: &mut Client) -> Result<Vec<SectorData>, NetError> {
+    loop {
+        match try!(client.receive_message()) {
+            ClientStarMapPacket::Sectors(sectors) => { return Ok(sectors); },
+            ClientStarMapPacket::Action(_) => { println!("Skipping client action sent before the star map"); },
+        }
+    }
+}
+
+fn receive_client_action(client: &mut Client) -> Result<ClientAction, NetError> {
+    loop {
+        match try!(client.receive_message()) {
+            ClientStarMapPacket::Action(client_action) => { return Ok(client_action); },
+            ClientStarMapPacket::Sectors(_) => { println!("Skipping star map sent more than once"); },
+        }
+    }
 }
\ No newline at end of file
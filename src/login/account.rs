@@ -15,6 +15,7 @@ pub enum LoginError {
     AlreadyLoggedIn,
 }
 
+#[derive(RustcEncodable, RustcDecodable)]
 pub struct Account {
     pub username: String,
     pub password: String,
@@ -22,9 +23,23 @@ pub struct Account {
     pub client_id: Option<ClientId>,
     pub sector: SectorId,
     
+    // Sector the player is watching without their ship, if they logged in to spectate. Their ship
+    // stays in `sector` meanwhile.
+    pub spectating: Option<SectorId>,
+    
+    // Station the player goes back to when their ship is destroyed
+    pub home_station: SectorId,
+    
     pub module_inventory: HashMap<ModelIndex, u16>,
 }
 
+impl Account {
+    // The sector the player's client is in right now
+    pub fn current_sector(&self) -> SectorId {
+        self.spectating.unwrap_or(self.sector)
+    }
+}
+
 pub struct AccountManager {
     accounts: HashMap<String, Option<AccountBox>>,
 }
@@ -44,6 +59,8 @@ impl AccountManager {
             ship: None,
             client_id: None,
             sector: SectorId(1),
+            spectating: None,
+            home_station: SectorId(0),
             module_inventory: HashMap::new(),
         })));
     }
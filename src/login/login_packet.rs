@@ -1,5 +1,10 @@
+use sector_data::SectorId;
+
 #[derive(RustcEncodable, RustcDecodable)]
 pub struct LoginPacket {
     pub username: String,
     pub password: String,
+    
+    // Sector to watch instead of playing, if the player only wants to spectate
+    pub spectate: Option<SectorId>,
 }
\ No newline at end of file
@@ -1,11 +1,10 @@
 use std::sync::Arc;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Receiver, Select, Sender};
 
 use net::{
-    OutPacket,
     ServerSlot,
     ServerSlotId,
-    SlotInMsg,
+    SlotEvent,
 };
 
 use super::{
@@ -17,39 +16,44 @@ use super::LoginPacket;
 use module::ModelStore;
 use ship::{Ship, ShipId, ShipStored};
 
+// Clients send the login server their credentials, and it tells them whether they got in
+pub type LoginSlot = ServerSlot<LoginPacket, Option<LoginError>>;
+
+// Something that woke the login server up
+enum LoginEvent {
+    Slot(SlotEvent<LoginPacket>),
+    Logout(AccountBox),
+}
+
 pub fn run_login_server(model_store: Arc<ModelStore>, 
-                        slot: ServerSlot,
+                        slot: LoginSlot,
                         star_map_slot_id: ServerSlotId,
                         star_map_chan: Sender<AccountBox>,
                         logout_receiver: Receiver<AccountBox>) {
     let mut account_manager = AccountManager::new();
 
     loop {
-        if let Ok(msg) = slot.try_receive() {
-            match msg {
-                SlotInMsg::Joined(client_id) => {
+        // Sleep until something happens
+        match next_event(&slot, &logout_receiver) {
+            LoginEvent::Slot(msg) => match msg {
+                SlotEvent::Joined(client_id) => {
                     println!("Client {} logging in...", client_id);
                 },
-                SlotInMsg::ReceivedPacket(client_id, mut packet) => {
-                    let LoginPacket{username: username, password: password} = packet.read().ok().expect("Failed to receive login packet");
-                    
+                SlotEvent::Received(client_id, LoginPacket{username: username, password: password, spectate: spectate}) => {
+
                     match account_manager.login_account(username.clone(), password.clone(), client_id) {
-                        Ok(account) => {
+                        Ok(mut account) => {
+                            account.spectating = spectate;
+                            
                             // Login ok
-                            let mut result_packet = OutPacket::new();
-                            let login_result: Option<LoginError> = None;
-                            result_packet.write(&login_result);
-                            slot.send(client_id, result_packet);
+                            slot.send(client_id, &None);
                             
                             slot.transfer_client(account.client_id.expect("This must have a client ID"), star_map_slot_id);
                             star_map_chan.send(account);
                         },
                         Err(ref e) if *e == LoginError::NoSuchAccount => {
                             // Login ok
-                            let mut result_packet = OutPacket::new();
-                            let login_result: Option<LoginError> = None;
-                            result_packet.write(&login_result);
-                            slot.send(client_id, result_packet);
+                            slot.send(client_id, &None);
                         
                             // Account doesn't exist yet, make it
                             account_manager.create_account(username.clone(), password.clone());
@@ -60,6 +64,7 @@ pub fn run_login_server(model_store: Arc<ModelStore>,
                                 let player_ship = ShipStored::from_ship(Ship::generate_dev(&*model_store, client_id as ShipId, username.clone()));
                                 
                                 account.ship = Some(player_ship);
+                                account.spectating = spectate;
                                 
                                 slot.transfer_client(account.client_id.expect("This must have a client ID"), star_map_slot_id);
                                 star_map_chan.send(account);
@@ -69,19 +74,41 @@ pub fn run_login_server(model_store: Arc<ModelStore>,
                             }
                         },
                         Err(e) => {
-                            let mut result_packet = OutPacket::new();
-                            result_packet.write(&Some(e));
-                            slot.send(client_id, result_packet);
+                            slot.send(client_id, &Some(e));
                         },
                     }
                 },
+                SlotEvent::Malformed(client_id, e) => {
+                    println!("Client {} sent a bad login packet: {}", client_id, e);
+                },
+                SlotEvent::Shutdown => {
+                    println!("Login server shutting down");
+                    return;
+                },
                 _ => {},
-            }
+            },
+            LoginEvent::Logout(account) => {
+                println!("Client {} logging out", account.client_id.expect("This must have a client ID"));
+                account_manager.logout_account(account);
+            },
         }
-        
-        if let Ok(account) = logout_receiver.try_recv() {
-            println!("Client {} logging out", account.client_id.expect("This must have a client ID"));
-            account_manager.logout_account(account);
-        }        
+    }
+}
+
+// Block until a message arrives from either the slot or the star map
+fn next_event(slot: &LoginSlot, logout_receiver: &Receiver<AccountBox>) -> LoginEvent {
+    let select = Select::new();
+    let mut slot_h = select.handle(slot.receiver());
+    let mut logout_h = select.handle(logout_receiver);
+    unsafe {
+        slot_h.add();
+        logout_h.add();
+    }
+    
+    let ready_id = select.wait();
+    if ready_id == slot_h.id() {
+        LoginEvent::Slot(slot.decode(slot_h.recv().ok().expect("Failed to receive SlotInMsg")))
+    } else {
+        LoginEvent::Logout(logout_h.recv().ok().expect("Login server logout channel is broken"))
     }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 pub use self::login_packet::*;
-pub use self::login_server::run_login_server;
+pub use self::login_server::{LoginSlot, run_login_server};
 pub use self::account::{Account, AccountBox, AccountManager, LoginError};
 
 mod login_packet;
@@ -10,6 +10,7 @@ use opengl_graphics::glyph_cache::GlyphCache;
 
 use gui::{TextBox, TextButton};
 use login::LoginError;
+use net::NetError;
 
 #[derive(Clone)]
 pub enum LoginGuiAction {
@@ -24,6 +25,7 @@ pub struct LoginScreen {
     mouse_y: f64,
     
     pub login_error: Option<LoginError>,
+    pub net_error: Option<NetError>,
     
     // Text boxes
     username_box: TextBox,
@@ -47,6 +49,7 @@ impl LoginScreen {
             mouse_y: 0.0,
             
             login_error: None,
+            net_error: None,
             
             username_box: TextBox::new("user".to_string(), 24, [600.0, 300.0], [300.0, 40.0]),
             password_box: password_box,
@@ -211,5 +214,15 @@ impl LoginScreen {
                 },
             }
         }
+        
+        if let Some(ref net_error) = self.net_error {
+            let context = context.trans(400.0, 600.0);
+            Text::new_color([1.0, 0.0, 0.0, 1.0], 20).draw(
+                format!("{}", net_error).as_str(),
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
+        }
     }
 }
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use rand::Rng;
 
 #[cfg(feature = "client")]
 use graphics::Context;
@@ -12,6 +13,7 @@ use net::{InPacket, OutPacket};
 use ship::{Ship, ShipState};
 use sim::SimEvents;
 use sim_events::DamageEvent;
+use turn_seed::RngStream;
 use vec::{Vec2, Vec2f};
 
 #[cfg(feature = "client")]
@@ -106,14 +108,18 @@ impl IModule for BeamWeaponModule {
     fn before_simulation(&mut self, context: &ModuleContext, events: &mut SimEvents) {
         if let Some(ref target) = context.target {
             if let module::TargetManifestData::Beam(beam_start, beam_end) = target.data {
+                let mut visual_rng = context.rng(RngStream::DamageVisuals);
+                
                 target.ship.beam_hits(Some((beam_start, beam_end)), |module, _, _, hit| {
                     if let Some(hit_dist) = hit {
-                        let hit_tick = 20 + (((3.0 - 1.0)*hit_dist*20.0) as u32);
+                        // The beam sweeps across the ship from 1 to 3 seconds in
+                        let ticks_per_second = context.turn_config.ticks_per_second;
+                        let hit_tick = ticks_per_second + (((3.0 - 1.0)*hit_dist*(ticks_per_second as f64)) as u32);
                     
                         events.add(
                             hit_tick,
                             target.ship.index,
-                            Box::new(DamageEvent::new(module.index, self.damage, 0, false)),
+                            Box::new(DamageEvent::new(module.index, self.damage, 0, false, visual_rng.gen())),
                         );
                     }
                 });
@@ -125,7 +131,7 @@ impl IModule for BeamWeaponModule {
     fn add_plan_effects(&self, context: &ModuleContext, asset_store: &AssetStore, effects: &mut SimEffects) {
         if let Some(ref base_sprite_name) = self.base_sprite {
             let mut base_sprite = SpriteSheet::new(asset_store.get_sprite_info(base_sprite_name));
-            base_sprite.add_named_stay(&"idle".to_string(), 0.0, 7.0);
+            base_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.effects_end());
             effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, base_sprite));
         }
         let mut weapon_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.turret_sprite));
@@ -133,9 +139,9 @@ impl IModule for BeamWeaponModule {
         weapon_sprite.center = self.turret_center;
         
         if context.is_active {
-            weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, 7.0);
+            weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.effects_end());
         } else {
-            weapon_sprite.add_named_stay(&"off".to_string(), 0.0, 7.0);
+            weapon_sprite.add_named_stay(&"off".to_string(), 0.0, context.turn_config.effects_end());
         }
         
         // Monolithic beam textures need beam above sprite.
@@ -154,7 +160,7 @@ impl IModule for BeamWeaponModule {
     fn add_simulation_effects(&self, context: &ModuleContext, asset_store: &AssetStore, effects: &mut SimEffects) {
         if let Some(ref base_sprite_name) = self.base_sprite {
             let mut base_sprite = SpriteSheet::new(asset_store.get_sprite_info(base_sprite_name));
-            base_sprite.add_named_stay(&"idle".to_string(), 0.0, 7.0);
+            base_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.effects_end());
             effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, base_sprite));
         }
         
@@ -173,7 +179,7 @@ impl IModule for BeamWeaponModule {
                     
                     weapon_sprite.add_named_once(&"pre_fire".to_string(), 0.0, 1.0);
                     weapon_sprite.add_named_once(&"fire".to_string(), self.fire_anim_interval.0, self.fire_anim_interval.1);
-                    weapon_sprite.add_named_stay(&"post_fire".to_string(), self.fire_anim_interval.1, 7.0);
+                    weapon_sprite.add_named_stay(&"post_fire".to_string(), self.fire_anim_interval.1, context.turn_config.effects_end());
                     
                     // Add the simulation visual for beam leaving ship screen
                     effects.add_visual(ship_id, 1, BeamExitVisual {
@@ -200,10 +206,10 @@ impl IModule for BeamWeaponModule {
                     effects.add_sound(start_time, 1, asset_store.get_sound(&"effects/beam1.ogg".to_string()).clone());
                 }
             } else {
-                weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, 7.0);
+                weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.effects_end());
             }
         } else {
-            weapon_sprite.add_named_stay(&"off".to_string(), 0.0, 7.0);
+            weapon_sprite.add_named_stay(&"off".to_string(), 0.0, context.turn_config.effects_end());
         }
         
         // Monolithic beam textures need beam above sprite.
@@ -39,9 +39,9 @@ impl IModule for CabinModule {
         let mut command_sprite = SpriteSheet::new(asset_store.get_sprite_info_str("cabin"));
 
         if context.is_active {
-            command_sprite.add_animation(SpriteAnimation::Loop(0.0, 7.0, 0, 7, 0.2));
+            command_sprite.add_animation(SpriteAnimation::Loop(0.0, context.turn_config.effects_end(), 0, 7, 0.2));
         } else {
-            command_sprite.add_animation(SpriteAnimation::Stay(0.0, 7.0, 0));
+            command_sprite.add_animation(SpriteAnimation::Stay(0.0, context.turn_config.effects_end(), 0));
         }
     
         effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, command_sprite));
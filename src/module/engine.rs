@@ -36,14 +36,14 @@ impl IModule for EngineModule {
     #[cfg(feature = "client")]
     fn add_plan_effects(&self, context: &ModuleContext, asset_store: &AssetStore, effects: &mut SimEffects) {
         let mut engine_sprite = SpriteSheet::new(asset_store.get_sprite_info_str("engine1"));
-        engine_sprite.add_animation(SpriteAnimation::Stay(0.0, 7.0, 0));
+        engine_sprite.add_animation(SpriteAnimation::Stay(0.0, context.turn_config.effects_end(), 0));
     
         effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, engine_sprite));
         
         // Propulsion sprite
         if context.is_active {
             let mut prop_sprite = SpriteSheet::new(asset_store.get_sprite_info_str("propulsion1"));
-            prop_sprite.add_animation(SpriteAnimation::Loop(0.0, 7.0, 0, 7, 0.05));
+            prop_sprite.add_animation(SpriteAnimation::Loop(0.0, context.turn_config.effects_end(), 0, 7, 0.05));
         
             effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position() + Vec2{x: -48.0, y: 2.0}, 0.0, prop_sprite));
         }
@@ -2,8 +2,7 @@ use std::any::TypeId;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::ops::{Deref, DerefMut};
-use rand;
-use rand::Rng;
+use rand::{Rng, XorShiftRng};
 use std::marker::Reflect;
 
 use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
@@ -12,6 +11,8 @@ use battle_context::BattleContext;
 use net::{InPacket, OutPacket};
 use ship::{Ship, ShipId, ShipIndex, ShipState, ShipStored};
 use sim::SimEvents;
+use turn_config::TurnConfig;
+use turn_seed::{RngStream, TurnSeed};
 use vec::{Vec2, Vec2f};
 
 #[cfg(feature = "client")]
@@ -68,9 +69,17 @@ pub struct ModuleContext<'a> {
     pub ship_state: &'a ShipState,
     pub ship_position: Vec2f,
     pub ship_next_waypoint: Option<Vec2f>,
+    
+    pub turn_config: TurnConfig,
+    pub turn_seed: TurnSeed,
 }
 
 impl<'a> ModuleContext<'a> {
+    // This module's own random numbers for the turn
+    pub fn rng(&self, stream: RngStream) -> XorShiftRng {
+        self.turn_seed.rng(stream, self.ship_id, self.index.0)
+    }
+    
     pub fn get_render_position(&self) -> Vec2f {
         Vec2{x: (self.x as f64) * 48.0, y: (self.y as f64) * 48.0}
     }
@@ -85,7 +94,7 @@ impl<'a> ModuleContext<'a> {
 
     pub fn ship_lerp_next_waypoint(&self, time: f64) -> Vec2f {
         if let Some(next_waypoint) = self.ship_next_waypoint {
-            self.ship_position + (next_waypoint - self.ship_position)*(time/5.0)
+            self.ship_position + (next_waypoint - self.ship_position)*(time/self.turn_config.sim_seconds())
         } else {
             self.ship_position
         }
@@ -146,7 +155,7 @@ impl ModulePlans {
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Copy, Clone, RustcEncodable, RustcDecodable)]
+#[derive(Copy, Clone, Debug, RustcEncodable, RustcDecodable)]
 pub struct ModuleStats {
     pub hp: u8,
     pub max_hp: u8,
@@ -353,15 +362,10 @@ impl Module {
         self.power > 0 && !self.is_damaged()
     }
     
-    // Returns the amount of damage dealt
-    pub fn deal_damage(&mut self, damage: u8) {
-        self.stats.deal_damage(damage);
-        
-        // Create damage visual at random location
-        if self.stats.hp < self.min_hp {
-            // Random number generater
-            let mut rng = rand::thread_rng();
-            
+    // Create a damage visual at a random location if a hit left the module with `hp` and out of
+    // action
+    pub fn add_damage_visual<R: Rng>(&mut self, hp: u8, rng: &mut R) {
+        if hp < self.min_hp {
             let x = rng.gen::<f64>() * ((self.shape.side() as f64) * 48.0);
             let y = rng.gen::<f64>() * ((self.shape.side() as f64) * 48.0);
 
@@ -427,6 +431,9 @@ impl Module {
             ship_state: &ship.state,
             ship_position: ship.position,
             ship_next_waypoint: ship.next_waypoint,
+            
+            turn_config: bc.turn_config,
+            turn_seed: bc.turn_seed,
         }
     }
     
@@ -582,6 +589,10 @@ impl ModuleStored {
             ship_state: &ship.state,
             ship_position: Vec2::new(0.0, 0.0),
             ship_next_waypoint: None,
+            
+            // Stored ships aren't in a sector
+            turn_config: TurnConfig::new(),
+            turn_seed: TurnSeed::new(0),
         }
     }
 }
@@ -4,20 +4,20 @@ use std::iter::repeat;
 use num::Float;
 use std::ops::DerefMut;
 use rand::Rng;
-use rand;
 
 #[cfg(feature = "client")]
 use graphics::Context;
 #[cfg(feature = "client")]
 use opengl_graphics::GlGraphics;
 
-use battle_context::{BattleContext, tick_to_time};
+use battle_context::BattleContext;
 use module;
 use module::{IModule, Model, ModelIndex, Module, ModuleClass, ModuleContext, ModuleShape, TargetManifest, TargetManifestData};
 use net::{ClientId, InPacket, OutPacket};
 use ship::{Ship, ShipId, ShipState};
 use sim::SimEvents;
 use sim_events::DamageEvent;
+use turn_seed::RngStream;
 use vec::{Vec2, Vec2f};
 
 #[cfg(feature = "client")]
@@ -110,8 +110,8 @@ impl IModule for ProjectileWeaponModule {
 
     fn server_preprocess(&mut self, context: &ModuleContext) {    
         if let Some(ref target) = context.target {                
-            // Random number generater
-            let mut rng = rand::thread_rng();
+            // Hits are rolled from the turn's seed, so the turn can be simulated again
+            let mut rng = context.rng(RngStream::HitRolls);
             
             for projectile in self.projectiles.iter_mut() {
                 if rng.gen::<f64>() > (0.15 * (cmp::min(target.ship.state.thrust, 5) as f64)) {
@@ -126,19 +126,19 @@ impl IModule for ProjectileWeaponModule {
     fn before_simulation(&mut self, context: &ModuleContext, events: &mut SimEvents) {
         use std::f64::consts::PI;
     
-        let mut rng = rand::thread_rng();
-        
         self.old_rotation = self.next_rotation;
     
         if let Some(ref target) = context.target {
             if let module::TargetManifestData::TargetModule(ref target_module) = target.data {
-                let target_move_vector = target.ship.lerp_next_waypoint(tick_to_time(10)) -
-                                         context.ship_lerp_next_waypoint(tick_to_time(10));
+                let target_move_vector = target.ship.lerp_next_waypoint(&context.turn_config, context.turn_config.tick_to_time(10)) -
+                                         context.ship_lerp_next_waypoint(context.turn_config.tick_to_time(10));
                 self.rotation = f64::atan2(-target_move_vector.y, target_move_vector.x);
 
-                let target_move_vector = target.ship.lerp_next_waypoint(tick_to_time(100)) -
-                                         context.ship_lerp_next_waypoint(tick_to_time(100));
+                let target_move_vector = target.ship.lerp_next_waypoint(&context.turn_config, context.turn_config.sim_seconds()) -
+                                         context.ship_lerp_next_waypoint(context.turn_config.sim_seconds());
                 self.next_rotation = f64::atan2(-target_move_vector.y, target_move_vector.x);
+                
+                let mut visual_rng = context.rng(RngStream::DamageVisuals);
             
                 for (i, projectile) in self.projectiles.iter_mut().enumerate() {                                            
                     let start = (i*10) as u32;
@@ -149,7 +149,7 @@ impl IModule for ProjectileWeaponModule {
                         events.add(
                             hit_tick,
                             target.ship.index,
-                            Box::new(DamageEvent::new(target_module.index, 1, 0, true)),
+                            Box::new(DamageEvent::new(target_module.index, 1, 0, true, visual_rng.gen())),
                         );
                     }
                 }
@@ -160,7 +160,7 @@ impl IModule for ProjectileWeaponModule {
     #[cfg(feature = "client")]
     fn add_plan_effects(&self, context: &ModuleContext, asset_store: &AssetStore, effects: &mut SimEffects) {
         let mut base_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.base_sprite));
-        base_sprite.add_named_stay(&"idle".to_string(), 0.0, 7.0);
+        base_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.effects_end());
         effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, base_sprite));
 
         let mut weapon_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.turret_sprite));
@@ -168,9 +168,9 @@ impl IModule for ProjectileWeaponModule {
         weapon_sprite.center = self.turret_center;
         
         if context.is_active {
-            weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, 7.0);
+            weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.effects_end());
         } else {
-            weapon_sprite.add_named_stay(&"off".to_string(), 0.0, 7.0);
+            weapon_sprite.add_named_stay(&"off".to_string(), 0.0, context.turn_config.effects_end());
         }
         
         effects.add_visual(context.ship_id, 2, SpriteVisual::new(context.get_render_position() + weapon_sprite.center, self.rotation, weapon_sprite));
@@ -183,11 +183,11 @@ impl IModule for ProjectileWeaponModule {
         // Add rotating lerp visual
         let mut weapon_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.turret_sprite));
         weapon_sprite.center = self.turret_center;
-        weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, tick_to_time(10));
+        weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.tick_to_time(10));
         effects.add_visual(ship_id, 2,
             LerpVisual {
                 start_time: 0.0,
-                end_time: tick_to_time(10),
+                end_time: context.turn_config.tick_to_time(10),
                 start_pos: context.get_render_position() + weapon_sprite.center,
                 end_pos: context.get_render_position() + weapon_sprite.center,
                 start_rot: self.rotation,
@@ -197,7 +197,7 @@ impl IModule for ProjectileWeaponModule {
     
         // Base sprite animation
         let mut base_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.base_sprite));
-        base_sprite.add_named_stay(&"idle".to_string(), 0.0, 7.0);
+        base_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.effects_end());
         effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, base_sprite));
         
         let mut weapon_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.turret_sprite));
@@ -233,8 +233,8 @@ impl IModule for ProjectileWeaponModule {
                         let hit_tick = start + 40;
                     
                         // Set up interpolation stuff to send projectile from weapon to offscreen
-                        let start_time = tick_to_time(fire_tick);
-                        let end_time = tick_to_time(offscreen_tick);
+                        let start_time = context.turn_config.tick_to_time(fire_tick);
+                        let end_time = context.turn_config.tick_to_time(offscreen_tick);
                         let start_pos = fire_pos;
                         let end_pos = to_offscreen_pos;
                         
@@ -243,7 +243,7 @@ impl IModule for ProjectileWeaponModule {
                         
                         let mut laser_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.projectile_sprite));
                         laser_sprite.center();
-                        laser_sprite.add_named_loop(&"loop".to_string(), 0.0, 7.0, 0.05);
+                        laser_sprite.add_named_loop(&"loop".to_string(), 0.0, context.turn_config.effects_end(), 0.05);
                         
                         let weapon_anim_start = start_time;
                         let weapon_anim_end = start_time+0.15;
@@ -272,8 +272,8 @@ impl IModule for ProjectileWeaponModule {
                         effects.add_sound(start_time, 0, asset_store.get_sound(&"effects/laser.wav".to_string()).clone());
                         
                         // Set up interpolation stuff to send projectile from offscreen to target
-                        let start_time = tick_to_time(offscreen_tick);
-                        let end_time = tick_to_time(hit_tick);
+                        let start_time = context.turn_config.tick_to_time(offscreen_tick);
+                        let end_time = context.turn_config.tick_to_time(hit_tick);
                         let start_pos = from_offscreen_pos;
                         let end_pos = hit_pos;
                         
@@ -282,7 +282,7 @@ impl IModule for ProjectileWeaponModule {
 
                         let mut laser_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.projectile_sprite));
                         laser_sprite.center();
-                        laser_sprite.add_named_loop(&"loop".to_string(), 0.0, 7.0, 0.05);
+                        laser_sprite.add_named_loop(&"loop".to_string(), 0.0, context.turn_config.effects_end(), 0.05);
                         
                         // Add the simulation visual for projectile entering target screen
                         effects.add_visual(target_ship_id, 3, LerpVisual {
@@ -296,7 +296,7 @@ impl IModule for ProjectileWeaponModule {
                         });
                         
                         // Set up explosion visual
-                        let start_time = tick_to_time(hit_tick);
+                        let start_time = context.turn_config.tick_to_time(hit_tick);
                         let end_time = start_time + 0.7;
                         
                         let mut explosion_sprite =  SpriteSheet::new(asset_store.get_sprite_info(&self.explosion_sprite));
@@ -310,15 +310,15 @@ impl IModule for ProjectileWeaponModule {
                     }
                     
                     // Add last stay animation
-                    weapon_sprite.add_named_stay(&"idle".to_string(), last_weapon_anim_end, 5.0);
+                    weapon_sprite.add_named_stay(&"idle".to_string(), last_weapon_anim_end, context.turn_config.sim_seconds());
 
-                    let end_aim_dir = target.ship.lerp_next_waypoint(5.0) -
-                                      context.ship_lerp_next_waypoint(5.0);
+                    let end_aim_dir = target.ship.lerp_next_waypoint(&context.turn_config, context.turn_config.sim_seconds()) -
+                                      context.ship_lerp_next_waypoint(context.turn_config.sim_seconds());
                     let end_rotation = f64::atan2(-end_aim_dir.y, end_aim_dir.x);
                     effects.add_visual(ship_id, 2, 
                                        LerpVisual {
-                                           start_time: tick_to_time(10),
-                                           end_time: 5.0,
+                                           start_time: context.turn_config.tick_to_time(10),
+                                           end_time: context.turn_config.sim_seconds(),
                                            start_pos: context.get_render_position() + weapon_sprite.center,
                                            end_pos: context.get_render_position() + weapon_sprite.center,
                                            start_rot: self.rotation,
@@ -327,20 +327,20 @@ impl IModule for ProjectileWeaponModule {
                                        });
 
                     let mut weapon_sprite = SpriteSheet::new(asset_store.get_sprite_info(&self.turret_sprite));
-                    weapon_sprite.add_named_stay(&"idle".to_string(), 5.0, 7.0);
+                    weapon_sprite.add_named_stay(&"idle".to_string(), context.turn_config.sim_seconds(), context.turn_config.effects_end());
                     weapon_sprite.center = self.turret_center;
                     effects.add_visual(ship_id, 2, 
                                        SpriteVisual::new(context.get_render_position() + weapon_sprite.center,
                                                          self.rotation, weapon_sprite));
                 }
             } else {
-                weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, 7.0);
+                weapon_sprite.add_named_stay(&"idle".to_string(), 0.0, context.turn_config.effects_end());
                 effects.add_visual(ship_id, 2, 
                                    SpriteVisual::new(context.get_render_position() + weapon_sprite.center,
                                                      self.rotation, weapon_sprite));
             }
         } else {
-            weapon_sprite.add_named_stay(&"off".to_string(), 0.0, 7.0);
+            weapon_sprite.add_named_stay(&"off".to_string(), 0.0, context.turn_config.effects_end());
             effects.add_visual(ship_id, 2, 
                                SpriteVisual::new(context.get_render_position() + weapon_sprite.center,
                                                  self.rotation, weapon_sprite));
@@ -48,9 +48,9 @@ impl IModule for RepairModule {
         let mut sprite = SpriteSheet::new(asset_store.get_sprite_info_str("repair"));
         
         if context.is_active {
-            sprite.add_animation(SpriteAnimation::Loop(0.0, 7.0, 1, 18, 0.055));
+            sprite.add_animation(SpriteAnimation::Loop(0.0, context.turn_config.effects_end(), 1, 18, 0.055));
         } else {
-            sprite.add_animation(SpriteAnimation::Stay(0.0, 7.0, 0));
+            sprite.add_animation(SpriteAnimation::Stay(0.0, context.turn_config.effects_end(), 0));
         }
     
         effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, sprite));
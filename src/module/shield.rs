@@ -27,9 +27,9 @@ impl IModule for ShieldModule {
         let mut shield_sprite = SpriteSheet::new(asset_store.get_sprite_info_str("shield"));
         
         if context.is_active {
-            shield_sprite.add_animation(SpriteAnimation::Loop(0.0, 7.0, 0, 9, 0.05));
+            shield_sprite.add_animation(SpriteAnimation::Loop(0.0, context.turn_config.effects_end(), 0, 9, 0.05));
         } else {
-            shield_sprite.add_animation(SpriteAnimation::Stay(0.0, 7.0, 0));
+            shield_sprite.add_animation(SpriteAnimation::Stay(0.0, context.turn_config.effects_end(), 0));
         }
     
         effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, shield_sprite));
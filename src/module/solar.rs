@@ -37,9 +37,9 @@ impl IModule for SolarModule {
         let mut solar_sprite = SpriteSheet::new(asset_store.get_sprite_info_str("solar"));
         
         if context.is_active {
-            solar_sprite.add_animation(SpriteAnimation::Loop(0.0, 7.0, 1, 4, 0.1));
+            solar_sprite.add_animation(SpriteAnimation::Loop(0.0, context.turn_config.effects_end(), 1, 4, 0.1));
         } else {
-            solar_sprite.add_animation(SpriteAnimation::Stay(0.0, 7.0, 0));
+            solar_sprite.add_animation(SpriteAnimation::Stay(0.0, context.turn_config.effects_end(), 0));
         }
     
         effects.add_visual(context.ship_id, 0, SpriteVisual::new(context.get_render_position(), 0.0, solar_sprite));
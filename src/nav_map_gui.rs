@@ -10,12 +10,17 @@ use asset_store::AssetStore;
 use battle_context::BattleContext;
 use gui::TextButton;
 use ship::{Ship, ShipIndex};
+use team::{ALLY_COLOR, ENEMY_COLOR};
 use vec::{Vec2, Vec2f};
 
 pub enum NavMapGuiAction {
     Close,
+    Follow(ShipIndex), // Spectators picked a ship to watch
 }
 
+// How far WASD moves the free camera, in screen pixels
+const CAMERA_STEP: f64 = 40.0;
+
 pub struct NavMapGui {
     scale: f64,
     move_dir: Vec2f,
@@ -25,6 +30,10 @@ pub struct NavMapGui {
     waypoints: VecDeque<Vec2f>,
     current_waypoint: Option<Vec2f>,
     
+    // Where the radar is centered, for spectators, who move it around instead of it following
+    // their ship
+    camera: Option<Vec2f>,
+    
     // Buttons
     close_button: TextButton,
     
@@ -40,6 +49,7 @@ impl NavMapGui {
             selection: None,
             waypoints: VecDeque::new(),
             current_waypoint: None,
+            camera: None,
             close_button: TextButton::new("Close".to_string(), 20, [450.0, 400.0], [150.0, 40.0]),
             frame: asset_store.get_texture_str("nav_map").clone(),
         }
@@ -80,7 +90,7 @@ impl NavMapGui {
         self.selection = None;
 
         let mouse_pos = Vec2::new(mouse_pos[0] - 288.0, mouse_pos[1] - 202.0);
-        let radar_center = client_ship.get(bc).lerp_next_waypoint(time);
+        let radar_center = self.radar_center(bc, client_ship.get(bc), time);
     
         // If inside circle clicked
         if mouse_pos.length() < 160.0 {
@@ -99,21 +109,50 @@ impl NavMapGui {
                        mouse_pos.y > screen_pos.y-half_size.y && mouse_pos.y < screen_pos.y+half_size.y {
                         // Select the clicked ship
                         self.selection = Some(ship.index);
+                        if self.camera.is_some() {
+                            self.action = Some(NavMapGuiAction::Follow(ship.index));
+                        }
                         return;
                     }
                 }
             }
 
-            // If nothing was selected, then it's a waypoint
+            // If nothing was selected, then it's a waypoint, or where a spectator wants to look
             let mut screen_pos = mouse_pos/self.scale;
             screen_pos.y *= -1.0;
             screen_pos = screen_pos + radar_center;
 
-            self.waypoints.push_back(screen_pos);
+            if self.camera.is_some() {
+                self.camera = Some(screen_pos);
+            } else {
+                self.waypoints.push_back(screen_pos);
+            }
         }
     }
     
     fn on_key_pressed(&mut self, key: keyboard::Key) {
+        if let Some(ref mut camera) = self.camera {
+            let step = CAMERA_STEP / self.scale;
+            match key {
+                keyboard::Key::A => { camera.x -= step; },
+                keyboard::Key::D => { camera.x += step; },
+                keyboard::Key::W => { camera.y += step; },
+                keyboard::Key::S => { camera.y -= step; },
+                _ => { },
+            }
+        }
+    }
+    
+    // Let the radar be moved around freely, starting out centered on `center`
+    pub fn set_free_camera(&mut self, center: Vec2f) {
+        self.camera = Some(center);
+    }
+    
+    fn radar_center(&self, bc: &BattleContext, client_ship: &Ship, time: f64) -> Vec2f {
+        match self.camera {
+            Some(camera) => camera,
+            None => client_ship.lerp_next_waypoint(&bc.turn_config, time),
+        }
     }
 
     pub fn draw(&mut self, context: &Context, gl: &mut GlGraphics, glyph_cache: &mut GlyphCache,
@@ -123,7 +162,7 @@ impl NavMapGui {
         Ellipse::new([0.0, 0.5, 0.0, 1.0])
                 .draw([118.0, 32.0, 340.0, 340.0], &context.draw_state, context.transform, gl);
 
-        let client_pos = client_ship.lerp_next_waypoint(time);
+        let client_pos = self.radar_center(bc, client_ship, time);
         
         // Render all the stuff in the nav map
         {
@@ -131,7 +170,7 @@ impl NavMapGui {
             
             for ship in bc.ships_iter() {
                 // Draw ship's icon if it's in the radar
-                let screen_pos = (ship.lerp_next_waypoint(time) - client_pos) * self.scale;
+                let screen_pos = (ship.lerp_next_waypoint(&bc.turn_config, time) - client_pos) * self.scale;
                 
                 if screen_pos.length() < 170.0 {
                     let context = context.scale(self.scale, self.scale)
@@ -139,18 +178,14 @@ impl NavMapGui {
                     let size = Vec2::new(ship.get_width() as f64, ship.get_height() as f64);
                     let half_size = size / 2.0;
                     let color =
-                        if let Some(selection) = self.selection {
-                            if ship.index == selection {
-                                [0.0, 0.0, 1.0, 1.0]
-                            } else if ship.index == client_ship.index {
-                                [0.0, 1.0, 0.0, 1.0]
-                            } else {
-                                [1.0, 0.0, 0.0, 1.0]
-                            }
+                        if Some(ship.index) == self.selection {
+                            [0.0, 0.0, 1.0, 1.0]
                         } else if ship.index == client_ship.index {
                             [0.0, 1.0, 0.0, 1.0]
+                        } else if bc.alliances.are_allies(client_ship, ship) {
+                            ALLY_COLOR
                         } else {
-                            [1.0, 0.0, 0.0, 1.0]
+                            ENEMY_COLOR
                         };
                     Rectangle::new(color)
                         .draw([-half_size.x, -half_size.y, size.x, size.y],
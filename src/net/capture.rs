@@ -0,0 +1,329 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::thread::Builder;
+use std::time::Duration;
+use time;
+
+use super::{ClientId, InPacket, NetError, OutPacket, ServerSlotId, SlotInMsg};
+use super::frame::{read_exact, read_u8, read_u32, read_u64, write_u8, write_u32, write_u64};
+
+// Capture files start with these magic bytes, then a u32 format version and the wall clock time
+// the capture started, in seconds since the epoch. After that come records until the end of the
+// file, each laid out as:
+//
+//     u64 nanoseconds since the capture started
+//     u32 slot ID
+//     u8  record kind
+//     u32 client ID (zero for broadcasts)
+//     u32 data length, then the data
+//
+// Everything is little-endian.
+const CAPTURE_MAGIC: [u8; 4] = [b'R', b'F', b'C', b'P'];
+
+// Bump this whenever the record layout changes
+const CAPTURE_VERSION: u32 = 1;
+
+const KIND_RECEIVED: u8 = 0;
+const KIND_SENT: u8 = 1;
+const KIND_BROADCAST: u8 = 2;
+const KIND_JOINED: u8 = 3;
+const KIND_DISCONNECTED: u8 = 4;
+
+// Something that passed between a slot and the server master
+#[derive(Clone)]
+pub enum CaptureEvent {
+    Received(ClientId, Vec<u8>), // Packet from a client to the slot
+    Sent(ClientId, Vec<u8>),     // Packet from the slot to one client
+    Broadcast(Vec<u8>),          // Packet from the slot to every client in it
+    Joined(ClientId),
+    Disconnected(ClientId),
+}
+
+#[derive(Clone)]
+pub struct CaptureRecord {
+    pub time_ns: u64, // Since the capture started
+    pub slot_id: ServerSlotId,
+    pub event: CaptureEvent,
+}
+
+impl CaptureRecord {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let no_data: &[u8] = &[];
+        let (kind, client_id, data): (u8, ClientId, &[u8]) =
+            match self.event {
+                CaptureEvent::Received(client_id, ref data) => (KIND_RECEIVED, client_id, data),
+                CaptureEvent::Sent(client_id, ref data) => (KIND_SENT, client_id, data),
+                CaptureEvent::Broadcast(ref data) => (KIND_BROADCAST, 0, data),
+                CaptureEvent::Joined(client_id) => (KIND_JOINED, client_id, no_data),
+                CaptureEvent::Disconnected(client_id) => (KIND_DISCONNECTED, client_id, no_data),
+            };
+
+        try!(write_u64(writer, self.time_ns));
+        try!(write_u32(writer, self.slot_id));
+        try!(write_u8(writer, kind));
+        try!(write_u32(writer, client_id));
+        try!(write_u32(writer, data.len() as u32));
+        writer.write_all(data)
+    }
+
+    // Returns Ok(None) at the end of the capture
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<CaptureRecord>> {
+        // A capture cut off part way through a record, say because the server crashed, just ends
+        // at the last whole record
+        let time_ns =
+            match read_u64(reader) {
+                Ok(time_ns) => time_ns,
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => { return Ok(None); },
+                Err(e) => { return Err(e); },
+            };
+
+        let record = (|| {
+            let slot_id = try!(read_u32(reader));
+            let kind = try!(read_u8(reader));
+            let client_id = try!(read_u32(reader));
+            let data_len = try!(read_u32(reader)) as usize;
+            let mut data = vec![0u8; data_len];
+            try!(read_exact(reader, &mut data));
+
+            let event =
+                match kind {
+                    KIND_RECEIVED => CaptureEvent::Received(client_id, data),
+                    KIND_SENT => CaptureEvent::Sent(client_id, data),
+                    KIND_BROADCAST => CaptureEvent::Broadcast(data),
+                    KIND_JOINED => CaptureEvent::Joined(client_id),
+                    KIND_DISCONNECTED => CaptureEvent::Disconnected(client_id),
+                    _ => {
+                        return Err(Error::new(ErrorKind::InvalidData, format!("Unknown capture record kind {}", kind)));
+                    },
+                };
+
+            Ok(CaptureRecord {
+                time_ns: time_ns,
+                slot_id: slot_id,
+                event: event,
+            })
+        })();
+
+        match record {
+            Ok(record) => Ok(Some(record)),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Recording
+
+// Writes records to a capture file. The writing happens on its own thread, so recording never
+// holds up the server master.
+pub struct Capture {
+    sender: Sender<CaptureRecord>,
+    start_ns: u64,
+}
+
+impl Capture {
+    pub fn create(path: &str) -> io::Result<Capture> {
+        let mut writer = BufWriter::new(try!(File::create(path)));
+        try!(writer.write_all(&CAPTURE_MAGIC));
+        try!(write_u32(&mut writer, CAPTURE_VERSION));
+        try!(write_u64(&mut writer, time::get_time().sec as u64));
+        try!(writer.flush());
+
+        let (sender, receiver) = channel();
+        let path = path.to_string();
+        try!(Builder::new().name("packet_capture".to_string()).spawn(move || {
+            write_records(writer, receiver, path);
+        }));
+
+        Ok(Capture {
+            sender: sender,
+            start_ns: time::precise_time_ns(),
+        })
+    }
+
+    pub fn record(&self, slot_id: ServerSlotId, event: CaptureEvent) {
+        let record = CaptureRecord {
+            time_ns: time::precise_time_ns() - self.start_ns,
+            slot_id: slot_id,
+            event: event,
+        };
+
+        // If the writer thread is gone it's already said why
+        let _ = self.sender.send(record);
+    }
+}
+
+fn write_records(mut writer: BufWriter<File>, receiver: Receiver<CaptureRecord>, path: String) {
+    for record in receiver.iter() {
+        // Flush every record, so a capture of a server that crashes still has everything up to the
+        // crash in it
+        let result = record.write_to(&mut writer).and_then(|_| writer.flush());
+        if let Err(e) = result {
+            println!("Failed to write to capture file {}, capture stopped: {}", path, e);
+            return;
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Replaying
+
+// Reads the records back out of a capture file
+pub struct CaptureReader<R> {
+    reader: R,
+
+    // Wall clock time the capture started, in seconds since the epoch
+    pub started_at: u64,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open(path: &str) -> io::Result<CaptureReader<BufReader<File>>> {
+        CaptureReader::new(BufReader::new(try!(File::open(path))))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> io::Result<CaptureReader<R>> {
+        let mut magic = [0u8; 4];
+        try!(read_exact(&mut reader, &mut magic));
+        if magic != CAPTURE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a capture file"));
+        }
+
+        let version = try!(read_u32(&mut reader));
+        if version != CAPTURE_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("Capture file is version {}, expected version {}", version, CAPTURE_VERSION)));
+        }
+
+        let started_at = try!(read_u64(&mut reader));
+
+        Ok(CaptureReader {
+            reader: reader,
+            started_at: started_at,
+        })
+    }
+
+    // Returns Ok(None) at the end of the capture
+    pub fn next_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        CaptureRecord::read_from(&mut self.reader)
+    }
+}
+
+// What to do with a record during playback
+enum Playback<T> {
+    Skip,
+    Play(T),
+    Stop,
+}
+
+// Plays records back with the same gaps between them as when they were captured. `select` picks out
+// the records being replayed and turns them into something for `deliver`, which returns false to
+// stop early. Timing starts from the first record played, so a replay doesn't sit through
+// everything that was captured before it.
+fn play<R: Read, T, S, D>(mut capture: CaptureReader<R>, mut select: S, mut deliver: D) -> io::Result<()>
+    where S: FnMut(CaptureRecord) -> Playback<T>,
+          D: FnMut(T) -> bool
+{
+    // When playback started, and the capture time of the first record played
+    let mut start: Option<(u64, u64)> = None;
+
+    while let Some(record) = try!(capture.next_record()) {
+        let time_ns = record.time_ns;
+        let item =
+            match select(record) {
+                Playback::Skip => { continue; },
+                Playback::Play(item) => item,
+                Playback::Stop => { break; },
+            };
+
+        let (start_ns, first_time_ns) =
+            match start {
+                Some(start) => start,
+                None => {
+                    let first = (time::precise_time_ns(), time_ns);
+                    start = Some(first);
+                    first
+                },
+            };
+        let due_ns = time_ns - first_time_ns;
+        let elapsed_ns = time::precise_time_ns() - start_ns;
+        if due_ns > elapsed_ns {
+            thread::sleep(Duration::from_millis((due_ns - elapsed_ns) / 1_000_000));
+        }
+
+        if !deliver(item) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Feed everything clients sent to a captured slot into another slot, as if those clients were
+// connected to it. What the slot sends back goes nowhere, but can be captured and compared with
+// the original.
+pub fn replay_into_slot<R: Read + Send + 'static>(capture: CaptureReader<R>,
+                                                 captured_slot: ServerSlotId,
+                                                 slot_in: Sender<SlotInMsg>) {
+    thread::spawn(move || {
+        let select = |record: CaptureRecord| {
+            if record.slot_id != captured_slot {
+                return Playback::Skip;
+            }
+
+            match record.event {
+                CaptureEvent::Received(client_id, data) => Playback::Play(SlotInMsg::ReceivedPacket(client_id, InPacket::new(data))),
+                CaptureEvent::Joined(client_id) => Playback::Play(SlotInMsg::Joined(client_id)),
+                CaptureEvent::Disconnected(client_id) => Playback::Play(SlotInMsg::Disconnected(client_id)),
+                CaptureEvent::Sent(..) | CaptureEvent::Broadcast(..) => Playback::Skip,
+            }
+        };
+
+        match play(capture, select, |msg| slot_in.send(msg).is_ok()) {
+            Ok(()) => println!("Finished replaying slot {}", captured_slot),
+            Err(e) => println!("Failed to replay slot {}: {}", captured_slot, e),
+        }
+    });
+}
+
+// Feed everything the server sent a captured client to a channel, the same way a client's receiver
+// thread would. Anything the client sends is thrown away.
+pub fn replay_into_client<R: Read + Send + 'static>(capture: CaptureReader<R>,
+                                                   client_id: ClientId,
+                                                   to_server: Receiver<OutPacket>,
+                                                   packet_sender: Sender<Result<InPacket, NetError>>) {
+    thread::spawn(move || {
+        // Which slot the client is in, to tell which broadcasts reached it
+        let mut slot_id: Option<ServerSlotId> = None;
+
+        let select = |record: CaptureRecord| {
+            match record.event {
+                CaptureEvent::Sent(to_client, data) if to_client == client_id => Playback::Play(data),
+                CaptureEvent::Broadcast(data) if Some(record.slot_id) == slot_id => Playback::Play(data),
+                CaptureEvent::Joined(joined) if joined == client_id => {
+                    slot_id = Some(record.slot_id);
+                    Playback::Skip
+                },
+                CaptureEvent::Disconnected(disconnected) if disconnected == client_id => Playback::Stop,
+                _ => Playback::Skip,
+            }
+        };
+
+        let deliver = |data| {
+            // The replay doesn't care what the client says
+            while let Ok(_) = to_server.try_recv() { }
+
+            packet_sender.send(Ok(InPacket::new(data))).is_ok()
+        };
+
+        match play(capture, select, deliver) {
+            Ok(()) => println!("Finished replaying client {}", client_id),
+            Err(e) => println!("Failed to replay client {}: {}", client_id, e),
+        }
+    });
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use flate2;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+// Packets smaller than this aren't worth compressing
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+// How one end of a connection compresses the packets it sends
+#[derive(Clone)]
+pub struct Compression {
+    // Packets at least this big get compressed. None if the peers didn't agree on compression.
+    pub threshold: Option<usize>,
+
+    pub stats: Arc<CompressionStats>,
+}
+
+impl Compression {
+    pub fn new(threshold: Option<usize>, stats: Arc<CompressionStats>) -> Compression {
+        Compression {
+            threshold: threshold,
+            stats: stats,
+        }
+    }
+
+    // Compress a packet if it's big enough to bother and actually gets smaller
+    pub fn compress(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match self.threshold {
+            Some(threshold) if data.len() >= threshold => {
+                let mut encoder = ZlibEncoder::new(Vec::with_capacity(data.len()), flate2::Compression::Default);
+                try!(encoder.write_all(data));
+                let compressed = try!(encoder.finish());
+
+                if compressed.len() < data.len() {
+                    Ok(Some(compressed))
+                } else {
+                    Ok(None)
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+// Inflate a packet, refusing to go past the largest packet we'd accept uncompressed
+pub fn decompress(data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut decompressed = vec!();
+    try!(ZlibDecoder::new(data).take(max_size as u64 + 1).read_to_end(&mut decompressed));
+
+    if decompressed.len() > max_size {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("Compressed packet inflates past max packet size {}", max_size)));
+    }
+
+    Ok(decompressed)
+}
+
+// Running totals of packet sizes before and after compression on one connection
+pub struct CompressionStats {
+    sent_raw: AtomicUsize,
+    sent_wire: AtomicUsize,
+    received_raw: AtomicUsize,
+    received_wire: AtomicUsize,
+}
+
+impl CompressionStats {
+    pub fn new() -> CompressionStats {
+        CompressionStats {
+            sent_raw: AtomicUsize::new(0),
+            sent_wire: AtomicUsize::new(0),
+            received_raw: AtomicUsize::new(0),
+            received_wire: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record_sent(&self, raw: usize, wire: usize) {
+        self.sent_raw.fetch_add(raw, Ordering::Relaxed);
+        self.sent_wire.fetch_add(wire, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, raw: usize, wire: usize) {
+        self.received_raw.fetch_add(raw, Ordering::Relaxed);
+        self.received_wire.fetch_add(wire, Ordering::Relaxed);
+    }
+
+    // Bytes on the wire per byte of packet data sent. Lower is better.
+    pub fn sent_ratio(&self) -> f64 {
+        ratio(self.sent_wire.load(Ordering::Relaxed), self.sent_raw.load(Ordering::Relaxed))
+    }
+
+    // Bytes on the wire per byte of packet data received. Lower is better.
+    pub fn received_ratio(&self) -> f64 {
+        ratio(self.received_wire.load(Ordering::Relaxed), self.received_raw.load(Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sent {} bytes as {} ({:.0}%), received {} bytes as {} ({:.0}%)",
+               self.sent_raw.load(Ordering::Relaxed), self.sent_wire.load(Ordering::Relaxed), self.sent_ratio() * 100.0,
+               self.received_raw.load(Ordering::Relaxed), self.received_wire.load(Ordering::Relaxed), self.received_ratio() * 100.0)
+    }
+}
+
+fn ratio(wire: usize, raw: usize) -> f64 {
+    if raw == 0 {
+        1.0
+    } else {
+        wire as f64 / raw as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+
+    use super::{Compression, CompressionStats, decompress};
+
+    #[test]
+    fn round_trip_over_threshold() {
+        let data = vec![7u8; 4096];
+        let compression = Compression::new(Some(1024), Arc::new(CompressionStats::new()));
+
+        let compressed = compression.compress(&data).unwrap().expect("Packet over the threshold should be compressed");
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+
+        assert!(compression.compress(&data[.. 1023]).unwrap().is_none());
+        assert!(Compression::new(None, Arc::new(CompressionStats::new())).compress(&data).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_to_inflate_past_max_size() {
+        let data = vec![7u8; 4096];
+        let compression = Compression::new(Some(0), Arc::new(CompressionStats::new()));
+        let compressed = compression.compress(&data).unwrap().unwrap();
+
+        assert_eq!(decompress(&compressed, data.len() - 1).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn ratio_of_bytes_on_the_wire() {
+        let stats = CompressionStats::new();
+        assert_eq!(stats.sent_ratio(), 1.0);
+
+        stats.record_sent(1000, 250);
+        stats.record_received(400, 400);
+        assert_eq!(stats.sent_ratio(), 0.25);
+        assert_eq!(stats.received_ratio(), 1.0);
+    }
+}
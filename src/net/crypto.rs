@@ -0,0 +1,487 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use sodiumoxide;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::box_::{MACBYTES, NONCEBYTES, PUBLICKEYBYTES, SECRETKEYBYTES, Nonce, PrecomputedKey, PublicKey, SecretKey};
+
+use super::frame::{read_exact, read_u32, write_u32};
+
+// Encrypted streams are sent as a series of records, each a little-endian u32 length followed by
+// that many bytes of sealed data. This is the most plaintext that goes in one record.
+const MAX_RECORD_SIZE: usize = 64 * 1024;
+
+// Which end of the connection we are, and what we bring to the key exchange. Each direction gets
+// its own nonces, so the two ends never seal with the same nonce under their shared key.
+pub enum Role {
+    // Clients check the server's identity key against the one they were given, if any
+    Client(Option<ServerKey>),
+
+    // The server proves it holds the secret half of its identity key
+    Server(Arc<ServerIdentity>),
+}
+
+impl Role {
+    fn direction(&self) -> u8 {
+        match *self {
+            Role::Client(_) => 0,
+            Role::Server(_) => 1,
+        }
+    }
+
+    fn peer_direction(&self) -> u8 {
+        match *self {
+            Role::Client(_) => 1,
+            Role::Server(_) => 0,
+        }
+    }
+}
+
+// Seals or opens the records going one way down a connection. Nonces are counted rather than
+// sent, so a record that's dropped, replayed or reordered fails to open.
+pub struct Cipher {
+    key: PrecomputedKey,
+    direction: u8,
+    counter: u64,
+}
+
+impl Cipher {
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; NONCEBYTES];
+        nonce[0] = self.direction;
+        for i in 0..8 {
+            nonce[1 + i] = (self.counter >> (i * 8)) as u8;
+        }
+        self.counter += 1;
+        Nonce(nonce)
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        box_::seal_precomputed(data, &nonce, &self.key)
+    }
+
+    fn open(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        box_::open_precomputed(data, &nonce, &self.key)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Received record that failed to decrypt"))
+    }
+}
+
+// A server's long-lived public key. Players can pin it, so a client only finishes the key
+// exchange with the server it meant to reach.
+pub type ServerKey = PublicKey;
+
+// The key pair a server proves its identity with. Keep the same one across restarts, or pinned
+// keys stop matching.
+pub struct ServerIdentity {
+    pub public_key: ServerKey,
+    secret_key: SecretKey,
+}
+
+impl ServerIdentity {
+    pub fn generate() -> ServerIdentity {
+        sodiumoxide::init();
+
+        let (public_key, secret_key) = box_::gen_keypair();
+        ServerIdentity {
+            public_key: public_key,
+            secret_key: secret_key,
+        }
+    }
+
+    // Load the identity kept in `path`, making a new one there if there isn't one yet
+    pub fn load_or_create(path: &str) -> io::Result<ServerIdentity> {
+        match File::open(path) {
+            Ok(mut file) => {
+                let mut public_key = [0u8; PUBLICKEYBYTES];
+                let mut secret_key = [0u8; SECRETKEYBYTES];
+                try!(read_exact(&mut file, &mut public_key));
+                try!(read_exact(&mut file, &mut secret_key));
+
+                Ok(ServerIdentity {
+                    public_key: PublicKey(public_key),
+                    secret_key: SecretKey(secret_key),
+                })
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let identity = ServerIdentity::generate();
+
+                let mut file = try!(File::create(path));
+                try!(restrict_to_owner(&file));
+                try!(file.write_all(&identity.public_key.0));
+                try!(file.write_all(&identity.secret_key.0));
+
+                Ok(identity)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    // The public key in hex, to give to players to pin
+    pub fn fingerprint(&self) -> String {
+        self.public_key.0.to_hex()
+    }
+}
+
+// Read a server key given as hex
+pub fn parse_server_key(hex: &str) -> Option<ServerKey> {
+    let bytes = match hex.from_hex() {
+        Ok(ref bytes) if bytes.len() == PUBLICKEYBYTES => bytes.clone(),
+        _ => return None,
+    };
+
+    let mut key = [0u8; PUBLICKEYBYTES];
+    for (dest, src) in key.iter_mut().zip(bytes.iter()) {
+        *dest = *src;
+    }
+    Some(PublicKey(key))
+}
+
+// The identity file holds a secret key, so nobody else should be able to read it
+#[cfg(unix)]
+fn restrict_to_owner(file: &File) -> io::Result<()> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+
+    file.set_permissions(Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict_to_owner(_: &File) -> io::Result<()> {
+    Ok(())
+}
+
+// Swap freshly generated public keys with the peer, and derive the ciphers for sending and
+// receiving. The server also sends its identity key, with its fresh key sealed by it under a random
+// nonce, so only the holder of the identity's secret key can finish the exchange. A client that
+// pinned the identity key can't be fooled by someone sitting in the middle of the connection. One
+// that didn't takes whatever key it's shown, and is only safe from eavesdroppers.
+pub fn key_exchange<S: Read + Write>(stream: &mut S, role: &Role) -> io::Result<(Cipher, Cipher)> {
+    sodiumoxide::init();
+
+    let (public_key, secret_key) = box_::gen_keypair();
+
+    let peer_key =
+        match *role {
+            Role::Client(ref pinned_key) => {
+                try!(stream.write_all(&public_key.0));
+                try!(stream.flush());
+
+                let mut identity_key = [0u8; PUBLICKEYBYTES];
+                try!(read_exact(stream, &mut identity_key));
+                if let Some(ref pinned_key) = *pinned_key {
+                    if identity_key != pinned_key.0 {
+                        return Err(Error::new(ErrorKind::PermissionDenied, "Server's identity key doesn't match the pinned key"));
+                    }
+                }
+
+                let mut nonce = [0u8; NONCEBYTES];
+                try!(read_exact(stream, &mut nonce));
+                let mut sealed_key = [0u8; PUBLICKEYBYTES + MACBYTES];
+                try!(read_exact(stream, &mut sealed_key));
+                let opened_key = try!(box_::open(&sealed_key, &Nonce(nonce), &PublicKey(identity_key), &secret_key)
+                    .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Server failed to prove its identity")));
+
+                let mut peer_key = [0u8; PUBLICKEYBYTES];
+                for (dest, src) in peer_key.iter_mut().zip(opened_key.iter()) {
+                    *dest = *src;
+                }
+                PublicKey(peer_key)
+            },
+            Role::Server(ref identity) => {
+                let mut peer_key = [0u8; PUBLICKEYBYTES];
+                try!(read_exact(stream, &mut peer_key));
+                let peer_key = PublicKey(peer_key);
+
+                // Sealed for the client's fresh key, so it's no use to anyone else. The nonce is
+                // fresh too, since someone in the middle can show us the same client key as often
+                // as they like, and sealing twice with one nonce would give the identity away.
+                let nonce = box_::gen_nonce();
+                let sealed_key = box_::seal(&public_key.0, &nonce, &peer_key, &identity.secret_key);
+                try!(stream.write_all(&identity.public_key.0));
+                try!(stream.write_all(&nonce.0));
+                try!(stream.write_all(&sealed_key));
+                try!(stream.flush());
+
+                peer_key
+            },
+        };
+
+    let sending = Cipher {
+        key: box_::precompute(&peer_key, &secret_key),
+        direction: role.direction(),
+        counter: 0,
+    };
+    let receiving = Cipher {
+        key: box_::precompute(&peer_key, &secret_key),
+        direction: role.peer_direction(),
+        counter: 0,
+    };
+
+    Ok((sending, receiving))
+}
+
+// Split a connection into its reading and writing halves, encrypting both if asked to
+pub fn split_stream(stream: &mut TcpStream, encrypt: bool, role: &Role)
+    -> io::Result<(CipherReader<TcpStream>, CipherWriter<TcpStream>)>
+{
+    let (sending, receiving) =
+        if encrypt {
+            let (sending, receiving) = try!(key_exchange(stream, role));
+            (Some(sending), Some(receiving))
+        } else {
+            (None, None)
+        };
+
+    Ok((CipherReader::new(try!(stream.try_clone()), receiving),
+        CipherWriter::new(try!(stream.try_clone()), sending)))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Reads from a stream, opening records first if the stream is encrypted
+pub struct CipherReader<R> {
+    inner: R,
+    cipher: Option<Cipher>,
+
+    // Opened record being read out, and how far into it we are
+    record: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> CipherReader<R> {
+    pub fn new(inner: R, cipher: Option<Cipher>) -> CipherReader<R> {
+        CipherReader {
+            inner: inner,
+            cipher: cipher,
+            record: vec!(),
+            pos: 0,
+        }
+    }
+
+    fn read_record(&mut self) -> io::Result<()> {
+        let cipher =
+            match self.cipher {
+                Some(ref mut cipher) => cipher,
+                None => { return Ok(()); },
+            };
+
+        let sealed_len = try!(read_u32(&mut self.inner)) as usize;
+        if sealed_len > MAX_RECORD_SIZE + MACBYTES {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("Record of {} bytes exceeds max record size", sealed_len)));
+        }
+
+        let mut sealed = vec![0u8; sealed_len];
+        try!(read_exact(&mut self.inner, &mut sealed));
+
+        self.record = try!(cipher.open(&sealed));
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for CipherReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cipher.is_none() {
+            return self.inner.read(buf);
+        }
+
+        while self.pos == self.record.len() {
+            try!(self.read_record());
+        }
+
+        let count = cmp::min(buf.len(), self.record.len() - self.pos);
+        for (dest, src) in buf.iter_mut().zip(self.record[self.pos..].iter()) {
+            *dest = *src;
+        }
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+// Writes to a stream, sealing what was written into records on every flush if the stream is
+// encrypted
+pub struct CipherWriter<W> {
+    inner: W,
+    cipher: Option<Cipher>,
+
+    // Written but not yet sealed
+    pending: Vec<u8>,
+}
+
+impl<W: Write> CipherWriter<W> {
+    pub fn new(inner: W, cipher: Option<Cipher>) -> CipherWriter<W> {
+        CipherWriter {
+            inner: inner,
+            cipher: cipher,
+            pending: vec!(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    fn write_records(&mut self) -> io::Result<()> {
+        let cipher =
+            match self.cipher {
+                Some(ref mut cipher) => cipher,
+                None => { return Ok(()); },
+            };
+
+        for chunk in self.pending.chunks(MAX_RECORD_SIZE) {
+            let sealed = cipher.seal(chunk);
+
+            let mut record = Vec::with_capacity(4 + sealed.len());
+            try!(write_u32(&mut record, sealed.len() as u32));
+            record.extend(sealed.into_iter());
+            try!(self.inner.write_all(&record));
+        }
+        self.pending.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for CipherWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cipher.is_none() {
+            return self.inner.write(buf);
+        }
+
+        self.pending.extend(buf.iter().cloned());
+        if self.pending.len() >= MAX_RECORD_SIZE {
+            try!(self.write_records());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_records());
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{Cursor, ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    use sodiumoxide::crypto::box_;
+    use sodiumoxide::crypto::box_::MACBYTES;
+
+    use net::frame::read_exact;
+    use super::*;
+
+    type Halves = (CipherReader<TcpStream>, CipherWriter<TcpStream>);
+
+    // Run the key exchange over a connection on localhost. Returns the client's end, then the
+    // server's.
+    fn exchange(identity: ServerIdentity, pinned_key: Option<ServerKey>) -> (io::Result<Halves>, io::Result<Halves>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            split_stream(&mut stream, true, &Role::Server(Arc::new(identity)))
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let client = split_stream(&mut stream, true, &Role::Client(pinned_key));
+
+        (client, server.join().unwrap())
+    }
+
+    // Ciphers for both ends of one direction of a connection
+    fn cipher_pair() -> (Cipher, Cipher) {
+        let (public_a, secret_a) = box_::gen_keypair();
+        let (public_b, secret_b) = box_::gen_keypair();
+
+        (Cipher { key: box_::precompute(&public_b, &secret_a), direction: 0, counter: 0 },
+         Cipher { key: box_::precompute(&public_a, &secret_b), direction: 0, counter: 0 })
+    }
+
+    // Seal each message into its own record
+    fn seal_records(cipher: Cipher, messages: &[&[u8]]) -> Vec<u8> {
+        let mut writer = CipherWriter::new(vec!(), Some(cipher));
+        for message in messages {
+            writer.write_all(message).unwrap();
+            writer.flush().unwrap();
+        }
+        writer.get_ref().clone()
+    }
+
+    #[test]
+    fn round_trip_with_pinned_key() {
+        let identity = ServerIdentity::generate();
+        let server_key = identity.public_key;
+
+        let (client, server) = exchange(identity, Some(server_key));
+        let (mut client_reader, mut client_writer) = client.unwrap();
+        let (mut server_reader, mut server_writer) = server.unwrap();
+
+        client_writer.write_all(b"hello server").unwrap();
+        client_writer.flush().unwrap();
+        let mut buf = [0u8; 12];
+        read_exact(&mut server_reader, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello server");
+
+        server_writer.write_all(b"hello client").unwrap();
+        server_writer.flush().unwrap();
+        read_exact(&mut client_reader, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello client");
+    }
+
+    #[test]
+    fn rejects_server_with_different_key() {
+        let pinned_key = ServerIdentity::generate().public_key;
+
+        let (client, _) = exchange(ServerIdentity::generate(), Some(pinned_key));
+        assert_eq!(client.err().unwrap().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn parses_fingerprint() {
+        let identity = ServerIdentity::generate();
+        assert_eq!(parse_server_key(&identity.fingerprint()).unwrap().0, identity.public_key.0);
+        assert!(parse_server_key("not a key").is_none());
+    }
+
+    #[test]
+    fn rejects_tampered_record() {
+        let (sending, receiving) = cipher_pair();
+        let mut wire = seal_records(sending, &[&b"hello"[..]]);
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+
+        let mut reader = CipherReader::new(Cursor::new(wire), Some(receiving));
+        let mut buf = [0u8; 5];
+        assert_eq!(reader.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_record_with_wrong_nonce() {
+        let (sending, receiving) = cipher_pair();
+        let wire = seal_records(sending, &[&b"first"[..], &b"second"[..]]);
+
+        // Swap the records, so the second one is opened with the first one's nonce
+        let first_len = 4 + b"first".len() + MACBYTES;
+        let mut reordered = wire[first_len..].to_vec();
+        reordered.extend(wire[..first_len].iter().cloned());
+
+        let mut reader = CipherReader::new(Cursor::new(reordered), Some(receiving));
+        let mut buf = [0u8; 6];
+        assert_eq!(reader.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
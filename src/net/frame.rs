@@ -0,0 +1,250 @@
+use std::cmp;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::iter;
+
+// Every frame starts with a little-endian u32 header. The low 31 bits are the length of the
+// frame's payload, and the high bit is set when more frames of the same packet follow. Packets
+// larger than the max frame size get split across several frames.
+const MORE_FRAGMENTS: u32 = 1 << 31;
+
+// Largest payload length a frame header can describe
+pub const MAX_FRAME_SIZE_LIMIT: u32 = MORE_FRAGMENTS - 1;
+
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+// Splits packets into frames and joins them back together
+#[derive(Copy, Clone)]
+pub struct FrameCodec {
+    // Largest frame payload we send, and the largest we accept
+    pub max_frame_size: u32,
+
+    // Largest packet we'll reassemble before giving up on the peer
+    pub max_packet_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new() -> FrameCodec {
+        FrameCodec {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+
+    pub fn with_max_frame_size(max_frame_size: u32) -> FrameCodec {
+        assert!(max_frame_size > 0 && max_frame_size <= MAX_FRAME_SIZE_LIMIT,
+                "Max frame size must be between 1 and {}", MAX_FRAME_SIZE_LIMIT);
+
+        FrameCodec {
+            max_frame_size: max_frame_size,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+
+    pub fn write_packet<W: Write>(&self, writer: &mut W, data: &[u8]) -> io::Result<()> {
+        if data.len() > self.max_packet_size {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("Packet of {} bytes exceeds max packet size {}", data.len(), self.max_packet_size)));
+        }
+
+        let max_frame_size = self.max_frame_size as usize;
+        let mut offset = 0;
+        loop {
+            let frame_end = cmp::min(offset + max_frame_size, data.len());
+            let more = frame_end < data.len();
+
+            let mut header = (frame_end - offset) as u32;
+            if more {
+                header |= MORE_FRAGMENTS;
+            }
+
+            // Write the header and payload in one go so small packets go out in one segment
+            let mut frame = Vec::with_capacity(4 + frame_end - offset);
+            try!(write_u32(&mut frame, header));
+            frame.extend(data[offset..frame_end].iter().cloned());
+            try!(writer.write_all(&frame));
+
+            offset = frame_end;
+            if !more {
+                break;
+            }
+        }
+
+        writer.flush()
+    }
+
+    pub fn read_packet<R: Read>(&self, reader: &mut R) -> io::Result<Vec<u8>> {
+        let mut data = vec!();
+        loop {
+            let header = try!(read_u32(reader));
+            let more = header & MORE_FRAGMENTS != 0;
+            let frame_size = header & !MORE_FRAGMENTS;
+
+            if frame_size > self.max_frame_size {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("Frame of {} bytes exceeds max frame size {}", frame_size, self.max_frame_size)));
+            }
+            if data.len() + frame_size as usize > self.max_packet_size {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("Packet exceeds max packet size {}", self.max_packet_size)));
+            }
+
+            let start = data.len();
+            data.extend(iter::repeat(0).take(frame_size as usize));
+            try!(read_exact(reader, &mut data[start..]));
+
+            if !more {
+                break;
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Fill the whole buffer, retrying short reads. Hitting EOF part way through is an error.
+pub fn read_exact<T: Read>(reader: &mut T, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed in the middle of a read"));
+            },
+            Ok(bytes_read) => {
+                let tmp = buf;
+                buf = &mut tmp[bytes_read..];
+            },
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => { },
+            Err(e) => {
+                return Err(e);
+            },
+        }
+    }
+    Ok(())
+}
+
+pub fn read_u8<T: Read>(reader: &mut T) -> io::Result<u8> {
+    let mut buf: [u8; 1] = [0];
+    try!(read_exact(reader, &mut buf));
+    Ok(buf[0])
+}
+
+pub fn write_u8<T: Write>(writer: &mut T, data: u8) -> io::Result<()> {
+    writer.write_all(&[data])
+}
+
+pub fn read_u16<T: Read>(reader: &mut T) -> io::Result<u16> {
+    let mut buf: [u8; 2] = [0; 2];
+    try!(read_exact(reader, &mut buf));
+    Ok((buf[0] as u16) | ((buf[1] as u16) << 8))
+}
+
+pub fn write_u16<T: Write>(writer: &mut T, data: u16) -> io::Result<()> {
+    writer.write_all(&[data as u8, (data >> 8) as u8])
+}
+
+pub fn read_u32<T: Read>(reader: &mut T) -> io::Result<u32> {
+    let mut buf: [u8; 4] = [0; 4];
+    try!(read_exact(reader, &mut buf));
+    Ok((buf[0] as u32) | ((buf[1] as u32) << 8) | ((buf[2] as u32) << 16) | ((buf[3] as u32) << 24))
+}
+
+pub fn write_u32<T: Write>(writer: &mut T, data: u32) -> io::Result<()> {
+    writer.write_all(&[data as u8, (data >> 8) as u8, (data >> 16) as u8, (data >> 24) as u8])
+}
+
+pub fn read_u64<T: Read>(reader: &mut T) -> io::Result<u64> {
+    let low = try!(read_u32(reader)) as u64;
+    let high = try!(read_u32(reader)) as u64;
+    Ok(low | (high << 32))
+}
+
+pub fn write_u64<T: Write>(writer: &mut T, data: u64) -> io::Result<()> {
+    try!(write_u32(writer, data as u32));
+    write_u32(writer, (data >> 32) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{ErrorKind, Read};
+
+    use super::*;
+
+    // Hands out at most one byte per read, like a connection that's trickling data in
+    struct Trickle<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[0];
+            self.data = &self.data[1..];
+            Ok(1)
+        }
+    }
+
+    fn header(frame_size: u32, more: bool) -> Vec<u8> {
+        let mut header = vec!();
+        write_u32(&mut header, if more { frame_size | MORE_FRAGMENTS } else { frame_size }).unwrap();
+        header
+    }
+
+    #[test]
+    fn reassembles_packet_read_a_byte_at_a_time() {
+        let codec = FrameCodec::with_max_frame_size(3);
+        let packet: Vec<u8> = (0..10).collect();
+
+        let mut wire = vec!();
+        codec.write_packet(&mut wire, &packet).unwrap();
+
+        assert_eq!(codec.read_packet(&mut Trickle { data: &wire }).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_packet_cut_off_part_way() {
+        let codec = FrameCodec::with_max_frame_size(3);
+        let packet: Vec<u8> = (0..10).collect();
+
+        let mut wire = vec!();
+        codec.write_packet(&mut wire, &packet).unwrap();
+        wire.truncate(wire.len() - 1);
+
+        let e = codec.read_packet(&mut Trickle { data: &wire }).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_frame_over_max_frame_size() {
+        let codec = FrameCodec::with_max_frame_size(16);
+
+        // Only the header is there, so trying to read the announced payload would fail with EOF
+        // instead. The biggest header possible makes allocating it fail loudly too.
+        let wire = header(MAX_FRAME_SIZE_LIMIT, false);
+
+        let e = codec.read_packet(&mut Trickle { data: &wire }).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_fragments_adding_up_to_more_than_max_packet_size() {
+        let codec = FrameCodec { max_frame_size: 4, max_packet_size: 10 };
+
+        // Two full frames fit, the third would take the packet to 12 bytes
+        let mut wire = vec!();
+        for _ in 0..2 {
+            wire.extend(header(4, true));
+            wire.extend([0u8; 4].iter().cloned());
+        }
+        wire.extend(header(4, true));
+
+        let e = codec.read_packet(&mut Trickle { data: &wire }).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::ClientId;
+
+pub const DEFAULT_PING_INTERVAL_MS: u64 = 2000;
+
+pub const DEFAULT_IDLE_TIMEOUT_MS: u64 = 15000;
+
+// How the server keeps track of whether its clients are still there. Every connection gets pinged
+// regularly, and a connection that goes quiet for too long is cut off, pongs included.
+#[derive(Copy, Clone)]
+pub struct Heartbeat {
+    pub ping_interval_ms: u64,
+
+    // Should be a few ping intervals, so a couple of late pongs don't get anyone kicked
+    pub idle_timeout_ms: u64,
+}
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        Heartbeat {
+            ping_interval_ms: DEFAULT_PING_INTERVAL_MS,
+            idle_timeout_ms: DEFAULT_IDLE_TIMEOUT_MS,
+        }
+    }
+}
+
+// Latest round trip time to each connected client, in milliseconds. Written by the connection
+// threads, and read by slots through `ServerSlot::get_latency`.
+pub struct Latencies {
+    rtts: RwLock<HashMap<ClientId, u32>>,
+}
+
+impl Latencies {
+    pub fn new() -> Latencies {
+        Latencies {
+            rtts: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, client_id: ClientId) -> Option<u32> {
+        self.rtts.read().ok().expect("Latency table lock is poisoned").get(&client_id).cloned()
+    }
+
+    pub fn record(&self, client_id: ClientId, rtt_ms: u32) {
+        self.rtts.write().ok().expect("Latency table lock is poisoned").insert(client_id, rtt_ms);
+    }
+
+    pub fn remove(&self, client_id: ClientId) {
+        self.rtts.write().ok().expect("Latency table lock is poisoned").remove(&client_id);
+    }
+
+    pub fn snapshot(&self) -> Vec<(ClientId, u32)> {
+        self.rtts.read().ok().expect("Latency table lock is poisoned").iter().map(|(&client_id, &rtt_ms)| (client_id, rtt_ms)).collect()
+    }
+
+    // Swap in a snapshot taken from another process's table
+    pub fn replace(&self, rtts: Vec<(ClientId, u32)>) {
+        *self.rtts.write().ok().expect("Latency table lock is poisoned") = rtts.into_iter().collect();
+    }
+}
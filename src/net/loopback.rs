@@ -0,0 +1,175 @@
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::spawn;
+
+use super::{
+    BUILD_ID,
+    LOOPBACK_CAPABILITIES,
+    Client,
+    ClientId,
+    ConnectionEvent,
+    ConnectionId,
+    DisconnectReason,
+    InPacket,
+    NetError,
+    OutPacket,
+    PendingClient,
+    ResumeTicket,
+    ServerTransport,
+};
+
+// Connects clients to a server running in the same process, over channels instead of sockets.
+// Get one from `Server::loopback_connector`.
+#[derive(Clone)]
+pub struct LoopbackConnector {
+    new_client_t: Sender<PendingClient>,
+}
+
+impl LoopbackConnector {
+    pub fn new(new_client_t: Sender<PendingClient>) -> LoopbackConnector {
+        LoopbackConnector {
+            new_client_t: new_client_t,
+        }
+    }
+
+    pub fn connect(&self) -> Result<Client, NetError> {
+        let (to_server_t, to_server_r) = channel();
+        let (to_client_t, to_client_r) = channel();
+        let (accept_t, accept_r) = channel();
+
+        // Both ends are this very build, so there's nothing to negotiate
+        let pending_client = PendingClient {
+            transport: ServerTransport::Loopback(LoopbackServerEnd {
+                from_client: to_server_r,
+                to_client: to_client_t,
+                accept: accept_t,
+            }),
+            capabilities: LOOPBACK_CAPABILITIES,
+            build_id: BUILD_ID.to_string(),
+            resume: None,
+        };
+        try!(self.new_client_t.send(pending_client).map_err(|_| NetError::Disconnected));
+
+        // Wait for the server master to give us our ID
+        let ticket = try!(accept_r.recv().map_err(|_| NetError::Disconnected));
+
+        Ok(Client::new_loopback(ticket, to_server_t, to_client_r))
+    }
+}
+
+// The server's end of a loopback connection
+pub struct LoopbackServerEnd {
+    from_client: Receiver<OutPacket>,
+    to_client: Sender<Result<InPacket, NetError>>,
+    accept: Sender<ResumeTicket>,
+}
+
+impl LoopbackServerEnd {
+    pub fn accept(&self, ticket: ResumeTicket) -> io::Result<()> {
+        self.accept.send(ticket)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Loopback client went away during handshake"))
+    }
+
+    pub fn start(self,
+                 client_id: ClientId,
+                 connection_id: ConnectionId,
+                 packet_in_t: Sender<(ClientId, ConnectionId, ConnectionEvent)>,
+                 out_r: Receiver<OutPacket>,
+                 kick_r: Receiver<DisconnectReason>) {
+        let LoopbackServerEnd { from_client, to_client, .. } = self;
+
+        // Client input process
+        spawn(move || {
+            for packet in from_client.iter() {
+                packet_in_t.send((client_id, connection_id, ConnectionEvent::Packet(packet.into_in_packet())));
+            }
+
+            println!("Loopback client {} input thread shutting down", client_id);
+            packet_in_t.send((client_id, connection_id, ConnectionEvent::Closed));
+        });
+
+        // Client output process
+        spawn(move || {
+            for packet in out_r.iter() {
+                // Don't send anything else once the client has been kicked
+                if let Ok(reason) = kick_r.try_recv() {
+                    to_client.send(Err(NetError::Kicked(reason)));
+                    return;
+                }
+
+                if to_client.send(Ok(packet.into_in_packet())).is_err() {
+                    println!("Loopback client {} went away, shutting output thread down", client_id);
+                    return;
+                }
+            }
+
+            // The master dropped the connection. Tell the client why if it was kicked.
+            if let Ok(reason) = kick_r.try_recv() {
+                to_client.send(Err(NetError::Kicked(reason)));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::spawn;
+
+    use net::{DisconnectReason, Message, NetError, Server, ServerSlot, SlotEvent, TypeTag};
+
+    #[derive(Debug, PartialEq, RustcEncodable, RustcDecodable)]
+    struct Echo(u32);
+
+    impl Message for Echo {
+        fn type_tag() -> TypeTag { 1000 }
+    }
+
+    #[test]
+    fn round_trip_through_in_process_server() {
+        let mut server = Server::new();
+        let connector = server.loopback_connector();
+        let slot: ServerSlot<Echo, Echo> = server.create_slot();
+        spawn(move || {
+            server.run();
+        });
+
+        let mut client = connector.connect().unwrap();
+        match slot.receive() {
+            SlotEvent::Joined(client_id) => assert_eq!(client_id, client.get_id()),
+            _ => panic!("Expected the client to join the default slot"),
+        }
+
+        client.send_message(&Echo(7)).unwrap();
+        match slot.receive() {
+            SlotEvent::Received(client_id, Echo(n)) => slot.send(client_id, &Echo(n + 1)),
+            _ => panic!("Expected the slot to receive the client's message"),
+        }
+
+        assert_eq!(client.receive_message::<Echo>().unwrap(), Echo(8));
+    }
+
+    #[test]
+    fn kicks_client_left_with_no_slot() {
+        let mut server = Server::new();
+        let connector = server.loopback_connector();
+        let counters = server.rate_limit_counters();
+        let slot: ServerSlot<Echo, Echo> = server.create_slot();
+        spawn(move || {
+            server.run();
+        });
+
+        let mut client = connector.connect().unwrap();
+        match slot.receive() {
+            SlotEvent::Joined(_) => { },
+            _ => panic!("Expected the client to join the default slot"),
+        }
+
+        // The default slot is also the fallback, so there's nowhere left to put the client
+        slot.close();
+        match client.receive() {
+            Err(NetError::Kicked(DisconnectReason::NoSlot)) => { },
+            _ => panic!("Expected the client to be told there's no slot for it"),
+        }
+        assert_eq!(counters.kicked(DisconnectReason::NoSlot), 1);
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use bincode::rustc_serialize::{DecodingError, decode, encode};
+use bincode::SizeLimit;
+
+use super::ClientId;
+
+// Goes on the wire in front of every message, saying which type it is
+pub type TypeTag = u16;
+
+// Something slots and clients send each other. Each message type needs a tag no other message type
+// uses, so a packet of one type is never mistaken for another.
+pub trait Message: Encodable + Decodable {
+    fn type_tag() -> TypeTag;
+}
+
+// For slots that never expect to hear anything from their clients
+impl Message for () {
+    fn type_tag() -> TypeTag { 0 }
+}
+
+// Why a packet couldn't be read as the message we wanted
+#[derive(Debug)]
+pub enum MessageError {
+    WrongType { expected: TypeTag, found: TypeTag },
+    Decode(DecodingError),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageError::WrongType { expected, found } =>
+                write!(f, "expected message type {}, got message type {}", expected, found),
+            MessageError::Decode(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<DecodingError> for MessageError {
+    fn from(e: DecodingError) -> MessageError {
+        MessageError::Decode(e)
+    }
+}
+
+// What a typed slot hears from the server master
+pub enum SlotEvent<In> {
+    Joined(ClientId),
+    Disconnected(ClientId),
+    Received(ClientId, In),
+    Malformed(ClientId, MessageError), // The client sent something that wasn't an `In`
+    Shutdown,
+}
+
+// A value encoded up front, so it can go in a message without being moved or cloned into it. It
+// remembers its type, so the receiver can only decode what the sender encoded.
+pub struct Encoded<T> {
+    data: Vec<u8>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T: Encodable> Encoded<T> {
+    pub fn new(value: &T) -> Encoded<T> {
+        Encoded {
+            data: encode(value, SizeLimit::Infinite).ok().expect("Failed to encode value"),
+            value: PhantomData,
+        }
+    }
+}
+
+impl<T: Decodable> Encoded<T> {
+    pub fn decode(&self) -> Result<T, DecodingError> {
+        decode(&self.data)
+    }
+}
+
+impl<T> Encodable for Encoded<T> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.data.encode(s)
+    }
+}
+
+impl<T> Decodable for Encoded<T> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Encoded<T>, D::Error> {
+        Ok(Encoded {
+            data: try!(Decodable::decode(d)),
+            value: PhantomData,
+        })
+    }
+}
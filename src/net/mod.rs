@@ -0,0 +1,2289 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Select, Sender, SyncSender, TryRecvError, TrySendError};
+use std::thread;
+use std::thread::{Builder, spawn};
+use std::time::Duration;
+use time;
+
+use rand;
+
+use timer::Timer;
+
+use rustc_serialize::Encodable;
+use rustc_serialize::Decodable;
+
+use bincode::rustc_serialize::{EncoderWriter, EncodingError, DecoderReader, DecodingError, encode_into, decode_from};
+use bincode::SizeLimit;
+
+pub use self::capture::{Capture, CaptureEvent, CaptureReader, CaptureRecord};
+pub use self::compress::{Compression, CompressionStats, DEFAULT_COMPRESSION_THRESHOLD};
+pub use self::crypto::{CipherReader, CipherWriter, ServerIdentity, ServerKey, parse_server_key};
+pub use self::frame::FrameCodec;
+pub use self::heartbeat::{Heartbeat, Latencies};
+pub use self::loopback::LoopbackConnector;
+pub use self::message::{Encoded, Message, MessageError, SlotEvent, TypeTag};
+pub use self::rate_limit::{RateLimitCounters, RateLimits};
+pub use self::remote::{LinkSecret, authenticate_accepted_link, authenticate_connected_link, read_value, write_value};
+use self::capture::{replay_into_client, replay_into_slot};
+use self::compress::decompress;
+use self::crypto::{Role, split_stream};
+use self::frame::{read_exact, read_u8, read_u16, read_u32, read_u64, write_u8, write_u16, write_u32, write_u64};
+use self::loopback::LoopbackServerEnd;
+use self::rate_limit::RateLimiter;
+
+mod capture;
+mod compress;
+mod crypto;
+mod frame;
+mod heartbeat;
+mod loopback;
+mod message;
+mod rate_limit;
+mod remote;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Some basic types
+
+pub type ClientId = u32;
+
+pub type ServerSlotId = u32;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Handshake
+
+// Bump this whenever the wire format of any packet changes
+pub const PROTOCOL_VERSION: u32 = 22;
+
+pub const BUILD_ID: &'static str = concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION"));
+
+// Magic bytes at the start of every handshake, so we can tell a reforge peer from random garbage
+const HANDSHAKE_MAGIC: [u8; 4] = [b'R', b'F', b'G', b'E'];
+
+// Bit set of optional features a peer supports. The negotiated set is the intersection of both
+// peers' capabilities. A server can also require some, and turns away clients without them, so
+// nobody can talk it out of encrypting by leaving the bit off their hello.
+pub type Capabilities = u32;
+
+pub const CAPABILITIES_NONE: Capabilities = 0;
+
+// Traffic after the handshake is encrypted
+pub const CAPABILITY_ENCRYPTION: Capabilities = 1 << 0;
+
+// Large packets may be sent compressed
+pub const CAPABILITY_COMPRESSION: Capabilities = 1 << 1;
+
+// Capabilities this build supports
+pub const LOCAL_CAPABILITIES: Capabilities = CAPABILITY_ENCRYPTION | CAPABILITY_COMPRESSION;
+
+// Capabilities of in-process connections, which never touch the network
+pub const LOOPBACK_CAPABILITIES: Capabilities = LOCAL_CAPABILITIES & !CAPABILITY_ENCRYPTION & !CAPABILITY_COMPRESSION;
+
+// How big a packet has to be to get compressed on a connection with the negotiated `capabilities`,
+// or None if the peers didn't agree on compression and everything goes out raw
+fn compression_threshold(capabilities: Capabilities, threshold: usize) -> Option<usize> {
+    if capabilities & CAPABILITY_COMPRESSION != 0 {
+        Some(threshold)
+    } else {
+        None
+    }
+}
+
+// Why the server turned a client away
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HandshakeRejection {
+    BadMagic,
+    ProtocolMismatch,
+    MissingCapabilities, // The client doesn't support everything the server requires
+}
+
+impl HandshakeRejection {
+    fn to_u8(self) -> u8 {
+        match self {
+            HandshakeRejection::BadMagic => 0,
+            HandshakeRejection::ProtocolMismatch => 1,
+            HandshakeRejection::MissingCapabilities => 2,
+        }
+    }
+    
+    fn from_u8(code: u8) -> Option<HandshakeRejection> {
+        match code {
+            0 => Some(HandshakeRejection::BadMagic),
+            1 => Some(HandshakeRejection::ProtocolMismatch),
+            2 => Some(HandshakeRejection::MissingCapabilities),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    BadMagic,
+    UnknownRejection(u8),
+    Unencrypted, // We pinned the server's key, but it wouldn't encrypt so it couldn't prove it has it
+    Rejected {
+        reason: HandshakeRejection,
+        server_version: u32,
+        server_build: String,
+    },
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeError::Io(ref e) => write!(f, "Connection failed during handshake: {}", e),
+            HandshakeError::BadMagic => write!(f, "Server is not a reforge server"),
+            HandshakeError::UnknownRejection(code) => write!(f, "Server rejected connection (code {})", code),
+            HandshakeError::Unencrypted => write!(f, "Server refused to encrypt the connection, so it can't be trusted"),
+            HandshakeError::Rejected { reason, server_version, ref server_build } => match reason {
+                HandshakeRejection::BadMagic =>
+                    write!(f, "Server rejected our handshake"),
+                HandshakeRejection::ProtocolMismatch =>
+                    write!(f, "Version mismatch: server v{} ({}), client v{} ({})",
+                           server_version, server_build, PROTOCOL_VERSION, BUILD_ID),
+                HandshakeRejection::MissingCapabilities =>
+                    write!(f, "Server requires features this client doesn't support"),
+            },
+        }
+    }
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> HandshakeError {
+        HandshakeError::Io(e)
+    }
+}
+
+// The first thing each peer sends. The layout of this must never change, or mismatched peers
+// won't even be able to tell each other they're mismatched.
+pub struct Hello {
+    pub protocol_version: u32,
+    pub build_id: String,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn local() -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            build_id: BUILD_ID.to_string(),
+            capabilities: LOCAL_CAPABILITIES,
+        }
+    }
+    
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(writer.write_all(&HANDSHAKE_MAGIC));
+        try!(write_u32(writer, self.protocol_version));
+        try!(write_u32(writer, self.capabilities));
+        try!(write_u16(writer, self.build_id.len() as u16));
+        try!(writer.write_all(self.build_id.as_bytes()));
+        Ok(())
+    }
+    
+    fn read_from<R: Read>(reader: &mut R) -> Result<Hello, HandshakeError> {
+        use std::io::{Error, ErrorKind};
+    
+        let mut magic = [0u8; 4];
+        try!(read_exact(reader, &mut magic));
+        if magic != HANDSHAKE_MAGIC {
+            return Err(HandshakeError::BadMagic);
+        }
+        
+        let protocol_version = try!(read_u32(reader));
+        let capabilities = try!(read_u32(reader));
+        let build_id_len = try!(read_u16(reader)) as usize;
+        
+        let mut build_id = vec![0u8; build_id_len];
+        try!(read_exact(reader, &mut build_id));
+        let build_id = try!(String::from_utf8(build_id)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Build ID is not valid UTF-8")));
+        
+        Ok(Hello {
+            protocol_version: protocol_version,
+            build_id: build_id,
+            capabilities: capabilities,
+        })
+    }
+}
+
+// Proof that a client owns a session, so it can pick it back up after its connection drops
+#[derive(Copy, Clone)]
+pub struct ResumeTicket {
+    pub client_id: ClientId,
+    pub token: u64,
+}
+
+// What a reconnecting client asks the server for
+#[derive(Copy, Clone)]
+pub struct ResumeRequest {
+    pub ticket: ResumeTicket,
+    
+    // How many data packets the client has read this session. The server replays everything after
+    // them.
+    pub received: u64,
+}
+
+// Result of a successful handshake
+pub struct Handshake {
+    pub client_id: ClientId,
+    pub capabilities: Capabilities,
+    pub peer_build_id: String,
+    pub resume_ticket: ResumeTicket,
+    
+    // Whether the server reattached us to the session we asked to resume
+    pub resumed: bool,
+}
+
+// A client that passed the handshake and is waiting for the server master to give it an ID
+pub struct PendingClient {
+    transport: ServerTransport,
+    capabilities: Capabilities,
+    build_id: String,
+    resume: Option<ResumeRequest>,
+}
+
+// Seconds a client gets to finish the handshake before it's hung up on
+const HANDSHAKE_TIMEOUT_SECONDS: u64 = 10;
+
+// What the server offers and asks of TCP clients during the handshake
+#[derive(Clone)]
+struct HandshakePolicy {
+    capabilities: Capabilities,
+    
+    // Clients without all of these are turned away
+    required: Capabilities,
+    
+    // What the server proves itself with during the key exchange
+    identity: Arc<ServerIdentity>,
+}
+
+// Server side of the handshake, up to the point where the client ID gets assigned
+fn server_handshake(mut stream: TcpStream, policy: &HandshakePolicy) -> Result<PendingClient, HandshakeError> {
+    // Each handshake has a thread to itself, so a peer that connects and goes quiet mustn't be
+    // able to hold on to it forever
+    try!(stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS))));
+    
+    let client_hello = Hello::read_from(&mut stream);
+    
+    // Always introduce ourselves, even if we're about to reject the client, so it can tell the
+    // player what version we are.
+    try!(Hello { capabilities: policy.capabilities, ..Hello::local() }.write_to(&mut stream));
+    
+    let rejection =
+        match client_hello {
+            Ok(ref hello) if hello.protocol_version != PROTOCOL_VERSION =>
+                Some(HandshakeRejection::ProtocolMismatch),
+            Ok(ref hello) if hello.capabilities & policy.required != policy.required =>
+                Some(HandshakeRejection::MissingCapabilities),
+            Ok(_) => None,
+            Err(HandshakeError::BadMagic) => Some(HandshakeRejection::BadMagic),
+            Err(e) => { return Err(e); },
+        };
+    
+    match rejection {
+        Some(reason) => {
+            try!(write_u8(&mut stream, 1));
+            try!(write_u8(&mut stream, reason.to_u8()));
+            Err(HandshakeError::Rejected {
+                reason: reason,
+                server_version: PROTOCOL_VERSION,
+                server_build: BUILD_ID.to_string(),
+            })
+        },
+        None => {
+            try!(write_u8(&mut stream, 0));
+            
+            let hello = client_hello.ok().expect("Client hello must be valid here");
+            let capabilities = hello.capabilities & policy.capabilities;
+            
+            // Everything from here on is encrypted if we agreed to
+            let (mut reader, writer) =
+                try!(split_stream(&mut stream, capabilities & CAPABILITY_ENCRYPTION != 0, &Role::Server(policy.identity.clone())));
+            
+            // Same protocol version, so the client follows up with its resume request
+            let resume =
+                if try!(read_u8(&mut reader)) != 0 {
+                    let client_id = try!(read_u32(&mut reader));
+                    let token = try!(read_u64(&mut reader));
+                    let received = try!(read_u64(&mut reader));
+                    Some(ResumeRequest { ticket: ResumeTicket { client_id: client_id, token: token }, received: received })
+                } else {
+                    None
+                };
+            
+            // The reader shares the socket, so this lifts the timeout for it too
+            try!(stream.set_read_timeout(None));
+            
+            Ok(PendingClient {
+                transport: ServerTransport::Tcp(reader, writer),
+                capabilities: capabilities,
+                build_id: hello.build_id,
+                resume: resume,
+            })
+        },
+    }
+}
+
+// Tell a client that passed the handshake its ID and how to resume its session later
+fn server_accept(writer: &mut CipherWriter<TcpStream>, ticket: ResumeTicket, resumed: bool) -> io::Result<()> {
+    try!(write_u32(writer, ticket.client_id));
+    try!(write_u64(writer, ticket.token));
+    try!(write_u8(writer, if resumed { 1 } else { 0 }));
+    writer.flush()
+}
+
+// With `server_key`, the server has to encrypt, and prove it holds that key while doing it
+fn client_handshake(mut stream: TcpStream, resume: Option<ResumeRequest>, server_key: Option<ServerKey>)
+    -> Result<(Handshake, CipherReader<TcpStream>, CipherWriter<TcpStream>), HandshakeError>
+{
+    // A server that takes the connection and then says nothing mustn't leave us waiting forever
+    try!(stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS))));
+    
+    try!(Hello::local().write_to(&mut stream));
+    
+    let server_hello = try!(Hello::read_from(&mut stream));
+    
+    match try!(read_u8(&mut stream)) {
+        0 => {
+            let capabilities = server_hello.capabilities & LOCAL_CAPABILITIES;
+            if server_key.is_some() && capabilities & CAPABILITY_ENCRYPTION == 0 {
+                return Err(HandshakeError::Unencrypted);
+            }
+            
+            // Everything from here on is encrypted if we agreed to
+            let (mut reader, mut writer) =
+                try!(split_stream(&mut stream, capabilities & CAPABILITY_ENCRYPTION != 0, &Role::Client(server_key)));
+            
+            match resume {
+                Some(request) => {
+                    try!(write_u8(&mut writer, 1));
+                    try!(write_u32(&mut writer, request.ticket.client_id));
+                    try!(write_u64(&mut writer, request.ticket.token));
+                    try!(write_u64(&mut writer, request.received));
+                },
+                None => {
+                    try!(write_u8(&mut writer, 0));
+                },
+            }
+            try!(writer.flush());
+            
+            let client_id = try!(read_u32(&mut reader));
+            let token = try!(read_u64(&mut reader));
+            let resumed = try!(read_u8(&mut reader)) != 0;
+            
+            // The reader shares the socket, so this lifts the timeout for it too
+            try!(stream.set_read_timeout(None));
+            
+            if server_hello.build_id != BUILD_ID {
+                println!("Server build {} differs from client build {}", server_hello.build_id, BUILD_ID);
+            }
+            
+            let handshake = Handshake {
+                client_id: client_id,
+                capabilities: capabilities,
+                peer_build_id: server_hello.build_id,
+                resume_ticket: ResumeTicket { client_id: client_id, token: token },
+                resumed: resumed,
+            };
+            
+            Ok((handshake, reader, writer))
+        },
+        _ => {
+            let code = try!(read_u8(&mut stream));
+            match HandshakeRejection::from_u8(code) {
+                Some(reason) => Err(HandshakeError::Rejected {
+                    reason: reason,
+                    server_version: server_hello.protocol_version,
+                    server_build: server_hello.build_id,
+                }),
+                None => Err(HandshakeError::UnknownRejection(code)),
+            }
+        },
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Errors
+
+// Why the server cut a client off. Sent to the client just before its connection is closed.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DisconnectReason {
+    PacketRateExceeded,
+    ByteRateExceeded,
+    QueueOverflow,
+    IdleTimeout,
+    NoSlot, // The slots the client could have been put in were all destroyed
+}
+
+impl DisconnectReason {
+    fn to_u8(self) -> u8 {
+        match self {
+            DisconnectReason::PacketRateExceeded => 0,
+            DisconnectReason::ByteRateExceeded => 1,
+            DisconnectReason::QueueOverflow => 2,
+            DisconnectReason::IdleTimeout => 3,
+            DisconnectReason::NoSlot => 4,
+        }
+    }
+    
+    fn from_u8(code: u8) -> Option<DisconnectReason> {
+        match code {
+            0 => Some(DisconnectReason::PacketRateExceeded),
+            1 => Some(DisconnectReason::ByteRateExceeded),
+            2 => Some(DisconnectReason::QueueOverflow),
+            3 => Some(DisconnectReason::IdleTimeout),
+            4 => Some(DisconnectReason::NoSlot),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectReason::PacketRateExceeded => write!(f, "sent too many packets"),
+            DisconnectReason::ByteRateExceeded => write!(f, "sent too much data"),
+            DisconnectReason::QueueOverflow => write!(f, "fell too far behind"),
+            DisconnectReason::IdleTimeout => write!(f, "stopped responding"),
+            DisconnectReason::NoSlot => write!(f, "nowhere left to put you"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),                // Reading from or writing to the connection failed
+    Handshake(HandshakeError),    // Couldn't agree with the server on how to talk
+    Decode(DecodingError),        // Packet didn't contain what we expected
+    Message(MessageError),        // Packet wasn't the message we expected
+    Disconnected,                 // The other end went away
+    SessionExpired,               // Reconnected, but the server had already given up on our session
+    Kicked(DisconnectReason),     // The server cut us off
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetError::Io(ref e) => write!(f, "Network error: {}", e),
+            NetError::Handshake(ref e) => write!(f, "{}", e),
+            NetError::Decode(ref e) => write!(f, "Received malformed packet: {}", e),
+            NetError::Message(ref e) => write!(f, "Received unexpected message: {}", e),
+            NetError::Disconnected => write!(f, "Connection lost"),
+            NetError::SessionExpired => write!(f, "Connection lost for too long, please log in again"),
+            NetError::Kicked(reason) => write!(f, "Disconnected by server: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> NetError {
+        use std::io::ErrorKind;
+    
+        match e.kind() {
+            ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset |
+            ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => NetError::Disconnected,
+            _ => NetError::Io(e),
+        }
+    }
+}
+
+impl From<HandshakeError> for NetError {
+    fn from(e: HandshakeError) -> NetError {
+        NetError::Handshake(e)
+    }
+}
+
+impl From<DecodingError> for NetError {
+    fn from(e: DecodingError) -> NetError {
+        NetError::Decode(e)
+    }
+}
+
+impl From<MessageError> for NetError {
+    fn from(e: MessageError) -> NetError {
+        NetError::Message(e)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Server Slot
+
+// Messages incoming to slots
+pub enum SlotInMsg {
+    Joined(ClientId),                   // Client joined slot (client_id)
+    Disconnected(ClientId),             // Client was disconnected from server (client_id)
+    ReceivedPacket(ClientId, InPacket), // Received packet from client (client_id, packet)
+    Shutdown,                           // Slot was destroyed. Nothing more will arrive, so the owner should stop.
+}
+
+// Messages outgoing from slots
+pub enum SlotOutMsg {
+    SendPacket(ServerSlotId, ClientId, OutPacket),        // Send a packet to a client (my_slot_id, client_id, packet)
+    BroadcastPacket(ServerSlotId, OutPacket),             // Send packet to all clients in slot (my_slot_id, packet)
+    CreateSlot(ServerSlotId),                             // Tell the server to make a new ServerSlot (slot_id)
+    TransferClient(ServerSlotId, ClientId, ServerSlotId), // Tell the server to transfer a client to a different slot
+    DestroySlot(ServerSlotId, ServerSlotId),              // Tell the server to destroy a slot (my_slot_id, slot_id)
+}
+
+// The untyped end of a slot's channels with the server master
+struct SlotHandle {
+    id: ServerSlotId,
+    sender: Sender<SlotOutMsg>,
+    receiver: Receiver<SlotInMsg>,
+    
+    // When this server slot requests to make a new slot, the new slot will come on this channel.
+    create_slot: Receiver<SlotHandle>,
+    
+    latencies: Arc<Latencies>,
+}
+
+impl Drop for SlotHandle {
+    fn drop(&mut self) {
+        self.sender.send(SlotOutMsg::DestroySlot(self.id, self.id));
+    }
+}
+
+// A slot's end of its channels with the server master. Clients in the slot send it `In` messages,
+// and it sends them `Out` messages.
+pub struct ServerSlot<In, Out> {
+    handle: SlotHandle,
+    
+    // A function type, so the slot can be sent between threads whatever the messages hold
+    messages: PhantomData<fn(In) -> Out>,
+}
+
+impl<In: Message, Out: Message> ServerSlot<In, Out> {
+    fn new(handle: SlotHandle) -> ServerSlot<In, Out> {
+        ServerSlot{handle: handle, messages: PhantomData}
+    }
+    
+    pub fn send(&self, client_id: ClientId, msg: &Out) {
+        self.handle.sender.send(SlotOutMsg::SendPacket(self.handle.id, client_id, OutPacket::message(msg)));
+    }
+    
+    pub fn broadcast(&self, msg: &Out) {
+        self.handle.sender.send(SlotOutMsg::BroadcastPacket(self.handle.id, OutPacket::message(msg)));
+    }
+    
+    pub fn receive(&self) -> SlotEvent<In> {
+        match self.handle.receiver.recv() {
+            Ok(msg) => self.decode(msg),
+            _ => panic!("Failed to receive SlotInMsg"),
+        }
+    }
+    
+    pub fn try_receive(&self) -> Result<SlotEvent<In>, TryRecvError> {
+        self.handle.receiver.try_recv().map(|msg| self.decode(msg))
+    }
+    
+    // For waiting on the slot alongside other channels with `Select`. Pass what comes out of it
+    // through `decode`.
+    pub fn receiver(&self) -> &Receiver<SlotInMsg> {
+        &self.handle.receiver
+    }
+    
+    // Read the message out of a packet from a client
+    pub fn decode(&self, msg: SlotInMsg) -> SlotEvent<In> {
+        match msg {
+            SlotInMsg::Joined(client_id) => SlotEvent::Joined(client_id),
+            SlotInMsg::Disconnected(client_id) => SlotEvent::Disconnected(client_id),
+            SlotInMsg::ReceivedPacket(client_id, mut packet) => match packet.read_message() {
+                Ok(msg) => SlotEvent::Received(client_id, msg),
+                Err(e) => SlotEvent::Malformed(client_id, e),
+            },
+            SlotInMsg::Shutdown => SlotEvent::Shutdown,
+        }
+    }
+    
+    pub fn create_slot<NewIn: Message, NewOut: Message>(&self) -> ServerSlot<NewIn, NewOut> {
+        self.handle.sender.send(SlotOutMsg::CreateSlot(self.handle.id));
+        match self.handle.create_slot.recv() {
+            Ok(handle) => ServerSlot::new(handle),
+            _ => panic!("Failed to receive newly created ServerSlot"),
+        }
+    }
+    
+    // Transfer a client to a different slot
+    pub fn transfer_client(&self, client_id: ClientId, to_slot: ServerSlotId) {
+        self.handle.sender.send(SlotOutMsg::TransferClient(self.handle.id, client_id, to_slot));
+    }
+    
+    pub fn create_slot_and_transfer_clients<NewIn: Message, NewOut: Message>(&self, clients: &Vec<ClientId>) -> ServerSlot<NewIn, NewOut> {
+        let new_slot = self.create_slot();
+        
+        for client_id in clients.iter() {
+            self.transfer_client(*client_id, new_slot.get_id());
+        }
+        
+        new_slot
+    }
+    
+    // Destroy another slot. Its owner gets a Shutdown message, and any clients still in it are
+    // moved to the server's fallback slot.
+    pub fn destroy_slot(&self, slot_id: ServerSlotId) {
+        self.handle.sender.send(SlotOutMsg::DestroySlot(self.handle.id, slot_id));
+    }
+    
+    // Destroy this slot, moving any clients still in it to the server's fallback slot. Dropping a
+    // slot does the same, so a slot's thread ending cleans it up too.
+    pub fn close(self) {
+        drop(self);
+    }
+    
+    pub fn get_id(&self) -> ServerSlotId {
+        self.handle.id
+    }
+    
+    // Round trip time to a client in milliseconds. None until the client has answered a ping, and
+    // always None for loopback clients.
+    pub fn get_latency(&self, client_id: ClientId) -> Option<u32> {
+        self.handle.latencies.get(client_id)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Server
+
+// The server's end of a client's connection
+enum ServerTransport {
+    Tcp(CipherReader<TcpStream>, CipherWriter<TcpStream>),
+    Loopback(LoopbackServerEnd),
+}
+
+impl ServerTransport {
+    // Finish the handshake by telling the client its ID
+    fn accept(&mut self, ticket: ResumeTicket, resumed: bool) -> io::Result<()> {
+        match *self {
+            ServerTransport::Tcp(_, ref mut writer) => server_accept(writer, ticket, resumed),
+            ServerTransport::Loopback(ref end) => end.accept(ticket),
+        }
+    }
+    
+    // Spawn the threads that move packets between the client and the server master
+    fn start(self,
+             client_id: ClientId,
+             connection_id: ConnectionId,
+             codec: FrameCodec,
+             compression: Compression,
+             limits: RateLimits,
+             counters: Arc<RateLimitCounters>,
+             heartbeat: Heartbeat,
+             latencies: Arc<Latencies>,
+             packet_in_t: Sender<(ClientId, ConnectionId, ConnectionEvent)>)
+             -> io::Result<ClientConnection>
+    {
+        let (client_out_t, client_out_r) = sync_channel(limits.max_queue_depth);
+        let (kick_t, kick_r) = channel();
+        
+        let stream =
+            match self {
+                ServerTransport::Tcp(reader, writer) => {
+                    let master_stream = try!(writer.get_ref().try_clone());
+                    
+                    // Pongs keep the connection busy, so a read that times out means the client
+                    // has gone quiet
+                    try!(master_stream.set_read_timeout(Some(Duration::from_millis(heartbeat.idle_timeout_ms))));
+                    
+                    // Client input process
+                    let limiter = RateLimiter::new(limits, counters);
+                    let in_compression = compression.clone();
+                    spawn(move || {
+                        handle_client_in(client_id, connection_id, reader, codec, in_compression, limiter, latencies, packet_in_t);
+                    });
+                    
+                    // Client output process
+                    spawn(move || {
+                        handle_client_out(client_id, writer, codec, compression, heartbeat, client_out_r, kick_r);
+                    });
+                    
+                    Some(master_stream)
+                },
+                ServerTransport::Loopback(end) => {
+                    // Loopback clients are part of this process, so there's no point rate limiting them
+                    end.start(client_id, connection_id, packet_in_t, client_out_r, kick_r);
+                    None
+                },
+            };
+        
+        Ok(ClientConnection {
+            id: connection_id,
+            out: client_out_t,
+            kick: kick_t,
+            stream: stream,
+        })
+    }
+}
+
+// How long the server holds on to a dropped client's session by default
+pub const DEFAULT_RESUME_GRACE_SECONDS: i64 = 30;
+
+// Most packets we'll hold for a client that hasn't said it got them. A dropped client that misses
+// more than this is given up on, and a connected one can only resume from the newest of them.
+const MAX_UNACKED_PACKETS: usize = 1024;
+
+// Identifies one TCP connection, so packets and disconnects from a connection that has since been
+// replaced by a resumed one can be told apart
+type ConnectionId = u32;
+
+// What a client's input thread tells the server master
+pub enum ConnectionEvent {
+    Packet(InPacket),
+    Acked(u64),               // The client has read this many data packets this session
+    Closed,
+    Kicked(DisconnectReason), // The client broke its rate limits
+    TimedOut,                 // Nothing came from the client for too long
+}
+
+// A client's live connection
+struct ClientConnection {
+    id: ConnectionId,
+    out: SyncSender<OutPacket>,
+    
+    // Tells the output thread to give the client a reason and hang up
+    kick: Sender<DisconnectReason>,
+    
+    // Kept so we can cut the connection off when a newer one resumes the session. None for
+    // loopback clients.
+    stream: Option<TcpStream>,
+}
+
+// Everything the server master knows about a client
+struct ClientEntry {
+    slot_id: ServerSlotId,
+    resume_token: u64,
+    
+    // None while the client is disconnected but may still resume its session
+    connection: Option<ClientConnection>,
+    
+    // When the client gets dropped for good if it doesn't resume
+    resume_deadline: Option<time::Timespec>,
+    
+    // Packets the client may not have read yet, oldest first, replayed when it resumes. Packets
+    // are numbered in the order they're sent, starting from zero for each session. TCP keeps them
+    // in that order, so the numbers never go on the wire: the client counts what it's read and
+    // acknowledges that count in its pongs.
+    unacked: VecDeque<OutPacket>,
+    
+    // Number of the next packet to send
+    next_seq: u64,
+}
+
+// Something that woke the server master up
+enum MasterEvent {
+    NewClient(PendingClient),
+    PacketIn(ClientId, ConnectionId, ConnectionEvent),
+    SlotOut(SlotOutMsg),
+    
+    // A held session may have run out
+    Expiry,
+}
+
+impl ClientEntry {
+    fn new(slot_id: ServerSlotId, resume_token: u64, connection: ClientConnection) -> ClientEntry {
+        ClientEntry {
+            slot_id: slot_id,
+            resume_token: resume_token,
+            connection: Some(connection),
+            resume_deadline: None,
+            unacked: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+    
+    // Number of the oldest packet we're holding on to
+    fn first_unacked(&self) -> u64 {
+        self.next_seq - self.unacked.len() as u64
+    }
+    
+    // Returns false if the client has fallen too far behind to take any more
+    fn send(&mut self, packet: OutPacket) -> bool {
+        // A loopback connection only drops when the whole server does, so there's nothing to
+        // replay to it
+        let resumable =
+            match self.connection {
+                Some(ref connection) => connection.stream.is_some(),
+                None => true,
+            };
+        
+        if resumable {
+            self.unacked.push_back(packet.clone());
+            self.next_seq += 1;
+            
+            // A dropped client gets given up on once it's missed too much, but a connected one
+            // that's this far behind on acknowledging will just be unable to resume from before
+            if self.connection.is_some() && self.unacked.len() > MAX_UNACKED_PACKETS {
+                self.unacked.pop_front();
+            }
+        }
+        
+        match self.connection {
+            Some(ref connection) => match connection.out.try_send(packet) {
+                Err(TrySendError::Full(_)) => false,
+                
+                // If the output thread is gone the input thread will report the connection closed
+                _ => true,
+            },
+            None => true,
+        }
+    }
+    
+    // The client has read the first `received` packets of the session
+    fn acknowledge(&mut self, received: u64) {
+        while self.first_unacked() < received && self.unacked.pop_front().is_some() { }
+    }
+    
+    // Whether we still have everything sent after the first `received` packets
+    fn can_resume_from(&self, received: u64) -> bool {
+        received >= self.first_unacked() && received <= self.next_seq
+    }
+    
+    // Send a resumed client everything after the first `received` packets. Returns false if that's
+    // more than it can take.
+    fn replay(&mut self, received: u64) -> bool {
+        self.acknowledge(received);
+        
+        let connection =
+            match self.connection {
+                Some(ref connection) => connection,
+                None => { return true; },
+            };
+        
+        for packet in self.unacked.iter() {
+            if let Err(TrySendError::Full(_)) = connection.out.try_send(packet.clone()) {
+                return false;
+            }
+        }
+        
+        true
+    }
+}
+
+pub struct Server {
+    // Server slots. Maps slot ID to communication channels with slot
+    slots: HashMap<ServerSlotId, (Sender<SlotInMsg>, Sender<SlotHandle>)>,
+    
+    // Channel for communication between server master task and slots
+    slot_channel_t: Sender<SlotOutMsg>,
+    slot_channel_r: Receiver<SlotOutMsg>,
+    
+    // ID to give to next slot
+    next_slot_id: ServerSlotId,
+    
+    // Clients in a slot that gets destroyed are moved here
+    fallback_slot: ServerSlotId,
+    
+    // Frames packets going to and coming from clients
+    codec: FrameCodec,
+    
+    // How long a dropped client has to reconnect before it's logged out
+    resume_grace: time::Duration,
+    
+    // Handshaken clients from every client source (TCP acceptor, loopback connectors)
+    new_client_t: Sender<PendingClient>,
+    new_client_r: Receiver<PendingClient>,
+    
+    // Capabilities offered to TCP clients, and the ones they must have
+    capabilities: Capabilities,
+    required_capabilities: Capabilities,
+    
+    // Proves who we are to clients that encrypt
+    identity: Arc<ServerIdentity>,
+    
+    // Packets at least this big get compressed, if the client supports it
+    compression_threshold: usize,
+    
+    // What each client connection is allowed to send
+    rate_limits: RateLimits,
+    rate_limit_counters: Arc<RateLimitCounters>,
+    
+    // How often clients get pinged, and how long they can go quiet
+    heartbeat: Heartbeat,
+    latencies: Arc<Latencies>,
+    
+    // Where slot traffic gets recorded, if anywhere
+    capture: Option<Capture>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::with_codec(FrameCodec::new())
+    }
+    
+    pub fn with_codec(codec: FrameCodec) -> Server {
+        let (slot_channel_t, slot_channel_r) = channel();
+        let (new_client_t, new_client_r) = channel();
+    
+        Server {
+            slots: HashMap::new(),
+            slot_channel_t: slot_channel_t, slot_channel_r: slot_channel_r,
+            next_slot_id: 0,
+            fallback_slot: 0, // The default slot
+            codec: codec,
+            resume_grace: time::Duration::seconds(DEFAULT_RESUME_GRACE_SECONDS),
+            new_client_t: new_client_t, new_client_r: new_client_r,
+            capabilities: LOCAL_CAPABILITIES & !CAPABILITY_ENCRYPTION, // Encryption is opt-in
+            required_capabilities: CAPABILITIES_NONE,
+            identity: Arc::new(ServerIdentity::generate()),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            rate_limits: RateLimits::new(),
+            rate_limit_counters: Arc::new(RateLimitCounters::new()),
+            heartbeat: Heartbeat::new(),
+            latencies: Arc::new(Latencies::new()),
+            capture: None,
+        }
+    }
+    
+    // Get a handle for connecting in-process clients to this server
+    pub fn loopback_connector(&self) -> LoopbackConnector {
+        LoopbackConnector::new(self.new_client_t.clone())
+    }
+    
+    pub fn set_resume_grace_period(&mut self, seconds: i64) {
+        self.resume_grace = time::Duration::seconds(seconds);
+    }
+    
+    // Encrypt traffic with TCP clients after the handshake. Once it's on, clients that won't
+    // encrypt are turned away.
+    pub fn set_encryption(&mut self, enabled: bool) {
+        if enabled {
+            self.capabilities |= CAPABILITY_ENCRYPTION;
+            self.required_capabilities |= CAPABILITY_ENCRYPTION;
+        } else {
+            self.capabilities &= !CAPABILITY_ENCRYPTION;
+            self.required_capabilities &= !CAPABILITY_ENCRYPTION;
+        }
+    }
+    
+    // Turn away TCP clients that don't support all of `required`. Only offered capabilities can
+    // be required.
+    pub fn set_required_capabilities(&mut self, required: Capabilities) {
+        self.required_capabilities = required & self.capabilities;
+    }
+    
+    // Set the key pair we prove ourselves with, in place of the one made up for this run. Clients
+    // can only pin a key that stays the same.
+    pub fn set_identity(&mut self, identity: ServerIdentity) {
+        self.identity = Arc::new(identity);
+    }
+    
+    // Compress packets at least `threshold` bytes big sent to TCP clients, or turn compression
+    // off with None
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        match threshold {
+            Some(threshold) => {
+                self.capabilities |= CAPABILITY_COMPRESSION;
+                self.compression_threshold = threshold;
+            },
+            None => {
+                self.capabilities &= !CAPABILITY_COMPRESSION;
+            },
+        }
+    }
+    
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limits = limits;
+    }
+    
+    // Get a handle for watching how many clients are being throttled and kicked
+    pub fn rate_limit_counters(&self) -> Arc<RateLimitCounters> {
+        self.rate_limit_counters.clone()
+    }
+    
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = heartbeat;
+    }
+    
+    // Record everything going in and out of every slot to a capture file
+    pub fn start_capture(&mut self, path: &str) -> io::Result<()> {
+        self.capture = Some(try!(Capture::create(path)));
+        println!("Capturing slot traffic to {}", path);
+        Ok(())
+    }
+    
+    // Feed what clients sent to a slot in a capture into one of this server's slots. Call this
+    // before running the server.
+    pub fn replay_into_slot(&self, path: &str, captured_slot: ServerSlotId, slot_id: ServerSlotId) -> io::Result<()> {
+        use std::io::{Error, ErrorKind};
+        
+        let capture = try!(CaptureReader::open(path));
+        let slot_in_t =
+            match self.slots.get(&slot_id) {
+                Some(&(ref slot_in_t, _)) => slot_in_t.clone(),
+                None => { return Err(Error::new(ErrorKind::NotFound, format!("No slot {} to replay into", slot_id))); },
+            };
+        
+        replay_into_slot(capture, captured_slot, slot_in_t);
+        Ok(())
+    }
+    
+    // Set the slot that clients get moved to when the slot they're in is destroyed
+    pub fn set_fallback_slot(&mut self, slot_id: ServerSlotId) {
+        self.fallback_slot = slot_id;
+    }
+    
+    pub fn create_slot<In: Message, Out: Message>(&mut self) -> ServerSlot<In, Out> {
+        ServerSlot::new(self.create_slot_handle())
+    }
+    
+    fn create_slot_handle(&mut self) -> SlotHandle {
+        let (slot_in_t, slot_in_r) = channel();
+        let (create_slot_t, create_slot_r) = channel(); // Channel for sending newly created ServerSlots to the slot upon request
+        
+        let slot_id = self.next_slot_id;
+        self.slots.insert(slot_id, (slot_in_t, create_slot_t));
+        self.next_slot_id += 1;
+    
+        SlotHandle {
+            id: slot_id,
+            sender: self.slot_channel_t.clone(),
+            receiver: slot_in_r,
+            create_slot: create_slot_r,
+            latencies: self.latencies.clone(),
+        }
+    }
+    
+    // Accept TCP clients on the given address, and run the server
+    pub fn listen(&mut self, address: &str) {
+        if let Err(e) = self.start_listening(address) {
+            panic!("Server failed to listen on address {}: {}", address, e);
+        }
+        
+        self.run();
+    }
+    
+    // Accept TCP clients on the given address alongside loopback ones, once the server is running
+    pub fn start_listening(&self, address: &str) -> io::Result<()> {
+        let listener = try!(TcpListener::bind(address));
+        
+        let new_client_t = self.new_client_t.clone();
+        let policy = HandshakePolicy {
+            capabilities: self.capabilities,
+            required: self.required_capabilities,
+            identity: self.identity.clone(),
+        };
+        spawn(move || {
+            client_acceptor(listener, policy, new_client_t);
+        });
+        
+        Ok(())
+    }
+    
+    // Run the server master. Clients can only connect through loopback connectors unless the
+    // server was started with `listen`.
+    pub fn run(&mut self) {
+        // All connected clients, and disconnected clients that may still resume
+        let mut clients: HashMap<ClientId, ClientEntry> = HashMap::new();
+        
+        // Client task to master: packet channel
+        let (packet_in_t, packet_in_r): (Sender<(ClientId, ConnectionId, ConnectionEvent)>, Receiver<(ClientId, ConnectionId, ConnectionEvent)>) = channel();
+        
+        // Next ID to give to each client
+        let mut next_client_id = 0;
+        
+        // Next ID to give to each connection
+        let mut next_connection_id: ConnectionId = 0;
+        
+        // Wakes the master up when the earliest held session runs out
+        let mut expiry_timer = Timer::never();
+        let mut expiry_deadline: Option<time::Timespec> = None;
+        
+        // Manage server slots
+        loop {
+            // Sleep until there's something to do
+            let event = {
+                let select = Select::new();
+                let mut new_client_h = select.handle(&self.new_client_r);
+                let mut packet_in_h = select.handle(&packet_in_r);
+                let mut slot_h = select.handle(&self.slot_channel_r);
+                let mut expiry_h = select.handle(expiry_timer.receiver());
+                unsafe {
+                    new_client_h.add();
+                    packet_in_h.add();
+                    slot_h.add();
+                    expiry_h.add();
+                }
+                
+                let ready_id = select.wait();
+                if ready_id == new_client_h.id() {
+                    MasterEvent::NewClient(new_client_h.recv().ok().expect("Server new client channel is broken"))
+                } else if ready_id == packet_in_h.id() {
+                    let (client_id, connection_id, connection_event) = packet_in_h.recv().ok().expect("Server packet receiver channel is broken");
+                    MasterEvent::PacketIn(client_id, connection_id, connection_event)
+                } else if ready_id == slot_h.id() {
+                    MasterEvent::SlotOut(slot_h.recv().ok().expect("Server slot channel is broken"))
+                } else {
+                    let _ = expiry_h.recv();
+                    MasterEvent::Expiry
+                }
+            };
+            
+            // Clients whose outgoing queue filled up while handling this event
+            let mut overflowed: Vec<ClientId> = vec!();
+            
+            match event {
+                // Accept connections and process them, spawning a new tasks for each one
+                MasterEvent::NewClient(PendingClient { mut transport, capabilities, build_id, resume }) => {
+                    let now = time::now().to_timespec();
+                    
+                    // See if this client is picking up a session it lost, and we still have
+                    // everything it missed
+                    let resumed =
+                        resume.and_then(|request| match clients.get(&request.ticket.client_id) {
+                            Some(client) if client.resume_token == request.ticket.token && client.can_resume_from(request.received) => {
+                                match client.resume_deadline {
+                                    Some(deadline) if deadline < now => None,
+                                    _ => Some((request.ticket.client_id, request.received)),
+                                }
+                            },
+                            _ => None,
+                        });
+                    
+                    let client_id =
+                        match resumed {
+                            Some((client_id, _)) => client_id,
+                            None => {
+                                let client_id = next_client_id;
+                                next_client_id += 1;
+                                client_id
+                            },
+                        };
+                    
+                    // Hand out a fresh token with every connection
+                    let ticket = ResumeTicket { client_id: client_id, token: rand::random() };
+                    
+                    // Finish the handshake by sending back the client ID
+                    if let Err(e) = transport.accept(ticket, resumed.is_some()) {
+                        println!("Failed to send client ID to client: {}", e);
+                        continue;
+                    }
+                    
+                    let connection_id = next_connection_id;
+                    next_connection_id += 1;
+                    
+                    let compression_threshold = compression_threshold(capabilities, self.compression_threshold);
+                    let compression = Compression::new(compression_threshold, Arc::new(CompressionStats::new()));
+                    
+                    // Start moving packets to and from the client
+                    let connection =
+                        match transport.start(client_id, connection_id, self.codec, compression, self.rate_limits,
+                                              self.rate_limit_counters.clone(), self.heartbeat, self.latencies.clone(),
+                                              packet_in_t.clone()) {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                println!("Failed to start connection for client {}: {}", client_id, e);
+                                continue;
+                            },
+                        };
+                    
+                    match resumed {
+                        Some((_, received)) => {
+                            let client = clients.get_mut(&client_id).expect("Resumed client must exist");
+                            
+                            // If the old connection hasn't noticed it's dead yet, finish it off
+                            if let Some(ClientConnection { stream: Some(ref old_stream), .. }) = client.connection {
+                                old_stream.shutdown(Shutdown::Both);
+                            }
+                            
+                            client.connection = Some(connection);
+                            client.resume_token = ticket.token;
+                            client.resume_deadline = None;
+                            
+                            // Replay everything the client missed, including whatever the old
+                            // connection took with it
+                            if !client.replay(received) {
+                                overflowed.push(client_id);
+                            }
+                            
+                            println!("Client {} resumed its session in slot {}", client_id, client.slot_id);
+                        },
+                        None => {
+                            // New clients start in the default slot, whose ID is zero. If that's
+                            // been destroyed they go where its clients would have gone.
+                            let slot_id = if self.slots.contains_key(&0) { 0 } else { self.fallback_slot };
+                            clients.insert(client_id, ClientEntry::new(slot_id, ticket.token, connection));
+                            
+                            println!("Client {} connected (build {}, capabilities {:#x})", client_id, build_id, capabilities);
+                            
+                            // Tell the slot that it's been joined
+                            match self.slots.get(&slot_id) {
+                                Some(&(ref slot_in_t, _)) => {
+                                    slot_in_t.send(SlotInMsg::Joined(client_id));
+                                    self.capture(slot_id, || CaptureEvent::Joined(client_id));
+                                },
+                                None => {
+                                    self.rate_limit_counters.record_kick(DisconnectReason::NoSlot);
+                                    self.kick_client(client_id, DisconnectReason::NoSlot, &mut clients);
+                                },
+                            }
+                        },
+                    }
+                },
+                MasterEvent::PacketIn(client_id, connection_id, connection_event) => {
+                    // Ignore anything from a connection that has since been replaced
+                    let current =
+                        match clients.get(&client_id) {
+                            Some(client) => match client.connection {
+                                Some(ref connection) => connection.id == connection_id,
+                                None => false,
+                            },
+                            None => false,
+                        };
+                    if !current {
+                        continue;
+                    }
+                    
+                    match connection_event {
+                        ConnectionEvent::Packet(packet) => {
+                            // Send the received packet to the slot the client is in
+                            let slot_id = clients[&client_id].slot_id;
+                            match self.slots.get(&slot_id) {
+                                Some(&(ref slot, _)) => {
+                                    self.capture(slot_id, || CaptureEvent::Received(client_id, packet.buffer.get_ref().clone()));
+                                    slot.send(SlotInMsg::ReceivedPacket(client_id, packet));
+                                },
+                                None => {
+                                    self.rate_limit_counters.record_kick(DisconnectReason::NoSlot);
+                                    self.kick_client(client_id, DisconnectReason::NoSlot, &mut clients);
+                                },
+                            }
+                        },
+                        ConnectionEvent::Acked(received) => {
+                            clients.get_mut(&client_id).expect("Current client must exist").acknowledge(received);
+                        },
+                        ConnectionEvent::Kicked(reason) => {
+                            self.kick_client(client_id, reason, &mut clients);
+                        },
+                        ConnectionEvent::TimedOut => {
+                            // A half-open connection never closes by itself, so this is the only
+                            // way a client whose machine went away gets dropped
+                            self.rate_limit_counters.record_kick(DisconnectReason::IdleTimeout);
+                            self.kick_client(client_id, DisconnectReason::IdleTimeout, &mut clients);
+                        },
+                        ConnectionEvent::Closed => {
+                            // Client's connection dropped. Hold on to its session for a while in
+                            // case it comes back.
+                            self.latencies.remove(client_id);
+                            
+                            let client = clients.get_mut(&client_id).expect("Current client must exist");
+                            client.connection = None;
+                            client.resume_deadline = Some(time::now().to_timespec() + self.resume_grace);
+                            
+                            println!("Client {} lost its connection, holding its session for {} seconds", client_id, self.resume_grace.num_seconds());
+                        },
+                    }
+                },
+                MasterEvent::SlotOut(msg) => {
+                    match msg {
+                        SlotOutMsg::SendPacket(slot_id, client_id, packet) => match clients.get_mut(&client_id) {
+                            Some(client) => {
+                                self.capture(slot_id, || CaptureEvent::Sent(client_id, packet.buffer.get_ref().clone()));
+                                
+                                if !client.send(packet) {
+                                    overflowed.push(client_id);
+                                }
+                            },
+                            None => { println!("WARNING: Failed to send packet to invalid client ID {}", client_id); }
+                        },
+                        SlotOutMsg::BroadcastPacket(slot_id, packet) => {
+                            self.capture(slot_id, || CaptureEvent::Broadcast(packet.buffer.get_ref().clone()));
+                            
+                            for (client_id, client) in clients.iter_mut() {
+                                if slot_id == client.slot_id && !client.send(packet.clone()) {
+                                    overflowed.push(*client_id);
+                                }
+                            }
+                        },
+                        SlotOutMsg::CreateSlot(slot_id) =>  {
+                            // A destroyed slot's requests have nowhere to go
+                            let create_slot_t = self.slots.get(&slot_id).map(|&(_, ref create_slot_t)| create_slot_t.clone());
+                            if let Some(create_slot_t) = create_slot_t {
+                                create_slot_t.send(self.create_slot_handle());
+                            }
+                        },
+                        SlotOutMsg::TransferClient(slot_id, client_id, new_slot_id) => {
+                            match self.slots.get(&new_slot_id) {
+                                Some(slot) => {
+                                    if let Some(client) = clients.get_mut(&client_id) {
+                                        if client.slot_id == slot_id {
+                                            let &(ref slot_in_t, _) = slot;
+                                            client.slot_id = new_slot_id; // set the client's new slot ID
+                                            slot_in_t.send(SlotInMsg::Joined(client_id));
+                                            self.capture(new_slot_id, || CaptureEvent::Joined(client_id));
+                                        } else {
+                                            println!("WARNING: Non-owning slot can't transfer client {}", client_id);
+                                        }
+                                    } else {
+                                        println!("WARNING: Can't transfer non-existant client {}", client_id);
+                                    }
+                                },
+                                None => { println!("WARNING: Failed to transfer client {} to non-existant slot {}", client_id, new_slot_id); }
+                            }
+                        },
+                        SlotOutMsg::DestroySlot(_, slot_id) => {
+                            self.destroy_slot(slot_id, &mut clients);
+                        },
+                    }
+                },
+                MasterEvent::Expiry => {
+                    // A fired timer is spent, so make sure a new one gets set below
+                    expiry_deadline = None;
+                },
+            }
+            
+            // Kick clients that have fallen too far behind on what we're sending them
+            for client_id in overflowed {
+                self.rate_limit_counters.record_kick(DisconnectReason::QueueOverflow);
+                self.kick_client(client_id, DisconnectReason::QueueOverflow, &mut clients);
+            }
+            
+            // Drop clients that didn't come back in time, or missed too much while they were gone
+            {
+                let now = time::now().to_timespec();
+                let expired: Vec<ClientId> =
+                    clients.iter()
+                        .filter(|&(_, client)| match client.resume_deadline {
+                            Some(deadline) => deadline < now || client.unacked.len() > MAX_UNACKED_PACKETS,
+                            None => false,
+                        })
+                        .map(|(client_id, _)| *client_id)
+                        .collect();
+                
+                for client_id in expired {
+                    let client = clients.remove(&client_id).expect("Expired client must exist");
+                    self.leave_slot(client_id, client.slot_id);
+                    
+                    println!("Client {} disconnected from server master", client_id);
+                }
+            }
+            
+            // Wake up again when the next held session runs out
+            let next_deadline = clients.values().filter_map(|client| client.resume_deadline).min();
+            if next_deadline != expiry_deadline {
+                expiry_timer =
+                    match next_deadline {
+                        Some(deadline) => Timer::at(deadline),
+                        None => Timer::never(),
+                    };
+                expiry_deadline = next_deadline;
+            }
+        }
+    }
+    
+    // Cut a client off for misbehaving. Unlike a dropped connection, its session can't be resumed.
+    fn kick_client(&self, client_id: ClientId, reason: DisconnectReason, clients: &mut HashMap<ClientId, ClientEntry>) {
+        let client =
+            match clients.remove(&client_id) {
+                Some(client) => client,
+                None => { return; },
+            };
+        
+        self.latencies.remove(client_id);
+        
+        if let Some(ref connection) = client.connection {
+            connection.kick.send(reason);
+            
+            // A client that isn't reading won't get to the reason either, and would leave its
+            // output thread stuck writing to it
+            if reason == DisconnectReason::QueueOverflow || reason == DisconnectReason::IdleTimeout {
+                if let Some(ref stream) = connection.stream {
+                    stream.shutdown(Shutdown::Both);
+                }
+            }
+        }
+        
+        self.leave_slot(client_id, client.slot_id);
+        
+        println!("Client {} kicked: {}", client_id, reason);
+    }
+    
+    // Tell a slot that a client is gone for good. A slot that's already been destroyed doesn't
+    // need telling.
+    fn leave_slot(&self, client_id: ClientId, slot_id: ServerSlotId) {
+        if let Some(&(ref slot, _)) = self.slots.get(&slot_id) {
+            slot.send(SlotInMsg::Disconnected(client_id));
+            self.capture(slot_id, || CaptureEvent::Disconnected(client_id));
+        }
+    }
+    
+    // Record something to the capture file, if we're capturing. The event is only built if it's
+    // going to be recorded, since that means copying packets.
+    fn capture<F: FnOnce() -> CaptureEvent>(&self, slot_id: ServerSlotId, event: F) {
+        if let Some(ref capture) = self.capture {
+            capture.record(slot_id, event());
+        }
+    }
+    
+    // Remove a slot, telling its owner to shut down and moving any clients still in it to the
+    // fallback slot. If the fallback slot is gone too, the clients are disconnected.
+    fn destroy_slot(&mut self, slot_id: ServerSlotId, clients: &mut HashMap<ClientId, ClientEntry>) {
+        let (slot_in_t, _) =
+            match self.slots.remove(&slot_id) {
+                Some(slot) => slot,
+                None => { return; }, // Already destroyed
+            };
+        
+        slot_in_t.send(SlotInMsg::Shutdown);
+        
+        let stranded: Vec<ClientId> =
+            clients.iter()
+                .filter(|&(_, client)| client.slot_id == slot_id)
+                .map(|(client_id, _)| *client_id)
+                .collect();
+        
+        match self.slots.get(&self.fallback_slot) {
+            Some(&(ref fallback_in_t, _)) => {
+                for client_id in stranded {
+                    let client = clients.get_mut(&client_id).expect("Stranded client must exist");
+                    client.slot_id = self.fallback_slot;
+                    fallback_in_t.send(SlotInMsg::Joined(client_id));
+                    self.capture(self.fallback_slot, || CaptureEvent::Joined(client_id));
+                    
+                    println!("Client {} moved from destroyed slot {} to fallback slot {}", client_id, slot_id, self.fallback_slot);
+                }
+            },
+            None => {
+                for client_id in stranded {
+                    println!("Client {} stranded, slot {} was destroyed with no fallback slot", client_id, slot_id);
+                    
+                    self.rate_limit_counters.record_kick(DisconnectReason::NoSlot);
+                    self.kick_client(client_id, DisconnectReason::NoSlot, clients);
+                }
+            },
+        }
+        
+        println!("Destroyed slot {}", slot_id);
+    }
+}
+
+fn client_acceptor(listener: TcpListener, policy: HandshakePolicy, new_client_t: Sender<PendingClient>) {
+    for stream in listener.incoming() {
+        match stream {
+            Err(e) => { println!("Incoming connection failed: {}", e); },
+            Ok(stream) => {
+                // Handshake on its own thread so a slow client can't hold up everyone else
+                let new_client_t = new_client_t.clone();
+                let policy = policy.clone();
+                spawn(move || {
+                    match server_handshake(stream, &policy) {
+                        Ok(pending_client) => { new_client_t.send(pending_client); },
+                        Err(e) => { println!("Rejected incoming connection: {}", e); },
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn handle_client_in(client_id: ClientId,
+                    connection_id: ConnectionId,
+                    mut reader: CipherReader<TcpStream>,
+                    codec: FrameCodec,
+                    compression: Compression,
+                    mut limiter: RateLimiter,
+                    latencies: Arc<Latencies>,
+                    packet_in_t: Sender<(ClientId, ConnectionId, ConnectionEvent)>) {
+    use std::io::ErrorKind;
+    
+    loop {
+        let frame =
+            match read_frame(&mut reader, &codec, &compression) {
+                Ok(frame) => frame,
+                Err(NetError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    println!("Client {} input thread shutting down: timed out", client_id);
+                    packet_in_t.send((client_id, connection_id, ConnectionEvent::TimedOut));
+                    break;
+                },
+                Err(e) => {
+                    println!("Client {} input thread shutting down: {}", client_id, e);
+                    packet_in_t.send((client_id, connection_id, ConnectionEvent::Closed));
+                    break;
+                },
+            };
+        
+        if let Err(reason) = limiter.admit(frame.len()) {
+            packet_in_t.send((client_id, connection_id, ConnectionEvent::Kicked(reason)));
+            break;
+        }
+        
+        match frame {
+            Frame::Data(packet) => {
+                packet_in_t.send((client_id, connection_id, ConnectionEvent::Packet(packet)));
+            },
+            Frame::Pong(sent_ns, received) => {
+                let rtt_ms = (time::precise_time_ns().saturating_sub(sent_ns) / 1_000_000) as u32;
+                latencies.record(client_id, rtt_ms);
+                packet_in_t.send((client_id, connection_id, ConnectionEvent::Acked(received)));
+            },
+            Frame::Ping(_) => { }, // Only the server pings
+        }
+    }
+}
+
+// Something for a client's output thread to do
+enum ClientOutEvent {
+    Packet(OutPacket),
+    Kick(DisconnectReason),
+    Ping,
+    Closed, // The master dropped the connection
+}
+
+fn next_client_out_event(out_r: &Receiver<OutPacket>, kick_r: &Receiver<DisconnectReason>, ping_timer: &Timer) -> ClientOutEvent {
+    let select = Select::new();
+    let mut out_h = select.handle(out_r);
+    let mut kick_h = select.handle(kick_r);
+    let mut ping_h = select.handle(ping_timer.receiver());
+    unsafe {
+        out_h.add();
+        kick_h.add();
+        ping_h.add();
+    }
+    
+    let ready_id = select.wait();
+    if ready_id == out_h.id() {
+        match out_h.recv() {
+            Ok(packet) => ClientOutEvent::Packet(packet),
+            Err(_) => ClientOutEvent::Closed,
+        }
+    } else if ready_id == kick_h.id() {
+        match kick_h.recv() {
+            Ok(reason) => ClientOutEvent::Kick(reason),
+            Err(_) => ClientOutEvent::Closed,
+        }
+    } else {
+        let _ = ping_h.recv();
+        ClientOutEvent::Ping
+    }
+}
+
+fn handle_client_out(client_id: ClientId,
+                     mut writer: CipherWriter<TcpStream>,
+                     codec: FrameCodec,
+                     compression: Compression,
+                     heartbeat: Heartbeat,
+                     out_r: Receiver<OutPacket>,
+                     kick_r: Receiver<DisconnectReason>) {
+    // One timer for the life of the connection, rather than a new one every ping
+    let ping_timer = Timer::every(time::Duration::milliseconds(heartbeat.ping_interval_ms as i64));
+    
+    loop {
+        match next_client_out_event(&out_r, &kick_r, &ping_timer) {
+            ClientOutEvent::Packet(packet) => {
+                // Don't send anything else once the client has been kicked
+                if let Ok(reason) = kick_r.try_recv() {
+                    kick_stream(&mut writer, &codec, reason);
+                    break;
+                }
+                
+                if let Err(e) = packet.write_to(&mut writer, &codec, &compression) {
+                    println!("Client out failed to write packet, shutting output thread down: {}", e);
+                    break;
+                }
+            },
+            ClientOutEvent::Kick(reason) => {
+                kick_stream(&mut writer, &codec, reason);
+                break;
+            },
+            ClientOutEvent::Ping => {
+                if let Err(e) = codec.write_packet(&mut writer, &ping_frame(time::precise_time_ns())) {
+                    println!("Client out failed to write ping, shutting output thread down: {}", e);
+                    break;
+                }
+            },
+            ClientOutEvent::Closed => {
+                // The master dropped the connection. Tell the client why if it was kicked.
+                match kick_r.try_recv() {
+                    Ok(reason) => { kick_stream(&mut writer, &codec, reason); },
+                    Err(_) => { println!("Client out packet channel closed, shutting output thread down"); },
+                }
+                break;
+            },
+        }
+    }
+    
+    println!("Client {} compression: {}", client_id, compression.stats);
+}
+
+// Tell a client why it's being cut off, then cut it off
+fn kick_stream(writer: &mut CipherWriter<TcpStream>, codec: &FrameCodec, reason: DisconnectReason) {
+    if let Err(e) = codec.write_packet(writer, &[PACKET_KIND_KICK, reason.to_u8()]) {
+        println!("Failed to tell kicked client why: {}", e);
+    }
+    writer.get_ref().shutdown(Shutdown::Both);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Client
+
+// How many times a client tries to get its session back after losing its connection
+const RESUME_ATTEMPTS: u32 = 10;
+
+// Milliseconds to wait between attempts to resume a session
+const RESUME_RETRY_DELAY_MS: u64 = 1000;
+
+// The client's end of its connection to the server
+enum ClientTransport {
+    Tcp {
+        host: String,
+        codec: FrameCodec,
+        compression: Compression,
+        
+        // Shared with the receiver thread, which answers pings
+        writer: Arc<Mutex<CipherWriter<TcpStream>>>,
+    },
+    Loopback(Sender<OutPacket>),
+}
+
+// A freshly made TCP connection to the server
+struct TcpConnection {
+    writer: Arc<Mutex<CipherWriter<TcpStream>>>,
+    compression: Compression,
+    handshake: Handshake,
+    packet_receiver: Receiver<Result<InPacket, NetError>>,
+}
+
+pub struct Client {
+    id: ClientId,
+    capabilities: Capabilities,
+    resume_ticket: ResumeTicket,
+    transport: ClientTransport,
+    packet_receiver: Receiver<Result<InPacket, NetError>>,
+    
+    // Kept across reconnects
+    compression_stats: Arc<CompressionStats>,
+    
+    // The server's key, if we were given one to check it against
+    server_key: Option<ServerKey>,
+    
+    // Data packets handed to the caller this session. Shared with the receiver thread, which
+    // acknowledges them in its pongs.
+    received: Arc<AtomicUsize>,
+    
+    // Set while a lost connection is being resumed in the background
+    resuming: Option<Receiver<Result<TcpConnection, NetError>>>,
+    
+    // Packets sent while resuming, to go out once we're back
+    unsent: Vec<OutPacket>,
+}
+
+impl Client {
+    pub fn new(host: &str) -> Result<Client, NetError> {
+        Client::with_codec(host, FrameCodec::new())
+    }
+    
+    pub fn with_codec(host: &str, codec: FrameCodec) -> Result<Client, NetError> {
+        Client::with_server_key(host, codec, None)
+    }
+    
+    // Only talk to a server that proves it has `server_key`, so nobody can get in between us.
+    // Without one, any server that answers at `host` is taken at its word.
+    pub fn with_server_key(host: &str, codec: FrameCodec, server_key: Option<ServerKey>) -> Result<Client, NetError> {
+        let compression_stats = Arc::new(CompressionStats::new());
+        let received = Arc::new(AtomicUsize::new(0));
+        let connection = try!(Client::connect(host, codec, None, server_key, compression_stats.clone(), received.clone()));
+    
+        Ok(Client {
+            id: connection.handshake.client_id,
+            capabilities: connection.handshake.capabilities,
+            resume_ticket: connection.handshake.resume_ticket,
+            transport: ClientTransport::Tcp {
+                host: host.to_string(),
+                codec: codec,
+                compression: connection.compression,
+                writer: connection.writer,
+            },
+            packet_receiver: connection.packet_receiver,
+            compression_stats: compression_stats,
+            server_key: server_key,
+            received: received,
+            resuming: None,
+            unsent: vec!(),
+        })
+    }
+    
+    fn new_loopback(ticket: ResumeTicket,
+                    to_server: Sender<OutPacket>,
+                    packet_receiver: Receiver<Result<InPacket, NetError>>) -> Client {
+        Client {
+            id: ticket.client_id,
+            capabilities: LOOPBACK_CAPABILITIES,
+            resume_ticket: ticket,
+            transport: ClientTransport::Loopback(to_server),
+            packet_receiver: packet_receiver,
+            compression_stats: Arc::new(CompressionStats::new()),
+            server_key: None,
+            received: Arc::new(AtomicUsize::new(0)),
+            resuming: None,
+            unsent: vec!(),
+        }
+    }
+    
+    // Play back what the server sent a client in a capture, with no server involved. Anything sent
+    // is thrown away.
+    pub fn replay(path: &str, client_id: ClientId) -> Result<Client, NetError> {
+        let capture = try!(CaptureReader::open(path));
+        let (to_server, to_server_r) = channel();
+        let (packet_sender, packet_receiver) = channel();
+        
+        replay_into_client(capture, client_id, to_server_r, packet_sender);
+        
+        let ticket = ResumeTicket { client_id: client_id, token: 0 };
+        Ok(Client::new_loopback(ticket, to_server, packet_receiver))
+    }
+    
+    fn connect(host: &str,
+               codec: FrameCodec,
+               resume: Option<ResumeRequest>,
+               server_key: Option<ServerKey>,
+               compression_stats: Arc<CompressionStats>,
+               received: Arc<AtomicUsize>)
+               -> Result<TcpConnection, NetError>
+    {
+        let stream = try!(TcpStream::connect(host));
+
+        let (handshake, mut reader, writer) = try!(client_handshake(stream, resume, server_key));
+        let writer = Arc::new(Mutex::new(writer));
+        
+        let compression_threshold = compression_threshold(handshake.capabilities, DEFAULT_COMPRESSION_THRESHOLD);
+        let compression = Compression::new(compression_threshold, compression_stats);
+        
+        let (packet_sender, packet_receiver) = channel();
+        
+        let thread_compression = compression.clone();
+        let thread_writer = writer.clone();
+        try!(Builder::new().name("client_packet_receiver".to_string()).spawn(move || {
+            loop {
+                let packet =
+                    match read_frame(&mut reader, &codec, &thread_compression) {
+                        Ok(Frame::Data(packet)) => Ok(packet),
+                        Ok(Frame::Ping(sent_ns)) => {
+                            // Answer straight away so the server measures the network, not us.
+                            // If this fails the next read will too.
+                            let mut writer = thread_writer.lock().ok().expect("Client writer lock is poisoned");
+                            let _ = codec.write_packet(&mut *writer, &pong_frame(sent_ns, received.load(Ordering::SeqCst) as u64));
+                            continue;
+                        },
+                        Ok(Frame::Pong(_, _)) => { continue; }, // We never ping
+                        Err(e) => Err(e),
+                    };
+                let failed = packet.is_err();
+                
+                if packet_sender.send(packet).is_err() || failed {
+                    // Either the client is gone or the connection is. Either way we're done.
+                    break;
+                }
+            }
+        }));
+        
+        Ok(TcpConnection {
+            writer: writer,
+            compression: compression,
+            handshake: handshake,
+            packet_receiver: packet_receiver,
+        })
+    }
+    
+    // Start reconnecting in the background to pick our session back up where the server left it.
+    // Until that's done, sending queues packets up and `try_receive` has nothing for the caller.
+    fn start_resume(&mut self) -> Result<(), NetError> {
+        if self.resuming.is_some() {
+            return Ok(());
+        }
+        
+        let (host, codec) =
+            match self.transport {
+                ClientTransport::Tcp { ref host, codec, .. } => (host.clone(), codec),
+                
+                // A loopback connection only drops when the server itself is gone
+                ClientTransport::Loopback(_) => { return Err(NetError::Disconnected); },
+            };
+    
+        println!("Lost connection to server, trying to resume session");
+        
+        // Anything we hadn't handed to the caller yet gets sent again, so it's fine to lose
+        // whatever is still waiting in the old receiver
+        let request = ResumeRequest {
+            ticket: self.resume_ticket,
+            received: self.received.load(Ordering::SeqCst) as u64,
+        };
+        let server_key = self.server_key;
+        let compression_stats = self.compression_stats.clone();
+        let received = self.received.clone();
+        
+        let (result_t, result_r) = channel();
+        try!(Builder::new().name("client_resume".to_string()).spawn(move || {
+            result_t.send(Client::reconnect(host.as_str(), codec, request, server_key, compression_stats, received));
+        }));
+        
+        self.resuming = Some(result_r);
+        Ok(())
+    }
+    
+    // Keep trying to get our session back. Runs on its own thread so the caller doesn't sit
+    // through the retries.
+    fn reconnect(host: &str,
+                 codec: FrameCodec,
+                 request: ResumeRequest,
+                 server_key: Option<ServerKey>,
+                 compression_stats: Arc<CompressionStats>,
+                 received: Arc<AtomicUsize>)
+                 -> Result<TcpConnection, NetError>
+    {
+        let mut last_error = NetError::Disconnected;
+        for attempt in 0..RESUME_ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(Duration::from_millis(RESUME_RETRY_DELAY_MS));
+            }
+            
+            match Client::connect(host, codec, Some(request), server_key, compression_stats.clone(), received.clone()) {
+                Ok(connection) => {
+                    if !connection.handshake.resumed {
+                        return Err(NetError::SessionExpired);
+                    }
+                    return Ok(connection);
+                },
+                Err(NetError::Handshake(e)) => {
+                    // Retrying won't change the server's mind
+                    return Err(NetError::Handshake(e));
+                },
+                Err(e) => {
+                    println!("Attempt {} to resume session failed: {}", attempt + 1, e);
+                    last_error = e;
+                },
+            }
+        }
+        
+        Err(last_error)
+    }
+    
+    // Take up the resumed connection if there is one. Returns Ok(false) if we're still waiting for
+    // it and `wait` is false.
+    fn finish_resume(&mut self, wait: bool) -> Result<bool, NetError> {
+        let result =
+            match self.resuming {
+                Some(ref resuming) if wait => resuming.recv().unwrap_or(Err(NetError::Disconnected)),
+                Some(ref resuming) => match resuming.try_recv() {
+                    Ok(result) => result,
+                    Err(TryRecvError::Empty) => { return Ok(false); },
+                    Err(TryRecvError::Disconnected) => Err(NetError::Disconnected),
+                },
+                None => { return Ok(true); },
+            };
+        self.resuming = None;
+        
+        let connection = try!(result);
+        let (host, codec) =
+            match self.transport {
+                ClientTransport::Tcp { ref host, codec, .. } => (host.clone(), codec),
+                ClientTransport::Loopback(_) => unreachable!(),
+            };
+        
+        self.capabilities = connection.handshake.capabilities;
+        self.resume_ticket = connection.handshake.resume_ticket;
+        self.transport = ClientTransport::Tcp { host: host, codec: codec, compression: connection.compression, writer: connection.writer };
+        self.packet_receiver = connection.packet_receiver;
+        
+        println!("Resumed session as client {}", self.id);
+        
+        // Send what piled up while we were away. If the connection drops again, the rest just
+        // queues back up.
+        let unsent: Vec<OutPacket> = self.unsent.drain(..).collect();
+        for packet in unsent.iter() {
+            try!(self.send(packet));
+        }
+        
+        Ok(true)
+    }
+    
+    fn send_once(&mut self, packet: &OutPacket) -> Result<(), NetError> {
+        match self.transport {
+            ClientTransport::Tcp { ref writer, ref codec, ref compression, .. } => {
+                let mut writer = writer.lock().ok().expect("Client writer lock is poisoned");
+                try!(packet.write_to(&mut *writer, codec, compression));
+                Ok(())
+            },
+            ClientTransport::Loopback(ref to_server) => {
+                to_server.send(packet.clone()).map_err(|_| NetError::Disconnected)
+            },
+        }
+    }
+    
+    pub fn send(&mut self, packet: &OutPacket) -> Result<(), NetError> {
+        if !try!(self.finish_resume(false)) {
+            self.unsent.push(packet.clone());
+            return Ok(());
+        }
+        
+        match self.send_once(packet) {
+            Err(NetError::Disconnected) => {
+                try!(self.start_resume());
+                self.unsent.push(packet.clone());
+                Ok(())
+            },
+            result => result,
+        }
+    }
+    
+    pub fn receive(&mut self) -> Result<InPacket, NetError> {
+        loop {
+            try!(self.finish_resume(true));
+            
+            let packet =
+                match self.packet_receiver.recv() {
+                    Ok(packet) => packet,
+                    Err(_) => Err(NetError::Disconnected),
+                };
+            
+            match packet {
+                Ok(packet) => {
+                    self.received.fetch_add(1, Ordering::SeqCst);
+                    return Ok(packet);
+                },
+                Err(NetError::Disconnected) => { try!(self.start_resume()); },
+                Err(e) => { return Err(e); },
+            }
+        }
+    }
+    
+    // Returns Ok(None) if no packet has arrived yet, or we're still getting our session back
+    pub fn try_receive(&mut self) -> Result<Option<InPacket>, NetError> {
+        if !try!(self.finish_resume(false)) {
+            return Ok(None);
+        }
+        
+        let packet =
+            match self.packet_receiver.try_recv() {
+                Ok(packet) => packet,
+                Err(TryRecvError::Empty) => { return Ok(None); },
+                Err(TryRecvError::Disconnected) => Err(NetError::Disconnected),
+            };
+        
+        match packet {
+            Ok(packet) => {
+                self.received.fetch_add(1, Ordering::SeqCst);
+                Ok(Some(packet))
+            },
+            Err(NetError::Disconnected) => {
+                try!(self.start_resume());
+                Ok(None)
+            },
+            Err(e) => Err(e),
+        }
+    }
+    
+    // Send a message, tagged with its type
+    pub fn send_message<M: Message>(&mut self, msg: &M) -> Result<(), NetError> {
+        self.send(&OutPacket::message(msg))
+    }
+    
+    // Wait for the next packet and read it as an `M`. A client moves between slots that speak
+    // different messages, so the caller says which it expects.
+    pub fn receive_message<M: Message>(&mut self) -> Result<M, NetError> {
+        let mut packet = try!(self.receive());
+        Ok(try!(packet.read_message()))
+    }
+    
+    // Returns Ok(None) if no packet has arrived yet
+    pub fn try_receive_message<M: Message>(&mut self) -> Result<Option<M>, NetError> {
+        match try!(self.try_receive()) {
+            Some(mut packet) => Ok(Some(try!(packet.read_message()))),
+            None => Ok(None),
+        }
+    }
+    
+    pub fn get_id(&self) -> ClientId {
+        self.id
+    }
+    
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+    
+    pub fn get_compression_stats(&self) -> &CompressionStats {
+        &self.compression_stats
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Packet
+
+// First byte of every packet on the wire, saying what the rest of it holds
+const PACKET_KIND_DATA: u8 = 0;
+const PACKET_KIND_KICK: u8 = 1; // Followed by a DisconnectReason code
+const PACKET_KIND_COMPRESSED: u8 = 2; // Data packet, deflated
+const PACKET_KIND_PING: u8 = 3; // Followed by the sender's u64 timestamp in nanoseconds
+const PACKET_KIND_PONG: u8 = 4; // Followed by the timestamp from the ping it answers, and the u64 number of data packets read
+
+// What a packet on the wire turned out to be. Heartbeats are handled by the transport and never
+// reach slots or game code.
+enum Frame {
+    Data(InPacket),
+    Ping(u64),
+    Pong(u64, u64),
+}
+
+impl Frame {
+    fn len(&self) -> usize {
+        match *self {
+            Frame::Data(ref packet) => packet.len(),
+            Frame::Ping(_) => 8,
+            Frame::Pong(_, _) => 16,
+        }
+    }
+}
+
+fn ping_frame(timestamp: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(9);
+    data.push(PACKET_KIND_PING);
+    write_u64(&mut data, timestamp).ok().expect("Writing to a Vec can't fail");
+    data
+}
+
+// Pongs double as acknowledgements, so the server can let go of packets we've got
+fn pong_frame(timestamp: u64, received: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(17);
+    data.push(PACKET_KIND_PONG);
+    write_u64(&mut data, timestamp).ok().expect("Writing to a Vec can't fail");
+    write_u64(&mut data, received).ok().expect("Writing to a Vec can't fail");
+    data
+}
+
+// Read the next packet off a connection
+fn read_frame<T: Read>(reader: &mut T, codec: &FrameCodec, compression: &Compression) -> Result<Frame, NetError> {
+    use std::io::{Error, ErrorKind};
+    
+    let mut data = try!(codec.read_packet(reader));
+    
+    // Versions have to match exactly to get through the handshake, so anything unexpected here
+    // means the stream is garbage
+    match data.first().cloned() {
+        Some(PACKET_KIND_DATA) => {
+            data.remove(0);
+            compression.stats.record_received(data.len(), data.len());
+            Ok(Frame::Data(InPacket::new(data)))
+        },
+        Some(PACKET_KIND_COMPRESSED) => {
+            let raw = try!(decompress(&data[1..], codec.max_packet_size));
+            compression.stats.record_received(raw.len(), data.len() - 1);
+            Ok(Frame::Data(InPacket::new(raw)))
+        },
+        Some(PACKET_KIND_KICK) if data.len() == 2 => match DisconnectReason::from_u8(data[1]) {
+            Some(reason) => Err(NetError::Kicked(reason)),
+            None => Err(NetError::Io(Error::new(ErrorKind::InvalidData, "Kicked for unknown reason"))),
+        },
+        Some(PACKET_KIND_PING) if data.len() == 9 => Ok(Frame::Ping(try!(read_u64(&mut &data[1..])))),
+        Some(PACKET_KIND_PONG) if data.len() == 17 => {
+            let mut rest = &data[1..];
+            let sent_ns = try!(read_u64(&mut rest));
+            Ok(Frame::Pong(sent_ns, try!(read_u64(&mut rest))))
+        },
+        _ => Err(NetError::Io(Error::new(ErrorKind::InvalidData, "Received packet of unknown kind"))),
+    }
+}
+
+#[derive(Clone)]
+pub struct OutPacket {
+    buffer: io::Cursor<Vec<u8>>,
+}
+
+impl OutPacket {
+    pub fn new() -> OutPacket {
+        OutPacket{buffer: io::Cursor::new(vec!())}
+    }
+    
+    // A packet holding a single message, tagged with its type
+    pub fn message<M: Message>(msg: &M) -> OutPacket {
+        let mut packet = OutPacket::new();
+        packet.write(&M::type_tag()).ok().expect("Failed to encode message type tag");
+        packet.write(msg).ok().expect("Failed to encode message");
+        packet
+    }
+    
+    pub fn len(&self) -> usize {
+        self.buffer.get_ref().len()
+    }
+    
+    // Hand the packet's contents straight to the receiving side, for transports that don't
+    // need framing
+    pub fn into_in_packet(self) -> InPacket {
+        InPacket::new(self.buffer.into_inner())
+    }
+    
+    // The packet's contents, for data that gets written by hand and carried inside a message
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer.into_inner()
+    }
+    
+    // For packets that come in over a slot link already encoded
+    fn from_bytes(data: Vec<u8>) -> OutPacket {
+        OutPacket{buffer: io::Cursor::new(data)}
+    }
+    
+    // Frame the packet and write it to a stream, compressing it first if it's worth it
+    pub fn write_to<W: Write>(&self, writer: &mut W, codec: &FrameCodec, compression: &Compression) -> io::Result<()> {
+        let raw = self.buffer.get_ref();
+        
+        let data =
+            match try!(compression.compress(raw)) {
+                Some(compressed) => {
+                    let mut data = Vec::with_capacity(1 + compressed.len());
+                    data.push(PACKET_KIND_COMPRESSED);
+                    data.extend(compressed.into_iter());
+                    data
+                },
+                None => {
+                    let mut data = Vec::with_capacity(1 + raw.len());
+                    data.push(PACKET_KIND_DATA);
+                    data.extend(raw.iter().cloned());
+                    data
+                },
+            };
+        
+        compression.stats.record_sent(raw.len(), data.len() - 1);
+        codec.write_packet(writer, &data)
+    }
+    
+    pub fn write<'a, T>(&mut self, t: &T) -> Result<(), EncodingError>
+        where T: Encodable
+    {
+        encode_into(t, &mut self.buffer, SizeLimit::Infinite)
+    }
+}
+
+pub struct InPacket {
+    buffer: io::Cursor<Vec<u8>>,
+}
+
+impl InPacket {
+    pub fn new(data: Vec<u8>) -> InPacket {
+        InPacket{buffer: io::Cursor::new(data)}
+    }
+    
+    // Read the next data packet off a connection, skipping over heartbeats
+    pub fn new_from_reader<T: Read>(reader: &mut T, codec: &FrameCodec, compression: &Compression) -> Result<InPacket, NetError> {
+        loop {
+            if let Frame::Data(packet) = try!(read_frame(reader, codec, compression)) {
+                return Ok(packet);
+            }
+        }
+    }
+    
+    pub fn len(&self) -> usize {
+        self.buffer.get_ref().len()
+    }
+    
+    pub fn read<T: Decodable>(&mut self) -> Result<T, DecodingError> {
+        Ok(try!(decode_from(&mut self.buffer, SizeLimit::Infinite)))
+    }
+    
+    // Read a packet made with `OutPacket::message`
+    pub fn read_message<M: Message>(&mut self) -> Result<M, MessageError> {
+        let tag: TypeTag = try!(self.read());
+        if tag != M::type_tag() {
+            return Err(MessageError::WrongType { expected: M::type_tag(), found: tag });
+        }
+        
+        Ok(try!(self.read()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    use super::{CAPABILITY_COMPRESSION, CAPABILITY_ENCRYPTION, LOCAL_CAPABILITIES, LOOPBACK_CAPABILITIES};
+    use super::{HandshakeError, HandshakePolicy, HandshakeRejection, Hello, ServerIdentity, server_handshake};
+    use super::{Compression, CompressionStats, FrameCodec, Frame, OutPacket, PACKET_KIND_COMPRESSED, PACKET_KIND_DATA};
+    use super::{compression_threshold, read_frame};
+    use super::frame::read_u8;
+
+    // A packet that compresses well
+    fn big_packet() -> OutPacket {
+        let mut packet = OutPacket::new();
+        for _ in 0 .. 200 {
+            packet.write(&"the same ship, again").unwrap();
+        }
+        packet
+    }
+
+    // Write `packet` like a connection would, and read it back. Returns what it came out as, and
+    // the kind of packet it went over the wire as.
+    fn send_through(packet: &OutPacket, compression: &Compression) -> (Vec<u8>, u8) {
+        let codec = FrameCodec::new();
+        let mut wire = vec!();
+        packet.write_to(&mut wire, &codec, compression).unwrap();
+
+        let kind = codec.read_packet(&mut &wire[..]).unwrap()[0];
+        match read_frame(&mut &wire[..], &codec, compression) {
+            Ok(Frame::Data(received)) => (received.buffer.into_inner(), kind),
+            _ => panic!("Expected a data packet"),
+        }
+    }
+
+    #[test]
+    fn rejects_client_without_required_encryption() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let policy = HandshakePolicy {
+            capabilities: LOCAL_CAPABILITIES,
+            required: CAPABILITY_ENCRYPTION,
+            identity: Arc::new(ServerIdentity::generate()),
+        };
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server_handshake(stream, &policy).err()
+        });
+
+        // A client, or someone in the middle, leaving the encryption bit off
+        let mut stream = TcpStream::connect(address).unwrap();
+        Hello { capabilities: CAPABILITY_COMPRESSION, ..Hello::local() }.write_to(&mut stream).unwrap();
+
+        Hello::read_from(&mut stream).ok().expect("Server should introduce itself");
+        assert_eq!(read_u8(&mut stream).unwrap(), 1);
+        assert_eq!(HandshakeRejection::from_u8(read_u8(&mut stream).unwrap()), Some(HandshakeRejection::MissingCapabilities));
+
+        match server.join().unwrap() {
+            Some(HandshakeError::Rejected { reason: HandshakeRejection::MissingCapabilities, .. }) => { },
+            _ => panic!("Server should have rejected the client"),
+        }
+    }
+
+    #[test]
+    fn compresses_packets_over_threshold() {
+        let packet = big_packet();
+        let raw = packet.buffer.get_ref().clone();
+        let compression = Compression::new(Some(raw.len()), Arc::new(CompressionStats::new()));
+
+        assert_eq!(send_through(&packet, &compression), (raw.clone(), PACKET_KIND_COMPRESSED));
+        assert!(compression.stats.sent_ratio() < 0.5);
+        assert!(compression.stats.received_ratio() < 0.5);
+
+        // Just under the threshold isn't worth it
+        let compression = Compression::new(Some(raw.len() + 1), Arc::new(CompressionStats::new()));
+        assert_eq!(send_through(&packet, &compression), (raw, PACKET_KIND_DATA));
+        assert_eq!(compression.stats.sent_ratio(), 1.0);
+    }
+
+    #[test]
+    fn peer_without_compression_gets_raw_packets() {
+        let capabilities = LOCAL_CAPABILITIES & !CAPABILITY_COMPRESSION;
+        assert_eq!(compression_threshold(capabilities, 64), None);
+        assert_eq!(compression_threshold(LOOPBACK_CAPABILITIES, 64), None);
+        assert_eq!(compression_threshold(LOCAL_CAPABILITIES, 64), Some(64));
+
+        let packet = big_packet();
+        let raw = packet.buffer.get_ref().clone();
+        let compression = Compression::new(compression_threshold(capabilities, 64), Arc::new(CompressionStats::new()));
+        assert_eq!(send_through(&packet, &compression), (raw, PACKET_KIND_DATA));
+    }
+}
@@ -0,0 +1,258 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use time;
+
+use super::DisconnectReason;
+
+// Limits on what a single client connection may send. Clients that go over get throttled, and
+// clients that stay over get disconnected.
+#[derive(Copy, Clone)]
+pub struct RateLimits {
+    // Sustained packets per second, and how many packets can come in one burst
+    pub packets_per_second: u32,
+    pub packet_burst: u32,
+
+    // Sustained bytes per second, and how many bytes can come in one burst
+    pub bytes_per_second: u32,
+    pub byte_burst: u32,
+
+    // Most packets that can be waiting to go out to a client before it's considered too slow to
+    // keep around
+    pub max_queue_depth: usize,
+
+    // How many packets in a row a client can send over its limits before it's disconnected
+    pub max_strikes: u32,
+}
+
+impl RateLimits {
+    pub fn new() -> RateLimits {
+        RateLimits {
+            packets_per_second: 50,
+            packet_burst: 100,
+            bytes_per_second: 64 * 1024,
+            byte_burst: 256 * 1024,
+            max_queue_depth: 1024,
+            max_strikes: 100,
+        }
+    }
+}
+
+// Counts of throttled and disconnected clients across the whole server, for operators to keep an
+// eye on. Get them from `Server::rate_limit_counters`.
+pub struct RateLimitCounters {
+    throttled: AtomicUsize,
+    kicked_packet_rate: AtomicUsize,
+    kicked_byte_rate: AtomicUsize,
+    kicked_queue_overflow: AtomicUsize,
+    kicked_idle_timeout: AtomicUsize,
+    kicked_no_slot: AtomicUsize,
+}
+
+impl RateLimitCounters {
+    pub fn new() -> RateLimitCounters {
+        RateLimitCounters {
+            throttled: AtomicUsize::new(0),
+            kicked_packet_rate: AtomicUsize::new(0),
+            kicked_byte_rate: AtomicUsize::new(0),
+            kicked_queue_overflow: AtomicUsize::new(0),
+            kicked_idle_timeout: AtomicUsize::new(0),
+            kicked_no_slot: AtomicUsize::new(0),
+        }
+    }
+
+    // Number of packets that had to wait because their client was over its limits
+    pub fn throttled(&self) -> usize {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    // Number of clients disconnected for the given reason
+    pub fn kicked(&self, reason: DisconnectReason) -> usize {
+        self.kick_counter(reason).load(Ordering::Relaxed)
+    }
+
+    pub fn record_kick(&self, reason: DisconnectReason) {
+        self.kick_counter(reason).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn kick_counter(&self, reason: DisconnectReason) -> &AtomicUsize {
+        match reason {
+            DisconnectReason::PacketRateExceeded => &self.kicked_packet_rate,
+            DisconnectReason::ByteRateExceeded => &self.kicked_byte_rate,
+            DisconnectReason::QueueOverflow => &self.kicked_queue_overflow,
+            DisconnectReason::IdleTimeout => &self.kicked_idle_timeout,
+            DisconnectReason::NoSlot => &self.kicked_no_slot,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+struct TokenBucket {
+    capacity: f64,
+    rate: f64, // Tokens per second
+    tokens: f64,
+    last_refill_ns: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, capacity: u32) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity as f64,
+            rate: rate as f64,
+            tokens: capacity as f64,
+            last_refill_ns: time::precise_time_ns(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = time::precise_time_ns();
+        let elapsed = (now - self.last_refill_ns) as f64 / 1_000_000_000.0;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill_ns = now;
+    }
+
+    // Seconds until there's enough in the bucket to take this many tokens. Anything bigger than
+    // the bucket only needs a full one, and leaves it in debt.
+    fn wait_time(&self, amount: f64) -> f64 {
+        let needed = amount.min(self.capacity);
+        if self.tokens >= needed {
+            0.0
+        } else {
+            (needed - self.tokens) / self.rate
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+// Keeps one client connection within its rate limits
+pub struct RateLimiter {
+    limits: RateLimits,
+    packets: TokenBucket,
+    bytes: TokenBucket,
+
+    // Packets in a row that came in over the limits
+    strikes: u32,
+
+    counters: Arc<RateLimitCounters>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, counters: Arc<RateLimitCounters>) -> RateLimiter {
+        RateLimiter {
+            limits: limits,
+            packets: TokenBucket::new(limits.packets_per_second, limits.packet_burst),
+            bytes: TokenBucket::new(limits.bytes_per_second, limits.byte_burst),
+            strikes: 0,
+            counters: counters,
+        }
+    }
+
+    // Account for a packet that was just read. If the client is over its limits this blocks until
+    // it's back under them, which stops us reading from it in the meantime. Returns the reason to
+    // disconnect the client if it keeps going over.
+    pub fn admit(&mut self, packet_size: usize) -> Result<(), DisconnectReason> {
+        self.packets.refill();
+        self.bytes.refill();
+
+        let packet_wait = self.packets.wait_time(1.0);
+        let byte_wait = self.bytes.wait_time(packet_size as f64);
+
+        if packet_wait > 0.0 || byte_wait > 0.0 {
+            self.strikes += 1;
+            if self.strikes > self.limits.max_strikes {
+                let reason =
+                    if packet_wait >= byte_wait {
+                        DisconnectReason::PacketRateExceeded
+                    } else {
+                        DisconnectReason::ByteRateExceeded
+                    };
+                self.counters.record_kick(reason);
+                return Err(reason);
+            }
+
+            self.counters.throttled.fetch_add(1, Ordering::Relaxed);
+
+            let wait = packet_wait.max(byte_wait);
+            thread::sleep(Duration::from_millis((wait * 1000.0).ceil() as u64));
+
+            self.packets.refill();
+            self.bytes.refill();
+        } else {
+            self.strikes = 0;
+        }
+
+        self.packets.take(1.0);
+        self.bytes.take(packet_size as f64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use net::DisconnectReason;
+    use super::{RateLimitCounters, RateLimiter, RateLimits};
+
+    // Limits that are hard to hit by accident, for a test to tighten
+    fn loose_limits() -> RateLimits {
+        RateLimits {
+            packets_per_second: 1_000_000,
+            packet_burst: 1_000_000,
+            bytes_per_second: 1_000_000_000,
+            byte_burst: 1_000_000_000,
+            max_queue_depth: 16,
+            max_strikes: 2,
+        }
+    }
+
+    #[test]
+    fn admits_burst() {
+        let counters = Arc::new(RateLimitCounters::new());
+        let mut limiter = RateLimiter::new(RateLimits { packets_per_second: 1, packet_burst: 5, ..loose_limits() }, counters.clone());
+
+        for _ in 0 .. 5 {
+            assert_eq!(limiter.admit(100), Ok(()));
+        }
+        assert_eq!(counters.throttled(), 0);
+    }
+
+    #[test]
+    fn throttles_then_kicks_packet_flood() {
+        let counters = Arc::new(RateLimitCounters::new());
+        let mut limiter = RateLimiter::new(RateLimits { packets_per_second: 20, packet_burst: 2, ..loose_limits() }, counters.clone());
+
+        assert_eq!(limiter.admit(1), Ok(()));
+        assert_eq!(limiter.admit(1), Ok(()));
+
+        // Over the limit, so these wait for the bucket to refill and count a strike each
+        assert_eq!(limiter.admit(1), Ok(()));
+        assert_eq!(limiter.admit(1), Ok(()));
+        assert_eq!(counters.throttled(), 2);
+
+        // One strike too many
+        assert_eq!(limiter.admit(1), Err(DisconnectReason::PacketRateExceeded));
+        assert_eq!(counters.kicked(DisconnectReason::PacketRateExceeded), 1);
+        assert_eq!(counters.kicked(DisconnectReason::ByteRateExceeded), 0);
+    }
+
+    #[test]
+    fn throttles_then_kicks_byte_flood() {
+        let counters = Arc::new(RateLimitCounters::new());
+        let limits = RateLimits { bytes_per_second: 10_000, byte_burst: 1000, max_strikes: 1, ..loose_limits() };
+        let mut limiter = RateLimiter::new(limits, counters.clone());
+
+        assert_eq!(limiter.admit(1000), Ok(()));
+        assert_eq!(limiter.admit(1000), Ok(()));
+        assert_eq!(counters.throttled(), 1);
+
+        assert_eq!(limiter.admit(1000), Err(DisconnectReason::ByteRateExceeded));
+        assert_eq!(counters.kicked(DisconnectReason::ByteRateExceeded), 1);
+        assert_eq!(counters.kicked(DisconnectReason::PacketRateExceeded), 0);
+    }
+}
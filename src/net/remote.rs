@@ -0,0 +1,342 @@
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::thread::Builder;
+use std::time::Duration;
+
+use rustc_serialize::{Decodable, Encodable};
+use bincode::rustc_serialize::{decode, encode};
+use bincode::SizeLimit;
+use sodiumoxide;
+use sodiumoxide::crypto::auth;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes;
+
+use super::{ClientId, FrameCodec, InPacket, Latencies, Message, OutPacket, ServerSlot, ServerSlotId, SlotHandle, SlotInMsg, SlotOutMsg};
+use super::frame::{read_exact, read_u32, write_u32};
+
+// A slot can be run by another process. The process with the client connections exports the slot
+// over TCP, and the other process imports it and gets a ServerSlot that works the same as a local
+// one. Each side also gets a channel to the other, for whatever else the two need to talk about.
+//
+// Before anything else goes over a link, both ends prove they know the same secret, without it ever
+// crossing the wire. Past that, links are meant for the same machine or a private network, so
+// they're neither encrypted nor compressed.
+
+// How often the exporting side passes on its clients' round trip times
+const LATENCY_UPDATE_INTERVAL_MS: u64 = 2000;
+
+// How many random bytes each end challenges the other with
+const LINK_CHALLENGE_BYTES: usize = 32;
+
+// Mixed into each end's answer, so one end's answer can't be passed off as the other's
+const ACCEPTOR_LABEL: &'static [u8] = b"reforge link acceptor";
+const CONNECTOR_LABEL: &'static [u8] = b"reforge link connector";
+
+// A secret shared by the processes allowed to link up
+pub struct LinkSecret {
+    key: auth::Key,
+}
+
+impl LinkSecret {
+    pub fn new(secret: &str) -> LinkSecret {
+        let sha256::Digest(key) = sha256::hash(secret.as_bytes());
+        LinkSecret {
+            key: auth::Key(key),
+        }
+    }
+
+    // What the end labelled `label` answers the two challenges with
+    fn answer(&self, label: &[u8], acceptor_challenge: &[u8], connector_challenge: &[u8]) -> auth::Tag {
+        auth::authenticate(&answered(label, acceptor_challenge, connector_challenge), &self.key)
+    }
+
+    fn check(&self, answer: &auth::Tag, label: &[u8], acceptor_challenge: &[u8], connector_challenge: &[u8]) -> io::Result<()> {
+        if auth::verify(answer, &answered(label, acceptor_challenge, connector_challenge), &self.key) {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::PermissionDenied, "The other end of the link doesn't know the link secret"))
+        }
+    }
+}
+
+// What gets authenticated to answer the challenges
+fn answered(label: &[u8], acceptor_challenge: &[u8], connector_challenge: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(label.len() + acceptor_challenge.len() + connector_challenge.len());
+    data.extend(label.iter().cloned());
+    data.extend(acceptor_challenge.iter().cloned());
+    data.extend(connector_challenge.iter().cloned());
+    data
+}
+
+fn read_tag<R: Read>(reader: &mut R) -> io::Result<auth::Tag> {
+    let mut tag = [0u8; auth::TAGBYTES];
+    try!(read_exact(reader, &mut tag));
+    Ok(auth::Tag(tag))
+}
+
+// Check that whoever connected on `stream` knows `secret`, and prove that we do too. Call this
+// before reading anything else from the stream.
+pub fn authenticate_accepted_link<S: Read + Write>(stream: &mut S, secret: &LinkSecret) -> io::Result<()> {
+    sodiumoxide::init();
+
+    let acceptor_challenge = randombytes(LINK_CHALLENGE_BYTES);
+    try!(stream.write_all(&acceptor_challenge));
+    try!(stream.flush());
+
+    let mut connector_challenge = [0u8; LINK_CHALLENGE_BYTES];
+    try!(read_exact(stream, &mut connector_challenge));
+    let connector_answer = try!(read_tag(stream));
+    try!(secret.check(&connector_answer, CONNECTOR_LABEL, &acceptor_challenge, &connector_challenge));
+
+    let auth::Tag(answer) = secret.answer(ACCEPTOR_LABEL, &acceptor_challenge, &connector_challenge);
+    try!(stream.write_all(&answer));
+    stream.flush()
+}
+
+// Prove to whoever we connected to on `stream` that we know `secret`, and check that they do too.
+// Call this before writing anything else to the stream.
+pub fn authenticate_connected_link<S: Read + Write>(stream: &mut S, secret: &LinkSecret) -> io::Result<()> {
+    sodiumoxide::init();
+
+    let mut acceptor_challenge = [0u8; LINK_CHALLENGE_BYTES];
+    try!(read_exact(stream, &mut acceptor_challenge));
+
+    let connector_challenge = randombytes(LINK_CHALLENGE_BYTES);
+    let auth::Tag(answer) = secret.answer(CONNECTOR_LABEL, &acceptor_challenge, &connector_challenge);
+    try!(stream.write_all(&connector_challenge));
+    try!(stream.write_all(&answer));
+    try!(stream.flush());
+
+    let acceptor_answer = try!(read_tag(stream));
+    secret.check(&acceptor_answer, ACCEPTOR_LABEL, &acceptor_challenge, &connector_challenge)
+}
+
+// What the exporting side sends the importing side
+#[derive(RustcEncodable, RustcDecodable)]
+enum HostFrame<T> {
+    Joined(ClientId),
+    Disconnected(ClientId),
+    Received(ClientId, Vec<u8>),
+    Shutdown,
+    Latencies(Vec<(ClientId, u32)>),
+    App(T),
+}
+
+// What the importing side sends the exporting side
+#[derive(RustcEncodable, RustcDecodable)]
+enum RemoteFrame<T> {
+    Send(ClientId, Vec<u8>),
+    Broadcast(Vec<u8>),
+    Transfer(ClientId, ServerSlotId),
+    Destroy(ServerSlotId),
+    App(T),
+}
+
+// Write one value to a link, or to a stream that's about to become one
+pub fn write_value<W: Write, T: Encodable>(writer: &mut W, codec: &FrameCodec, value: &T) -> io::Result<()> {
+    let data = encode(value, SizeLimit::Infinite).ok().expect("Failed to encode value");
+    try!(codec.write_packet(writer, &data));
+    writer.flush()
+}
+
+pub fn read_value<R: Read, T: Decodable>(reader: &mut R, codec: &FrameCodec) -> io::Result<T> {
+    let data = try!(codec.read_packet(reader));
+    decode(&data).map_err(|e| Error::new(ErrorKind::InvalidData, format!("Failed to decode value: {}", e)))
+}
+
+fn write_frame<T: Encodable>(writer: &Mutex<TcpStream>, codec: &FrameCodec, frame: &T) -> io::Result<()> {
+    let mut writer = writer.lock().ok().expect("Slot link writer lock is poisoned");
+    write_value(&mut *writer, codec, frame)
+}
+
+impl<In: Message, Out: Message> ServerSlot<In, Out> {
+    // Hand this slot to the process at the other end of `stream`, which picks it up with
+    // `ServerSlot::import`. The slot is destroyed when the link drops. Returns the channels to and
+    // from the other process.
+    pub fn export<ToRemote, FromRemote>(self, stream: TcpStream, codec: FrameCodec) -> io::Result<(Sender<ToRemote>, Receiver<FromRemote>)>
+        where ToRemote: Encodable + Send + 'static,
+              FromRemote: Decodable + Send + 'static
+    {
+        let handle = self.handle;
+
+        let mut reader = try!(stream.try_clone());
+        let writer = Arc::new(Mutex::new(stream));
+
+        try!(write_u32(&mut *writer.lock().ok().expect("Slot link writer lock is poisoned"), handle.id));
+
+        let (to_remote_t, to_remote_r) = channel::<ToRemote>();
+        let (from_remote_t, from_remote_r) = channel();
+
+        let slot_id = handle.id;
+        let slot_sender = handle.sender.clone();
+        let latencies = handle.latencies.clone();
+
+        // Pass on what the master tells the slot. Ends when the slot is destroyed, which drops it.
+        let slot_writer = writer.clone();
+        try!(Builder::new().name(format!("slot_{}_export_out", slot_id)).spawn(move || {
+            while let Ok(msg) = handle.receiver.recv() {
+                let (frame, shutdown) =
+                    match msg {
+                        SlotInMsg::Joined(client_id) => (HostFrame::Joined(client_id), false),
+                        SlotInMsg::Disconnected(client_id) => (HostFrame::Disconnected(client_id), false),
+                        SlotInMsg::ReceivedPacket(client_id, packet) => (HostFrame::Received(client_id, packet.buffer.into_inner()), false),
+                        SlotInMsg::Shutdown => (HostFrame::Shutdown, true),
+                    };
+
+                if write_frame::<HostFrame<ToRemote>>(&slot_writer, &codec, &frame).is_err() || shutdown {
+                    break;
+                }
+            }
+        }));
+
+        // Pass on whatever the caller has for the other process
+        let app_writer = writer.clone();
+        try!(Builder::new().name(format!("slot_{}_export_app", slot_id)).spawn(move || {
+            while let Ok(msg) = to_remote_r.recv() {
+                if write_frame(&app_writer, &codec, &HostFrame::App(msg)).is_err() {
+                    break;
+                }
+            }
+        }));
+
+        // Keep the other process up to date on how laggy everyone is
+        let latency_writer = writer.clone();
+        try!(Builder::new().name(format!("slot_{}_export_latency", slot_id)).spawn(move || {
+            loop {
+                let frame: HostFrame<ToRemote> = HostFrame::Latencies(latencies.snapshot());
+                if write_frame(&latency_writer, &codec, &frame).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(LATENCY_UPDATE_INTERVAL_MS));
+            }
+        }));
+
+        // Carry out what the other process asks of the slot
+        try!(Builder::new().name(format!("slot_{}_export_in", slot_id)).spawn(move || {
+            loop {
+                let frame: RemoteFrame<FromRemote> =
+                    match read_value(&mut reader, &codec) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            println!("Link to remote slot {} closed: {}", slot_id, e);
+                            break;
+                        },
+                    };
+
+                match frame {
+                    RemoteFrame::Send(client_id, data) =>
+                        { slot_sender.send(SlotOutMsg::SendPacket(slot_id, client_id, OutPacket::from_bytes(data))); },
+                    RemoteFrame::Broadcast(data) =>
+                        { slot_sender.send(SlotOutMsg::BroadcastPacket(slot_id, OutPacket::from_bytes(data))); },
+                    RemoteFrame::Transfer(client_id, to_slot) =>
+                        { slot_sender.send(SlotOutMsg::TransferClient(slot_id, client_id, to_slot)); },
+                    RemoteFrame::Destroy(destroyed_slot) =>
+                        { slot_sender.send(SlotOutMsg::DestroySlot(slot_id, destroyed_slot)); },
+                    RemoteFrame::App(msg) => {
+                        // The caller may have stopped listening, but the slot still works
+                        let _ = from_remote_t.send(msg);
+                    },
+                }
+            }
+
+            // Nobody's running the slot anymore, so get rid of it. That stops the other threads.
+            reader.shutdown(Shutdown::Both);
+            slot_sender.send(SlotOutMsg::DestroySlot(slot_id, slot_id));
+        }));
+
+        Ok((to_remote_t, from_remote_r))
+    }
+
+    // Pick up a slot exported by the process at the other end of `stream`. Returns the slot, and
+    // the channels to and from the other process. Slots made this way can't create slots.
+    pub fn import<ToHost, FromHost>(stream: TcpStream, codec: FrameCodec) -> io::Result<(ServerSlot<In, Out>, Sender<ToHost>, Receiver<FromHost>)>
+        where ToHost: Encodable + Send + 'static,
+              FromHost: Decodable + Send + 'static
+    {
+        let mut reader = try!(stream.try_clone());
+        let slot_id = try!(read_u32(&mut reader));
+        let writer = Arc::new(Mutex::new(stream));
+
+        let (slot_out_t, slot_out_r) = channel();
+        let (slot_in_t, slot_in_r) = channel();
+        let (_, create_slot_r) = channel();
+        let latencies = Arc::new(Latencies::new());
+
+        let (to_host_t, to_host_r) = channel::<ToHost>();
+        let (from_host_t, from_host_r) = channel();
+
+        // Pass on what the slot's owner does with it
+        let slot_writer = writer.clone();
+        try!(Builder::new().name(format!("slot_{}_import_out", slot_id)).spawn(move || {
+            while let Ok(msg) = slot_out_r.recv() {
+                let frame =
+                    match msg {
+                        SlotOutMsg::SendPacket(_, client_id, packet) => RemoteFrame::Send(client_id, packet.into_bytes()),
+                        SlotOutMsg::BroadcastPacket(_, packet) => RemoteFrame::Broadcast(packet.into_bytes()),
+                        SlotOutMsg::TransferClient(_, client_id, to_slot) => RemoteFrame::Transfer(client_id, to_slot),
+                        SlotOutMsg::DestroySlot(_, destroyed_slot) => RemoteFrame::Destroy(destroyed_slot),
+                        SlotOutMsg::CreateSlot(_) => {
+                            println!("WARNING: Imported slot {} can't create slots", slot_id);
+                            continue;
+                        },
+                    };
+
+                if write_frame::<RemoteFrame<ToHost>>(&slot_writer, &codec, &frame).is_err() {
+                    break;
+                }
+            }
+        }));
+
+        // Pass on whatever the caller has for the other process
+        let app_writer = writer.clone();
+        try!(Builder::new().name(format!("slot_{}_import_app", slot_id)).spawn(move || {
+            while let Ok(msg) = to_host_r.recv() {
+                if write_frame(&app_writer, &codec, &RemoteFrame::App(msg)).is_err() {
+                    break;
+                }
+            }
+        }));
+
+        // Feed the slot what the master sent it
+        let slot_latencies = latencies.clone();
+        try!(Builder::new().name(format!("slot_{}_import_in", slot_id)).spawn(move || {
+            loop {
+                let frame: HostFrame<FromHost> =
+                    match read_value(&mut reader, &codec) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            println!("Link to exported slot {} closed: {}", slot_id, e);
+                            break;
+                        },
+                    };
+
+                match frame {
+                    HostFrame::Joined(client_id) => { slot_in_t.send(SlotInMsg::Joined(client_id)); },
+                    HostFrame::Disconnected(client_id) => { slot_in_t.send(SlotInMsg::Disconnected(client_id)); },
+                    HostFrame::Received(client_id, data) => { slot_in_t.send(SlotInMsg::ReceivedPacket(client_id, InPacket::new(data))); },
+                    HostFrame::Shutdown => { break; },
+                    HostFrame::Latencies(rtts) => { slot_latencies.replace(rtts); },
+                    HostFrame::App(msg) => { let _ = from_host_t.send(msg); },
+                }
+            }
+
+            // Without the link the slot is as good as destroyed
+            reader.shutdown(Shutdown::Both);
+            slot_in_t.send(SlotInMsg::Shutdown);
+        }));
+
+        let handle = SlotHandle {
+            id: slot_id,
+            sender: slot_out_t,
+            receiver: slot_in_r,
+            create_slot: create_slot_r,
+            latencies: latencies,
+        };
+
+        Ok((ServerSlot::new(handle), to_host_t, from_host_r))
+    }
+}
@@ -1,19 +1,81 @@
+use battle_type::MatchEvent;
 use chat::ChatMsg;
+use client_action::ClientAction;
+use login::{LoginError, LoginPacket};
+use net::{ClientId, Encoded, Message, TypeTag};
+use sector_data::SectorData;
+use ship::{Ship, ShipIndex, ShipPlans, ShipStored};
+use star_map::station::StationAction;
+use team::Alliances;
+use turn_config::TurnConfig;
 
 // Packets sent from client to server
 #[derive(RustcEncodable, RustcDecodable)]
 pub enum ServerBattlePacket {
-    Plan,
+    Plan(u32, ShipPlans), // Plans for the numbered turn
     Chat(String),
     Logout,
+    Resync, // Our simulation doesn't match the server's, send everything again
+    ResultsAck(u32), // We have the numbered turn's results, and they checked out
 }
 
 // Packets sent from server to client
 #[derive(RustcEncodable, RustcDecodable)]
 pub enum ClientBattlePacket {
-    NewShipsPre,
-    SimResults,
-    NewShipsPost,
-    Tick(Option<u8>), // Tick and whether it's the last
+    Join(Encoded<Ship>, bool, Vec<Encoded<Ship>>, TurnConfig, Alliances), // Player's ship, whether the turn is being simulated, the other ships in sensor range, how the sector paces its turns, and who's on whose side there
+    Spectate(bool, Vec<Encoded<Ship>>, TurnConfig, Alliances), // Like Join, for spectators, who have no ship and can see every ship in the sector
+    NewShipsPre(Vec<Encoded<Ship>>, Vec<ShipIndex>), // Ships to add and remove, including ones coming into and going out of sensor range
+    SimResults(Vec<u8>), // Written by ResultsEncoder::write_results, followed by checksums of the ships' states after the turn
+    NewShipsPost(Vec<Encoded<Ship>>, Vec<ShipIndex>),
+    Tick(Option<u8>, u32), // Tick, whether it's the last, and the number of the turn being planned from now on
     Chat(ChatMsg),
-}
\ No newline at end of file
+    Pings(Vec<(ClientId, u32)>), // Round trip time in milliseconds of each player that has one
+    Resync(Vec<Encoded<Ship>>), // Fresh copies of every ship in sensor range, to replace the player's before the next turn
+    Match(MatchEvent), // News about the sector's match, for sectors that run one
+}
+
+// Packets sent from the star map to a client
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum ClientStarMapPacket {
+    Sectors(Vec<SectorData>),
+    Action(ClientAction),
+}
+
+// Packets sent from a station to a client
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum ClientStationPacket {
+    Join(Encoded<Option<ShipStored>>), // Player's ship
+    Chat(ChatMsg),
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Message type tags. Every message type gets its own, so keep them all here where clashes are easy
+// to spot. Tag 0 is taken by (), for slots that don't expect to hear anything.
+
+impl Message for LoginPacket {
+    fn type_tag() -> TypeTag { 1 }
+}
+
+impl Message for Option<LoginError> {
+    fn type_tag() -> TypeTag { 2 }
+}
+
+impl Message for ClientStarMapPacket {
+    fn type_tag() -> TypeTag { 3 }
+}
+
+impl Message for ServerBattlePacket {
+    fn type_tag() -> TypeTag { 4 }
+}
+
+impl Message for ClientBattlePacket {
+    fn type_tag() -> TypeTag { 5 }
+}
+
+impl Message for StationAction {
+    fn type_tag() -> TypeTag { 6 }
+}
+
+impl Message for ClientStationPacket {
+    fn type_tag() -> TypeTag { 7 }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::Builder;
+use time;
+
+use battle_context::BattleContext;
+use net::{Encoded, FrameCodec, NetError, OutPacket, read_value, write_value};
+use sector_data::SectorId;
+use ship::{Ship, ShipIndex, ShipPlans};
+use team::Alliances;
+use turn_config::TurnConfig;
+use turn_seed::TurnSeed;
+
+// Replay files start with these magic bytes, then a frame with the u32 format version, then a
+// frame with the header. After that comes a frame for each turn until the end of the file. Frames
+// hold bincoded values.
+const REPLAY_MAGIC: [u8; 4] = [b'R', b'F', b'R', b'P'];
+
+// Bump this whenever the header or turn layout changes
+const REPLAY_VERSION: u32 = 2;
+
+// The battle as it was when recording started
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct ReplayHeader {
+    pub sector_id: SectorId,
+    pub started_at: u64, // Wall clock time, in seconds since the epoch
+    pub turn_config: TurnConfig,
+    pub alliances: Alliances,
+    pub turn_seed: TurnSeed,
+    pub ships: Vec<Encoded<Ship>>,
+}
+
+// Everything that happened in a turn, in the order a client that could see the whole sector would
+// have heard about it
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct ReplayTurn {
+    pub turn_number: u32,
+    pub new_ships_pre: (Vec<Encoded<Ship>>, Vec<ShipIndex>),
+    pub plans: Vec<(ShipIndex, ShipPlans)>,
+    pub results: Vec<u8>, // Laid out like a SimResults packet, covering every ship
+    pub new_ships_post: (Vec<Encoded<Ship>>, Vec<ShipIndex>),
+}
+
+// Where a sector's replay goes when recording to `dir`
+pub fn replay_path(dir: &str, sector_id: SectorId) -> String {
+    format!("{}/sector_{}_{}.replay", dir, sector_id.0, time::get_time().sec)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Recording
+
+// Records a sector's battle turn by turn. Like packet captures, the writing happens on its own
+// thread, so recording doesn't hold up the sector.
+pub struct BattleRecorder {
+    sender: Sender<ReplayTurn>,
+
+    // Ships the replay has been told about
+    ships: HashSet<ShipIndex>,
+
+    // The turn being recorded, and its results until the simulation is done
+    turn: Option<ReplayTurn>,
+    results: Option<OutPacket>,
+}
+
+impl BattleRecorder {
+    pub fn create(path: &str, sector_id: SectorId, bc: &BattleContext) -> io::Result<BattleRecorder> {
+        let codec = FrameCodec::new();
+        let header = ReplayHeader {
+            sector_id: sector_id,
+            started_at: time::get_time().sec as u64,
+            turn_config: bc.turn_config,
+            alliances: bc.alliances.clone(),
+            turn_seed: bc.turn_seed,
+            ships: bc.ships_iter().map(|s| Encoded::new(s)).collect(),
+        };
+
+        let mut writer = BufWriter::new(try!(File::create(path)));
+        try!(writer.write_all(&REPLAY_MAGIC));
+        try!(write_value(&mut writer, &codec, &REPLAY_VERSION));
+        try!(write_value(&mut writer, &codec, &header));
+
+        let (sender, receiver) = channel();
+        let path = path.to_string();
+        try!(Builder::new().name(format!("sector_{}_recorder", sector_id.0)).spawn(move || {
+            write_turns(writer, codec, receiver, path);
+        }));
+
+        Ok(BattleRecorder {
+            sender: sender,
+            ships: bc.ships_iter().map(|s| s.index).collect(),
+            turn: None,
+            results: None,
+        })
+    }
+
+    // Works out the ships that came and went, the same way a player's sensors do, but for a player
+    // that can see everything
+    fn ships_changed(&mut self, bc: &BattleContext, removed: &HashSet<ShipIndex>) -> (Vec<Encoded<Ship>>, Vec<ShipIndex>) {
+        let ships: HashSet<ShipIndex> = bc.ships_iter().map(|s| s.index).filter(|s| !removed.contains(s)).collect();
+
+        let entered = ships.difference(&self.ships).map(|s| Encoded::new(s.get(bc))).collect();
+        let left = self.ships.difference(&ships).cloned().collect();
+        self.ships = ships;
+
+        (entered, left)
+    }
+
+    pub fn record_new_ships_pre(&mut self, turn_number: u32, bc: &BattleContext, removed: &HashSet<ShipIndex>) {
+        let new_ships_pre = self.ships_changed(bc, removed);
+        self.turn = Some(ReplayTurn {
+            turn_number: turn_number,
+            new_ships_pre: new_ships_pre,
+            plans: vec!(),
+            results: vec!(),
+            new_ships_post: (vec!(), vec!()),
+        });
+    }
+
+    pub fn record_plans(&mut self, plans: &[(ShipIndex, ShipPlans)]) {
+        if let Some(ref mut turn) = self.turn {
+            turn.plans = plans.to_vec();
+        }
+    }
+
+    // Results are written before simulating, like the ones players get
+    pub fn record_results(&mut self, bc: &BattleContext) {
+        let mut results = OutPacket::new();
+        bc.write_results(&mut results);
+        self.results = Some(results);
+    }
+
+    // Then finished with the checksums of how the simulation turned out
+    pub fn record_simulated(&mut self, bc: &BattleContext) {
+        if let Some(mut results) = self.results.take() {
+            if let Some(ref mut turn) = self.turn {
+                let all_ships: HashSet<ShipIndex> = bc.ships_iter().map(|s| s.index).collect();
+                results.write(&bc.visible_checksums(&all_ships));
+                turn.results = results.into_bytes();
+            }
+        }
+    }
+
+    // Finishes the turn
+    pub fn record_new_ships_post(&mut self, bc: &BattleContext, removed: &HashSet<ShipIndex>) {
+        let new_ships_post = self.ships_changed(bc, removed);
+        if let Some(mut turn) = self.turn.take() {
+            turn.new_ships_post = new_ships_post;
+
+            // If the writer thread is gone it's already said why
+            let _ = self.sender.send(turn);
+        }
+    }
+}
+
+fn write_turns(mut writer: BufWriter<File>, codec: FrameCodec, receiver: Receiver<ReplayTurn>, path: String) {
+    for turn in receiver.iter() {
+        // write_value flushes every turn, so the replay of a sector that crashes still has
+        // everything up to the crash in it
+        if let Err(e) = write_value(&mut writer, &codec, &turn) {
+            println!("Failed to write to replay file {}, recording stopped: {}", path, e);
+            return;
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Playing back
+
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub turns: Vec<ReplayTurn>,
+}
+
+impl Replay {
+    pub fn open(path: &str) -> io::Result<Replay> {
+        let codec = FrameCodec::new();
+        let mut reader = BufReader::new(try!(File::open(path)));
+
+        let mut magic = [0u8; 4];
+        try!(read_magic(&mut reader, &mut magic));
+        if magic != REPLAY_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a replay file"));
+        }
+
+        let version: u32 = try!(read_value(&mut reader, &codec));
+        if version != REPLAY_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("Replay file is version {}, expected version {}", version, REPLAY_VERSION)));
+        }
+
+        let header = try!(read_value(&mut reader, &codec));
+
+        // A replay cut off part way through a turn just ends at the last whole turn
+        let mut turns = vec!();
+        loop {
+            match read_value(&mut reader, &codec) {
+                Ok(turn) => turns.push(turn),
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Replay {
+            header: header,
+            turns: turns,
+        })
+    }
+
+    // The battle as it was before the first turn
+    pub fn initial_context(&self) -> Result<BattleContext, NetError> {
+        let mut bc = BattleContext::new(vec!());
+        bc.turn_config = self.header.turn_config;
+        bc.alliances = self.header.alliances.clone();
+        bc.turn_seed = self.header.turn_seed;
+
+        for ship in &self.header.ships {
+            bc.insert_ship(try!(ship.decode()));
+        }
+
+        Ok(bc)
+    }
+}
+
+fn read_magic<R: Read>(reader: &mut R, magic: &mut [u8; 4]) -> io::Result<()> {
+    let mut read = 0;
+    while read < magic.len() {
+        match try!(reader.read(&mut magic[read..])) {
+            0 => return Err(Error::new(ErrorKind::UnexpectedEof, "Replay file is too short")),
+            n => read += n,
+        }
+    }
+    Ok(())
+}
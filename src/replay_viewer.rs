@@ -0,0 +1,221 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::Rc;
+
+use glutin_window::GlutinWindow;
+use opengl_graphics::GlGraphics;
+use opengl_graphics::glyph_cache::GlyphCache;
+use piston::event_loop::Events;
+use piston::input::*;
+use piston::input::keyboard::Key;
+
+use asset_store::AssetStore;
+use battle_context::BattleContext;
+use chat::ChatGui;
+use module::ModelStore;
+use net::{InPacket, NetError};
+use replay::{Replay, ReplayTurn};
+use sector_client::{apply_new_ships, finish_simulation, follow, follow_ship, ship_to_follow, simulate_ticks, start_simulation};
+use ship::ShipIndex;
+use sim::{SimEffects, SimEvents};
+use space_gui::{SpaceGui, SpaceGuiAction};
+
+// Plays back a recorded battle. Turns play out the same way they do for a player in the sector,
+// except everything comes from the replay instead of a server.
+//
+//     Space           Play/pause
+//     Right           Skip to the next turn
+//     Left            Back to the last turn
+//     Page Down/Up    Forward/back 10 turns
+//     Home/End        First/last turn
+//     Tab             Watch the next ship
+//     Escape          Stop watching
+
+// What the viewer wants to do once it stops playing
+enum ReplayControl {
+    Seek(usize), // Play from this turn
+    Quit,
+}
+
+pub fn run_replay(window: &Rc<RefCell<GlutinWindow>>,
+                  gl: &mut GlGraphics,
+                  glyph_cache: &mut GlyphCache,
+                  asset_store: &AssetStore,
+                  model_store: &ModelStore,
+                  replay: &Replay) -> Result<(), NetError> {
+    println!("Replaying {} turns of sector {}", replay.turns.len(), replay.header.sector_id.0);
+
+    let mut chat_gui = ChatGui::new();
+    let mut sim_effects = SimEffects::new();
+    let mut paused = false;
+    let mut seek_to = 0;
+
+    loop {
+        // Turns only play forwards, so seeking starts over and plays up to the turn we want without
+        // showing anything
+        let mut bc = try!(replay.initial_context());
+        let mut followed =
+            match ship_to_follow(&bc) {
+                Some(ship) => ship,
+                None => {
+                    println!("The replay starts out with no ships, so there's nothing to follow");
+                    return Ok(());
+                },
+            };
+        let ref mut gui = SpaceGui::new(asset_store, &bc, &mut chat_gui, vec!(), followed);
+        gui.set_spectating();
+
+        for turn in &replay.turns[..seek_to] {
+            let mut sim_events = try!(start_turn(&mut bc, gui, &mut followed, turn, asset_store, model_store, &mut sim_effects));
+            try!(end_turn(&mut bc, gui, &mut followed, turn, &mut sim_events, 0));
+        }
+
+        let control = try!(play(window, gl, glyph_cache, asset_store, model_store, replay, &mut bc, gui, &mut followed, &mut sim_effects, seek_to, &mut paused));
+        match control {
+            ReplayControl::Seek(turn) => { seek_to = turn; },
+            ReplayControl::Quit => { return Ok(()); },
+        }
+    }
+}
+
+// Play turns from `first_turn` on, until the viewer seeks somewhere else or quits
+fn play(window: &Rc<RefCell<GlutinWindow>>,
+        gl: &mut GlGraphics,
+        glyph_cache: &mut GlyphCache,
+        asset_store: &AssetStore,
+        model_store: &ModelStore,
+        replay: &Replay,
+        bc: &mut BattleContext,
+        gui: &mut SpaceGui,
+        followed: &mut ShipIndex,
+        sim_effects: &mut SimEffects,
+        first_turn: usize,
+        paused: &mut bool) -> Result<ReplayControl, NetError> {
+    let turn_config = replay.header.turn_config;
+    let last_turn = replay.turns.len();
+
+    let mut turn_index = first_turn;
+    let mut turn: Option<(&ReplayTurn, SimEvents)> = None;
+    let mut next_tick = 0;
+    let mut elapsed_seconds = 0.0;
+
+    for e in Events::events(window.clone()) {
+        use piston::input;
+
+        let e: input::Event<input::Input> = e;
+
+        // Start the next turn, if there is one
+        if turn.is_none() && turn_index < last_turn {
+            let replay_turn = &replay.turns[turn_index];
+            let sim_events = try!(start_turn(bc, gui, followed, replay_turn, asset_store, model_store, sim_effects));
+            turn = Some((replay_turn, sim_events));
+            next_tick = 0;
+            elapsed_seconds = 0.0;
+        }
+
+        let mut control = None;
+        let mut skip_turn = false;
+        e.press(|button| {
+            if let Button::Keyboard(key) = button {
+                match key {
+                    Key::Space => { *paused = !*paused; },
+                    Key::Right => { skip_turn = true; },
+                    Key::Left => { control = Some(ReplayControl::Seek(turn_index.saturating_sub(1))); },
+                    Key::PageDown => { control = Some(ReplayControl::Seek(cmp::min(turn_index + 10, last_turn))); },
+                    Key::PageUp => { control = Some(ReplayControl::Seek(turn_index.saturating_sub(10))); },
+                    Key::Home => { control = Some(ReplayControl::Seek(0)); },
+                    Key::End => { control = Some(ReplayControl::Seek(last_turn.saturating_sub(1))); },
+                    Key::Escape => { control = Some(ReplayControl::Quit); },
+                    _ => { },
+                }
+            }
+        });
+        if let Some(control) = control {
+            return Ok(control);
+        }
+
+        e.update(|args: &UpdateArgs| {
+            if !*paused {
+                elapsed_seconds += args.dt;
+            }
+        });
+
+        if let Some((replay_turn, mut sim_events)) = turn.take() {
+            if skip_turn || elapsed_seconds >= turn_config.turn_seconds() {
+                try!(end_turn(bc, gui, followed, replay_turn, &mut sim_events, next_tick));
+                turn_index += 1;
+            } else {
+                let tick = turn_config.time_to_tick(elapsed_seconds);
+                next_tick = simulate_ticks(bc, &mut sim_events, next_tick, tick);
+                turn = Some((replay_turn, sim_events));
+            }
+        }
+
+        // The GUI works like it does for a spectator
+        let following = bc.ships.get(followed.to_usize()).map_or(false, |s| s.is_some());
+        if following {
+            if let Some(SpaceGuiAction::Follow(ship)) = gui.event(bc, &e, *followed, elapsed_seconds) {
+                follow_ship(bc, gui, followed, ship);
+            }
+        }
+
+        e.render(|args: &RenderArgs| {
+            gl.draw(args.viewport(), |c, gl| {
+                use graphics::*;
+
+                if following {
+                    gui.draw_simulating(bc, &c, gl, glyph_cache, asset_store, sim_effects, followed.get(bc), elapsed_seconds, (1.0/60.0) + args.ext_dt);
+                } else {
+                    clear([0.0; 4], gl);
+                }
+
+                let status =
+                    if turn_index >= last_turn {
+                        format!("End of replay, {} turns", last_turn)
+                    } else if *paused {
+                        format!("Turn {} of {} (paused)", turn_index + 1, last_turn)
+                    } else {
+                        format!("Turn {} of {}", turn_index + 1, last_turn)
+                    };
+                Text::new_color([1.0; 4], 14).draw(
+                    status.as_str(),
+                    glyph_cache,
+                    &c.draw_state, c.trans(10.0, 710.0).transform,
+                    gl
+                );
+            });
+        });
+    }
+
+    // The window closed
+    Ok(ReplayControl::Quit)
+}
+
+// Bring in the turn's ships and results and get its simulation ready
+fn start_turn<'e>(bc: &mut BattleContext,
+                  gui: &mut SpaceGui,
+                  followed: &mut ShipIndex,
+                  turn: &ReplayTurn,
+                  asset_store: &AssetStore,
+                  model_store: &ModelStore,
+                  sim_effects: &mut SimEffects) -> Result<SimEvents<'e>, NetError> {
+    try!(apply_new_ships(bc, gui, followed, &turn.new_ships_pre));
+    follow(bc, gui, followed);
+
+    // The checksums after the results are for checking a live client's simulation
+    bc.read_results(&mut InPacket::new(turn.results.clone()));
+
+    Ok(start_simulation(bc, gui, asset_store, model_store, sim_effects))
+}
+
+fn end_turn(bc: &mut BattleContext,
+            gui: &mut SpaceGui,
+            followed: &mut ShipIndex,
+            turn: &ReplayTurn,
+            sim_events: &mut SimEvents,
+            next_tick: u32) -> Result<(), NetError> {
+    finish_simulation(bc, sim_events, next_tick);
+    try!(apply_new_ships(bc, gui, followed, &turn.new_ships_post));
+    follow(bc, gui, followed);
+    Ok(())
+}
@@ -0,0 +1,29 @@
+// What happens to a ship after it blows up. Each sector has one policy for players' ships and
+// another for NPCs. Until the policy kicks in, the wreck stays in the sector exploding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RespawnPolicy {
+    // Send the ship home the next turn. Players go back to their home station with the ship they
+    // brought into the sector, or with what's left of the one that blew up if `damaged` is set.
+    // NPCs have no home, so they're just gone.
+    ReturnHome { damaged: bool },
+
+    // Bring the ship back where it blew up, as it was when it came into the sector, after this
+    // many turns
+    InPlace { turns: u32 },
+
+    // Replace the ship with a random one a level up the next turn. Only for NPCs, since it throws
+    // the ship's design away.
+    Escalate,
+}
+
+impl RespawnPolicy {
+    // Players keep their ships
+    pub fn for_players() -> RespawnPolicy {
+        RespawnPolicy::ReturnHome { damaged: false }
+    }
+
+    // NPCs get tougher every time they're beaten
+    pub fn for_npcs() -> RespawnPolicy {
+        RespawnPolicy::Escalate
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use battle_context::BattleContext;
+use net::{InPacket, OutPacket};
+use ship::ShipIndex;
+use turn_seed::TurnSeed;
+
+// Every result field of the ships a player can see, each encoded on its own, as of one turn
+pub type ResultsSnapshot = HashMap<ShipIndex, Vec<Vec<u8>>>;
+
+// How many turns of results the server keeps waiting for a player to confirm. Confirmations for
+// anything older are ignored, and the player just keeps getting changes from an older turn.
+const MAX_UNCONFIRMED: usize = 8;
+
+// Writes turn results for one player as the fields that changed since the last turn they
+// confirmed. Until they've confirmed one, or after a resync, they get everything.
+pub struct ResultsEncoder {
+    // The last results the player confirmed, and the turn they're from
+    baseline: Option<(u32, ResultsSnapshot)>,
+
+    // Results sent since, waiting to be confirmed
+    sent: VecDeque<(u32, ResultsSnapshot)>,
+}
+
+impl ResultsEncoder {
+    pub fn new() -> ResultsEncoder {
+        ResultsEncoder {
+            baseline: None,
+            sent: VecDeque::new(),
+        }
+    }
+
+    // Forget what the player has, so the next results go out in full
+    pub fn reset(&mut self) {
+        self.baseline = None;
+        self.sent.clear();
+    }
+
+    // The player has the results for `turn`, and can have the next ones as changes from them
+    pub fn confirm(&mut self, turn: u32) {
+        while let Some((sent_turn, snapshot)) = self.sent.pop_front() {
+            if sent_turn == turn {
+                self.baseline = Some((sent_turn, snapshot));
+                break;
+            } else if sent_turn > turn {
+                self.sent.push_front((sent_turn, snapshot));
+                break;
+            }
+        }
+    }
+
+    // Write the results of the ships in `visible` for `turn`
+    pub fn write_results(&mut self, packet: &mut OutPacket, turn: u32, bc: &BattleContext, visible: &HashSet<ShipIndex>) {
+        let snapshot: ResultsSnapshot =
+            bc.ships_iter()
+                .filter(|s| visible.contains(&s.index))
+                .map(|s| (s.index, s.result_fields()))
+                .collect();
+
+        self.write_snapshot(packet, turn, &bc.turn_seed, snapshot);
+    }
+
+    fn write_snapshot(&mut self, packet: &mut OutPacket, turn: u32, turn_seed: &TurnSeed, snapshot: ResultsSnapshot) {
+        packet.write(&turn);
+        packet.write(&self.baseline.as_ref().map(|&(baseline_turn, _)| baseline_turn));
+        packet.write(turn_seed);
+        packet.write(&(snapshot.len() as u32));
+        for (ship, fields) in snapshot.iter() {
+            let base = self.baseline.as_ref().and_then(|&(_, ref baseline)| baseline.get(ship));
+
+            packet.write(ship);
+            write_ship_delta(packet, fields, base);
+        }
+
+        self.sent.push_back((turn, snapshot));
+        if self.sent.len() > MAX_UNCONFIRMED {
+            self.sent.pop_front();
+        }
+    }
+}
+
+// Reads results written by a `ResultsEncoder`, keeping the results we've confirmed to the server
+// around for as long as it might send changes from them
+pub struct ResultsDecoder {
+    confirmed: Vec<(u32, ResultsSnapshot)>,
+}
+
+impl ResultsDecoder {
+    pub fn new() -> ResultsDecoder {
+        ResultsDecoder {
+            confirmed: vec!(),
+        }
+    }
+
+    // Read a turn's results into `bc`. Returns the turn and the results, to confirm once the turn
+    // checks out, or None if they're changes from results we don't have.
+    pub fn read_results(&mut self, packet: &mut InPacket, bc: &mut BattleContext) -> Option<(u32, ResultsSnapshot)> {
+        let (turn, turn_seed, snapshot) = self.read_snapshot(packet);
+        bc.turn_seed = turn_seed;
+
+        let snapshot =
+            match snapshot {
+                Some(snapshot) => snapshot,
+                None => return None,
+            };
+
+        for (ship, fields) in snapshot.iter() {
+            let mut ship_results = InPacket::new(fields.concat());
+            ship.get_mut(bc).read_results(&mut ship_results);
+        }
+
+        Some((turn, snapshot))
+    }
+
+    // Read a turn's results without applying them. The snapshot is None if it's changes from
+    // results we don't have.
+    fn read_snapshot(&mut self, packet: &mut InPacket) -> (u32, TurnSeed, Option<ResultsSnapshot>) {
+        let turn: u32 = packet.read().ok().expect("Failed to read results turn");
+        let baseline: Option<u32> = packet.read().ok().expect("Failed to read results baseline");
+        let turn_seed: TurnSeed = packet.read().ok().expect("Failed to read turn seed");
+
+        // Read everything before applying any of it, so the rest of the packet can still be read
+        // if the baseline is missing
+        let num_ships: u32 = packet.read().ok().expect("Failed to read number of ships in results");
+        let mut deltas = vec!();
+        for _ in 0 .. num_ships {
+            let ship: ShipIndex = packet.read().ok().expect("Failed to read results ship index");
+            deltas.push((ship, read_ship_delta(packet)));
+        }
+
+        // The server never goes back to results older than the ones it's using now
+        match baseline {
+            Some(baseline) => self.confirmed.retain(|&(confirmed_turn, _)| confirmed_turn >= baseline),
+            None => self.confirmed.clear(),
+        }
+
+        let snapshot = {
+            let base =
+                match baseline {
+                    Some(baseline) => {
+                        match self.confirmed.iter().find(|&&(confirmed_turn, _)| confirmed_turn == baseline) {
+                            Some(&(_, ref snapshot)) => Some(snapshot),
+                            None => return (turn, turn_seed, None),
+                        }
+                    },
+                    None => None,
+                };
+
+            let mut snapshot = HashMap::new();
+            for (ship, delta) in deltas {
+                match delta.apply(base.and_then(|b| b.get(&ship))) {
+                    Some(fields) => { snapshot.insert(ship, fields); },
+                    None => return (turn, turn_seed, None),
+                }
+            }
+            snapshot
+        };
+
+        (turn, turn_seed, Some(snapshot))
+    }
+
+    // We've told the server we have these results
+    pub fn confirm(&mut self, turn: u32, snapshot: ResultsSnapshot) {
+        self.confirmed.push((turn, snapshot));
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// A ship's results, as the fields that changed from the baseline
+struct ShipDelta {
+    num_fields: u32,
+    changed_mask: Vec<u8>, // A bit for each field, set if it's in `changed`
+    changed: Vec<Vec<u8>>,
+}
+
+impl ShipDelta {
+    // All the ship's fields, with the ones that didn't change taken from `base`
+    fn apply(self, base: Option<&Vec<Vec<u8>>>) -> Option<Vec<Vec<u8>>> {
+        let ShipDelta { num_fields, changed_mask, changed } = self;
+        let mut changed = changed.into_iter();
+
+        (0 .. num_fields as usize)
+            .map(|i| {
+                if bit_set(&changed_mask, i) {
+                    changed.next()
+                } else {
+                    base.and_then(|b| b.get(i)).cloned()
+                }
+            })
+            .collect()
+    }
+}
+
+// Fields are all sent if there's nothing to compare them to. A ship with a different number of
+// fields than the baseline's isn't the same ship anymore, so it gets everything too.
+fn write_ship_delta(packet: &mut OutPacket, fields: &Vec<Vec<u8>>, base: Option<&Vec<Vec<u8>>>) {
+    let base = match base {
+        Some(base) if base.len() == fields.len() => Some(base),
+        _ => None,
+    };
+
+    let changed: Vec<bool> = fields.iter().enumerate().map(|(i, f)| base.map_or(true, |b| b[i] != *f)).collect();
+
+    let mut changed_mask = vec![0u8; (fields.len() + 7) / 8];
+    for (i, _) in changed.iter().enumerate().filter(|&(_, &c)| c) {
+        changed_mask[i / 8] |= 1 << (i % 8);
+    }
+
+    packet.write(&(fields.len() as u32));
+    packet.write(&changed_mask);
+    for (field, _) in fields.iter().zip(changed.iter()).filter(|&(_, &c)| c) {
+        packet.write(field);
+    }
+}
+
+fn read_ship_delta(packet: &mut InPacket) -> ShipDelta {
+    let num_fields: u32 = packet.read().ok().expect("Failed to read number of result fields");
+    let changed_mask: Vec<u8> = packet.read().ok().expect("Failed to read changed result fields");
+
+    let num_changed = (0 .. num_fields as usize).filter(|&i| bit_set(&changed_mask, i)).count();
+    let changed = (0 .. num_changed).map(|_| packet.read().ok().expect("Failed to read result field")).collect();
+
+    ShipDelta {
+        num_fields: num_fields,
+        changed_mask: changed_mask,
+        changed: changed,
+    }
+}
+
+fn bit_set(mask: &[u8], i: usize) -> bool {
+    mask.get(i / 8).map_or(false, |&byte| byte & (1 << (i % 8)) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use net::{InPacket, OutPacket};
+    use ship::ShipIndex;
+    use turn_seed::TurnSeed;
+    use super::{MAX_UNCONFIRMED, ResultsDecoder, ResultsEncoder, ResultsSnapshot};
+
+    fn snapshot(ships: &[(u32, &[&[u8]])]) -> ResultsSnapshot {
+        ships.iter()
+            .map(|&(index, fields)| (ShipIndex(index), fields.iter().map(|field| field.to_vec()).collect()))
+            .collect()
+    }
+
+    fn write(encoder: &mut ResultsEncoder, turn: u32, results: &ResultsSnapshot) -> Vec<u8> {
+        let mut packet = OutPacket::new();
+        encoder.write_snapshot(&mut packet, turn, &TurnSeed::new(7).for_turn(turn), results.clone());
+        packet.into_bytes()
+    }
+
+    // The turn a packet's results are changes from, if any
+    fn baseline_of(data: &[u8]) -> Option<u32> {
+        let mut packet = InPacket::new(data.to_vec());
+        let _: u32 = packet.read().unwrap();
+        packet.read().unwrap()
+    }
+
+    fn read(decoder: &mut ResultsDecoder, data: &[u8]) -> Option<ResultsSnapshot> {
+        let (_, _, results) = decoder.read_snapshot(&mut InPacket::new(data.to_vec()));
+        results
+    }
+
+    #[test]
+    fn full_snapshot_round_trip() {
+        let mut encoder = ResultsEncoder::new();
+        let mut decoder = ResultsDecoder::new();
+        let results = snapshot(&[(0, &[&b"power"[..], &b"position"[..]]), (3, &[&b"waypoint"[..]])]);
+
+        let data = write(&mut encoder, 1, &results);
+        assert_eq!(baseline_of(&data), None);
+
+        let (turn, turn_seed, read_results) = decoder.read_snapshot(&mut InPacket::new(data));
+        assert_eq!(turn, 1);
+        assert_eq!(turn_seed, TurnSeed::new(7).for_turn(1));
+        assert_eq!(read_results, Some(results));
+    }
+
+    #[test]
+    fn delta_against_confirmed_baseline() {
+        let mut encoder = ResultsEncoder::new();
+        let mut decoder = ResultsDecoder::new();
+        let first = snapshot(&[(0, &[&b"power"[..], &b"position"[..]]), (3, &[&b"waypoint"[..]])]);
+        let second = snapshot(&[(0, &[&b"power"[..], &b"moved on"[..]]), (3, &[&b"waypoint"[..]])]);
+
+        let data = write(&mut encoder, 1, &first);
+        decoder.confirm(1, read(&mut decoder, &data).unwrap());
+        encoder.confirm(1);
+
+        let delta = write(&mut encoder, 2, &second);
+        assert_eq!(baseline_of(&delta), Some(1));
+        assert!(delta.len() < write(&mut ResultsEncoder::new(), 2, &second).len());
+        assert_eq!(read(&mut decoder, &delta), Some(second));
+    }
+
+    #[test]
+    fn delta_without_baseline_needs_full_snapshot() {
+        let mut encoder = ResultsEncoder::new();
+        let results = snapshot(&[(0, &[&b"power"[..], &b"position"[..]])]);
+
+        write(&mut encoder, 1, &results);
+        encoder.confirm(1);
+
+        // A player who never got turn 1, or resynced since, can't use changes from it
+        let delta = write(&mut encoder, 2, &results);
+        assert_eq!(read(&mut ResultsDecoder::new(), &delta), None);
+
+        // A decoder that read turn 1 but never confirmed it can't either
+        let mut decoder = ResultsDecoder::new();
+        read(&mut decoder, &write(&mut ResultsEncoder::new(), 1, &results)).unwrap();
+        assert_eq!(read(&mut decoder, &delta), None);
+
+        // After a resync everything goes out again
+        encoder.reset();
+        let full = write(&mut encoder, 3, &results);
+        assert_eq!(baseline_of(&full), None);
+        assert_eq!(read(&mut decoder, &full), Some(results));
+    }
+
+    #[test]
+    fn confirm_drops_older_results() {
+        let mut encoder = ResultsEncoder::new();
+        let mut decoder = ResultsDecoder::new();
+        let mut sent = HashMap::new();
+        for turn in 1 .. 4 {
+            let results = snapshot(&[(0, &[&[turn as u8][..]])]);
+            let data = write(&mut encoder, turn, &results);
+            decoder.confirm(turn, read(&mut decoder, &data).unwrap());
+            sent.insert(turn, results);
+        }
+
+        encoder.confirm(2);
+        assert_eq!(encoder.baseline.as_ref().map(|&(turn, _)| turn), Some(2));
+        assert_eq!(encoder.sent.iter().map(|&(turn, _)| turn).collect::<Vec<_>>(), vec![3]);
+
+        // Turn 1 went with it, so confirming it late changes nothing
+        encoder.confirm(1);
+        assert_eq!(encoder.baseline.as_ref().map(|&(turn, _)| turn), Some(2));
+
+        // The decoder forgets what's older than the baseline it's sent changes from
+        let data = write(&mut encoder, 4, &sent[&3]);
+        assert_eq!(read(&mut decoder, &data), Some(sent[&3].clone()));
+        assert_eq!(decoder.confirmed.iter().map(|&(turn, _)| turn).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn unconfirmed_results_are_forgotten() {
+        let mut encoder = ResultsEncoder::new();
+        let results = snapshot(&[(0, &[&b"power"[..]])]);
+        for turn in 0 .. MAX_UNCONFIRMED as u32 + 2 {
+            write(&mut encoder, turn, &results);
+        }
+        assert_eq!(encoder.sent.len(), MAX_UNCONFIRMED);
+
+        // Too old to be a baseline anymore
+        encoder.confirm(0);
+        assert!(encoder.baseline.is_none());
+    }
+}
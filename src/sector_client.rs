@@ -15,11 +15,13 @@ use glutin_window::GlutinWindow;
 use piston::window::Window;
 
 use asset_store::AssetStore;
-use battle_context::{BattleContext, TICKS_PER_SECOND};
-use chat::ChatGui;
+use battle_context::BattleContext;
+use battle_type::{MatchEvent, MatchOutcome, VictoryCondition};
+use chat::{ChatGui, ChatMsg};
 use module::ModelStore;
-use net::{Client, InPacket, OutPacket};
+use net::{Client, Encoded, InPacket, NetError};
 use packet_types::{ClientBattlePacket, ServerBattlePacket};
+use results_delta::{ResultsDecoder, ResultsSnapshot};
 use sector_data::SectorData;
 use ship::{Ship, ShipId, ShipIndex};
 use sim::{SimEvents, SimEffects};
@@ -31,27 +33,54 @@ pub struct ClientBattleState<'a> {
     // Context holding all the things involved in this battle
     bc: BattleContext,
     
-    // The player's ship
+    // The player's ship. Spectators have none, so it's the ship they're watching, which may be
+    // gone if there's nothing left to watch.
     player_ship: ShipIndex,
+    spectating: bool,
     
-    new_ships_pre: Option<InPacket>,
+    // Ships to add and remove before and after simulating
+    new_ships_pre: Option<(Vec<Encoded<Ship>>, Vec<ShipIndex>)>,
     results: Option<InPacket>,
-    new_ships_post: Option<InPacket>,
+    new_ships_post: Option<(Vec<Encoded<Ship>>, Vec<ShipIndex>)>,
     
     final_ticks: Option<u8>,
+    
+    // Ships from the server to replace ours with, once we've asked for them
+    resync: Option<Vec<Encoded<Ship>>>,
+    resync_requested: bool,
+    
+    // Results come as changes from the last ones we confirmed
+    results_decoder: ResultsDecoder,
+    
+    // The turn being planned, as of the server's last tick
+    turn_number: u32,
 }
 
 impl<'a> ClientBattleState<'a> {
     pub fn new(client: &'a mut Client, bc: BattleContext) -> ClientBattleState<'a> {
         let player_ship = bc.get_ship_by_client_id(client.get_id()).index;
+        ClientBattleState::with_ship(client, bc, player_ship, false)
+    }
+    
+    pub fn spectate(client: &'a mut Client, bc: BattleContext) -> ClientBattleState<'a> {
+        let ship = ship_to_follow(&bc).unwrap_or(ShipIndex(0));
+        ClientBattleState::with_ship(client, bc, ship, true)
+    }
+    
+    fn with_ship(client: &'a mut Client, bc: BattleContext, player_ship: ShipIndex, spectating: bool) -> ClientBattleState<'a> {
         ClientBattleState {
             client: client,
             bc: bc,
             player_ship: player_ship,
+            spectating: spectating,
             new_ships_pre: None,
             results: None,
             new_ships_post: None,
             final_ticks: None,
+            resync: None,
+            resync_requested: false,
+            results_decoder: ResultsDecoder::new(),
+            turn_number: 0,
         }
     }
     
@@ -63,10 +92,13 @@ impl<'a> ClientBattleState<'a> {
                model_store: &ModelStore,
                chat_gui: &mut ChatGui,
                sectors: Vec<SectorData>,
-               server_results_sent: bool) {
+               server_results_sent: bool) -> Result<(), NetError> {
         use piston::window::Window;
     
         let ref mut gui = SpaceGui::new(asset_store, &self.bc, chat_gui, sectors, self.player_ship);
+        if self.spectating {
+            gui.set_spectating();
+        }
     
         let ref mut sim_effects = SimEffects::new();
         
@@ -76,8 +108,8 @@ impl<'a> ClientBattleState<'a> {
             // Wait for the tick
             loop {
                 // We might get chat packets here
-                let tick_packet = self.client.receive();
-                let ticked = self.handle_packet(gui, tick_packet);
+                let tick_packet = try!(self.client.receive_message());
+                let ticked = try!(self.handle_packet(gui, tick_packet));
                 
                 if ticked {
                     break;
@@ -88,8 +120,8 @@ impl<'a> ClientBattleState<'a> {
         // Get first turn's results
         loop {
             // Loop until tick packet is received
-            let packet = self.client.receive();
-            let ticked = self.handle_packet(gui, packet);
+            let packet = try!(self.client.receive_message());
+            let ticked = try!(self.handle_packet(gui, packet));
             if ticked {
                 break;
             }
@@ -108,21 +140,32 @@ impl<'a> ClientBattleState<'a> {
             }
             
             // Receive simulation results
-            let mut new_ships_pre = self.new_ships_pre.take().expect("New ships pre packet must exist here");
+            let new_ships_pre = self.new_ships_pre.take().expect("New ships pre packet must exist here");
             let mut results = self.results.take().expect("Results packet must exist here");
-            let mut new_ships_post = self.new_ships_post.take().expect("New ships post packet must exist here");
+            let new_ships_post = self.new_ships_post.take().expect("New ships post packet must exist here");
             
-            self.handle_new_ships_packet(gui, &mut new_ships_pre);
-            self.handle_simulation_results(&mut results);
+            try!(apply_new_ships(&mut self.bc, gui, &mut self.player_ship, &new_ships_pre));
+            if let Some(ships) = self.resync.take() {
+                try!(self.handle_resync(gui, ships));
+            }
+            self.follow(gui);
+            let results_read = self.handle_simulation_results(&mut results);
+            let checksums: Vec<(ShipIndex, u32)> = results.read().ok().expect("Failed to read ship checksums");
+            
+            try!(self.run_simulation_phase(window, gl, glyph_cache, asset_store, model_store, gui, sim_effects));
             
-            self.run_simulation_phase(window, gl, glyph_cache, asset_store, model_store, gui, sim_effects);
+            try!(self.check_simulation(&checksums));
+            try!(self.confirm_results(results_read));
             
             // Receive ships after sim
-            self.handle_new_ships_packet(gui, &mut new_ships_post);
+            try!(apply_new_ships(&mut self.bc, gui, &mut self.player_ship, &new_ships_post));
+            self.follow(gui);
             
             // Check if it's time to exit
             if window.borrow().should_close() { break; }
         }
+        
+        Ok(())
     }
     
     fn run_simulation_phase(&mut self,
@@ -132,31 +175,17 @@ impl<'a> ClientBattleState<'a> {
                             asset_store: &AssetStore,
                             model_store: &ModelStore,
                             gui: &mut SpaceGui,
-                            mut sim_effects: &mut SimEffects) -> bool {
-        // Unlock any exploding or jumping ships
-        let ships_to_unlock: Vec<ShipIndex> =
-            self.bc.ships_iter()
-                .filter_map(|s| if s.jumping || s.exploding { Some(s.index) } else { None })
-                .collect();
-        
-        for ship_index in ships_to_unlock {
-            self.bc.on_ship_removed(ship_index);
-            gui.plans.on_ship_removed(ship_index);
-        }
-        
+                            mut sim_effects: &mut SimEffects) -> Result<bool, NetError> {
         let mut logging_out = false;
         
-        let mut sim_events = SimEvents::new();
-            
-        // Before simulation
-        sim_effects.reset();
-        self.bc.before_simulation(model_store, &mut sim_events);
-        self.bc.add_simulation_effects(asset_store, model_store, &mut sim_effects);
+        let turn_config = self.bc.turn_config;
+        let mut sim_events = start_simulation(&mut self.bc, gui, asset_store, model_store, sim_effects);
         
         // Simulation
         let start_time = time::now().to_timespec();
         let mut next_tick = 0;
         let mut plans_sent = false;
+        let mut ready = false;
         for e in Events::events(window.clone()) {
             use piston::event_loop as event;
             use piston::input;
@@ -169,18 +198,21 @@ impl<'a> ClientBattleState<'a> {
             let elapsed_time = current_time - start_time;
             let elapsed_seconds = (elapsed_time.num_milliseconds() as f64)/1000.0;
             
-            if !self.final_ticks.is_some() && !self.player_ship.get(&self.bc).exploding && !plans_sent && elapsed_seconds >= 2.5 {
+            // Spectators never plan, so they're always listening for the tick
+            let planning = !self.spectating && !self.player_ship.get(&self.bc).exploding;
+            
+            if !self.final_ticks.is_some() && planning && !plans_sent && (ready || elapsed_seconds >= turn_config.send_plans_seconds()) {
                 // Send plans
                 let packet = self.build_plans_packet(gui);
-                self.client.send(&packet);
+                try!(self.client.send_message(&packet));
                 plans_sent = true;
                 println!("Sent plans at {}", elapsed_seconds);
             }
             
             if !self.final_ticks.is_some() {
-                if plans_sent || self.player_ship.get(&self.bc).exploding {
-                    if let Ok(packet) = self.client.try_receive() {
-                        let ticked = self.handle_packet(gui, packet);
+                if plans_sent || !planning {
+                    if let Some(packet) = try!(self.client.try_receive_message()) {
+                        let ticked = try!(self.handle_packet(gui, packet));
                         
                         if ticked && !self.final_ticks.is_some() {
                             // If the tick we got isn't the last tick, this turn is done.
@@ -189,32 +221,43 @@ impl<'a> ClientBattleState<'a> {
                         }
                     }
                 }
-            } else if elapsed_seconds >= 5.0 {
+            } else if elapsed_seconds >= turn_config.turn_seconds() {
                 println!("Finished turn because we're leaving this state");
                 break;
             }
             
             // Calculate current tick
-            let tick = (elapsed_time.num_milliseconds() as u32)/(1000/TICKS_PER_SECOND);
+            let tick = turn_config.time_to_tick(elapsed_seconds);
             
             // Simulate any new ticks
-            if next_tick < 100 {
-                for t in next_tick .. cmp::min(next_tick+tick-next_tick+1, 100) {
-                    sim_events.apply_tick(&mut self.bc, t);
-                }
-                next_tick = tick+1;
-            }
+            next_tick = simulate_ticks(&mut self.bc, &mut sim_events, next_tick, tick);
         
+            // A spectator can end up with nothing to watch until another ship comes along
+            let following = self.bc.ships.get(self.player_ship.to_usize()).map_or(false, |s| s.is_some());
+            
             // Forward events to GUI
-            let gui_action = gui.event(&mut self.bc, &e, self.player_ship, elapsed_seconds);
+            let gui_action =
+                if following {
+                    gui.event(&mut self.bc, &e, self.player_ship, elapsed_seconds)
+                } else {
+                    None
+                };
             
             if let Some(gui_action) = gui_action {
                 match gui_action {
                     SpaceGuiAction::Chat(msg) => {
-                        self.send_chat(msg);
+                        try!(self.send_chat(msg));
                     },
                     SpaceGuiAction::Logout => {
-                        self.send_logout();
+                        try!(self.send_logout());
+                    },
+                    SpaceGuiAction::Ready => {
+                        // Plans go out on the next frame. If everyone's ready, the server
+                        // resolves the turn early and the tick cuts this phase short.
+                        ready = true;
+                    },
+                    SpaceGuiAction::Follow(ship) => {
+                        follow_ship(&self.bc, gui, &mut self.player_ship, ship);
                     },
                 }
             }
@@ -222,119 +265,354 @@ impl<'a> ClientBattleState<'a> {
             // Render GUI
             e.render(|args: &RenderArgs| {
                 gl.draw(args.viewport(), |c, gl| {
-                    gui.draw_simulating(&self.bc, &c, gl, glyph_cache, asset_store, &mut sim_effects, self.player_ship.get(&self.bc), elapsed_seconds, (1.0/60.0) + args.ext_dt);
+                    use graphics::*;
+                    
+                    if following {
+                        gui.draw_simulating(&self.bc, &c, gl, glyph_cache, asset_store, &mut sim_effects, self.player_ship.get(&self.bc), elapsed_seconds, (1.0/60.0) + args.ext_dt);
+                    } else {
+                        clear([0.0; 4], gl);
+                        Text::new_color([1.0; 4], 14).draw(
+                            "There's nothing to watch in this sector yet",
+                            glyph_cache,
+                            &c.draw_state, c.trans(10.0, 710.0).transform,
+                            gl
+                        );
+                    }
                 });
             });
         }
         
-        // Simulate any remaining ticks
-        for t in next_tick .. 100 {
-            sim_events.apply_tick(&mut self.bc, t);
-        }
-        
-        // After simulation
-        self.bc.after_simulation();
-        
-        // Apply module stats
-        self.bc.apply_module_stats();
-        
-        // Deactivate modules that can no longer be powered
-        self.bc.deactivate_unpowerable_modules();
-        
-        // Set all the dead ships to exploding
-        for ship in self.bc.ships_iter_mut() {
-            if ship.state.get_hp() == 0 {
-                ship.exploding = true;
-            }
-        }
+        finish_simulation(&mut self.bc, &mut sim_events, next_tick);
         
-        logging_out
+        Ok(logging_out)
     }
     
-    fn build_plans_packet(&mut self, gui: &mut SpaceGui) -> OutPacket {
+    fn build_plans_packet(&mut self, gui: &mut SpaceGui) -> ServerBattlePacket {
         self.player_ship.get_mut(&mut self.bc).next_waypoint = gui.plans.next_waypoint;
         gui.set_next_waypoint();
-        let mut packet = OutPacket::new();
-        packet.write(&ServerBattlePacket::Plan).unwrap();
-        packet.write(&gui.plans).unwrap();
-        packet
+        ServerBattlePacket::Plan(self.turn_number, gui.plans.clone())
     }
     
-    fn send_chat(&mut self, msg: String) {
-        let mut packet = OutPacket::new();
-        packet.write(&ServerBattlePacket::Chat(msg)).unwrap();
-        self.client.send(&packet);
+    fn send_chat(&mut self, msg: String) -> Result<(), NetError> {
+        self.client.send_message(&ServerBattlePacket::Chat(msg))
     }
     
-    fn send_logout(&mut self) {
-        let mut packet = OutPacket::new();
-        packet.write(&ServerBattlePacket::Logout).unwrap();
-        self.client.send(&packet);
+    fn send_logout(&mut self) -> Result<(), NetError> {
+        self.client.send_message(&ServerBattlePacket::Logout)
     }
     
-    fn handle_packet(&mut self, gui: &mut SpaceGui, mut packet: InPacket) -> bool {
-        let battle_packet: ClientBattlePacket = packet.read().unwrap();
-        
+    fn handle_packet(&mut self, gui: &mut SpaceGui, battle_packet: ClientBattlePacket) -> Result<bool, NetError> {
         match battle_packet {
-            ClientBattlePacket::NewShipsPre => {
-                self.new_ships_pre = Some(packet);
+            ClientBattlePacket::Join(..) | ClientBattlePacket::Spectate(..) => {
+                println!("Got a sector join packet while already in the sector");
+            },
+            ClientBattlePacket::NewShipsPre(ships_to_add, ships_to_remove) => {
+                self.new_ships_pre = Some((ships_to_add, ships_to_remove));
             },
-            ClientBattlePacket::SimResults => {
-                self.results = Some(packet);
+            ClientBattlePacket::SimResults(results) => {
+                self.results = Some(InPacket::new(results));
             },
-            ClientBattlePacket::NewShipsPost => {
-                self.new_ships_post = Some(packet);
+            ClientBattlePacket::NewShipsPost(ships_to_add, ships_to_remove) => {
+                self.new_ships_post = Some((ships_to_add, ships_to_remove));
             },
-            ClientBattlePacket::Tick(final_ticks) => {
+            ClientBattlePacket::Tick(final_ticks, turn_number) => {
                 self.final_ticks = final_ticks;
-                return true;
+                self.turn_number = turn_number;
+                return Ok(true);
             },
             ClientBattlePacket::Chat(msg) => {
                 gui.chat_gui.add_message(msg);
             },
+            ClientBattlePacket::Pings(pings) => {
+                gui.set_pings(pings);
+            },
+            ClientBattlePacket::Resync(ships) => {
+                self.resync = Some(ships);
+            },
+            ClientBattlePacket::Match(event) => {
+                handle_match_event(gui, event);
+            },
         }
         
-        false
+        Ok(false)
     }
     
-    fn handle_simulation_results(&mut self, packet: &mut InPacket) {
-        // Results packet has both plans and results
-        self.bc.read_results(packet);
+    // Spectators move on to another ship when the one they're watching goes away
+    fn follow(&mut self, gui: &mut SpaceGui) {
+        if self.spectating {
+            follow(&self.bc, gui, &mut self.player_ship);
+        }
     }
     
-    fn handle_new_ships_packet(&mut self, gui: &mut SpaceGui, packet: &mut InPacket) {
-        let ships_to_add: Vec<Ship> = packet.read().ok().expect("Failed to read ships to add from packet");
-        let ships_to_remove: Vec<ShipIndex> = packet.read().ok().expect("Failed to read ships to remove from packet");
-        
-        let player_ship_id = self.player_ship.get(&self.bc).id;
-        let player_hp = self.player_ship.get(&self.bc).state.get_hp();
+    fn handle_simulation_results(&mut self, packet: &mut InPacket) -> Option<(u32, ResultsSnapshot)> {
+        // Results packet has both plans and results
+        let results_read = self.results_decoder.read_results(packet, &mut self.bc);
+        if results_read.is_none() {
+            println!("Got results as changes from ones we don't have");
+        }
         
-        for ship in ships_to_remove.into_iter() {
-            println!("Removing ship {:?}", ship);
-            
-            gui.on_ship_removed(self.player_ship, ship);
-            self.bc.remove_ship(ship);
+        results_read
+    }
+    
+    // Let the server send the next results as changes from these, as long as the turn played out
+    // the same for us as it did for the server. Results we couldn't read mean we're out of sync.
+    fn confirm_results(&mut self, results_read: Option<(u32, ResultsSnapshot)>) -> Result<(), NetError> {
+        match results_read {
+            Some((turn, snapshot)) => {
+                if !self.resync_requested {
+                    self.results_decoder.confirm(turn, snapshot);
+                    try!(self.client.send_message(&ServerBattlePacket::ResultsAck(turn)));
+                }
+            },
+            None => {
+                if !self.resync_requested {
+                    try!(self.client.send_message(&ServerBattlePacket::Resync));
+                    self.resync_requested = true;
+                }
+            },
         }
+        
+        Ok(())
+    }
     
-        for ship in ships_to_add.into_iter() {
-            println!("Got a new ship {:?}", ship.id);
-            let ship_id = ship.id;
-            if ship_id == player_ship_id {
-                if player_hp == 0 {
-                    println!("Replacing player's ship");
-                    self.player_ship = ship.index;
-                    gui.set_client_ship(&ship);
-                    self.bc.add_ship(ship);
+    // Compare how our ships ended up with how the server's did, and ask for a resync if they differ
+    fn check_simulation(&mut self, checksums: &[(ShipIndex, u32)]) -> Result<(), NetError> {
+        let mut in_sync = true;
+        
+        for &(ship_index, server_checksum) in checksums {
+            if let Some(&Some(ref ship)) = self.bc.ships.get(ship_index.to_usize()) {
+                let checksum = ship.state.checksum();
+                if checksum != server_checksum {
+                    println!("Desync on turn {}: ship {} ({:?}, {}) has checksum {:08x}, server has {:08x}",
+                             self.bc.turn_seed.turn_number, ship.id, ship_index, ship.name, checksum, server_checksum);
+                    println!("{:?}", ship.state);
+                    in_sync = false;
                 }
             } else {
-                println!("Trying to lock");
-                let ship_index = ship.index;
-                self.bc.add_ship(ship);
-                gui.try_lock(ship_index);
+                println!("Desync on turn {}: server has ship {:?}, we don't", self.bc.turn_seed.turn_number, ship_index);
+                in_sync = false;
+            }
+        }
+        
+        if !in_sync && !self.resync_requested {
+            try!(self.client.send_message(&ServerBattlePacket::Resync));
+            self.resync_requested = true;
+        }
+        
+        Ok(())
+    }
+    
+    fn handle_resync(&mut self, gui: &mut SpaceGui, ships: Vec<Encoded<Ship>>) -> Result<(), NetError> {
+        println!("Resyncing {} ships", ships.len());
+        
+        for ship in ships.iter() {
+            let ship = try!(ship.decode());
+            if ship.index == self.player_ship {
+                gui.set_client_ship(&ship);
+            }
+            self.bc.insert_ship(ship);
+        }
+        
+        self.resync_requested = false;
+        
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Playing out turns. Replays play them the same way, just without a server.
+
+// Add and remove the ships the server says to. If `player_ship` died and the server sent its
+// replacement, it's updated to point at the new one.
+pub fn apply_new_ships(bc: &mut BattleContext,
+                       gui: &mut SpaceGui,
+                       player_ship: &mut ShipIndex,
+                       new_ships: &(Vec<Encoded<Ship>>, Vec<ShipIndex>)) -> Result<(), NetError> {
+    let (ref ships_to_add, ref ships_to_remove) = *new_ships;
+    let mut decoded_ships = vec!();
+    for ship in ships_to_add.iter() {
+        decoded_ships.push(try!(ship.decode()));
+    }
+    
+    // A replay can end up with nothing left to follow
+    let (player_ship_id, player_hp) =
+        match bc.ships.get(player_ship.to_usize()).and_then(|s| s.as_ref()) {
+            Some(ship) => (Some(ship.id), ship.state.get_hp()),
+            None => (None, 0),
+        };
+    
+    for &ship in ships_to_remove.iter() {
+        println!("Removing ship {:?}", ship);
+        
+        gui.on_ship_removed(*player_ship, ship);
+        bc.remove_ship(ship);
+    }
+
+    for ship in decoded_ships.into_iter() {
+        println!("Got a new ship {:?}", ship.id);
+        let ship_id = ship.id;
+        if Some(ship_id) == player_ship_id {
+            if player_hp == 0 {
+                println!("Replacing player's ship");
+                *player_ship = ship.index;
+                gui.set_client_ship(&ship);
+                bc.insert_ship(ship);
             }
-            println!("Added the ship");
+        } else {
+            println!("Trying to lock");
+            let ship_index = ship.index;
+            bc.insert_ship(ship);
+            gui.try_lock(ship_index);
         }
+        println!("Added the ship");
+    }
+
+    println!("Finished readng new ships");
+    
+    Ok(())
+}
+
+// Find another ship to follow if the one we were following is gone
+pub fn follow(bc: &BattleContext, gui: &mut SpaceGui, followed: &mut ShipIndex) {
+    // Ships get put wherever there's room, so check it's the same ship and not a new one in its place
+    let still_there = bc.ships.get(followed.to_usize()).and_then(|s| s.as_ref()).map_or(false, |s| Some(s.id) == gui.client_ship_id());
+    if still_there {
+        return;
+    }
+    
+    if let Some(ship) = ship_to_follow(bc) {
+        *followed = ship;
+        gui.remove_lock(ship);
+        gui.set_client_ship(ship.get(bc));
+    }
+}
+
+// Switch to following `ship`. The ship we were following goes back to being one of the targets.
+pub fn follow_ship(bc: &BattleContext, gui: &mut SpaceGui, followed: &mut ShipIndex, ship: ShipIndex) {
+    if ship == *followed || bc.ships.get(ship.to_usize()).map_or(true, |s| s.is_none()) {
+        return;
+    }
+    
+    if bc.ships.get(followed.to_usize()).map_or(false, |s| s.is_some()) {
+        gui.try_lock(*followed);
+    }
+    
+    *followed = ship;
+    gui.remove_lock(ship);
+    gui.set_client_ship(ship.get(bc));
+}
+
+// Prefers players' ships
+pub fn ship_to_follow(bc: &BattleContext) -> Option<ShipIndex> {
+    bc.ships_iter().find(|s| s.client_id.is_some())
+        .or_else(|| bc.ships_iter().next())
+        .map(|s| s.index)
+}
 
-        println!("Finished readng new ships");
+// Show how the sector's match is going. The results also go in chat, so they're still there once
+// the player's back at the station.
+fn handle_match_event(gui: &mut SpaceGui, event: MatchEvent) {
+    match event {
+        MatchEvent::Lobby { players, needed } => {
+            gui.set_match_status(Some(format!("Waiting for players ({}/{})", players, needed)));
+        },
+        MatchEvent::Started(mode_name) => {
+            gui.set_match_status(Some(format!("Match on: {}", mode_name)));
+        },
+        MatchEvent::Full => {
+            gui.set_match_status(Some("There's no room in this match, heading home".to_string()));
+        },
+        MatchEvent::Over(results) => {
+            let outcome =
+                match results.outcome {
+                    MatchOutcome::Won(ref winners) if winners.is_empty() => "Nobody made it".to_string(),
+                    MatchOutcome::Won(ref winners) => format!("{} won", winners.join(", ")),
+                    MatchOutcome::AiWon => "The AI won".to_string(),
+                    MatchOutcome::Draw => "It's a draw".to_string(),
+                };
+            let ended_by =
+                match results.ended_by {
+                    VictoryCondition::LastShipStanding => "last ship standing".to_string(),
+                    VictoryCondition::ScoreLimit(score_limit) => format!("first to {} kills", score_limit),
+                    VictoryCondition::TurnLimit(turn_limit) => format!("out of time after {} turns", turn_limit),
+                };
+            
+            gui.set_match_status(Some(format!("Match over: {}", outcome)));
+            gui.chat_gui.add_message(ChatMsg {
+                author_name: "Match".to_string(),
+                content: format!("{} ({})", outcome, ended_by),
+            });
+            for (name, score) in results.scores {
+                gui.chat_gui.add_message(ChatMsg {
+                    author_name: "Match".to_string(),
+                    content: format!("{}: {} kills", name, score),
+                });
+            }
+        },
+    }
+}
+
+// Get a turn's simulation ready to play, once its results are in
+pub fn start_simulation<'e>(bc: &mut BattleContext,
+                            gui: &mut SpaceGui,
+                            asset_store: &AssetStore,
+                            model_store: &ModelStore,
+                            sim_effects: &mut SimEffects) -> SimEvents<'e> {
+    // Unlock any exploding or jumping ships
+    let ships_to_unlock: Vec<ShipIndex> =
+        bc.ships_iter()
+            .filter_map(|s| if s.jumping || s.exploding { Some(s.index) } else { None })
+            .collect();
+    
+    for ship_index in ships_to_unlock {
+        bc.on_ship_removed(ship_index);
+        gui.plans.on_ship_removed(ship_index);
+    }
+    
+    let mut sim_events = SimEvents::new(bc.turn_config.ticks_per_turn);
+        
+    // Before simulation
+    sim_effects.reset();
+    bc.before_simulation(model_store, &mut sim_events);
+    bc.add_simulation_effects(asset_store, model_store, sim_effects);
+    
+    sim_events
+}
+
+// Simulate from `next_tick` up to and including `tick`. Returns the next tick to simulate.
+pub fn simulate_ticks(bc: &mut BattleContext, sim_events: &mut SimEvents, next_tick: u32, tick: u32) -> u32 {
+    let ticks_per_turn = bc.turn_config.ticks_per_turn;
+    if next_tick >= ticks_per_turn || tick < next_tick {
+        return next_tick;
+    }
+    
+    for t in next_tick .. cmp::min(tick+1, ticks_per_turn) {
+        sim_events.apply_tick(bc, t);
+    }
+    
+    tick+1
+}
+
+// Simulate whatever's left of the turn and wrap it up
+pub fn finish_simulation(bc: &mut BattleContext, sim_events: &mut SimEvents, next_tick: u32) {
+    // Simulate any remaining ticks
+    for t in next_tick .. bc.turn_config.ticks_per_turn {
+        sim_events.apply_tick(bc, t);
+    }
+    
+    // After simulation
+    bc.after_simulation();
+    
+    // Apply module stats
+    bc.apply_module_stats();
+    
+    // Deactivate modules that can no longer be powered
+    bc.deactivate_unpowerable_modules();
+    
+    // Set all the dead ships to exploding
+    for ship in bc.ships_iter_mut() {
+        if ship.state.get_hp() == 0 {
+            ship.exploding = true;
+        }
     }
 }
@@ -2,9 +2,10 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::cmp;
 use std::collections::{HashMap, HashSet};
+use std::io;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Receiver, Select, Sender};
 use time;
 
 use rand::Rng;
@@ -12,18 +13,47 @@ use rand;
 
 use ai::run_ai;
 use battle_context::BattleContext;
+use battle_type::{BattleType, MatchEvent, MatchResults, SectorMatch};
 use chat::ChatMsg;
 use login::AccountBox;
 use module::{ModelStore, Module};
-use net::{ClientId, ServerSlot, ServerSlotId, SlotInMsg, InPacket, OutPacket};
+use net::{ClientId, Encoded, ServerSlot, ServerSlotId, SlotEvent, OutPacket};
 use packet_types::{ClientBattlePacket, ServerBattlePacket};
+use replay::BattleRecorder;
+use respawn_policy::RespawnPolicy;
+use results_delta::ResultsEncoder;
+use sector_data::SectorId;
 use ship::{Ship, ShipId, ShipIndex, ShipPlans, ShipStored};
 use sim::SimEvents;
 use star_map::StarMapAction;
+use team::Alliances;
+use timer::Timer;
+use turn_config::TurnConfig;
+use turn_seed::{RngStream, TurnSeed};
 use vec::Vec2;
 
+// Players in a sector send it their plans, and it sends them what happened
+pub type SectorSlot = ServerSlot<ServerBattlePacket, ClientBattlePacket>;
+
+// How far from a player's ship other ships show up for them, unless the sector says otherwise
+pub const DEFAULT_SENSOR_RANGE: f64 = 500.0;
+
+// Keys for a ship's spawn generators, so where it's put and what it's built from don't share rolls
+const SPAWN_KEY_POSITION: u32 = 0;
+const SPAWN_KEY_GENERATE: u32 = 1;
+
+// Something that woke the sector up
+enum SectorEvent {
+    Slot(SlotEvent<ServerBattlePacket>),
+    Chat(ChatMsg),
+    NewAccount(AccountBox),
+    
+    // The turn may have moved on to its next phase
+    TurnTimer,
+}
+
 pub struct SectorState {
-    slot: ServerSlot,
+    slot: SectorSlot,
     star_map_slot_id: ServerSlotId,
     chat_sender: Sender<ChatMsg>,
     chat_receiver: Receiver<ChatMsg>,
@@ -48,29 +78,70 @@ pub struct SectorState {
     // All the clients' accounts
     accounts: HashMap<ClientId, AccountBox>,
     
-    // Ships to add after simulation
-    ships_to_add: Vec<ShipIndex>,
+    // Ships each player knows about. Everything else is out of sensor range, and they don't hear
+    // about it.
+    visible_ships: HashMap<ClientId, HashSet<ShipIndex>>,
+    sensor_range: f64,
+    
+    // Clients watching without a ship. They see every ship in the sector, and never plan.
+    spectators: HashSet<ClientId>,
+    spectators_leaving: Vec<ClientId>,
     
     // Ships to remove after simulation
     ships_to_remove: Vec<ShipIndex>,
     
     ships_to_logout: Vec<ShipIndex>,
     
+    // Players' ships to send back to their home station after the next turn, and whether they go
+    // home damaged
+    ships_going_home: Vec<(ShipIndex, bool)>,
+    
+    // What happens to ships that blow up
+    player_respawn: RespawnPolicy,
+    npc_respawn: RespawnPolicy,
+    
+    // Every ship as it was when it came into the sector, to respawn it from
+    spawned_ships: HashMap<ShipId, Encoded<Ship>>,
+    
+    // The turn each wreck blew up on
+    wrecked_on: HashMap<ShipId, u32>,
+    
+    // The match the sector runs, if it isn't an endless free for all
+    sector_match: Option<SectorMatch>,
+    
+    // The sector's own NPCs, which are brought back for each match
+    npc_ids: Vec<ShipId>,
+    
+    // Players whose simulation stopped matching ours
+    clients_resyncing: HashSet<ClientId>,
+    
+    // What each client has confirmed of the results so far, so they only get what's changed
+    results_encoders: HashMap<ClientId, ResultsEncoder>,
+    
+    // Where the battle is being recorded to, if it is
+    recorder: Option<BattleRecorder>,
+    
     turn_number: u32,
     
     debug: bool,
 }
 
 impl SectorState {
-    pub fn new(slot: ServerSlot,
+    pub fn new(slot: SectorSlot,
                star_map_slot_id: ServerSlotId,
                chat_sender: Sender<ChatMsg>,
                chat_receiver: Receiver<ChatMsg>,
                to_map_sender: Sender<(AccountBox, StarMapAction)>,
                from_map_receiver: Receiver<AccountBox>,
-               context: BattleContext,
+               mut context: BattleContext,
                model_store: Arc<ModelStore>,
                debug: bool) -> SectorState {
+        // Every turn in this sector is seeded from this
+        context.turn_seed = TurnSeed::new(rand::random());
+        
+        let spawned_ships = context.ships_iter().map(|s| (s.id, Encoded::new(s))).collect();
+        let npc_ids = context.ships_iter().filter(|s| s.client_id.is_none()).map(|s| s.id).collect();
+        
         SectorState {
             slot: slot,
             star_map_slot_id: star_map_slot_id,
@@ -87,16 +158,64 @@ impl SectorState {
             clients_active: HashSet::new(),
             ship_plans: vec!(),
             accounts: HashMap::new(),
-            ships_to_add: vec!(),
+            visible_ships: HashMap::new(),
+            sensor_range: DEFAULT_SENSOR_RANGE,
+            spectators: HashSet::new(),
+            spectators_leaving: vec!(),
             ships_to_remove: vec!(),
             ships_to_logout: vec!(),
+            ships_going_home: vec!(),
+            player_respawn: RespawnPolicy::for_players(),
+            npc_respawn: RespawnPolicy::for_npcs(),
+            spawned_ships: spawned_ships,
+            wrecked_on: HashMap::new(),
+            sector_match: None,
+            npc_ids: npc_ids,
+            clients_resyncing: HashSet::new(),
+            results_encoders: HashMap::new(),
+            recorder: None,
             turn_number: 0,
             debug: debug,
         }
     }
     
+    pub fn set_sensor_range(&mut self, sensor_range: f64) {
+        self.sensor_range = sensor_range;
+    }
+    
+    pub fn set_turn_config(&mut self, turn_config: TurnConfig) {
+        self.context.turn_config = turn_config;
+    }
+    
+    pub fn set_alliances(&mut self, alliances: Alliances) {
+        self.context.alliances = alliances;
+    }
+    
+    pub fn set_respawn_policies(&mut self, player_respawn: RespawnPolicy, npc_respawn: RespawnPolicy) {
+        // Escalating would throw away the player's design
+        assert!(player_respawn != RespawnPolicy::Escalate, "Players' ships can't escalate");
+        
+        self.player_respawn = player_respawn;
+        self.npc_respawn = npc_respawn;
+    }
+    
+    // Run matches of this type instead of an endless free for all
+    pub fn set_battle_type(&mut self, battle_type: BattleType) {
+        self.sector_match = Some(SectorMatch::new(battle_type.game_mode()));
+    }
+    
+    // Record every turn from now on to a replay file. Call this once the sector is set up, since the
+    // replay starts from the battle as it is now.
+    pub fn record_to(&mut self, path: &str, sector_id: SectorId) -> io::Result<()> {
+        self.recorder = Some(try!(BattleRecorder::create(path, sector_id, &self.context)));
+        println!("Recording sector {} to {}", sector_id.0, path);
+        Ok(())
+    }
+    
     pub fn run(&mut self, ack: Sender<()>) {
-        let mut rng = rand::thread_rng();
+        // Wakes the sector up when the turn is due to move on
+        let mut turn_timer = Timer::never();
+        let mut turn_deadline: Option<time::Timespec> = None;
     
         loop {
             ///////////////////////////////////////////////////////////
@@ -105,89 +224,198 @@ impl SectorState {
             // Get the current time from our turn timer
             let turn_time = time::now().to_timespec() - self.turn_start_time;
             
-            if !self.simulated_turn && turn_time.num_milliseconds() >= 3500 {
+            let turn_config = self.context.turn_config;
+            if !self.simulated_turn && turn_time.num_milliseconds() >= turn_config.planning_ms as i64 {
                 self.simulate_next_turn();
 
                 self.simulated_turn = true;
             }
             
-            if turn_time.num_milliseconds() >= 5000 {
+            if turn_time.num_milliseconds() >= turn_config.turn_ms as i64 {
                 // Reset the turn stuff
                 self.simulated_turn = false;
                 self.turn_start_time = time::now().to_timespec();
                 
                 self.send_turn_tick();
             }
-        
-            ///////////////////////////////////////////////////////////
-            // Receiver ServerSlot messages
-            if let Ok(msg) = self.slot.try_receive() {
-                match msg {
-                    SlotInMsg::Joined(client_id) => {
-                        println!("Client {} joined battle {}", client_id, self.slot.get_id());
-                    },
-                    SlotInMsg::Disconnected(client_id) => {
-                        println!("Client {} disconnected at station {}, logging out...", client_id, self.slot.get_id());
-                        
-                        let ship = self.context.get_ship_by_client_id(client_id);
-                        self.ships_to_logout.push(ship.index);
-                    },
-                    SlotInMsg::ReceivedPacket(client_id, mut packet) => {
-                        self.handle_packet(client_id, &mut packet);
-                    },
-                }
-            }
             
-            ///////////////////////////////////////////////////////////
-            // Receive messages from chat server
-            if let Ok(msg) = self.chat_receiver.try_recv() {
-                let mut msg_packet = OutPacket::new();
-                msg_packet.write(&ClientBattlePacket::Chat(msg)).unwrap();
-                self.slot.broadcast(msg_packet);
+            // Set the timer for whichever turn phase comes next
+            let next_deadline =
+                if self.simulated_turn {
+                    self.turn_start_time + time::Duration::milliseconds(turn_config.turn_ms as i64)
+                } else {
+                    self.turn_start_time + time::Duration::milliseconds(turn_config.planning_ms as i64)
+                };
+            if turn_deadline != Some(next_deadline) {
+                turn_timer = Timer::at(next_deadline);
+                turn_deadline = Some(next_deadline);
             }
             
-            ///////////////////////////////////////////////////////////
-            // Receive new clients
-            if let Ok(mut account) = self.from_map_receiver.try_recv() {
-                if self.debug {
-                    println!("Receiving account");
-                }
-                println!("Receiving account {}", self.simulated_turn);
-                let client_id = account.client_id.expect("This must have a client ID");
-                
-                // Add the client to the waiting list
-                self.clients_waiting.insert(client_id);
-                
-                // Get the ship out of storage
-                let ship_stored = account.ship.take().expect("This account must have a ship");
-                let mut ship = ship_stored.to_ship(Some(client_id));
-                
-                ship.position = Vec2::new(rng.gen::<f64>() * 300.0 - 150.0, rng.gen::<f64>() * 300.0 - 150.0);
+            // Sleep until something happens
+            match self.next_event(&turn_timer) {
+                ///////////////////////////////////////////////////////////
+                // Receiver ServerSlot messages
+                SectorEvent::Slot(msg) => {
+                    match msg {
+                        SlotEvent::Joined(client_id) => {
+                            println!("Client {} joined battle {}", client_id, self.slot.get_id());
+                        },
+                        SlotEvent::Disconnected(client_id) => {
+                            println!("Client {} disconnected at station {}, logging out...", client_id, self.slot.get_id());
+                            
+                            self.logout(client_id);
+                        },
+                        SlotEvent::Received(client_id, battle_packet) => {
+                            self.handle_packet(client_id, battle_packet);
+                        },
+                        SlotEvent::Malformed(client_id, e) => {
+                            println!("Client {} sent battle {} a bad packet: {}", client_id, self.slot.get_id(), e);
+                        },
+                        SlotEvent::Shutdown => {
+                            println!("Battle {} shutting down", self.slot.get_id());
+                            return;
+                        },
+                    }
+                },
                 
-                // Add the player's account
-                self.accounts.insert(client_id, account);
+                ///////////////////////////////////////////////////////////
+                // Receive messages from chat server
+                SectorEvent::Chat(msg) => {
+                    self.slot.broadcast(&ClientBattlePacket::Chat(msg));
+                },
                 
-                // Send initial join packet
-                let mut packet = OutPacket::new();
-                packet.write(&ship).unwrap();
-                packet.write(&self.simulated_turn).unwrap(); // Whether or not to start at simulation instead of planning phase
-                packet.write(&self.context.ships).unwrap();
-                self.slot.send(client_id, packet);
+                ///////////////////////////////////////////////////////////
+                // Receive new clients
+                SectorEvent::NewAccount(mut account) => {
+                    if self.debug {
+                        println!("Receiving account");
+                    }
+                    println!("Receiving account {}", self.simulated_turn);
+                    let client_id = account.client_id.expect("This must have a client ID");
+                    
+                    if account.spectating.is_some() {
+                        self.add_spectator(client_id, account);
+                        ack.send(());
+                        continue;
+                    }
+                    
+                    // Add the client to the waiting list
+                    self.clients_waiting.insert(client_id);
+                    
+                    // Get the ship out of storage
+                    let ship_stored = account.ship.take().expect("This account must have a ship");
+                    let mut ship = ship_stored.to_ship(Some(client_id));
+                    
+                    let mut rng = self.context.turn_seed.rng(RngStream::Spawn, ship.id, SPAWN_KEY_POSITION);
+                    ship.position = Vec2::new(rng.gen::<f64>() * 300.0 - 150.0, rng.gen::<f64>() * 300.0 - 150.0);
+                    self.spawned_ships.insert(ship.id, Encoded::new(&ship));
+                    
+                    // Add the player's account
+                    self.accounts.insert(client_id, account);
+                    
+                    // Add the player's ship
+                    let ship_id = ship.id;
+                    let ship_name = ship.name.clone();
+                    let ship_index = self.context.add_ship(ship);
+                    
+                    // Send initial join packet with everything the player can see
+                    let visible = self.context.ships_in_sensor_range(ship_index, self.sensor_range);
+                    let ships: Vec<Encoded<Ship>> =
+                        visible.iter()
+                            .filter(|&&s| s != ship_index)
+                            .map(|s| Encoded::new(s.get(&self.context)))
+                            .collect();
+                    let join = ClientBattlePacket::Join(Encoded::new(ship_index.get(&self.context)), self.simulated_turn, ships, self.context.turn_config, self.context.alliances.clone());
+                    self.slot.send(client_id, &join);
+                    self.visible_ships.insert(client_id, visible);
+                    
+                    // Players that don't fit in the match go home after their first turn
+                    let joined_match = self.sector_match.as_mut().map(|m| m.add_player(ship_id, ship_name));
+                    match joined_match {
+                        Some(true) => {
+                            if let Some(event) = self.sector_match.as_ref().and_then(|m| m.lobby_event()) {
+                                self.send_match_event(&event);
+                            }
+                        },
+                        Some(false) => {
+                            self.slot.send(client_id, &ClientBattlePacket::Match(MatchEvent::Full));
+                            self.ships_going_home.push((ship_index, false));
+                        },
+                        None => {},
+                    }
+                    
+                    ack.send(());
+                },
                 
-                // Add the player's ship
-                let ship_index = self.context.add_ship(ship);
-                self.ships_to_add.push(ship_index);
-                
-                ack.send(());
+                SectorEvent::TurnTimer => {
+                    // A fired timer is spent, so make sure a new one gets set
+                    turn_deadline = None;
+                },
+            }
+        }
+    }
+    
+    // Spectators leave their ship in their account, and get a join packet with every ship in the
+    // sector
+    fn add_spectator(&mut self, client_id: ClientId, account: AccountBox) {
+        println!("Client {} is spectating battle {}", client_id, self.slot.get_id());
+        
+        self.accounts.insert(client_id, account);
+        self.spectators.insert(client_id);
+        
+        let visible: HashSet<ShipIndex> = self.context.ships_iter().map(|s| s.index).collect();
+        let ships: Vec<Encoded<Ship>> = visible.iter().map(|s| Encoded::new(s.get(&self.context))).collect();
+        self.slot.send(client_id, &ClientBattlePacket::Spectate(self.simulated_turn, ships, self.context.turn_config, self.context.alliances.clone()));
+        self.visible_ships.insert(client_id, visible);
+        
+        if let Some(event) = self.sector_match.as_ref().and_then(|m| m.lobby_event()) {
+            self.slot.send(client_id, &ClientBattlePacket::Match(event));
+        }
+    }
+    
+    // Players leave with their ship once the turn is simulated. So do spectators, so their client
+    // gets its final tick the same way.
+    fn logout(&mut self, client_id: ClientId) {
+        if self.spectators.contains(&client_id) {
+            if !self.spectators_leaving.contains(&client_id) {
+                self.spectators_leaving.push(client_id);
             }
+        } else {
+            let ship = self.context.get_ship_by_client_id(client_id);
+            self.ships_to_logout.push(ship.index);
         }
     }
     
-    fn handle_packet(&mut self, client_id: ClientId, packet: &mut InPacket) {
-        let battle_packet: ServerBattlePacket = packet.read().unwrap();
+    // Block until a message arrives on any of the sector's channels, or the turn timer fires
+    fn next_event(&self, turn_timer: &Timer) -> SectorEvent {
+        let select = Select::new();
+        let mut slot_h = select.handle(self.slot.receiver());
+        let mut chat_h = select.handle(&self.chat_receiver);
+        let mut from_map_h = select.handle(&self.from_map_receiver);
+        let mut turn_timer_h = select.handle(turn_timer.receiver());
+        unsafe {
+            slot_h.add();
+            chat_h.add();
+            from_map_h.add();
+            turn_timer_h.add();
+        }
         
+        let ready_id = select.wait();
+        if ready_id == slot_h.id() {
+            SectorEvent::Slot(self.slot.decode(slot_h.recv().ok().expect("Failed to receive SlotInMsg")))
+        } else if ready_id == chat_h.id() {
+            SectorEvent::Chat(chat_h.recv().ok().expect("Sector chat channel is broken"))
+        } else if ready_id == from_map_h.id() {
+            SectorEvent::NewAccount(from_map_h.recv().ok().expect("Sector star map channel is broken"))
+        } else {
+            let _ = turn_timer_h.recv();
+            SectorEvent::TurnTimer
+        }
+    }
+    
+    fn handle_packet(&mut self, client_id: ClientId, battle_packet: ServerBattlePacket) {
         match battle_packet {
-            ServerBattlePacket::Plan => { self.handle_plans(client_id, packet); },
+            ServerBattlePacket::Plan(turn, plans) => { self.handle_plans(client_id, turn, plans); },
             ServerBattlePacket::Chat(msg) => {
                 if self.debug {
                     println!("Handling chat packet");
@@ -203,23 +431,45 @@ impl SectorState {
                 self.chat_sender.send(msg);
             },
             ServerBattlePacket::Logout => {
-                let ship = self.context.get_ship_by_client_id(client_id);
-                self.ships_to_logout.push(ship.index);
+                self.logout(client_id);
+            },
+            ServerBattlePacket::Resync => {
+                println!("Client {} is out of sync, resending its ships next turn", client_id);
+                self.clients_resyncing.insert(client_id);
+                
+                // Its ships get replaced, so changes from what it had are no use
+                if let Some(encoder) = self.results_encoders.get_mut(&client_id) {
+                    encoder.reset();
+                }
+            },
+            ServerBattlePacket::ResultsAck(turn) => {
+                if let Some(encoder) = self.results_encoders.get_mut(&client_id) {
+                    encoder.confirm(turn);
+                }
             },
         }
     }
     
-    fn handle_plans(&mut self, client_id: ClientId, packet: &mut InPacket) {
+    fn handle_plans(&mut self, client_id: ClientId, turn: u32, plans: ShipPlans) {
         if self.debug {
             println!("Handling plans packet");
         }
+        
+        if self.spectators.contains(&client_id) {
+            println!("Client {} is spectating, ignoring its plans", client_id);
+            return;
+        }
+        
+        // Plans that missed their turn would otherwise end up applied to the next one
+        if turn != self.turn_number || self.simulated_turn {
+            println!("Client {} sent plans for turn {} too late, ignoring them", client_id, turn);
+            return;
+        }
     
         self.received_plans.insert(client_id);
     
         let ship = self.context.get_ship_by_client_id(client_id);
         
-        let plans = packet.read().unwrap();
-        
         if !ship.exploding {
             // Don't save these plans if the ship is exploding
             self.ship_plans.push((ship.index, plans));
@@ -227,35 +477,81 @@ impl SectorState {
         
         println!("Received plans packet from {} for turn {}", client_id, self.turn_number);
  
-        if self.received_plans == self.clients_active {
-            // TODO decide if we should simulate turn a little early here.
+        if self.all_plans_received() {
+            println!("Everyone's ready, resolving turn {} early", self.turn_number);
+            self.simulate_next_turn();
+            self.simulated_turn = true;
+            
+            // Move the turn clock up so the tick still comes as long after the results as it
+            // normally would. The next planning phase starts with the tick on both sides.
+            self.turn_start_time = time::now().to_timespec() - time::Duration::milliseconds(self.context.turn_config.planning_ms as i64);
         }
     }
     
+    // Whether every player who can plan this turn has sent their plans
+    fn all_plans_received(&self) -> bool {
+        !self.clients_active.is_empty() &&
+        self.clients_active.iter().all(|client_id| {
+            if self.received_plans.contains(client_id) {
+                return true;
+            }
+            
+            // Exploding ships don't get to plan
+            match self.context.ships_client_id.get(client_id).and_then(|&i| self.context.ships[i].as_ref()) {
+                Some(ship) => ship.exploding,
+                None => true,
+            }
+        })
+    }
+    
     fn simulate_next_turn(&mut self) {
         if self.debug {
             println!("Simulating next turn");
         }
+        
+        self.context.turn_seed = self.context.turn_seed.for_turn(self.turn_number);
     
         // Send new ships to added/removed before simulation
         self.send_new_ships_pre();
+        self.send_resyncs();
+        
+        // Ships sit still in the lobby, though players can still jump out
+        let match_waiting = self.sector_match.as_ref().map_or(false, |m| !m.in_progress());
+        if match_waiting {
+            for &mut (_, ref mut plans) in self.ship_plans.iter_mut() {
+                let target_sector = plans.target_sector;
+                *plans = ShipPlans::new();
+                plans.target_sector = target_sector;
+            }
+        }
     
         // Run AI on ships with no client
+        let alliances = &self.context.alliances;
         for ship in self.context.ships_iter() {
             let ship_id = ship.id;
             let enemies = 
                 &self.context.ships_iter()
-                    .filter(|s| s.id != ship_id && !s.exploding)
+                    .filter(|s| !alliances.are_allies(ship, s) && !s.exploding)
                     .collect();
             
-            if ship.client_id.is_none() {
+            if ship.client_id.is_none() && !match_waiting {
                 // Run AI
                 let mut plans = ship.create_plans();
-                run_ai(ship, &mut plans, enemies);
+                let mut rng = self.context.turn_seed.rng(RngStream::Ai, ship_id, 0);
+                run_ai(ship, &mut plans, enemies, &mut rng);
                 self.ship_plans.push((ship.index, plans));
             }
         }
         
+        // Friendly fire is up to the sector, not the players
+        for &mut (ship, ref mut plans) in self.ship_plans.iter_mut() {
+            self.context.remove_forbidden_targets(ship, plans);
+        }
+        
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_plans(&self.ship_plans);
+        }
+        
         // Apply all the plans
         let mut jumped_ships = vec!();
         for (ship, plans) in self.ship_plans.drain(..) {
@@ -280,48 +576,103 @@ impl SectorState {
         // Do server-side precalculations
         self.context.server_preprocess(&*self.model_store);
         
-        // Send the results packet
-        let mut results_packet = self.build_results_packet();
-        self.slot.broadcast(results_packet);
+        // Write the results packets
+        let results = self.write_results();
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_results(&self.context);
+        }
 
         // Run the simulation
         self.do_simulation();
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_simulated(&self.context);
+        }
+        
+        // Send the results with how the simulation turned out
+        self.send_results(results);
         
-        // Finish the results packet with ships to add and remove
+        // Finish the results packet with ships to add and remove. Wrecks are dealt with however
+        // the sector says to.
         let mut new_ships = vec!();
         let mut dead_ships = vec!();
+        let ships_respawn = self.sector_match.as_ref().map_or(true, |m| m.ships_respawn());
         for ship in self.context.ships_iter() {
-            // Replace dead ships with better ships
-            if ship.exploding {
-                let next_level = cmp::min(ship.level + 1, 15);
-                let mut better_ship = Ship::generate(ship.id, ship.name.clone(), next_level);
-                better_ship.client_id = ship.client_id;
-                
-                // Remove the old ship
-                dead_ships.push(ship.index);
-                
-                // Add the better ship
-                new_ships.push(better_ship);
+            // Wrecks logging out leave as they are, and wrecks in a match may have to wait for it
+            // to end
+            if !ship.exploding || self.ships_to_logout.contains(&ship.index) || !ships_respawn {
+                continue;
+            }
+            
+            let policy = if ship.client_id.is_some() { self.player_respawn } else { self.npc_respawn };
+            match policy {
+                RespawnPolicy::ReturnHome { damaged } => {
+                    if ship.client_id.is_some() {
+                        // Leaves with the jumping ships, once everyone's heard it's gone
+                        self.ships_going_home.push((ship.index, damaged));
+                    } else {
+                        dead_ships.push(ship.index);
+                        self.spawned_ships.remove(&ship.id);
+                    }
+                },
+                RespawnPolicy::InPlace { turns } => {
+                    let wrecked_on = self.wrecked_on.get(&ship.id).cloned().unwrap_or(self.turn_number);
+                    if self.turn_number >= wrecked_on + turns {
+                        dead_ships.push(ship.index);
+                        new_ships.push(self.respawned_ship(ship));
+                    }
+                },
+                RespawnPolicy::Escalate => {
+                    // Replace dead ships with better ships
+                    let next_level = cmp::min(ship.level + 1, 15);
+                    let mut rng = self.context.turn_seed.rng(RngStream::Spawn, ship.id, SPAWN_KEY_GENERATE);
+                    let mut better_ship = Ship::generate(ship.id, ship.name.clone(), next_level, &mut rng);
+                    better_ship.client_id = ship.client_id;
+                    better_ship.team = ship.team;
+                    
+                    dead_ships.push(ship.index);
+                    new_ships.push(better_ship);
+                },
             }
         }
         
         for dead_ship in dead_ships.into_iter() {
-            self.context.remove_ship(dead_ship);
+            let ship = self.context.remove_ship(dead_ship);
+            self.wrecked_on.remove(&ship.id);
             self.ships_to_remove.push(dead_ship);
         }
         
         for new_ship in new_ships.into_iter() {
-            let ship_index = self.context.add_ship(new_ship);
-            self.ships_to_add.push(ship_index);
+            self.context.add_ship(new_ship);
         }
         
         // Make dead ships start exploding
+        let mut new_wrecks = vec!();
         for ship in self.context.ships_iter_mut() {
             if ship.state.get_hp() == 0 {
+                if !ship.exploding {
+                    self.wrecked_on.insert(ship.id, self.turn_number);
+                    new_wrecks.push(ship.index);
+                }
                 ship.exploding = true;
             }
         }
         
+        if self.sector_match.is_some() {
+            self.score_kills(&new_wrecks);
+            
+            let results = self.sector_match.as_ref().and_then(|m| m.check_victory(&self.context, self.turn_number));
+            if let Some(results) = results {
+                self.end_match(results);
+            }
+        }
+        
+        // Ships going home leave with the jumping ships, once everyone's heard they're gone
+        for &(ship, _) in &self.ships_going_home {
+            if !self.ships_to_remove.contains(&ship) {
+                self.ships_to_remove.push(ship);
+            }
+        }
+        
         // Send new ships
         self.send_new_ships_post();
         
@@ -330,39 +681,64 @@ impl SectorState {
             let ship = self.context.remove_ship(jumped_ship);
 
             if let Some(client_id) = ship.client_id {
-                // Send the last tick
-                self.send_final_ticks(client_id, 1);
-                
-                let ship_stored = ShipStored::from_ship(ship);
-                
-                let mut account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
-                account.ship = Some(ship_stored);
-                
-                self.slot.transfer_client(client_id, self.star_map_slot_id);
+                self.send_to_map(client_id, ShipStored::from_ship(ship), StarMapAction::Jump(target_sector));
+            }
+        }
+        
+        // Send players home. Ships that already left some other way are skipped.
+        let ships_going_home: Vec<(ShipIndex, bool)> = self.ships_going_home.drain(..).collect();
+        for (ship_index, damaged) in ships_going_home.into_iter() {
+            if self.context.ships[ship_index.to_usize()].is_none() || self.ships_to_logout.contains(&ship_index) {
+                continue;
+            }
+            
+            let ship = self.context.remove_ship(ship_index);
+            let spawned_ship = self.spawned_ships.get(&ship.id).map(|s| s.decode());
+
+            if let Some(client_id) = ship.client_id {
+                let ship_stored =
+                    match spawned_ship {
+                        Some(Ok(spawned_ship)) if !damaged => ShipStored::from_ship(spawned_ship),
+                        _ => wreck_stored(ship),
+                    };
                 
-                self.to_map_sender.send((account, StarMapAction::Jump(target_sector)));
+                let home_station = self.accounts[&client_id].home_station;
+                self.send_to_map(client_id, ship_stored, StarMapAction::Jump(home_station));
             }
         }
         
         // Send off all of the ships logging out
-        for ship in &self.ships_to_logout {
-            let ship = self.context.remove_ship(*ship);
+        let ships_to_logout: Vec<ShipIndex> = self.ships_to_logout.drain(..).collect();
+        for ship in ships_to_logout {
+            let ship = self.context.remove_ship(ship);
 
             if let Some(client_id) = ship.client_id {
-                // Send the last tick
-                self.send_final_ticks(client_id, 1);
-                
-                let ship_stored = ShipStored::from_ship(ship);
-                
-                let mut account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
-                account.ship = Some(ship_stored);
-                
-                self.slot.transfer_client(client_id, self.star_map_slot_id);
-                
-                self.to_map_sender.send((account, StarMapAction::Logout));
+                let ship_stored = if ship.exploding { wreck_stored(ship) } else { ShipStored::from_ship(ship) };
+                self.send_to_map(client_id, ship_stored, StarMapAction::Logout);
             }
         }
-        self.ships_to_logout.clear();
+        
+        // Send off the spectators that are leaving
+        let spectators_leaving: Vec<ClientId> = self.spectators_leaving.drain(..).collect();
+        for client_id in spectators_leaving {
+            self.send_final_ticks(client_id, 1);
+            
+            let account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
+            
+            self.spectators.remove(&client_id);
+            self.visible_ships.remove(&client_id);
+            self.results_encoders.remove(&client_id);
+            self.slot.transfer_client(client_id, self.star_map_slot_id);
+            
+            self.to_map_sender.send((account, StarMapAction::Logout));
+        }
+        
+        // Start the match with the next turn once the lobby is full
+        let next_turn = self.turn_number + 1;
+        if let Some(event) = self.sector_match.as_mut().and_then(|m| m.try_start(next_turn)) {
+            println!("Starting a match in battle {}", self.slot.get_id());
+            self.send_match_event(&event);
+        }
         
         // Reset everything for the next turn
         self.received_plans.clear();
@@ -370,10 +746,123 @@ impl SectorState {
         
         // Transfer waiting clients to active clients
         self.clients_active = self.clients_active.union(&self.clients_waiting).map(|&x| x).collect();
+        self.clients_waiting.clear();
+    }
+    
+    // A wreck as it was when it came into the sector, back where it blew up
+    fn respawned_ship(&self, wreck: &Ship) -> Ship {
+        match self.spawned_ships.get(&wreck.id).map(|s| s.decode()) {
+            Some(Ok(mut ship)) => {
+                ship.position = wreck.position;
+                ship.client_id = wreck.client_id;
+                ship
+            },
+            _ => {
+                println!("Lost track of how ship {} came into the sector, building it a new one", wreck.id);
+                let mut rng = self.context.turn_seed.rng(RngStream::Spawn, wreck.id, SPAWN_KEY_GENERATE);
+                let mut ship = Ship::generate(wreck.id, wreck.name.clone(), wreck.level, &mut rng);
+                ship.team = wreck.team;
+                ship
+            },
+        }
+    }
+    
+    // Give a player's account back to the star map with `ship_stored` as their ship
+    fn send_to_map(&mut self, client_id: ClientId, ship_stored: ShipStored, action: StarMapAction) {
+        // Send the last tick
+        self.send_final_ticks(client_id, 1);
+        
+        let ship_stored_id = ship_stored.id;
+        self.spawned_ships.remove(&ship_stored_id);
+        self.wrecked_on.remove(&ship_stored_id);
+        
+        let mut account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
+        account.ship = Some(ship_stored);
+        
+        self.visible_ships.remove(&client_id);
+        self.results_encoders.remove(&client_id);
+        self.clients_active.remove(&client_id);
+        self.slot.transfer_client(client_id, self.star_map_slot_id);
+        
+        self.to_map_sender.send((account, action));
+        
+        // Let the rest of the lobby know there's room again
+        let lobby_event =
+            match self.sector_match {
+                Some(ref mut sector_match) => {
+                    if sector_match.remove_player(ship_stored_id) { sector_match.lobby_event() } else { None }
+                },
+                None => None,
+            };
+        if let Some(event) = lobby_event {
+            self.send_match_event(&event);
+        }
+    }
+    
+    // Credit each new wreck to every ship that had a module on it when it blew up
+    fn score_kills(&mut self, new_wrecks: &[ShipIndex]) {
+        for &wreck in new_wrecks {
+            let killers: Vec<ShipId> =
+                self.context.ships_iter()
+                    .filter(|s| s.index != wreck)
+                    .filter(|s| s.modules.iter().any(|m| m.active && m.target.as_ref().map_or(false, |t| t.ship == wreck)))
+                    .map(|s| s.id)
+                    .collect();
+            
+            if let Some(ref mut sector_match) = self.sector_match {
+                for killer in killers {
+                    sector_match.record_kill(killer);
+                }
+            }
+        }
+    }
+    
+    // Tell everyone how the match went, and send the players home. The sector's NPCs are put back
+    // the way they were, ready for the next match.
+    fn end_match(&mut self, results: MatchResults) {
+        println!("Match over in battle {}", self.slot.get_id());
+        self.send_match_event(&MatchEvent::Over(results));
+        
+        let mut npcs = vec!();
+        for ship in self.context.ships_iter() {
+            if ship.client_id.is_some() {
+                if !ship.jumping && !self.ships_to_logout.contains(&ship.index) &&
+                   !self.ships_going_home.iter().any(|&(s, _)| s == ship.index) {
+                    self.ships_going_home.push((ship.index, false));
+                }
+            } else {
+                npcs.push(ship.index);
+            }
+        }
+        
+        for npc in npcs {
+            let ship = self.context.remove_ship(npc);
+            self.wrecked_on.remove(&ship.id);
+            self.ships_to_remove.push(npc);
+        }
+        
+        for npc_id in self.npc_ids.iter() {
+            match self.spawned_ships.get(npc_id).map(|s| s.decode()) {
+                Some(Ok(ship)) => { self.context.add_ship(ship); },
+                _ => println!("Lost track of NPC {}, leaving it out of the next match", npc_id),
+            }
+        }
+        
+        if let Some(ref mut sector_match) = self.sector_match {
+            sector_match.reset();
+        }
+    }
+    
+    // Everyone in the sector hears about the match, spectators included. This goes to each client
+    // rather than the slot, so players who just arrived hear it too.
+    fn send_match_event(&self, event: &MatchEvent) {
+        for &client_id in self.accounts.keys() {
+            self.slot.send(client_id, &ClientBattlePacket::Match(event.clone()));
+        }
     }
     
     fn do_simulation(&mut self) {
-        let mut sim_events = SimEvents::new();
+        let mut sim_events = SimEvents::new(self.context.turn_config.ticks_per_turn);
     
         // Pre simulation
         self.context.before_simulation(&*self.model_store, &mut sim_events);
@@ -392,56 +881,113 @@ impl SectorState {
     }
     
     fn simulate(&mut self, sim_events: &mut SimEvents) {
-        for tick in 0..100 {
+        for tick in 0..self.context.turn_config.ticks_per_turn {
             sim_events.apply_tick(&mut self.context, tick);
         }
     }
     
-    fn build_results_packet(&mut self) -> OutPacket {
-        let mut packet = OutPacket::new();
-        packet.write(&ClientBattlePacket::SimResults).unwrap();
-        self.context.write_results(&mut packet);
-        packet
+    // Each player only gets the results of the ships they can see, and only what's changed since
+    // the last results they confirmed
+    fn write_results(&mut self) -> Vec<(ClientId, OutPacket)> {
+        let mut packets = vec!();
+        
+        for (&client_id, visible) in self.visible_ships.iter() {
+            let encoder = self.results_encoders.entry(client_id).or_insert_with(ResultsEncoder::new);
+            
+            let mut results = OutPacket::new();
+            encoder.write_results(&mut results, self.turn_number, &self.context, visible);
+            packets.push((client_id, results));
+        }
+        
+        packets
+    }
+    
+    // Results go out after simulating, with checksums of the ships' states for players to check
+    // their own simulation against
+    fn send_results(&self, results: Vec<(ClientId, OutPacket)>) {
+        for (client_id, mut results) in results {
+            if let Some(visible) = self.visible_ships.get(&client_id) {
+                results.write(&self.context.visible_checksums(visible));
+                self.slot.send(client_id, &ClientBattlePacket::SimResults(results.into_bytes()));
+            }
+        }
+    }
+    
+    // Players that are out of sync get a fresh copy of everything they can see. This goes out
+    // before the results, so they play the turn from the same state we do.
+    fn send_resyncs(&mut self) {
+        for client_id in self.clients_resyncing.drain() {
+            if let Some(visible) = self.visible_ships.get(&client_id) {
+                let ships: Vec<Encoded<Ship>> = visible.iter().map(|s| Encoded::new(s.get(&self.context))).collect();
+                self.slot.send(client_id, &ClientBattlePacket::Resync(ships));
+            }
+        }
     }
     
     fn send_new_ships_pre(&mut self) {
-        let mut ships_packet = OutPacket::new();
-        ships_packet.write(&ClientBattlePacket::NewShipsPre).unwrap();
-        self.write_new_ships(&mut ships_packet);
-        self.slot.broadcast(ships_packet);
+        let removed = self.send_visible_ships(ClientBattlePacket::NewShipsPre);
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_new_ships_pre(self.turn_number, &self.context, &removed);
+        }
     }
     
     fn send_new_ships_post(&mut self) {
-        let mut ships_packet = OutPacket::new();
-        ships_packet.write(&ClientBattlePacket::NewShipsPost).unwrap();
-        self.write_new_ships(&mut ships_packet);
-        self.slot.broadcast(ships_packet);
+        let removed = self.send_visible_ships(ClientBattlePacket::NewShipsPost);
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_new_ships_post(&self.context, &removed);
+        }
     }
     
-    fn write_new_ships(&mut self, ships_packet: &mut OutPacket) {
+    // Work out what each player can see now, and send them the ships that came into sensor range
+    // along with the ones that went out of range or are being removed. Returns the ships being
+    // removed.
+    fn send_visible_ships<F>(&mut self, new_ships_packet: F) -> HashSet<ShipIndex>
+        where F: Fn(Vec<Encoded<Ship>>, Vec<ShipIndex>) -> ClientBattlePacket
+    {
         if self.debug {
             println!("Sending new ships");
         }
         
-        {
-            let ships_to_add: Vec<&Ship> = self.ships_to_add.iter().map(|s| s.get(&self.context)).collect();
-            ships_packet.write(&ships_to_add);
-        }
+        let removed: HashSet<ShipIndex> = self.ships_to_remove.drain(..).collect();
         
-        ships_packet.write(&self.ships_to_remove);
+        for (&client_id, visible) in self.visible_ships.iter_mut() {
+            let player_ship = self.context.ships_client_id.get(&client_id).map(|&i| ShipIndex(i as u32));
+            let relevant: HashSet<ShipIndex> =
+                match player_ship {
+                    // Spectators see everything
+                    None if self.spectators.contains(&client_id) => {
+                        self.context.ships_iter().map(|s| s.index).filter(|s| !removed.contains(s)).collect()
+                    },
+                    Some(ship) if self.context.ships[ship.to_usize()].is_some() && !removed.contains(&ship) => {
+                        self.context.ships_in_sensor_range(ship, self.sensor_range).difference(&removed).cloned().collect()
+                    },
+                    // The player's ship is on its way out, so there's nothing new to see
+                    _ => visible.difference(&removed).cloned().collect(),
+                };
+            
+            let entered: Vec<Encoded<Ship>> = relevant.difference(visible).map(|s| Encoded::new(s.get(&self.context))).collect();
+            let left: Vec<ShipIndex> = visible.difference(&relevant).cloned().collect();
+            *visible = relevant;
+            
+            self.slot.send(client_id, &new_ships_packet(entered, left));
+        }
         
-        self.ships_to_add.clear();
-        self.ships_to_remove.clear();
+        removed
     }
     
     fn send_turn_tick(&mut self) {
         if self.debug {
             println!("Sending tick");
         }
+        
+        // Let everyone know how laggy everyone else is
+        let pings: Vec<(ClientId, u32)> =
+            self.accounts.keys()
+                .filter_map(|&client_id| self.slot.get_latency(client_id).map(|ping| (client_id, ping)))
+                .collect();
+        self.slot.broadcast(&ClientBattlePacket::Pings(pings));
 
-        let mut packet = OutPacket::new();
-        packet.write(&ClientBattlePacket::Tick(None));
-        self.slot.broadcast(packet);
+        self.slot.broadcast(&ClientBattlePacket::Tick(None, self.turn_number));
     }
     
     fn send_final_ticks(&self, client_id: ClientId, ticks_left: u8) {
@@ -449,8 +995,13 @@ impl SectorState {
             println!("Sending tick");
         }
 
-        let mut packet = OutPacket::new();
-        packet.write(&ClientBattlePacket::Tick(Some(ticks_left)));
-        self.slot.send(client_id, packet);
+        self.slot.send(client_id, &ClientBattlePacket::Tick(Some(ticks_left), self.turn_number));
     }
 }
+
+// What's left of a destroyed ship, for its player to take home
+fn wreck_stored(wreck: Ship) -> ShipStored {
+    let mut ship_stored = ShipStored::from_ship(wreck);
+    ship_stored.state.patch_hull();
+    ship_stored
+}
@@ -11,21 +11,27 @@
 #![feature(reflect_marker)]
 #![feature(raw)]
 #![feature(drain)]
+#![feature(mpsc_select)]
 
 extern crate bincode;
 extern crate float;
 extern crate num;
 extern crate rand;
 extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate flate2;
 extern crate time;
 
+use std::env;
 use std::thread::Builder;
 use std::sync::Arc;
 use std::sync::mpsc::channel;
 
+use login::LoginSlot;
 use module::ModelStore;
-use net::Server;
-use star_map::StarMapServer;
+use net::{LinkSecret, Server, ServerIdentity};
+use sector_data::SectorId;
+use star_map::{StarMapServer, StarMapSlot};
 
 mod ai;
 mod battle_context;
@@ -38,18 +44,85 @@ mod module;
 mod net;
 mod no_encode;
 mod packet_types;
+mod replay;
+mod respawn_policy;
+mod results_delta;
 mod sector_data;
 mod sector_server;
 mod ship;
 mod sim;
 mod sim_events;
 mod star_map;
+mod team;
+mod timer;
+mod turn_config;
+mod turn_seed;
 mod vec;
 
+// Where the star map listens for sectors running in other processes, unless --sector-address says
+// otherwise. Only this machine can reach it, so sectors on other machines need it changed.
+const SECTOR_ADDRESS: &'static str = "127.0.0.1:30001";
+
+// The environment variable holding the secret the star map and its remote sectors share. It's not
+// an argument so it doesn't show up in the process list.
+const LINK_SECRET_VAR: &'static str = "REFORGE_LINK_SECRET";
+
+// The argument after `name`, if it was passed
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1))
+}
+
+fn link_secret() -> LinkSecret {
+    match env::var(LINK_SECRET_VAR) {
+        Ok(ref secret) if !secret.is_empty() => LinkSecret::new(secret),
+        _ => panic!("Remote sectors need {} set to a secret shared with the star map", LINK_SECRET_VAR),
+    }
+}
+
+// To run sectors in their own processes, start the galaxy with --remote-sectors listing them, then
+// a process for each with --sector. All of them need the same secret in REFORGE_LINK_SECRET. On one
+// machine that's:
+//
+//     export REFORGE_LINK_SECRET=<something long and random>
+//     reforge_server --remote-sectors 1,2
+//     reforge_server --sector 1
+//     reforge_server --sector 2
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    
+    // Pass --sector <id> to run just that sector, for the star map at --star-map <host:port>
+    if let Some(sector_id) = arg_value(&args, "--sector") {
+        let sector_id = SectorId(sector_id.parse().ok().expect("--sector needs a sector ID"));
+        let star_map_address = arg_value(&args, "--star-map").map(|a| a.as_str()).unwrap_or("127.0.0.1:30001");
+        let record_dir = arg_value(&args, "--record").map(|dir| dir.as_str());
+        if let Err(e) = star_map::run_remote_sector(star_map_address, sector_id, &link_secret(), Arc::new(ModelStore::new()), record_dir) {
+            println!("Sector {} stopped: {}", sector_id.0, e);
+        }
+        return;
+    }
+    
     let mut server = Server::new();
-    let login_slot = server.create_slot();
-    let star_map_slot = server.create_slot();
+    
+    // Pass --encrypt to encrypt traffic with clients. The server proves itself with the key pair
+    // in --identity <file>, made on first run, so players can pin its key with --server-key.
+    if env::args().any(|arg| arg == "--encrypt") {
+        let identity_path = arg_value(&args, "--identity").map(|path| path.as_str()).unwrap_or("server_identity.key");
+        let identity = ServerIdentity::load_or_create(identity_path).ok().expect("Failed to load server identity");
+        println!("Server key: {}", identity.fingerprint());
+        
+        server.set_identity(identity);
+        server.set_encryption(true);
+    }
+    
+    // Pass --capture <file> to record all slot traffic to a capture file
+    if let Some(path) = arg_value(&args, "--capture") {
+        if let Err(e) = server.start_capture(path.as_str()) {
+            println!("Failed to start capture to {}: {}", path, e);
+        }
+    }
+    
+    let login_slot: LoginSlot = server.create_slot();
+    let star_map_slot: StarMapSlot = server.create_slot();
     let star_map_slot_id = star_map_slot.get_id();
     let (star_map_account_sender, star_map_account_receiver) = channel();
     let (logout_sender, logout_receiver) = channel();
@@ -64,6 +137,23 @@ fn main() {
         login::run_login_server(login_model_store, login_slot, star_map_slot_id, star_map_account_sender, logout_receiver);
     });
     
-    let mut star_map_server = StarMapServer::new(star_map_model_store, star_map_slot);
+    // Pass --remote-sectors <id,id,...> to leave those sectors to other processes
+    let remote_sectors: Vec<SectorId> =
+        match arg_value(&args, "--remote-sectors") {
+            Some(ids) => ids.split(',').map(|id| SectorId(id.parse().ok().expect("--remote-sectors needs sector IDs"))).collect(),
+            None => vec!(),
+        };
+    
+    // Pass --record <dir> to record every sector's battle to a replay file in that directory
+    let record_dir = arg_value(&args, "--record").cloned();
+    
+    let mut star_map_server = StarMapServer::new(star_map_model_store, star_map_slot, remote_sectors.clone(), record_dir);
+    if !remote_sectors.is_empty() {
+        // Pass --sector-address <host:port> to listen for sectors somewhere else
+        let sector_address = arg_value(&args, "--sector-address").map(|a| a.as_str()).unwrap_or(SECTOR_ADDRESS);
+        if let Err(e) = star_map_server.listen_for_sectors(sector_address, link_secret()) {
+            println!("Failed to listen for sectors on {}: {}", sector_address, e);
+        }
+    }
     star_map_server.run(star_map_account_receiver, logout_sender);
 }
@@ -4,6 +4,9 @@ use std::cmp;
 use std::collections::VecDeque;
 use std::marker::Reflect;
 
+use rand::Rng;
+use rustc_serialize::Encodable;
+
 use battle_context::BattleContext;
 use module;
 use module::{
@@ -21,6 +24,8 @@ use net::{ClientId, InPacket, OutPacket};
 use self::ship_gen::{generate_ship, generate_dummy_ship, generate_dev_ship};
 use sector_data::SectorId;
 use sim::SimEvents;
+use team::TeamId;
+use turn_config::TurnConfig;
 use vec::{Vec2, Vec2f};
 
 #[cfg(feature = "client")]
@@ -41,7 +46,7 @@ mod ship_gen;
 mod plans;
 
 // Holds everything about the ship's damage, capabilities, etc.
-#[derive(Clone, RustcEncodable, RustcDecodable)]
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
 pub struct ShipState {
     pub hp: u8,
     total_module_hp: u8, // Sum of HP of all modules, used to recalculate HP when damaged
@@ -71,6 +76,19 @@ impl ShipState {
         }
     }
     
+    // Stable hash of what the simulation changes, so clients can tell if their copy of the ship has
+    // drifted from the server's
+    pub fn checksum(&self) -> u32 {
+        let mut bytes = vec!(self.hp, self.shields, self.power_use);
+        for stats in &self.module_stats {
+            bytes.push(stats.hp);
+            bytes.push(stats.max_hp);
+        }
+        
+        // FNV-1a
+        bytes.iter().fold(2166136261, |hash: u32, &byte| (hash ^ byte as u32).wrapping_mul(16777619))
+    }
+    
     pub fn available_power(&self) -> u8 {
         if self.max_power > self.power_use {
             self.max_power - self.power_use
@@ -149,12 +167,17 @@ impl ShipState {
     pub fn get_hp(&self) -> u8 {
         self.hp
     }
+    
+    // Wrecks keep their damage, but get just enough hull back to fly again
+    pub fn patch_hull(&mut self) {
+        self.hp = cmp::max(self.hp, 1);
+    }
 }
 
 // Type for the ID of a ship
 pub type ShipId = u64;
 
-#[derive(Copy, Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
 pub struct ShipIndex(pub u32);
 
 impl ShipIndex {
@@ -182,6 +205,7 @@ pub struct Ship {
     pub name: String,
     pub client_id: Option<ClientId>,
     pub index: ShipIndex, // Index for ship in ship vector in BattleContext
+    pub team: Option<TeamId>,
     pub state: ShipState,
     pub modules: Vec<Module>,
     
@@ -208,6 +232,7 @@ impl Ship {
             name: name,
             client_id: None,
             index: ShipIndex(0),
+            team: None,
             state: ShipState::new(),
             modules: vec!(),
             
@@ -224,8 +249,8 @@ impl Ship {
         }
     }
     
-    pub fn generate(id: ShipId, name: String, level: u8) -> Ship {
-        generate_ship(id, name, level)
+    pub fn generate<R: Rng>(id: ShipId, name: String, level: u8, rng: &mut R) -> Ship {
+        generate_ship(id, name, level, rng)
     }
     
     pub fn generate_dummy(id: ShipId, name: String) -> Ship {
@@ -244,9 +269,10 @@ impl Ship {
         self.height
     }
 
-    pub fn lerp_next_waypoint(&self, time: f64) -> Vec2f {
+    // Where the ship is `time` seconds into simulating a turn
+    pub fn lerp_next_waypoint(&self, turn_config: &TurnConfig, time: f64) -> Vec2f {
         if let Some(next_waypoint) = self.next_waypoint {
-            self.position + (next_waypoint - self.position)*(time/5.0)
+            self.position + (next_waypoint - self.position)*(time/turn_config.sim_seconds())
         } else {
             self.position
         }
@@ -559,6 +585,29 @@ impl Ship {
         }
     }
     
+    // The same results as `write_results`, with each field encoded on its own so it can be
+    // compared with what a player already has. Read back with `read_results` once they're joined
+    // back together.
+    pub fn result_fields(&self) -> Vec<Vec<u8>> {
+        let mut fields = vec!();
+        fields.push(encode_field(&self.state.power_use));
+        fields.push(encode_field(&self.jumping));
+        fields.push(encode_field(&self.position));
+        fields.push(encode_field(&self.next_waypoint));
+        
+        for module in &self.modules {
+            fields.push(encode_field(&module.active));
+            fields.push(encode_field(&module.target));
+            
+            // Modules write their own results however they like, so they're compared as a whole
+            let mut inner_results = OutPacket::new();
+            module.inner.borrow().write_results(&mut inner_results);
+            fields.push(inner_results.into_bytes());
+        }
+        
+        fields
+    }
+    
     pub fn read_results(&mut self, packet: &mut InPacket) {
         self.state.power_use = packet.read().ok().expect("Failed to read ShipState::power_use");
         self.jumping = packet.read().ok().expect("Failed to read Ship::jumping");
@@ -668,6 +717,7 @@ impl Ship {
 pub struct ShipStored {
     pub id: ShipId,
     pub name: String,
+    pub team: Option<TeamId>,
     pub state: ShipState,
     pub modules: Vec<ModuleStored>,
     
@@ -683,6 +733,7 @@ impl ShipStored {
         ShipStored {
             id: id,
             name: String::new(),
+            team: None,
             state: ShipState::new(),
             modules: vec!(),
             
@@ -697,6 +748,7 @@ impl ShipStored {
         ShipStored {
             id: ship.id,
             name: ship.name,
+            team: ship.team,
             state: ship.state,
             modules: ship.modules.into_iter().map(|m| ModuleStored::from_module(m)).collect(),
             width: ship.width,
@@ -711,6 +763,7 @@ impl ShipStored {
             name: self.name,
             client_id: client_id,
             index: ShipIndex(0),
+            team: self.team,
             state: self.state,
             modules: self.modules.into_iter().map(|m| m.to_module()).collect(),
             width: self.width,
@@ -787,3 +840,11 @@ impl ShipStored {
         }
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+fn encode_field<T: Encodable>(field: &T) -> Vec<u8> {
+    let mut packet = OutPacket::new();
+    packet.write(field).ok().expect("Failed to encode result field");
+    packet.into_bytes()
+}
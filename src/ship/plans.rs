@@ -15,6 +15,17 @@ pub struct ShipPlans {
 }
 
 impl ShipPlans {    
+    // Plans for no ship in particular, that don't do anything
+    pub fn new() -> ShipPlans {
+        ShipPlans {
+            logout: false,
+            target_sector: None,
+            module_plans: vec!(),
+            plan_power_use: 0,
+            next_waypoint: None,
+        }
+    }
+    
     pub fn available_plan_power(&self, ship_state: &ShipState) -> u8 {
         if ship_state.max_power > self.plan_power_use {
             ship_state.max_power - self.plan_power_use
@@ -18,14 +18,13 @@ use module::{
     BeamWeaponModule
 };
 
-pub fn generate_ship(id: ShipId, name: String, level: u8) -> Ship {
+// Everything about the ship is rolled from `rng`, so the same generator state always builds the
+// same ship
+pub fn generate_ship<R: Rng>(id: ShipId, name: String, level: u8, rng: &mut R) -> Ship {
     if level == 0 {
         panic!("Can't generate ship with level 0");
     }
 
-    // Random number generater
-    let mut rng = rand::thread_rng();
-
     // Brand new ship!!
     let mut ship = Ship::new(id, name, level);
     
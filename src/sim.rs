@@ -1,9 +1,10 @@
+use std::cmp;
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 use std::cell::RefCell;
 
 use battle_context::BattleContext;
-use ship::{ShipId, ShipIndex, ShipState};
+use ship::{Ship, ShipId, ShipIndex};
 
 // SimVisual imports
 #[cfg(feature = "client")]
@@ -16,7 +17,7 @@ use sdl2_mixer;
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
 pub trait SimEvent {
-    fn apply(&mut self, ship_state: &mut ShipState);
+    fn apply(&mut self, ship: &mut Ship);
 }
 
 pub struct SimEvents<'a> {
@@ -24,9 +25,10 @@ pub struct SimEvents<'a> {
 }
 
 impl<'a> SimEvents<'a> {
-    pub fn new() -> SimEvents<'a> {
-        let mut events = Vec::with_capacity(100);
-        while events.len() < 100 {
+    pub fn new(num_ticks: u32) -> SimEvents<'a> {
+        let num_ticks = num_ticks as usize;
+        let mut events = Vec::with_capacity(num_ticks);
+        while events.len() < num_ticks {
             events.push(vec!());
         }
         SimEvents {
@@ -37,12 +39,15 @@ impl<'a> SimEvents<'a> {
     pub fn apply_tick(&mut self, bc: &mut BattleContext, tick: u32) {
         let tick = tick as usize;
         for (ship, mut event) in self.events[tick].drain(..) {
-            event.apply(&mut ship.get_mut(bc).state);
+            event.apply(ship.get_mut(bc));
         }
     }
     
     pub fn add(&mut self, tick: u32, ship: ShipIndex, event: Box<SimEvent+'a>) {
-        self.events[tick as usize].push((ship, event));
+        // Modules time their events for regular turns. In shorter turns, anything that would
+        // happen after the end happens on the last tick instead.
+        let last_tick = self.events.len() - 1;
+        self.events[cmp::min(tick as usize, last_tick)].push((ship, event));
     }
 }
 
@@ -1,7 +1,9 @@
 use std::ops::DerefMut;
 
+use rand::XorShiftRng;
+
 use module::ModuleIndex;
-use ship::ShipState;
+use ship::Ship;
 use sim::SimEvent;
 
 pub struct DamageEvent {
@@ -9,25 +11,33 @@ pub struct DamageEvent {
     damage: u8,
     shield_piercing: u8,
     damage_shields: bool,
+    
+    // Where the damage shows up on the module is rolled from this. It comes from the turn's seed.
+    visual_rng: XorShiftRng,
 }
 
 impl DamageEvent {
     pub fn new(module_index: ModuleIndex,
                damage: u8,
                shield_piercing: u8,
-               damage_shields: bool) -> DamageEvent {
+               damage_shields: bool,
+               visual_rng: XorShiftRng) -> DamageEvent {
         DamageEvent {
             module_index: module_index,
             damage: damage,
             shield_piercing: shield_piercing,
             damage_shields: damage_shields,
+            visual_rng: visual_rng,
         }
     }
 }
 
 impl SimEvent for DamageEvent {
-    fn apply(&mut self, ship_state: &mut ShipState) {
-        ship_state.deal_damage(self.module_index, self.damage, self.shield_piercing, self.damage_shields);
+    fn apply(&mut self, ship: &mut Ship) {
+        ship.state.deal_damage(self.module_index, self.damage, self.shield_piercing, self.damage_shields);
+        
+        let hp = ship.state.module_stats[self.module_index.to_usize()].hp;
+        ship.modules[self.module_index.to_usize()].add_damage_visual(hp, &mut self.visual_rng);
     }
 }
 
@@ -48,7 +58,7 @@ impl RepairEvent {
 }
 
 impl SimEvent for RepairEvent {
-    fn apply(&mut self, ship_state: &mut ShipState) {
-        ship_state.repair_damage(self.module_index, self.repair);
+    fn apply(&mut self, ship: &mut Ship) {
+        ship.state.repair_damage(self.module_index, self.repair);
     }
 }
\ No newline at end of file
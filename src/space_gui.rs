@@ -1,5 +1,6 @@
 use rand::Rng;
 use rand;
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
@@ -25,6 +26,7 @@ use sector_data::SectorData;
 use ship::{Ship, ShipId, ShipIndex, ShipPlans, ShipState};
 use sim::SimEffects;
 use star_map::{StarMapGui, StarMapGuiAction};
+use team::{ALLY_COLOR, ENEMY_COLOR};
 use vec::{Vec2, Vec2f};
 
 static SHIP_OFFSET_X: f64 = 80.0;
@@ -36,6 +38,8 @@ static ENEMY_OFFSET_Y: f64 = 50.0;
 pub enum SpaceGuiAction {
     Chat(String),
     Logout,
+    Ready, // Send plans now instead of waiting for the planning phase to run out
+    Follow(ShipIndex), // Spectators switching to watching another ship
 }
 
 pub struct ModuleIcons {
@@ -52,6 +56,9 @@ pub struct StatsLabels {
 pub struct SpaceGui<'a> {
     // Plans for player's ship
     pub plans: ShipPlans,
+    
+    // The ship the plans are for, if there is one
+    client_ship_id: Option<ShipId>,
 
     // The target ships' render areas
     render_area: ShipRenderArea,
@@ -95,9 +102,21 @@ pub struct SpaceGui<'a> {
     
     // Logout button
     logout_button: SpriteButton,
+    
+    // Set when the player presses space to say they're done planning
+    ready_pressed: bool,
 
     // targets
     target_icons: Vec<TargetIcon>,
+    
+    // Each player's ping in milliseconds, as last reported by the server
+    pings: HashMap<ClientId, u32>,
+    
+    // Spectators can't plan. They watch whichever ship they pick, and the nav map moves freely.
+    spectating: bool,
+    
+    // How the sector's match is going, for sectors that run one
+    match_status: Option<String>,
 }
 
 impl<'a> SpaceGui<'a> {
@@ -128,9 +147,13 @@ impl<'a> SpaceGui<'a> {
             .take(5)
             .map(|ship| TargetIcon { ship: ship.index })
             .collect();
+        
+        // Spectators can start out in a sector with nothing to watch
+        let client_ship = context.ships.get(my_ship.to_usize()).and_then(|s| s.as_ref());
     
         SpaceGui {
-            plans: my_ship.get(context).create_plans(),
+            plans: client_ship.map_or_else(ShipPlans::new, |s| s.create_plans()),
+            client_ship_id: client_ship.map(|s| s.id),
             render_area: render_area,
             selection: None,
             beam_targeting_state: None,
@@ -168,15 +191,27 @@ impl<'a> SpaceGui<'a> {
             chat_gui: chat_gui,
             
             logout_button: SpriteButton::new("content/textures/gui/logout.png", 3, 1, [16.0, 14.0]),
+            ready_pressed: false,
             
             target_icons: target_icons,
+            
+            pings: HashMap::new(),
+            
+            spectating: false,
+            
+            match_status: None,
         }
     }
     
+    pub fn set_spectating(&mut self) {
+        self.spectating = true;
+        self.nav_map_gui.set_free_camera(Vec2::new(0.0, 0.0));
+    }
+    
     pub fn event<E: GenericEvent>(&mut self, bc: &mut BattleContext, e: &E, client_ship: ShipIndex, time: f64) -> Option<SpaceGuiAction> {
         use piston::event_loop::*;
         
-        if client_ship.get(bc).state.get_hp() == 0 {
+        if !self.spectating && client_ship.get(bc).state.get_hp() == 0 {
             return None;
         }
     
@@ -212,7 +247,10 @@ impl<'a> SpaceGui<'a> {
             if let Some(star_map_result) = self.star_map_gui.event(e, [self.mouse_pos.x - 200.0, self.mouse_pos.y - 200.0]) {
                 match star_map_result {
                     StarMapGuiAction::Jump(sector) => {
-                        self.plans.target_sector = Some(sector);
+                        // Spectators have no ship to jump with
+                        if !self.spectating {
+                            self.plans.target_sector = Some(sector);
+                        }
                         self.show_star_map = false;
                     },
                     StarMapGuiAction::Close => {
@@ -229,8 +267,31 @@ impl<'a> SpaceGui<'a> {
                     NavMapGuiAction::Close => {
                         self.show_nav_map = false;
                     },
+                    NavMapGuiAction::Follow(ship) => {
+                        return Some(SpaceGuiAction::Follow(ship));
+                    },
                 }
             }
+        } else if self.spectating {
+            let mut follow = None;
+            e.press(|button| {
+                match button {
+                    Button::Keyboard(keyboard::Key::Tab) => {
+                        if !self.chat_gui.has_focus() {
+                            follow = next_ship(bc, client_ship);
+                        }
+                    },
+                    Button::Mouse(mouse::MouseButton::Left) => {
+                        let (mouse_x, mouse_y) = (self.mouse_pos.x, self.mouse_pos.y);
+                        self.pick_target_icon(mouse_x, mouse_y);
+                    },
+                    _ => { },
+                }
+            });
+            
+            if let Some(ship) = follow {
+                return Some(SpaceGuiAction::Follow(ship));
+            }
         } else {
             e.press(|button| {
                 match button {
@@ -253,6 +314,11 @@ impl<'a> SpaceGui<'a> {
             return Some(SpaceGuiAction::Logout);
         }
         
+        if self.ready_pressed {
+            self.ready_pressed = false;
+            return Some(SpaceGuiAction::Ready);
+        }
+        
         None
     }
     
@@ -277,11 +343,13 @@ impl<'a> SpaceGui<'a> {
         self.draw_screen(bc, context, gl, glyph_cache, asset_store, sim_effects, client_ship, time, dt);
         
         // Draw plan timer bar
+        let turn_seconds = bc.turn_config.turn_seconds();
+        let send_plans_seconds = bc.turn_config.send_plans_seconds();
         let plan_timer =
-            if time < 2.5 {
-                (2.5 + time) / 5.0
+            if time < send_plans_seconds {
+                (turn_seconds - send_plans_seconds + time) / turn_seconds
             } else {
-                (time - 2.5) / 5.0
+                (time - send_plans_seconds) / turn_seconds
             };
         
         Rectangle::new([0.0, 0.0, 1.0, 0.5])
@@ -297,6 +365,26 @@ impl<'a> SpaceGui<'a> {
                     gl
                 );
         
+        self.draw_pings(bc, &context.trans(1100.0, 20.0), gl, glyph_cache);
+        
+        if self.spectating {
+            Text::new_color([1.0; 4], 14).draw(
+                format!("Spectating {} (Tab for the next ship)", client_ship.name).as_str(),
+                glyph_cache,
+                &context.draw_state, context.trans(20.0, 140.0).transform,
+                gl,
+            );
+        }
+        
+        if let Some(ref match_status) = self.match_status {
+            Text::new_color([1.0; 4], 14).draw(
+                match_status.as_str(),
+                glyph_cache,
+                &context.draw_state, context.trans(20.0, 160.0).transform,
+                gl,
+            );
+        }
+        
         self.chat_gui.draw(&context.trans(self.chat_gui_pos.x, self.chat_gui_pos.y), gl, glyph_cache);
         
         if self.show_star_map {
@@ -308,6 +396,28 @@ impl<'a> SpaceGui<'a> {
         }
     }
     
+    // List every player in the sector with their ping
+    fn draw_pings(&self, bc: &BattleContext, context: &Context, gl: &mut GlGraphics, glyph_cache: &mut GlyphCache) {
+        use graphics::*;
+        
+        let players = bc.ships_iter().filter_map(|ship| ship.client_id.map(|client_id| (client_id, ship)));
+        for (i, (client_id, ship)) in players.enumerate() {
+            let ping =
+                match self.pings.get(&client_id) {
+                    Some(ping) => format!("{} ms", ping),
+                    None => "? ms".to_string(),
+                };
+            
+            let context = context.trans(0.0, 14.0 * (i as f64 + 1.0));
+            Text::new_color([1.0; 4], 12).draw(
+                format!("{} {}", ship.name, ping).as_str(),
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
+        }
+    }
+    
     fn draw_screen(
         &mut self,
         bc: &BattleContext,
@@ -563,11 +673,21 @@ impl<'a> SpaceGui<'a> {
                 },
             }
             
-            icon.draw(bc, &context, gl, glyph_cache, asset_store, target_screen, i, highlight_color);
+            let team_color = if bc.alliances.are_allies(client_ship, icon.ship.get(bc)) { ALLY_COLOR } else { ENEMY_COLOR };
+            
+            icon.draw(bc, &context, gl, glyph_cache, asset_store, target_screen, i, highlight_color, team_color);
         }
     }
     
     fn on_key_pressed(&mut self, key: keyboard::Key) {
+        match key {
+            keyboard::Key::Space => {
+                if !self.chat_gui.has_focus() {
+                    self.ready_pressed = true;
+                }
+            },
+            _ => { },
+        }
     }
     
     fn on_mouse_left_pressed(&mut self, bc: &BattleContext, x: f64, y: f64, client_ship: &Ship) {
@@ -609,7 +729,7 @@ impl<'a> SpaceGui<'a> {
                     let y = y - self.render_area.y - ENEMY_OFFSET_Y;
                     
                     if let Some(ship) = self.render_area.ship {
-                        if !ship.get(bc).jumping && !ship.get(bc).exploding {
+                        if !ship.get(bc).jumping && !ship.get(bc).exploding && bc.alliances.can_target(client_ship, ship.get(bc)) {
                             let ref mut plans = self.plans;
                             
                             apply_to_module_if_point_inside(ship.get(bc), x, y, |ship_index, _, module| {
@@ -645,7 +765,7 @@ impl<'a> SpaceGui<'a> {
                     
                     if x >= 0.0 && y >= 0.0 {
                         if let Some(ship) = self.render_area.ship {
-                            if !ship.get(bc).jumping && !ship.get(bc).exploding {
+                            if !ship.get(bc).jumping && !ship.get(bc).exploding && bc.alliances.can_target(client_ship, ship.get(bc)) {
                                 if let Some(beam_start) = self.beam_targeting_state {
                                     let beam_end = calculate_beam_end(beam_start, Vec2 { x: x, y: y }, beam_length);
                                     self.plans.module_plans(selected_module).target =
@@ -669,7 +789,12 @@ impl<'a> SpaceGui<'a> {
         if clear_selection {
             self.selection = None;
         }
-
+        
+        self.pick_target_icon(x, y);
+    }
+    
+    // Show whichever ship's target icon is at (x, y)
+    fn pick_target_icon(&mut self, x: f64, y: f64) {
         for (i, icon) in self.target_icons.iter().enumerate() {
             let i = i as f64;
             let icon_x = 715.0+(i*100.0);
@@ -746,8 +871,21 @@ impl<'a> SpaceGui<'a> {
     
     pub fn set_client_ship(&mut self, client_ship: &Ship) {
         self.plans = client_ship.create_plans();
+        self.client_ship_id = Some(client_ship.id);
+    }
+    
+    pub fn client_ship_id(&self) -> Option<ShipId> {
+        self.client_ship_id
     }
 
+    pub fn set_pings(&mut self, pings: Vec<(ClientId, u32)>) {
+        self.pings = pings.into_iter().collect();
+    }
+    
+    pub fn set_match_status(&mut self, match_status: Option<String>) {
+        self.match_status = match_status;
+    }
+    
     pub fn set_next_waypoint(&mut self) {
         self.plans.next_waypoint = self.nav_map_gui.get_next_waypoint();
     }
@@ -755,6 +893,13 @@ impl<'a> SpaceGui<'a> {
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
+// The ship after `ship`, in the order they're stored in, wrapping around to the first
+fn next_ship(bc: &BattleContext, ship: ShipIndex) -> Option<ShipIndex> {
+    bc.ships_iter().map(|s| s.index).find(|s| s.0 > ship.0)
+        .or_else(|| bc.ships_iter().map(|s| s.index).next())
+        .and_then(|s| if s != ship { Some(s) } else { None })
+}
+
 /// Applies function to a module in the ship if the mouse is over the module.
 /// Returns whether or not the function was applied.
 pub fn apply_to_module_if_point_inside<F>(ship: &Ship, x: f64, y: f64, mut f: F)
@@ -798,7 +943,8 @@ impl TargetIcon {
             asset_store: &AssetStore,
             target_screen: [f64; 4],
             i: f64,
-            highlight_color: Color) {
+            highlight_color: Color,
+            team_color: Color) {
         use graphics::*;
         
         let (target_screen_x1, target_screen_h1, target_screen_x2, target_screen_h2) =
@@ -830,9 +976,10 @@ impl TargetIcon {
         //let (half_icon_w, half_icon_h) = ((icon_w/2) as f64, (icon_h/2) as f64);
         //image(icon.deref(), context.trans(48.0 - half_icon_w, 34.0 - half_icon_h).transform, gl);
         
+        // Allies and enemies get tinted differently
         gl.tri_list_uv(
             &context.draw_state,
-            &[1.0; 4],
+            &team_color,
             icon.deref(),
             |f| f(
                 &squish_rect_tri_list_xy(context.transform, [icon_x + icon_offset_x,
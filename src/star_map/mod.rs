@@ -1,8 +1,10 @@
 #[cfg(feature = "client")]
 pub use self::star_map_gui::{StarMapGui, StarMapGuiAction};
-pub use self::star_map_server::{StarMapAction, StarMapServer};
+pub use self::remote_sector::run_remote_sector;
+pub use self::star_map_server::{StarMapAction, StarMapServer, StarMapSlot};
 
 #[cfg(feature = "client")]
 pub mod star_map_gui;
+pub mod remote_sector;
 pub mod star_map_server;
 pub mod station;
\ No newline at end of file
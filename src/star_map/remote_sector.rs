@@ -0,0 +1,359 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::Builder;
+use std::time::Duration;
+
+use battle_context::BattleContext;
+use chat::ChatMsg;
+use login::AccountBox;
+use module::ModelStore;
+use net::{FrameCodec, LinkSecret, ServerSlotId, authenticate_accepted_link, authenticate_connected_link, read_value, write_value};
+use replay::replay_path;
+use sector_data::{SectorId, SectorKind};
+use sector_server::{SectorSlot, SectorState};
+use super::star_map_server::{SectorLayout, StarMapAction, galaxy_layout};
+use super::station::{StationServer, StationSlot};
+
+// A sector can run in its own process. It connects to the star map, proves it knows the link
+// secret, says which sector it is, and the star map exports a slot to it. From then on the star map
+// talks to it over the slot link instead of channels.
+
+// How long a sector gets to register before the star map hangs up on it, and the other way around
+const REGISTRATION_TIMEOUT_SECONDS: u64 = 10;
+
+// What the star map tells a remote sector
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum SectorCommand {
+    NewAccount(AccountBox),
+    Chat(ChatMsg),
+}
+
+// What a remote sector tells the star map
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum SectorReport {
+    Ack, // Took the last account it was sent
+    Leaving(AccountBox, StarMapAction),
+    Chat(ChatMsg),
+}
+
+// Remote sectors coming and going, for the star map to deal with
+pub enum RemoteSectorEvent {
+    Registered(SectorId, TcpStream),
+    Lost(SectorId, ServerSlotId), // The link to the sector running in this slot dropped
+}
+
+// Accept remote sectors that know `secret` on `address` and pass their registrations on to the
+// star map. Returns the address it ended up listening on.
+pub fn listen_for_sectors(address: &str, secret: Arc<LinkSecret>, events: Sender<RemoteSectorEvent>) -> io::Result<SocketAddr> {
+    let listener = try!(TcpListener::bind(address));
+    let local_address = try!(listener.local_addr());
+    println!("Listening for sectors on {}", local_address);
+
+    try!(Builder::new().name("sector_listener".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream =
+                match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("Failed to accept sector: {}", e);
+                        continue;
+                    },
+                };
+
+            // Don't let a slow sector hold up the others
+            let events = events.clone();
+            let secret = secret.clone();
+            Builder::new().name("sector_registration".to_string()).spawn(move || {
+                match accept_registration(&mut stream, &secret) {
+                    Ok(sector_id) => { events.send(RemoteSectorEvent::Registered(sector_id, stream)); },
+                    Err(e) => println!("Sector failed to register: {}", e),
+                }
+            });
+        }
+    }));
+
+    Ok(local_address)
+}
+
+// Star map side of registering. Nothing the sector says is read until it's proven itself.
+fn accept_registration(stream: &mut TcpStream, secret: &LinkSecret) -> io::Result<SectorId> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(REGISTRATION_TIMEOUT_SECONDS))));
+    try!(authenticate_accepted_link(stream, secret));
+    let sector_id = try!(read_value(stream, &FrameCodec::new()));
+    try!(stream.set_read_timeout(None));
+    Ok(sector_id)
+}
+
+// Remote sector side of registering. Returns the link, ready to import the sector's slot, and the
+// star map's slot.
+pub fn register_with_star_map(address: &str, sector_id: SectorId, secret: &LinkSecret) -> io::Result<(TcpStream, ServerSlotId)> {
+    let codec = FrameCodec::new();
+    let mut stream = try!(TcpStream::connect(address));
+    try!(stream.set_read_timeout(Some(Duration::from_secs(REGISTRATION_TIMEOUT_SECONDS))));
+    try!(authenticate_connected_link(&mut stream, secret));
+    try!(write_value(&mut stream, &codec, &sector_id));
+    let star_map_slot_id =
+        match try!(read_value::<_, Option<ServerSlotId>>(&mut stream, &codec)) {
+            Some(slot_id) => slot_id,
+            None => return Err(Error::new(ErrorKind::Other, format!("The star map doesn't want sector {}", sector_id.0))),
+        };
+    try!(stream.set_read_timeout(None));
+    Ok((stream, star_map_slot_id))
+}
+
+// Move messages from one channel to another until either end goes away
+fn relay<T, U, F>(name: String, from: Receiver<T>, to: Sender<U>, wrap: F)
+    where T: Send + 'static,
+          U: Send + 'static,
+          F: Fn(T) -> U + Send + 'static
+{
+    Builder::new().name(name).spawn(move || {
+        while let Ok(msg) = from.recv() {
+            if to.send(wrap(msg)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// Star map side of a remote sector. Stands in for the sector's thread, so the star map can treat it
+// like any other sector.
+pub fn host_remote_sector(sector_id: SectorId,
+                          slot_id: ServerSlotId,
+                          to_remote: Sender<SectorCommand>,
+                          from_remote: Receiver<SectorReport>,
+                          from_map_receiver: Receiver<AccountBox>,
+                          chat_receiver: Receiver<ChatMsg>,
+                          ack: Sender<()>,
+                          to_map_sender: Sender<(AccountBox, StarMapAction)>,
+                          chat_sender: Sender<ChatMsg>,
+                          events: Sender<RemoteSectorEvent>) {
+    relay(format!("sector_{}_accounts", sector_id.0), from_map_receiver, to_remote.clone(), SectorCommand::NewAccount);
+    relay(format!("sector_{}_chat", sector_id.0), chat_receiver, to_remote, SectorCommand::Chat);
+
+    Builder::new().name(format!("sector_{}_reports", sector_id.0)).spawn(move || {
+        while let Ok(report) = from_remote.recv() {
+            match report {
+                SectorReport::Ack => { ack.send(()); },
+                SectorReport::Leaving(account, action) => { to_map_sender.send((account, action)); },
+                SectorReport::Chat(msg) => { chat_sender.send(msg); },
+            }
+        }
+
+        // Dropping `ack` here wakes the star map up if it's waiting on the sector
+        events.send(RemoteSectorEvent::Lost(sector_id, slot_id));
+    });
+}
+
+// Run sector `sector_id` in this process for the star map at `address`, which has to know `secret`
+// too, recording its battle to `record_dir` if there is one. Returns when the link to the star map
+// drops.
+pub fn run_remote_sector(address: &str,
+                         sector_id: SectorId,
+                         secret: &LinkSecret,
+                         model_store: Arc<ModelStore>,
+                         record_dir: Option<&str>) -> io::Result<()> {
+    let SectorLayout { data, ai_ships, sensor_range, turn_config, player_respawn, npc_respawn, battle_type, alliances } =
+        match galaxy_layout().into_iter().find(|layout| layout.data.id == sector_id) {
+            Some(layout) => layout,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("There's no sector {}", sector_id.0))),
+        };
+
+    let codec = FrameCodec::new();
+    let (stream, star_map_slot_id) = try!(register_with_star_map(address, sector_id, secret));
+
+    println!("Running sector {} for the star map at {}", sector_id.0, address);
+
+    let (account_sender, from_map_receiver) = channel();
+    let (chat_in_sender, chat_receiver) = channel();
+    let (chat_sender, chat_out_receiver) = channel();
+    let (to_map_sender, to_map_receiver) = channel();
+    let (ack_sender, ack_receiver) = channel();
+
+    match data.kind {
+        SectorKind::Station => {
+            let (slot, to_host, from_host) = try!(StationSlot::import(stream, codec));
+            relay_to_star_map(sector_id, to_host, from_host, account_sender, chat_in_sender, chat_out_receiver, to_map_receiver, ack_receiver);
+
+            let mut station_server = StationServer::new(slot,
+                                                        star_map_slot_id,
+                                                        chat_sender,
+                                                        chat_receiver,
+                                                        to_map_sender,
+                                                        from_map_receiver,
+                                                        model_store);
+            station_server.run(ack_sender);
+        },
+        SectorKind::Sector => {
+            let (slot, to_host, from_host) = try!(SectorSlot::import(stream, codec));
+            relay_to_star_map(sector_id, to_host, from_host, account_sender, chat_in_sender, chat_out_receiver, to_map_receiver, ack_receiver);
+
+            let mut sector_server = SectorState::new(slot,
+                                                     star_map_slot_id,
+                                                     chat_sender,
+                                                     chat_receiver,
+                                                     to_map_sender,
+                                                     from_map_receiver,
+                                                     BattleContext::new(ai_ships),
+                                                     model_store,
+                                                     false);
+            sector_server.set_sensor_range(sensor_range);
+            sector_server.set_turn_config(turn_config);
+            sector_server.set_alliances(alliances);
+            sector_server.set_respawn_policies(player_respawn, npc_respawn);
+            if let Some(battle_type) = battle_type {
+                sector_server.set_battle_type(battle_type);
+            }
+            if let Some(dir) = record_dir {
+                try!(sector_server.record_to(&replay_path(dir, sector_id), sector_id));
+            }
+            sector_server.run(ack_sender);
+        },
+    }
+
+    Ok(())
+}
+
+// Remote sector side of the link. Feeds the sector what the star map sends, and sends back what
+// the sector would have put on its channels to the star map.
+fn relay_to_star_map(sector_id: SectorId,
+                     to_host: Sender<SectorReport>,
+                     from_host: Receiver<SectorCommand>,
+                     account_sender: Sender<AccountBox>,
+                     chat_in_sender: Sender<ChatMsg>,
+                     chat_out_receiver: Receiver<ChatMsg>,
+                     to_map_receiver: Receiver<(AccountBox, StarMapAction)>,
+                     ack_receiver: Receiver<()>) {
+    Builder::new().name(format!("sector_{}_commands", sector_id.0)).spawn(move || {
+        while let Ok(command) = from_host.recv() {
+            match command {
+                SectorCommand::NewAccount(account) => { account_sender.send(account); },
+                SectorCommand::Chat(msg) => { chat_in_sender.send(msg); },
+            }
+        }
+    });
+
+    relay(format!("sector_{}_chat", sector_id.0), chat_out_receiver, to_host.clone(), SectorReport::Chat);
+    relay(format!("sector_{}_leaving", sector_id.0), to_map_receiver, to_host.clone(), |(account, action)| SectorReport::Leaving(account, action));
+    relay(format!("sector_{}_acks", sector_id.0), ack_receiver, to_host, |_| SectorReport::Ack);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::ErrorKind;
+    use std::net::{Shutdown, SocketAddr};
+    use std::process::{Child, Command};
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    use net::{FrameCodec, LinkSecret, Server, ServerSlot, ServerSlotId, write_value};
+    use sector_data::SectorId;
+    use super::{RemoteSectorEvent, SectorCommand, SectorReport, listen_for_sectors, register_with_star_map};
+
+    // How the tests tell the sector processes they start where the star map is
+    const STAR_MAP_VAR: &'static str = "REFORGE_TEST_STAR_MAP";
+    const SECRET_VAR: &'static str = "REFORGE_TEST_LINK_SECRET";
+
+    const SECRET: &'static str = "correct horse battery staple";
+    const STAR_MAP_SLOT_ID: ServerSlotId = 3;
+
+    // Run one of the ignored tests below in a process of its own, as a sector for the star map at
+    // `address`
+    fn start_sector_process(test: &str, address: SocketAddr, secret: &str) -> Child {
+        Command::new(env::current_exe().unwrap())
+            .arg(test)
+            .arg("--ignored")
+            .env(STAR_MAP_VAR, address.to_string())
+            .env(SECRET_VAR, secret)
+            .spawn()
+            .ok().expect("Failed to start sector process")
+    }
+
+    // The star map a sector process was started for, if one of the tests started it
+    fn star_map_from_env() -> Option<(String, LinkSecret)> {
+        match (env::var(STAR_MAP_VAR), env::var(SECRET_VAR)) {
+            (Ok(address), Ok(secret)) => Some((address, LinkSecret::new(&secret))),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn sector_runs_in_another_process() {
+        let mut server = Server::new();
+        let slot: ServerSlot<(), ()> = server.create_slot();
+
+        let (events_t, events_r) = channel();
+        let address = listen_for_sectors("127.0.0.1:0", Arc::new(LinkSecret::new(SECRET)), events_t).unwrap();
+        let mut sector = start_sector_process("remote_sector_process", address, SECRET);
+
+        let mut stream =
+            match events_r.recv().unwrap() {
+                RemoteSectorEvent::Registered(sector_id, stream) => {
+                    assert!(sector_id == SectorId(1));
+                    stream
+                },
+                RemoteSectorEvent::Lost(..) => panic!("Sector should have registered"),
+            };
+        write_value(&mut stream, &FrameCodec::new(), &Some(STAR_MAP_SLOT_ID)).unwrap();
+        let link = stream.try_clone().unwrap();
+        let (_to_remote, from_remote) = slot.export::<SectorCommand, SectorReport>(stream, FrameCodec::new()).unwrap();
+
+        match from_remote.recv().unwrap() {
+            SectorReport::Ack => { },
+            _ => panic!("Sector should have acked"),
+        }
+
+        // Hanging up lets the sector process finish
+        link.shutdown(Shutdown::Both).unwrap();
+        assert!(sector.wait().unwrap().success());
+    }
+
+    #[test]
+    #[ignore] // Run by sector_runs_in_another_process
+    fn remote_sector_process() {
+        let (address, secret) =
+            match star_map_from_env() {
+                Some(star_map) => star_map,
+                None => return,
+            };
+
+        let (stream, star_map_slot_id) = register_with_star_map(&address, SectorId(1), &secret).unwrap();
+        assert_eq!(star_map_slot_id, STAR_MAP_SLOT_ID);
+
+        let (_slot, to_host, from_host) = ServerSlot::<(), ()>::import::<SectorReport, SectorCommand>(stream, FrameCodec::new()).unwrap();
+        to_host.send(SectorReport::Ack).unwrap();
+
+        // Stay up until the star map hangs up, so the ack gets there
+        while from_host.recv().is_ok() { }
+    }
+
+    #[test]
+    fn star_map_turns_away_sector_without_secret() {
+        let (events_t, events_r) = channel();
+        let address = listen_for_sectors("127.0.0.1:0", Arc::new(LinkSecret::new(SECRET)), events_t).unwrap();
+
+        let mut sector = start_sector_process("unwelcome_sector_process", address, "a lucky guess");
+        assert!(sector.wait().unwrap().success());
+        assert!(events_r.try_recv().is_err());
+    }
+
+    #[test]
+    #[ignore] // Run by star_map_turns_away_sector_without_secret
+    fn unwelcome_sector_process() {
+        let (address, secret) =
+            match star_map_from_env() {
+                Some(star_map) => star_map,
+                None => return,
+            };
+
+        match register_with_star_map(&address, SectorId(1), &secret) {
+            Ok(_) => panic!("Star map should have turned the sector away"),
+            Err(e) => assert!(e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::TimedOut,
+                              "Star map should have hung up, not gone quiet"),
+        }
+    }
+}
@@ -1,279 +1,621 @@
 use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, TcpStream};
 use std::sync::Arc;
-use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::mpsc::{channel, Receiver, Select, Sender};
 use std::thread::Builder;
+use rand;
 use time;
 
 use battle_context::BattleContext;
-use chat::ChatServer;
+use battle_type::BattleType;
+use chat::{ChatMsg, ChatServer};
 use client_action::ClientAction;
 use login::AccountBox;
 use module::ModelStore;
 use net::{
-    OutPacket,
+    ClientId,
+    Encoded,
+    FrameCodec,
+    LinkSecret,
     ServerSlot,
     ServerSlotId,
-    SlotInMsg,
+    SlotEvent,
+    write_value,
 };
+use packet_types::ClientStarMapPacket;
+use replay::replay_path;
+use respawn_policy::RespawnPolicy;
 use sector_data::{SectorData, SectorId, SectorKind};
-use sector_server::SectorState;
+use sector_server::{DEFAULT_SENSOR_RANGE, SectorSlot, SectorState};
 use ship::{Ship, ShipId};
-use super::station::StationServer;
+use team::{Alliances, RAIDERS};
+use super::remote_sector;
+use super::remote_sector::RemoteSectorEvent;
+use super::station::{StationServer, StationSlot};
+use timer::Timer;
+use turn_config::TurnConfig;
 use vec::Vec2;
 
 // Reason a ship is leaving a sector
+#[derive(RustcEncodable, RustcDecodable)]
 pub enum StarMapAction {
     Jump(SectorId),
     Logout,
 }
 
+// Clients in the star map are between sectors and have nothing to say
+pub type StarMapSlot = ServerSlot<(), ClientStarMapPacket>;
+
+// How long to wait before trying again to send a ship to a sector that isn't running
+const SECTOR_RETRY_MS: i64 = 2000;
+
+// Something that woke the star map up
+enum StarMapEvent {
+    Slot(SlotEvent<()>),
+    Login(AccountBox),
+    FromSector(AccountBox, StarMapAction),
+    Remote(RemoteSectorEvent),
+    
+    // The next jumping ship may have arrived
+    JumpTimer,
+}
+
 pub struct Sector {
     pub slot_id: ServerSlotId,
     pub to_sector: Sender<AccountBox>,
-    pub from_sector: Receiver<(AccountBox, StarMapAction)>,
     pub ack: Receiver<()>,
     pub data: SectorData,
+    
+    // Copies of the accounts a remote sector has, so their players can be logged out if it goes
+    // away. Always empty for sectors running in this process.
+    pub remote: bool,
+    pub held_accounts: HashMap<ClientId, Encoded<AccountBox>>,
+}
+
+// How a sector in the galaxy is set up
+pub struct SectorLayout {
+    pub data: SectorData,
+    pub ai_ships: Vec<Option<Ship>>,
+    
+    // How far from a player's ship other ships show up for them
+    pub sensor_range: f64,
+    
+    pub turn_config: TurnConfig,
+    
+    // What happens to ships that blow up
+    pub player_respawn: RespawnPolicy,
+    pub npc_respawn: RespawnPolicy,
+    
+    // The match the sector runs, or None for an endless free for all
+    pub battle_type: Option<BattleType>,
+    
+    // Who's on whose side in the sector
+    pub alliances: Alliances,
+}
+
+// Every sector in the galaxy
+pub fn galaxy_layout() -> Vec<SectorLayout> {
+    vec![
+        SectorLayout {
+            data: SectorData {
+                id: SectorId(0),
+                kind: SectorKind::Station,
+                map_position: Vec2 { x: 100.0, y: 75.0 },
+            },
+            ai_ships: vec![],
+            sensor_range: DEFAULT_SENSOR_RANGE,
+            turn_config: TurnConfig::new(),
+            player_respawn: RespawnPolicy::for_players(),
+            npc_respawn: RespawnPolicy::for_npcs(),
+            battle_type: None,
+            alliances: Alliances::new(),
+        },
+        
+        SectorLayout {
+            data: SectorData {
+                id: SectorId(1),
+                kind: SectorKind::Sector,
+                map_position: Vec2 { x: 50.0, y: 50.0 },
+            },
+            ai_ships: vec![Some(Ship::generate_dummy((100000004) as ShipId, "test dummy".to_string()))],
+            sensor_range: DEFAULT_SENSOR_RANGE,
+            turn_config: TurnConfig::new(),
+            // Practice against the dummy without flying back from the station each time
+            player_respawn: RespawnPolicy::InPlace { turns: 3 },
+            npc_respawn: RespawnPolicy::for_npcs(),
+            battle_type: None,
+            alliances: Alliances::new(),
+        },
+        
+        SectorLayout {
+            data: SectorData {
+                id: SectorId(2),
+                kind: SectorKind::Sector,
+                map_position: Vec2 { x: 100.0, y: 100.0 },
+            },
+            ai_ships: vec![Some(Ship::generate((100000000) as ShipId, "n00bslayer808".to_string(), 2, &mut rand::thread_rng())),
+                           Some(Ship::generate((100000001) as ShipId, "thing1".to_string(), 2, &mut rand::thread_rng())),
+                           Some(Ship::generate((100000002) as ShipId, "thing2".to_string(), 2, &mut rand::thread_rng())),
+                           Some(Ship::generate((100000003) as ShipId, "daisy_girl".to_string(), 2, &mut rand::thread_rng()))],
+            sensor_range: DEFAULT_SENSOR_RANGE,
+            turn_config: TurnConfig::new(),
+            player_respawn: RespawnPolicy::for_players(),
+            npc_respawn: RespawnPolicy::for_npcs(),
+            battle_type: None,
+            alliances: Alliances::new(),
+        },
+        
+        // Two player duel arena
+        SectorLayout {
+            data: SectorData {
+                id: SectorId(3),
+                kind: SectorKind::Sector,
+                map_position: Vec2 { x: 150.0, y: 50.0 },
+            },
+            ai_ships: vec![],
+            sensor_range: DEFAULT_SENSOR_RANGE,
+            turn_config: TurnConfig::new(),
+            player_respawn: RespawnPolicy::for_players(),
+            npc_respawn: RespawnPolicy::for_npcs(),
+            battle_type: Some(BattleType::FreeForAll { num_players: 2, score_limit: None }),
+            alliances: Alliances::new(),
+        },
+        
+        SectorLayout {
+            data: SectorData {
+                id: SectorId(4),
+                kind: SectorKind::Sector,
+                map_position: Vec2 { x: 150.0, y: 100.0 },
+            },
+            ai_ships: vec![raider((100000005) as ShipId, "raider1", 3),
+                           raider((100000006) as ShipId, "raider2", 3)],
+            sensor_range: DEFAULT_SENSOR_RANGE,
+            turn_config: TurnConfig::new(),
+            player_respawn: RespawnPolicy::for_players(),
+            npc_respawn: RespawnPolicy::for_npcs(),
+            battle_type: Some(BattleType::Ai),
+            // The raiders work together, and don't hit each other
+            alliances: Alliances {
+                allied: vec![],
+                friendly_fire: false,
+            },
+        },
+    ]
+}
+
+// An NPC on the raiders' side
+fn raider(id: ShipId, name: &str, level: u8) -> Option<Ship> {
+    let mut ship = Ship::generate(id, name.to_string(), level, &mut rand::thread_rng());
+    ship.team = Some(RAIDERS);
+    Some(ship)
 }
 
 pub struct StarMapServer {
-    slot: ServerSlot,
+    slot: StarMapSlot,
+    model_store: Arc<ModelStore>,
     sectors: HashMap<SectorId, Sector>,
     
+    // Sectors run by other processes, whether or not they're up right now
+    remote_sectors: HashMap<SectorId, SectorData>,
+    
+    // Ships leaving any of the sectors
+    from_sectors: Receiver<(AccountBox, StarMapAction)>,
+    from_sector_sender: Sender<(AccountBox, StarMapAction)>,
+    
+    // Chat server input channel, and where sectors sign up to hear chat
+    to_chat_server: Sender<ChatMsg>,
+    chat_listeners: Sender<Sender<ChatMsg>>,
+    
+    remote_events: Receiver<RemoteSectorEvent>,
+    remote_event_sender: Sender<RemoteSectorEvent>,
+    
     jumping_accounts: VecDeque<(AccountBox, SectorId, time::Timespec)>,
+    
+    // Where sectors record their battles, if they do
+    record_dir: Option<String>,
 }
 
 impl StarMapServer {
-    pub fn new(model_store: Arc<ModelStore>, slot: ServerSlot) -> StarMapServer {
-        // Chat server input channel
+    // Sectors in `remote_sectors` aren't started here; they're expected to register with
+    // `listen_for_sectors`
+    pub fn new(model_store: Arc<ModelStore>, slot: StarMapSlot, remote_sectors: Vec<SectorId>, record_dir: Option<String>) -> StarMapServer {
         let (to_chat_server, chat_from_sector) = channel();
-        let mut chat_msg_senders = vec!();
+        let (chat_listeners, new_chat_listeners) = channel();
         
-        // Fire up the universe
-
-        let slot_id = slot.get_id();
-        let mut sectors = HashMap::new();
+        // Every sector reports ships leaving it on the same channel
+        let (from_sector_sender, from_sectors) = channel();
         
-        // Station
-        let (to_sector_sender, to_sector_receiver) = channel();
-        let (from_sector_sender, from_sector_receiver) = channel();
-        let (ack_sender, ack_receiver) = channel();
-        let (chat_sender, sector_chat_in) = channel();
-        chat_msg_senders.push(chat_sender);
-        let sector_slot = slot.create_slot();
-        let sector_id = SectorId(0);
-        let sector_chat_out = to_chat_server.clone();
-        let sector_model_store = model_store.clone();
-        sectors.insert(sector_id, Sector {
-            slot_id: sector_slot.get_id(),
-            to_sector: to_sector_sender,
-            from_sector: from_sector_receiver,
-            ack: ack_receiver,
-            data: SectorData {
-                id: sector_id,
-                kind: SectorKind::Station,
-                map_position: Vec2 { x: 100.0, y: 75.0 },
-            },
-        });
+        let (remote_event_sender, remote_events) = channel();
+        
+        let mut star_map = StarMapServer {
+            slot: slot,
+            model_store: model_store,
+            sectors: HashMap::new(),
+            remote_sectors: HashMap::new(),
+            from_sectors: from_sectors,
+            from_sector_sender: from_sector_sender,
+            to_chat_server: to_chat_server,
+            chat_listeners: chat_listeners,
+            remote_events: remote_events,
+            remote_event_sender: remote_event_sender,
+            jumping_accounts: VecDeque::new(),
+            record_dir: record_dir,
+        };
         
+        // Fire up the universe
+        for layout in galaxy_layout() {
+            if remote_sectors.contains(&layout.data.id) {
+                println!("Waiting for sector {} to register", layout.data.id.0);
+                star_map.remote_sectors.insert(layout.data.id, layout.data);
+            } else {
+                star_map.start_sector(layout);
+            }
+        }
         
+        // Start the chat server
         Builder::new()
-            .name(format!("station_{}_thread", 0))
+            .name("chat_server".to_string())
             .spawn(move || {
-                let mut sector_server = StationServer::new(sector_slot,
-                                                           slot_id,
-                                                           sector_chat_out,
-                                                           sector_chat_in,
-                                                           from_sector_sender,
-                                                           to_sector_receiver,
-                                                           sector_model_store);
-                sector_server.run(ack_sender);
+                let mut chat_server = ChatServer::new(chat_from_sector, new_chat_listeners);
+                chat_server.run();
             });
         
-        // Sector 1
+        star_map
+    }
+    
+    // Let remote sectors that know `secret` register on `address`. Returns the address it ended up
+    // listening on.
+    pub fn listen_for_sectors(&self, address: &str, secret: LinkSecret) -> io::Result<SocketAddr> {
+        remote_sector::listen_for_sectors(address, Arc::new(secret), self.remote_event_sender.clone())
+    }
+    
+    // Run a sector in this process
+    fn start_sector(&mut self, layout: SectorLayout) {
+        let SectorLayout { data, ai_ships, sensor_range, turn_config, player_respawn, npc_respawn, battle_type, alliances } = layout;
+        
         let (to_sector_sender, to_sector_receiver) = channel();
-        let (from_sector_sender, from_sector_receiver) = channel();
         let (ack_sender, ack_receiver) = channel();
         let (chat_sender, sector_chat_in) = channel();
-        chat_msg_senders.push(chat_sender);
-        let sector_slot = slot.create_slot();
-        let sector_id = SectorId(1);
-        let sector_chat_out = to_chat_server.clone();
-        let sector_model_store = model_store.clone();
-        let ai_ships = vec![Some(Ship::generate_dummy((100000004) as ShipId, "test dummy".to_string()))];
-        //let ai_ships = vec![];
-
-        sectors.insert(sector_id, Sector {
-            slot_id: sector_slot.get_id(),
-            to_sector: to_sector_sender,
-            from_sector: from_sector_receiver,
-            ack: ack_receiver,
-            data: SectorData {
-                id: sector_id,
-                kind: SectorKind::Sector,
-                map_position: Vec2 { x: 50.0, y: 50.0 },
+        self.chat_listeners.send(chat_sender);
+        let slot_id = self.slot.get_id();
+        let sector_chat_out = self.to_chat_server.clone();
+        let sector_from_sector = self.from_sector_sender.clone();
+        let sector_model_store = self.model_store.clone();
+        let replay_path = self.record_dir.as_ref().map(|dir| replay_path(dir, data.id));
+        
+        match data.kind {
+            SectorKind::Station => {
+                let sector_slot: StationSlot = self.slot.create_slot();
+                self.add_sector(data, sector_slot.get_id(), to_sector_sender, ack_receiver, false);
+                
+                Builder::new()
+                    .name(format!("station_{}_thread", data.id.0))
+                    .spawn(move || {
+                        let mut sector_server = StationServer::new(sector_slot,
+                                                                   slot_id,
+                                                                   sector_chat_out,
+                                                                   sector_chat_in,
+                                                                   sector_from_sector,
+                                                                   to_sector_receiver,
+                                                                   sector_model_store);
+                        sector_server.run(ack_sender);
+                    });
+            },
+            SectorKind::Sector => {
+                let sector_slot: SectorSlot = self.slot.create_slot();
+                self.add_sector(data, sector_slot.get_id(), to_sector_sender, ack_receiver, false);
+                
+                Builder::new()
+                    .name(format!("sector_{}_thread", data.id.0))
+                    .spawn(move || {
+                        let mut sector_server = SectorState::new(sector_slot,
+                                                                 slot_id,
+                                                                 sector_chat_out,
+                                                                 sector_chat_in,
+                                                                 sector_from_sector,
+                                                                 to_sector_receiver,
+                                                                 BattleContext::new(ai_ships),
+                                                                 sector_model_store,
+                                                                 false);
+                        sector_server.set_sensor_range(sensor_range);
+                        sector_server.set_turn_config(turn_config);
+                        sector_server.set_alliances(alliances);
+                        sector_server.set_respawn_policies(player_respawn, npc_respawn);
+                        if let Some(battle_type) = battle_type {
+                            sector_server.set_battle_type(battle_type);
+                        }
+                        if let Some(path) = replay_path {
+                            if let Err(e) = sector_server.record_to(&path, data.id) {
+                                println!("Failed to start recording sector {} to {}: {}", data.id.0, path, e);
+                            }
+                        }
+                        sector_server.run(ack_sender);
+                    });
             },
+        }
+    }
+    
+    fn add_sector(&mut self, data: SectorData, slot_id: ServerSlotId, to_sector: Sender<AccountBox>, ack: Receiver<()>, remote: bool) {
+        self.sectors.insert(data.id, Sector {
+            slot_id: slot_id,
+            to_sector: to_sector,
+            ack: ack,
+            data: data,
+            remote: remote,
+            held_accounts: HashMap::new(),
         });
+    }
+    
+    // Hand a slot to a sector that's connected from another process
+    fn register_remote_sector(&mut self, sector_id: SectorId, mut stream: TcpStream, logout_sender: &Sender<AccountBox>) {
+        let codec = FrameCodec::new();
         
-        Builder::new()
-            .name(format!("sector_{}_thread", 1))
-            .spawn(move || {
-                let mut sector_server = SectorState::new(sector_slot,
-                                                         slot_id,
-                                                         sector_chat_out,
-                                                         sector_chat_in,
-                                                         from_sector_sender,
-                                                         to_sector_receiver,
-                                                         BattleContext::new(ai_ships),
-                                                         sector_model_store,
-                                                         false);
-                sector_server.run(ack_sender);
-            });
+        let data =
+            match self.remote_sectors.get(&sector_id) {
+                Some(data) => data.clone(),
+                None => {
+                    println!("Turned down sector {}, it isn't meant to be remote", sector_id.0);
+                    let _ = write_value(&mut stream, &codec, &None::<ServerSlotId>);
+                    return;
+                },
+            };
+        
+        // A sector that restarted can beat the news that its old link dropped
+        if self.sectors.contains_key(&sector_id) {
+            self.lose_sector(sector_id, logout_sender);
+        }
+        
+        if let Err(e) = write_value(&mut stream, &codec, &Some(self.slot.get_id())) {
+            println!("Failed to register sector {}: {}", sector_id.0, e);
+            return;
+        }
+        
+        // The sector decides what its clients and it say to each other
+        let sector_slot: ServerSlot<(), ()> = self.slot.create_slot();
+        let slot_id = sector_slot.get_id();
+        let (to_remote, from_remote) =
+            match sector_slot.export(stream, codec) {
+                Ok(link) => link,
+                Err(e) => {
+                    println!("Failed to link up sector {}: {}", sector_id.0, e);
+                    return;
+                },
+            };
         
-        // Sector 2
         let (to_sector_sender, to_sector_receiver) = channel();
-        let (from_sector_sender, from_sector_receiver) = channel();
         let (ack_sender, ack_receiver) = channel();
         let (chat_sender, sector_chat_in) = channel();
-        chat_msg_senders.push(chat_sender);
-        let sector_slot = slot.create_slot();
-        let sector_id = SectorId(2);
-        let sector_chat_out = to_chat_server.clone();
-        let sector_model_store = model_store.clone();
-        let ai_ships = vec![Some(Ship::generate((100000000) as ShipId, "n00bslayer808".to_string(), 2)),
-                            Some(Ship::generate((100000001) as ShipId, "thing1".to_string(), 2)),
-                            Some(Ship::generate((100000002) as ShipId, "thing2".to_string(), 2)),
-                            Some(Ship::generate((100000003) as ShipId, "daisy_girl".to_string(), 2))];
-        sectors.insert(sector_id, Sector {
-            slot_id: sector_slot.get_id(),
-            to_sector: to_sector_sender,
-            from_sector: from_sector_receiver,
-            ack: ack_receiver,
-            data: SectorData {
-                id: sector_id,
-                kind: SectorKind::Sector,
-                map_position: Vec2 { x: 100.0, y: 100.0 },
-            },
-        });
+        self.chat_listeners.send(chat_sender);
+        remote_sector::host_remote_sector(sector_id,
+                                          slot_id,
+                                          to_remote,
+                                          from_remote,
+                                          to_sector_receiver,
+                                          sector_chat_in,
+                                          ack_sender,
+                                          self.from_sector_sender.clone(),
+                                          self.to_chat_server.clone(),
+                                          self.remote_event_sender.clone());
         
-        Builder::new()
-            .name(format!("sector_{}_thread", 2))
-            .spawn(move || {
-                let mut sector_server = SectorState::new(sector_slot,
-                                                         slot_id,
-                                                         sector_chat_out,
-                                                         sector_chat_in,
-                                                         from_sector_sender,
-                                                         to_sector_receiver,
-                                                         BattleContext::new(ai_ships),
-                                                         sector_model_store,
-                                                         false);
-                sector_server.run(ack_sender);
-            });
+        self.add_sector(data, slot_id, to_sector_sender, ack_receiver, true);
+        println!("Sector {} registered in slot {}", sector_id.0, slot_id);
+    }
+    
+    // Forget a remote sector whose link dropped. Its players' ships went down with it, so they're
+    // logged out with their accounts as they were when they went in.
+    fn lose_sector(&mut self, sector_id: SectorId, logout_sender: &Sender<AccountBox>) {
+        let sector =
+            match self.sectors.remove(&sector_id) {
+                Some(sector) => sector,
+                None => return,
+            };
         
-        // Start the chat server
-        Builder::new()
-            .name("chat_server".to_string())
-            .spawn(move || {
-                let mut chat_server = ChatServer::new(chat_from_sector, chat_msg_senders);
-                chat_server.run();
-            });
+        self.slot.destroy_slot(sector.slot_id);
         
-        ////////////////////////////////////////////////////////////////////////////////////////////
+        println!("Lost sector {}, logging out its {} players", sector_id.0, sector.held_accounts.len());
+        for (client_id, account) in sector.held_accounts {
+            match account.decode() {
+                Ok(account) => {
+                    self.slot.send(client_id, &ClientStarMapPacket::Action(ClientAction::Logout));
+                    logout_sender.send(account);
+                },
+                Err(e) => println!("Failed to decode account of client {}: {}", client_id, e),
+            }
+        }
+    }
+    
+    // Send an account to the sector it's in, or the one it's spectating, and move its client
+    // there. Gives the account back if the sector isn't running right now.
+    fn enter_sector(&mut self, mut account: AccountBox) -> Result<(), AccountBox> {
+        let client_id = account.client_id.expect("This needs to have a client ID");
         
-        StarMapServer {
-            slot: slot,
-            sectors: sectors,
-            jumping_accounts: VecDeque::new(),
+        // There's no battle to watch at a station, so spectators go back to their ship instead
+        if let Some(spectating) = account.spectating {
+            if self.sectors.get(&spectating).map_or(false, |s| s.data.kind == SectorKind::Station) {
+                println!("Client {} can't spectate station {}, sending them to their ship", client_id, spectating.0);
+                account.spectating = None;
+            }
         }
+        
+        let sector =
+            match self.sectors.get_mut(&account.current_sector()) {
+                Some(sector) => sector,
+                None => return Err(account),
+            };
+        
+        let client_action =
+            match sector.data.kind {
+                SectorKind::Sector if account.spectating.is_some() => ClientAction::SpectateSector,
+                SectorKind::Sector => ClientAction::JoinSector,
+                SectorKind::Station => ClientAction::JoinStation,
+            };
+        
+        self.slot.send(client_id, &ClientStarMapPacket::Action(client_action));
+        
+        if sector.remote {
+            sector.held_accounts.insert(client_id, Encoded::new(&account));
+        }
+        
+        sector.to_sector.send(account);
+        if sector.ack.recv().is_err() {
+            // The sector went away while taking the account, which gets logged out along with the
+            // sector's other players
+            return Ok(());
+        }
+        self.slot.transfer_client(client_id, sector.slot_id);
+        
+        Ok(())
+    }
+    
+    // Keep the jumping accounts in order of arrival time
+    fn queue_jump(&mut self, account: AccountBox, target_sector: SectorId, jump_time: time::Timespec) {
+        let index = self.jumping_accounts.iter().position(|&(_, _, t)| t > jump_time).unwrap_or(self.jumping_accounts.len());
+        self.jumping_accounts.insert(index, (account, target_sector, jump_time));
     }
     
     pub fn run(&mut self, from_login: Receiver<AccountBox>, logout_sender: Sender<AccountBox>) {
+        // Wakes the star map up when the next jumping ship is due to arrive
+        let mut jump_timer = Timer::never();
+        let mut jump_deadline: Option<time::Timespec> = None;
+        
         loop {
-            if let Ok(slot_msg) = self.slot.try_receive() {
-                match slot_msg {
-                    SlotInMsg::Joined(client_id) => {
-                        println!("Client {} joined the star map", client_id);
-                    },
-                    SlotInMsg::ReceivedPacket(client_id, mut packet) => {
-                    },
-                    _ => {},
-                }
-            }
-            
-            if let Ok(account) = from_login.try_recv() {
-                let client_id = account.client_id.expect("This needs to have a client ID");
-                
-                let sector_data: Vec<SectorData> = self.sectors.iter().map(|(_, s)| s.data.clone()).collect();
-                
-                let ref sector = self.sectors[&account.sector];
-            
-                let mut sectors_packet = OutPacket::new();
-                sectors_packet.write(&sector_data).unwrap();
-                self.slot.send(client_id, sectors_packet);
-                
-                ////////////////////////////////////////////////////////////////////////////////////
-                
-                let client_action =
-                    match sector.data.kind {
-                        SectorKind::Sector => ClientAction::JoinSector,
-                        SectorKind::Station => ClientAction::JoinStation,
-                    };
-                
-                let mut action_packet = OutPacket::new();
-                action_packet.write(&client_action).unwrap();
-                self.slot.send(client_id, action_packet);
+            // Sleep until something happens
+            match self.next_event(&from_login, &jump_timer) {
+                StarMapEvent::Slot(slot_msg) => {
+                    match slot_msg {
+                        SlotEvent::Joined(client_id) => {
+                            println!("Client {} joined the star map", client_id);
+                        },
+                        SlotEvent::Malformed(client_id, e) => {
+                            println!("Client {} sent the star map a packet it didn't expect: {}", client_id, e);
+                        },
+                        SlotEvent::Shutdown => {
+                            println!("Star map shutting down");
+                            return;
+                        },
+                        _ => {},
+                    }
+                },
+                StarMapEvent::Login(account) => {
+                    let client_id = account.client_id.expect("This needs to have a client ID");
+                    
+                    // Remote sectors that are down still belong on the map
+                    let sector_data: Vec<SectorData> =
+                        self.sectors.iter().map(|(_, s)| s.data.clone())
+                            .chain(self.remote_sectors.iter().filter(|&(id, _)| !self.sectors.contains_key(id)).map(|(_, d)| d.clone()))
+                            .collect();
+                    self.slot.send(client_id, &ClientStarMapPacket::Sectors(sector_data));
+                    
+                    // Goes through the jump queue so a sector that's down gets retried
+                    let sector_id = account.current_sector();
+                    self.queue_jump(account, sector_id, time::now().to_timespec());
+                },
                 
-                sector.to_sector.send(account);
-                sector.ack.recv();
-                self.slot.transfer_client(client_id, sector.slot_id);
-            }
-            
-            // Send any jumping ships to their new sector
-            for sector in self.sectors.values() {
-                if let Ok((account, exit_action)) = sector.from_sector.try_recv() {
+                // Send any jumping ships to their new sector
+                StarMapEvent::FromSector(account, exit_action) => {
+                    let client_id = account.client_id.expect("This needs to have a client ID");
+                    
+                    if let Some(sector) = self.sectors.get_mut(&account.current_sector()) {
+                        sector.held_accounts.remove(&client_id);
+                    }
+                    
                     match exit_action {
                         StarMapAction::Jump(sector) => {
-                            self.jumping_accounts.push_back((account, sector, time::now().to_timespec() + time::Duration::milliseconds(6000)));
+                            self.queue_jump(account, sector, time::now().to_timespec() + time::Duration::milliseconds(6000));
                         },
-                        StarMapAction::Logout => {    
-                            let client_id = account.client_id.expect("This needs to have a client ID");
-                        
-                            let mut action_packet = OutPacket::new();
-                            action_packet.write(&ClientAction::Logout).unwrap();
-                            self.slot.send(client_id, action_packet);
+                        StarMapAction::Logout => {
+                            self.slot.send(client_id, &ClientStarMapPacket::Action(ClientAction::Logout));
                         
                             logout_sender.send(account);
                         },
                     }
-                }
+                },
+                
+                StarMapEvent::Remote(event) => {
+                    match event {
+                        RemoteSectorEvent::Registered(sector_id, stream) => {
+                            self.register_remote_sector(sector_id, stream, &logout_sender);
+                        },
+                        RemoteSectorEvent::Lost(sector_id, slot_id) => {
+                            // Ignore news about a link that's already been replaced
+                            let current = self.sectors.get(&sector_id).map_or(false, |s| s.slot_id == slot_id);
+                            if current {
+                                self.lose_sector(sector_id, &logout_sender);
+                            }
+                        },
+                    }
+                },
+                
+                StarMapEvent::JumpTimer => {
+                    // A fired timer is spent, so make sure a new one gets set
+                    jump_deadline = None;
+                },
             }
             
+            let now = time::now().to_timespec();
             while let Some((mut account, target_sector, jump_time)) = self.jumping_accounts.pop_front() {
-                if (time::now().to_timespec() - jump_time).num_milliseconds() < 0 {
+                if (now - jump_time).num_milliseconds() < 0 {
                     self.jumping_accounts.push_front((account, target_sector, jump_time));
                     break;
                 } else {
-                    let client_id = account.client_id.expect("This needs to have a client ID");
-                    
-                    account.sector = target_sector;
-                    
-                    let ref sector = self.sectors[&target_sector];
-                    
-                    let client_action =
-                        match sector.data.kind {
-                            SectorKind::Sector => ClientAction::JoinSector,
-                            SectorKind::Station => ClientAction::JoinStation,
-                        };
-                
-                    let mut action_packet = OutPacket::new();
-                    action_packet.write(&client_action).unwrap();
-                    self.slot.send(client_id, action_packet);
+                    // Spectators' ships stay where they are
+                    match account.spectating {
+                        Some(_) => { account.spectating = Some(target_sector); },
+                        None => { account.sector = target_sector; },
+                    }
                     
-                    sector.to_sector.send(account);
-                    sector.ack.recv();
-                    self.slot.transfer_client(client_id, sector.slot_id);
+                    if let Err(account) = self.enter_sector(account) {
+                        // A spectator turned away from a station is headed for their ship now
+                        let target_sector = account.current_sector();
+                        println!("Sector {} isn't running, trying again shortly", target_sector.0);
+                        self.queue_jump(account, target_sector, now + time::Duration::milliseconds(SECTOR_RETRY_MS));
+                    }
                 }
             }
+            
+            // Wake up again when the next jumping ship arrives
+            let next_deadline = self.jumping_accounts.front().map(|&(_, _, jump_time)| jump_time);
+            if next_deadline != jump_deadline {
+                jump_timer =
+                    match next_deadline {
+                        Some(deadline) => Timer::at(deadline),
+                        None => Timer::never(),
+                    };
+                jump_deadline = next_deadline;
+            }
+        }
+    }
+    
+    // Block until a message arrives on any of the star map's channels, or the jump timer fires
+    fn next_event(&self, from_login: &Receiver<AccountBox>, jump_timer: &Timer) -> StarMapEvent {
+        let select = Select::new();
+        let mut slot_h = select.handle(self.slot.receiver());
+        let mut login_h = select.handle(from_login);
+        let mut from_sectors_h = select.handle(&self.from_sectors);
+        let mut remote_h = select.handle(&self.remote_events);
+        let mut jump_timer_h = select.handle(jump_timer.receiver());
+        unsafe {
+            slot_h.add();
+            login_h.add();
+            from_sectors_h.add();
+            remote_h.add();
+            jump_timer_h.add();
+        }
+        
+        let ready_id = select.wait();
+        if ready_id == slot_h.id() {
+            StarMapEvent::Slot(self.slot.decode(slot_h.recv().ok().expect("Failed to receive SlotInMsg")))
+        } else if ready_id == login_h.id() {
+            StarMapEvent::Login(login_h.recv().ok().expect("Star map login channel is broken"))
+        } else if ready_id == from_sectors_h.id() {
+            let (account, exit_action) = from_sectors_h.recv().ok().expect("Star map sector channel is broken");
+            StarMapEvent::FromSector(account, exit_action)
+        } else if ready_id == remote_h.id() {
+            StarMapEvent::Remote(remote_h.recv().ok().expect("Star map remote sector channel is broken"))
+        } else {
+            let _ = jump_timer_h.recv();
+            StarMapEvent::JumpTimer
         }
     }
 }
@@ -4,7 +4,7 @@ pub use self::station_action::StationAction;
 pub use self::station_client::StationClient;
 #[cfg(feature = "client")]
 pub use self::station_gui::StationGui;
-pub use self::station_server::StationServer;
+pub use self::station_server::{StationServer, StationSlot};
 
 pub mod ship_edit_action;
 #[cfg(feature = "client")]
@@ -10,7 +10,8 @@ use glutin_window::GlutinWindow;
 use asset_store::AssetStore;
 use chat::ChatGui;
 use module::{ModelIndex, ModelStore, ModuleStored};
-use net::{Client, OutPacket};
+use net::{Client, NetError};
+use packet_types::ClientStationPacket;
 use sector_data::SectorData;
 use ship::ShipStored;
 use sim::SimEffects;
@@ -39,7 +40,7 @@ impl<'a> StationClient<'a> {
                asset_store: &AssetStore,
                model_store: &ModelStore,
                chat_gui: &mut ChatGui,
-               sectors: Vec<SectorData>) {     
+               sectors: Vec<SectorData>) -> Result<(), NetError> {
         let module_inventory =
             vec![
                 ("engine".to_string(), vec![(ModelIndex(0), 100)]),
@@ -95,20 +96,23 @@ impl<'a> StationClient<'a> {
                 });
             });
             
-            if let Ok(mut packet) = self.client.try_receive() {
-                let chat_msg = packet.read().unwrap();
-                gui.chat_gui.add_message(chat_msg);
+            match try!(self.client.try_receive_message()) {
+                Some(ClientStationPacket::Chat(chat_msg)) => {
+                    gui.chat_gui.add_message(chat_msg);
+                },
+                Some(ClientStationPacket::Join(_)) => {
+                    println!("Got a station join packet while already at the station");
+                },
+                None => {},
             }
             
             // Handle GUI action
             if let Some(gui_action) = gui_action {
-                let mut packet = OutPacket::new();
-                packet.write(&gui_action);
-                self.client.send(&packet);
+                try!(self.client.send_message(&gui_action));
                 
                 match gui_action {
                     StationAction::Jump(_) => {
-                        return;
+                        return Ok(());
                     },
                     StationAction::ShipEdit(ship_edit) => {
                         if let Some(ref mut ship) = self.player_ship {
@@ -130,10 +134,12 @@ impl<'a> StationClient<'a> {
                     },
                     StationAction::Chat(_) => { },
                     StationAction::Logout => {
-                        return;
+                        return Ok(());
                     },
                 }
             }
         }
+        
+        Ok(())
     }
 }
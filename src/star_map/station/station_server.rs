@@ -1,16 +1,27 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Receiver, Select, Sender};
 
 use chat::ChatMsg;
 use login::AccountBox;
 use module::{ModelStore, ModuleStored};
-use net::{ClientId, ServerSlot, ServerSlotId, SlotInMsg, InPacket, OutPacket};
+use net::{ClientId, Encoded, ServerSlot, ServerSlotId, SlotEvent};
+use packet_types::ClientStationPacket;
 use star_map::StarMapAction;
 use star_map::station::{ShipEditAction, StationAction};
 
+// Clients at a station send it what they're doing, and it sends them their ship and chat
+pub type StationSlot = ServerSlot<StationAction, ClientStationPacket>;
+
+// Something that woke the station up
+enum StationEvent {
+    Slot(SlotEvent<StationAction>),
+    Chat(ChatMsg),
+    NewAccount(AccountBox),
+}
+
 pub struct StationServer {
-    slot: ServerSlot,
+    slot: StationSlot,
     star_map_slot_id: ServerSlotId,
     chat_sender: Sender<ChatMsg>,
     chat_receiver: Receiver<ChatMsg>,
@@ -24,7 +35,7 @@ pub struct StationServer {
 }
 
 impl StationServer {
-    pub fn new(slot: ServerSlot,
+    pub fn new(slot: StationSlot,
                star_map_slot_id: ServerSlotId,
                chat_sender: Sender<ChatMsg>,
                chat_receiver: Receiver<ChatMsg>,
@@ -45,54 +56,80 @@ impl StationServer {
     
     pub fn run(&mut self, ack: Sender<()>) {    
         loop {
-            ///////////////////////////////////////////////////////////
-            // Receiver ServerSlot messages
-            if let Ok(msg) = self.slot.try_receive() {
-                match msg {
-                    SlotInMsg::Joined(client_id) => {
-                        println!("Client {} joined station {}", client_id, self.slot.get_id());
-                    },
-                    SlotInMsg::Disconnected(client_id) => {
-                        println!("Client {} disconnected at station {}, logging out...", client_id, self.slot.get_id());
-                        
-                        let account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
-                        self.to_map_sender.send((account, StarMapAction::Logout));
-                    },
-                    SlotInMsg::ReceivedPacket(client_id, mut packet) => {
-                        self.handle_packet(client_id, &mut packet);
-                    },
-                }
-            }
-            
-            ///////////////////////////////////////////////////////////
-            // Receive messages from chat server
-            if let Ok(msg) = self.chat_receiver.try_recv() {
-                let mut msg_packet = OutPacket::new();
-                msg_packet.write(&msg).unwrap();
-                self.slot.broadcast(msg_packet);
-            }
-            
-            ///////////////////////////////////////////////////////////
-            // Receive new clients
-            if let Ok(mut account) = self.from_map_receiver.try_recv() {
-                let client_id = account.client_id.expect("This must have a client ID");
+            // Sleep until something happens
+            match self.next_event() {
+                ///////////////////////////////////////////////////////////
+                // Receiver ServerSlot messages
+                StationEvent::Slot(msg) => {
+                    match msg {
+                        SlotEvent::Joined(client_id) => {
+                            println!("Client {} joined station {}", client_id, self.slot.get_id());
+                        },
+                        SlotEvent::Disconnected(client_id) => {
+                            println!("Client {} disconnected at station {}, logging out...", client_id, self.slot.get_id());
+                            
+                            let account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
+                            self.to_map_sender.send((account, StarMapAction::Logout));
+                        },
+                        SlotEvent::Received(client_id, action) => {
+                            self.handle_action(client_id, action);
+                        },
+                        SlotEvent::Malformed(client_id, e) => {
+                            println!("Client {} sent station {} a bad packet: {}", client_id, self.slot.get_id(), e);
+                        },
+                        SlotEvent::Shutdown => {
+                            println!("Station {} shutting down", self.slot.get_id());
+                            return;
+                        },
+                    }
+                },
                 
-                // Send initial join packet
-                let mut packet = OutPacket::new();
-                packet.write(&account.ship).unwrap();
-                self.slot.send(client_id, packet);
+                ///////////////////////////////////////////////////////////
+                // Receive messages from chat server
+                StationEvent::Chat(msg) => {
+                    self.slot.broadcast(&ClientStationPacket::Chat(msg));
+                },
                 
-                // Add the player's account
-                self.accounts.insert(client_id, account);
-                
-                ack.send(());
+                ///////////////////////////////////////////////////////////
+                // Receive new clients
+                StationEvent::NewAccount(account) => {
+                    let client_id = account.client_id.expect("This must have a client ID");
+                    
+                    // Send initial join packet
+                    self.slot.send(client_id, &ClientStationPacket::Join(Encoded::new(&account.ship)));
+                    
+                    // Add the player's account
+                    self.accounts.insert(client_id, account);
+                    
+                    ack.send(());
+                },
             }
         }
     }
     
-    fn handle_packet(&mut self, client_id: ClientId, packet: &mut InPacket) {
-        let action: StationAction = packet.read().ok().expect("Failed to read StationAction packet");
-
+    // Block until a message arrives on any of the station's channels
+    fn next_event(&self) -> StationEvent {
+        let select = Select::new();
+        let mut slot_h = select.handle(self.slot.receiver());
+        let mut chat_h = select.handle(&self.chat_receiver);
+        let mut from_map_h = select.handle(&self.from_map_receiver);
+        unsafe {
+            slot_h.add();
+            chat_h.add();
+            from_map_h.add();
+        }
+        
+        let ready_id = select.wait();
+        if ready_id == slot_h.id() {
+            StationEvent::Slot(self.slot.decode(slot_h.recv().ok().expect("Failed to receive SlotInMsg")))
+        } else if ready_id == chat_h.id() {
+            StationEvent::Chat(chat_h.recv().ok().expect("Station chat channel is broken"))
+        } else {
+            StationEvent::NewAccount(from_map_h.recv().ok().expect("Station star map channel is broken"))
+        }
+    }
+    
+    fn handle_action(&mut self, client_id: ClientId, action: StationAction) {
         match action {
             StationAction::Jump(sector) => {
                 let mut account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
@@ -0,0 +1,53 @@
+use ship::Ship;
+
+// Which side a ship is on. Ships on the same team are always allies, and each sector says which
+// teams are allied with each other there. Ships without a team are on their own.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub struct TeamId(pub u8);
+
+// NPC raiders, who gang up on everyone else
+pub const RAIDERS: TeamId = TeamId(1);
+
+// How allies and enemies show up on the player's screen
+#[cfg(feature = "client")]
+pub const ALLY_COLOR: [f32; 4] = [0.0, 0.8, 0.8, 1.0];
+#[cfg(feature = "client")]
+pub const ENEMY_COLOR: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+
+// Who's on whose side in a sector
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct Alliances {
+    // Pairs of teams that are allied
+    pub allied: Vec<(TeamId, TeamId)>,
+
+    // Whether ships can target their allies. The server drops targets on allies from the plans
+    // when it's off.
+    pub friendly_fire: bool,
+}
+
+impl Alliances {
+    // Everyone for themselves
+    pub fn new() -> Alliances {
+        Alliances {
+            allied: vec!(),
+            friendly_fire: true,
+        }
+    }
+
+    // A ship counts as its own ally
+    pub fn are_allies(&self, a: &Ship, b: &Ship) -> bool {
+        if a.id == b.id {
+            return true;
+        }
+
+        match (a.team, b.team) {
+            (Some(a), Some(b)) => a == b || self.allied.iter().any(|&(x, y)| (x == a && y == b) || (x == b && y == a)),
+            _ => false,
+        }
+    }
+
+    // Ships can always target themselves, for their shields and repairs
+    pub fn can_target(&self, ship: &Ship, target: &Ship) -> bool {
+        ship.id == target.id || self.friendly_fire || !self.are_allies(ship, target)
+    }
+}
@@ -0,0 +1,167 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex, Once, ONCE_INIT};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use time;
+
+// Fires by sending on its channel when its deadline passes, once or every interval. Meant to be
+// waited on alongside other channels with `std::sync::mpsc::Select`, so a thread can block until
+// either a message arrives or it's time to do something.
+pub struct Timer {
+    receiver: Receiver<()>,
+
+    // Held by timers that are never going to fire, so their channel stays open
+    _sender: Option<Sender<()>>,
+}
+
+impl Timer {
+    // A timer that never fires
+    pub fn never() -> Timer {
+        let (sender, receiver) = channel();
+        Timer {
+            receiver: receiver,
+            _sender: Some(sender),
+        }
+    }
+
+    pub fn at(deadline: time::Timespec) -> Timer {
+        let (sender, receiver) = channel();
+
+        scheduler().schedule(Deadline { at: deadline, period: None, sender: sender });
+
+        Timer {
+            receiver: receiver,
+            _sender: None,
+        }
+    }
+
+    pub fn after(duration: time::Duration) -> Timer {
+        Timer::at(time::now().to_timespec() + duration)
+    }
+
+    // Fires every `interval` until it's dropped, so a loop doesn't need a new timer each time
+    pub fn every(interval: time::Duration) -> Timer {
+        let (sender, receiver) = channel();
+
+        scheduler().schedule(Deadline { at: time::now().to_timespec() + interval, period: Some(interval), sender: sender });
+
+        Timer {
+            receiver: receiver,
+            _sender: None,
+        }
+    }
+
+    pub fn receiver(&self) -> &Receiver<()> {
+        &self.receiver
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// A timer waiting to go off
+struct Deadline {
+    at: time::Timespec,
+    period: Option<time::Duration>, // Set for timers that go off repeatedly
+    sender: Sender<()>,
+}
+
+// Ordered soonest first, so the heap's top is the next to fire
+impl Ord for Deadline {
+    fn cmp(&self, other: &Deadline) -> Ordering {
+        other.at.cmp(&self.at)
+    }
+}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Deadline) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Deadline) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Deadline { }
+
+// Every timer in the process is fired from one thread, so making a timer never costs a thread,
+// however many connections are re-arming them
+struct Scheduler {
+    deadlines: Mutex<BinaryHeap<Deadline>>,
+
+    // Wakes the timer thread up when a deadline is added, in case it's sooner than the ones it's
+    // waiting on
+    added: Condvar,
+}
+
+impl Scheduler {
+    fn schedule(&self, deadline: Deadline) {
+        self.deadlines.lock().ok().expect("Timer deadlines lock is poisoned").push(deadline);
+        self.added.notify_one();
+    }
+
+    fn run(&self) {
+        let mut deadlines = self.deadlines.lock().ok().expect("Timer deadlines lock is poisoned");
+        loop {
+            let now = time::now().to_timespec();
+            while deadlines.peek().map_or(false, |deadline| deadline.at <= now) {
+                let deadline = deadlines.pop().expect("Peeked deadline must be there");
+
+                // If nobody's listening anymore the timer was dropped, which is fine, and it
+                // doesn't need to go off again
+                if deadline.sender.send(()).is_ok() {
+                    if let Some(period) = deadline.period {
+                        // A timer that fell behind skips the ticks it missed rather than
+                        // firing them all at once
+                        let next = deadline.at + period;
+                        deadlines.push(Deadline {
+                            at: if next > now { next } else { now + period },
+                            period: deadline.period,
+                            sender: deadline.sender,
+                        });
+                    }
+                }
+            }
+
+            let wait = deadlines.peek().map(|deadline| deadline.at - now);
+            deadlines =
+                match wait {
+                    Some(wait) => {
+                        let wait_ms = wait.num_milliseconds();
+                        let wait_ms = if wait_ms > 0 { wait_ms as u64 } else { 1 };
+                        self.added.wait_timeout(deadlines, Duration::from_millis(wait_ms))
+                            .ok().expect("Timer deadlines lock is poisoned").0
+                    },
+                    None => self.added.wait(deadlines).ok().expect("Timer deadlines lock is poisoned"),
+                };
+        }
+    }
+}
+
+// The process's scheduler, started with the first timer that needs it
+fn scheduler() -> &'static Scheduler {
+    static START: Once = ONCE_INIT;
+    static mut SCHEDULER: *const Scheduler = 0 as *const Scheduler;
+
+    unsafe {
+        START.call_once(|| {
+            let scheduler = Box::new(Scheduler {
+                deadlines: Mutex::new(BinaryHeap::new()),
+                added: Condvar::new(),
+            });
+
+            // Never freed, since the thread runs as long as the process does
+            SCHEDULER = Box::into_raw(scheduler);
+
+            thread::Builder::new().name("timer".to_string()).spawn(|| {
+                (*SCHEDULER).run();
+            }).ok().expect("Failed to start timer thread");
+        });
+
+        &*SCHEDULER
+    }
+}
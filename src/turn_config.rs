@@ -0,0 +1,83 @@
+// How a sector paces its turns. Every turn starts with a tick from the server. Players plan until
+// their plans are due, the server simulates the turn once planning is over, and the next turn
+// starts `turn_ms` after this one did. Meanwhile clients play back the last turn's simulation,
+// which lasts `ticks_per_turn` ticks.
+//
+// Each sector has its own, and sends it to players when they join so both sides keep time the
+// same way.
+#[derive(Clone, Copy, RustcEncodable, RustcDecodable)]
+pub struct TurnConfig {
+    pub planning_ms: u32,
+    pub turn_ms: u32,
+    
+    // When clients send their plans if the player doesn't say they're ready first. Leaves time for
+    // the plans to reach the server before planning is over.
+    pub send_plans_ms: u32,
+    
+    pub ticks_per_turn: u32,
+    pub ticks_per_second: u32,
+}
+
+impl TurnConfig {
+    // Regular 5 second turns
+    pub fn new() -> TurnConfig {
+        TurnConfig {
+            planning_ms: 3500,
+            turn_ms: 5000,
+            send_plans_ms: 2500,
+            ticks_per_turn: 100,
+            ticks_per_second: 20,
+        }
+    }
+    
+    // 2 second turns for fast and loose fights
+    pub fn blitz() -> TurnConfig {
+        TurnConfig {
+            planning_ms: 1400,
+            turn_ms: 2000,
+            send_plans_ms: 1000,
+            ticks_per_turn: 40,
+            ticks_per_second: 20,
+        }
+    }
+    
+    // 15 second turns to think things through. Battles play out as fast as regular ones, there's
+    // just more time to plan afterwards.
+    pub fn tactical() -> TurnConfig {
+        TurnConfig {
+            planning_ms: 13500,
+            turn_ms: 15000,
+            send_plans_ms: 12500,
+            ticks_per_turn: 100,
+            ticks_per_second: 20,
+        }
+    }
+    
+    pub fn tick_to_time(&self, tick: u32) -> f64 {
+        tick as f64 / self.ticks_per_second as f64
+    }
+    
+    // Which tick of the simulation is playing `time` seconds into it
+    pub fn time_to_tick(&self, time: f64) -> u32 {
+        (time * self.ticks_per_second as f64) as u32
+    }
+    
+    // How long a turn's simulation takes to play out, in seconds
+    pub fn sim_seconds(&self) -> f64 {
+        self.tick_to_time(self.ticks_per_turn)
+    }
+    
+    pub fn turn_seconds(&self) -> f64 {
+        self.turn_ms as f64 / 1000.0
+    }
+    
+    // How long effects have to last to stay on screen for a whole turn, with time to spare in case
+    // the next tick is late
+    pub fn effects_end(&self) -> f64 {
+        self.turn_seconds() + 2.0
+    }
+    
+    pub fn send_plans_seconds(&self) -> f64 {
+        self.send_plans_ms as f64 / 1000.0
+    }
+}
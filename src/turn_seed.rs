@@ -0,0 +1,58 @@
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use ship::ShipId;
+
+// Everything random in a battle comes from the turn's seed, so a turn simulated again from the same
+// seed and plans plays out exactly the same way. Each sector picks a seed when it starts, and a
+// turn's seed is that paired with the turn number. The two get mixed into each generator.
+//
+// Whatever needs random numbers gets its own generator, keyed on what it's for and whose it is, so
+// the order ships and modules get simulated in doesn't change what they roll.
+#[derive(Clone, Copy, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct TurnSeed {
+    pub sector_seed: u32,
+    pub turn_number: u32,
+}
+
+// What the random numbers are for
+#[derive(Clone, Copy, Debug)]
+pub enum RngStream {
+    Ai,
+    Spawn,
+    HitRolls,
+    DamageVisuals,
+}
+
+impl TurnSeed {
+    pub fn new(sector_seed: u32) -> TurnSeed {
+        TurnSeed {
+            sector_seed: sector_seed,
+            turn_number: 0,
+        }
+    }
+
+    pub fn for_turn(&self, turn_number: u32) -> TurnSeed {
+        TurnSeed {
+            sector_seed: self.sector_seed,
+            turn_number: turn_number,
+        }
+    }
+
+    pub fn rng(&self, stream: RngStream, ship: ShipId, key: u32) -> XorShiftRng {
+        let mut seed = [self.sector_seed ^ (stream as u32), self.turn_number, (ship ^ (ship >> 32)) as u32, key];
+
+        // XorShift can't be seeded with all zeroes
+        if seed == [0, 0, 0, 0] {
+            seed[3] = 1;
+        }
+
+        let mut rng = XorShiftRng::from_seed(seed);
+
+        // Seeds this close together start out giving similar numbers, so run the generator a bit first
+        for _ in 0..8 {
+            rng.next_u32();
+        }
+
+        rng
+    }
+}